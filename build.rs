@@ -0,0 +1,15 @@
+/*
+ * File: build.rs
+ *      Generates the gRPC service from proto/tao.proto. protoc comes from
+ *      protoc-bin-vendored unless PROTOC points at one already.
+ */
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    if std::env::var_os("PROTOC").is_none() {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    }
+    println!("cargo:rerun-if-changed=proto/tao.proto");
+    tonic_build::configure()
+        .build_client(false)
+        .compile_protos(&["proto/tao.proto"], &["proto"])?;
+    Ok(())
+}
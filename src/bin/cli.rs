@@ -1,12 +1,16 @@
 use std::env;
+use std::path::PathBuf;
 
-use awc::Client;
-
-use encrypted_tao::service;
+use encrypted_tao::service::client::{ClientOptions, TaoClient};
 
 pub struct Config {
     pub server_addr: String,
     pub server_port: String,
+    pub server_tls: bool,
+    pub server_ca_file: Option<PathBuf>,
+    pub client_cert_file: Option<PathBuf>,
+    pub client_key_file: Option<PathBuf>,
+    pub token: Option<String>,
 }
 
 impl Config {
@@ -14,9 +18,25 @@ impl Config {
         dotenv::from_path(env_path).ok();
         let server_addr = dotenv::var("SERVER_ADDR").unwrap();
         let server_port = dotenv::var("SERVER_PORT").unwrap();
+        let server_tls = match dotenv::var("SERVER_TLS") {
+            Ok(v) => v == "on",
+            Err(_) => false,
+        };
+        let server_ca_file =
+            dotenv::var("SERVER_CA_FILE").ok().map(PathBuf::from);
+        let client_cert_file =
+            dotenv::var("CLIENT_CERT_FILE").ok().map(PathBuf::from);
+        let client_key_file =
+            dotenv::var("CLIENT_KEY_FILE").ok().map(PathBuf::from);
+        let token = dotenv::var("TAO_TOKEN").ok();
         Config {
             server_addr,
             server_port,
+            server_tls,
+            server_ca_file,
+            client_cert_file,
+            client_key_file,
+            token,
         }
     }
 }
@@ -44,25 +64,6 @@ fn print_help() {
     println!("--------------------------------------------------------------------------");
 }
 
-async fn execute_tao_query(
-    host: String,
-    port: String,
-    query: String,
-) -> service::tao::QueryResponse {
-    let client = Client::new();
-    let endpoint = format!("http://{}:{}/query", host, port);
-    let resp = client
-        .post(endpoint)
-        .send_json(&service::tao::QueryRequest { query: query })
-        .await
-        .unwrap()
-        .json::<service::tao::QueryResponse>()
-        .await
-        .unwrap();
-
-    return resp;
-}
-
 #[actix_rt::main]
 async fn main() {
     let mut env_path = env!("CARGO_MANIFEST_DIR").to_string();
@@ -74,13 +75,23 @@ async fn main() {
     match query.as_str() {
         "--help" => print_help(),
         _ => {
-            let res = execute_tao_query(
-                config.server_addr,
-                config.server_port,
-                query.to_string(),
+            let options = ClientOptions {
+                tls: config.server_tls,
+                ca_file: config.server_ca_file,
+                cert_file: config.client_cert_file,
+                key_file: config.client_key_file,
+                token: config.token,
+            };
+            let client = TaoClient::new(
+                &config.server_addr,
+                &config.server_port,
+                options,
             )
-            .await;
-            println!("\n{:#?}\n", res)
+            .unwrap_or_else(|e| panic!("{}", e));
+            match client.query(query.to_string()).await {
+                Ok(res) => println!("\n{:#?}\n", res),
+                Err(e) => eprintln!("{}", e),
+            }
         }
     }
 }
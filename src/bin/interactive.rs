@@ -1,35 +1,38 @@
-use std::env;
 use std::io::{self, Write};
+use std::path::PathBuf;
 
-use awc::Client;
+use clap::Parser;
 
-use encrypted_tao::service;
+use encrypted_tao::service::client::{ClientOptions, TaoClient};
 
-async fn execute_tao_query(
+#[derive(Parser)]
+#[command(name = "tao-interactive", about = "Interactive TAO client")]
+struct Args {
     host: String,
     port: String,
-    query: String,
-) -> service::tao::QueryResponse {
-    let client = Client::new();
-    let endpoint = format!("http://{}:{}/query", host, port);
-    let resp = client
-        .post(endpoint)
-        .send_json(&service::tao::QueryRequest { query: query })
-        .await
-        .unwrap()
-        .json::<service::tao::QueryResponse>()
-        .await
-        .unwrap();
-
-    return resp;
+    /// Connect over https
+    #[arg(long)]
+    tls: bool,
+    /// Only trust server certificates issued by this CA, implies --tls
+    #[arg(long)]
+    ca_file: Option<PathBuf>,
+    /// Client certificate for mutual TLS, implies --tls
+    #[arg(long, requires = "key_file")]
+    cert_file: Option<PathBuf>,
+    /// Private key of the client certificate
+    #[arg(long, requires = "cert_file")]
+    key_file: Option<PathBuf>,
+    /// API token, prefer the environment over the command line
+    #[arg(long, env = "TAO_TOKEN", hide_env_values = true)]
+    token: Option<String>,
 }
 
-fn print_header(host: String, port: String) {
+fn print_header(endpoint: &str) {
     println!("==========================================================================");
     println!("TAO COMMAND LINE INTERFACE");
     println!("==========================================================================");
     println!("");
-    println!("Connecting to... host: {:#?} port: {:#?}", host, port);
+    println!("Connecting to... {}", endpoint);
     println!("");
 }
 
@@ -58,12 +61,18 @@ fn print_help() {
 
 #[actix_rt::main]
 async fn main() {
-    let args: Vec<String> = env::args().collect();
-
-    let host = &args[1];
-    let port = &args[2];
+    let args = Args::parse();
+    let options = ClientOptions {
+        tls: args.tls,
+        ca_file: args.ca_file,
+        cert_file: args.cert_file,
+        key_file: args.key_file,
+        token: args.token,
+    };
+    let client = TaoClient::new(&args.host, &args.port, options)
+        .unwrap_or_else(|e| panic!("{}", e));
 
-    print_header(host.to_string(), port.to_string());
+    print_header(client.endpoint());
     print_help();
 
     loop {
@@ -74,16 +83,16 @@ async fn main() {
             .read_line(&mut query)
             .expect("Failed to read line");
         let query = query.trim_end();
-        let res = execute_tao_query(
-            host.to_string(),
-            port.to_string(),
-            query.to_string(),
-        )
-        .await;
+        let res = client.query(query.to_string()).await;
         println!("");
         println!("Query: {:#?}", query);
-        println!("Result:");
-        println!("{:#?}", res);
+        match res {
+            Ok(res) => {
+                println!("Result:");
+                println!("{:#?}", res);
+            }
+            Err(e) => println!("Error: {}", e),
+        }
         println!("");
     }
 }
@@ -1,32 +1,538 @@
-use std::env;
-use std::sync::Mutex;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
-use actix_web::{web::Data, App, HttpServer};
+use actix_web::dev::ServerHandle;
+use actix_web::{
+    middleware,
+    web::{Data, JsonConfig},
+    App, HttpServer,
+};
+use tokio::time::Instant;
+use tokio_util::io::ReaderStream;
+use tracing::{error, info, warn};
 
+use encrypted_tao::query::crypto::CryptKeys;
 use encrypted_tao::service;
+use encrypted_tao::service::audit::{self, Repair};
+use encrypted_tao::service::auth::{self, Authenticator};
+use encrypted_tao::service::backup;
+use encrypted_tao::service::config::{
+    ServerArgs, ServerCommand, ServerConfig, StorageBackend,
+};
+use encrypted_tao::service::grpc::{self, TaoGrpc};
+use encrypted_tao::service::import::{self, ImportOptions};
+use encrypted_tao::service::store::SqliteStore;
+use encrypted_tao::service::tao::TaoServer;
+use encrypted_tao::service::tier::TierRole;
+use encrypted_tao::service::{migrate, shutdown, telemetry, tls};
+
+/// Time the database connections get to close once the server stopped
+const CLOSE_WAIT: Duration = Duration::from_millis(100);
+
+fn exit_with(err: String) -> ! {
+    eprintln!("tao-server: {}", err);
+    process::exit(1);
+}
+
+/*
+ * create_sqlite_tables(args, dry_run)
+ *      SQLite files get their tables when opened, migrate only does so
+ *      ahead of the first start.
+ */
+fn create_sqlite_tables(args: &ServerArgs, dry_run: bool) {
+    let namespaces = args.namespaces().unwrap_or_else(|e| exit_with(e));
+    if !dry_run {
+        SqliteStore::open(&args.sqlite_file, &namespaces)
+            .unwrap_or_else(|e| exit_with(e));
+    }
+    for namespace in &namespaces {
+        println!(
+            "[{}] Tables {} in {}",
+            namespace.name,
+            match dry_run {
+                true => "are created on startup",
+                false => "are up to date",
+            },
+            args.sqlite_file.display()
+        );
+    }
+}
+
+async fn run_migrations(args: &ServerArgs, dry_run: bool) {
+    if args.storage == StorageBackend::Sqlite {
+        return create_sqlite_tables(args, dry_run);
+    }
+    let shards = args.shards().unwrap_or_else(|e| exit_with(e));
+    let namespaces = args.namespaces().unwrap_or_else(|e| exit_with(e));
+
+    for (i, shard) in shards.iter().enumerate() {
+        let mut client = migrate::db_connect(shard)
+            .await
+            .unwrap_or_else(|e| exit_with(format!("shard {}: {}", i, e)));
+
+        for namespace in &namespaces {
+            let label = match shards.len() {
+                1 => namespace.name.clone(),
+                _ => format!("{}, shard {}", namespace.name, i),
+            };
+            let applied = migrate::migrate(&mut client, namespace, dry_run)
+                .await
+                .unwrap_or_else(|e| {
+                    exit_with(format!("migration of {} failed: {}", label, e))
+                });
+
+            if applied.is_empty() {
+                println!("[{}] Schema is up to date", label);
+            }
+            for migration in applied {
+                match dry_run {
+                    true => println!(
+                        "[{}] Pending {:04} {}\n{}",
+                        label,
+                        migration.version,
+                        migration.name,
+                        migration.render(namespace)
+                    ),
+                    false => println!(
+                        "[{}] Applied {:04} {}",
+                        label, migration.version, migration.name
+                    ),
+                }
+            }
+        }
+    }
+}
+
+/*
+ * run_import(args)
+ *      `tao-server import`, see import.rs. The progress file is rewritten
+ *      after every batch, a rerun skips the lines it counts.
+ */
+async fn run_import(mut args: ServerArgs) {
+    let (file, format, namespace, progress_file, errors_file) =
+        match args.command.take() {
+            Some(ServerCommand::Import {
+                file,
+                format,
+                namespace,
+                progress_file,
+                errors_file,
+            }) => (file, format, namespace, progress_file, errors_file),
+            _ => return,
+        };
+    let config = ServerConfig::from_args(args).unwrap_or_else(|e| exit_with(e));
+    let tao = TaoServer::new(config).unwrap_or_else(|e| exit_with(e));
+    if tao.tier.role != TierRole::Leader {
+        exit_with("imports run on the leader".to_string());
+    }
+    let ns = tao.namespaces.get(&namespace).unwrap_or_else(|| {
+        exit_with(format!("unknown namespace {}", namespace))
+    });
+    let skip = match &progress_file {
+        Some(path) => import::read_progress(path).unwrap_or_else(|e| {
+            exit_with(format!("{}: {}", path.display(), e))
+        }),
+        None => 0,
+    };
+    let source = tokio::fs::File::open(&file).await.unwrap_or_else(|e| {
+        exit_with(format!("cannot open {}: {}", file.display(), e))
+    });
+    let mut errors = errors_file.as_ref().map(|path| {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap_or_else(|e| {
+                exit_with(format!("cannot open {}: {}", path.display(), e))
+            })
+    });
+
+    let options = ImportOptions {
+        format,
+        skip,
+        batch_size: tao.import_batch_size,
+    };
+    let lines =
+        import::lines(ReaderStream::new(source), tao.limits.max_body_bytes);
+    let report =
+        import::import(&tao, None, ns, options, lines, |done, failed| {
+            if let Some(errors) = &mut errors {
+                for row in failed {
+                    let line = serde_json::to_string(row)
+                        .map_err(|e| e.to_string())?;
+                    writeln!(errors, "{}", line).map_err(|e| e.to_string())?;
+                }
+            }
+            if let Some(path) = &progress_file {
+                import::write_progress(path, done)?;
+            }
+            Ok(())
+        })
+        .await;
+
+    println!(
+        "[{}] {} lines done: {} imported, {} already present, {} failed",
+        namespace, report.done, report.imported, report.existing, report.failed
+    );
+    if let Some(e) = report.error {
+        exit_with(format!("import stopped after line {}: {}", report.done, e));
+    }
+}
+
+/*
+ * run_export(args)
+ *      Writes the backup next to its file and renames it into place once
+ *      complete, readable by the owner only. The key files given to
+ *      --plaintext are only read for their KeyIds, which must be the
+ *      namespace's.
+ */
+async fn run_export(mut args: ServerArgs) {
+    let (file, namespace, plaintext) = match args.command.take() {
+        Some(ServerCommand::Export {
+            file,
+            namespace,
+            plaintext,
+        }) => (file, namespace, plaintext),
+        _ => return,
+    };
+    let plaintext =
+        plaintext.map(|files| match CryptKeys::load(&files[0], &files[1]) {
+            Ok(keys) => keys.ids(),
+            Err(e) => exit_with(format!("--plaintext: {}", e)),
+        });
+    let config = ServerConfig::from_args(args).unwrap_or_else(|e| exit_with(e));
+    let tao = TaoServer::new(config).unwrap_or_else(|e| exit_with(e));
+    let ns = tao.namespaces.get(&namespace).unwrap_or_else(|| {
+        exit_with(format!("unknown namespace {}", namespace))
+    });
+    let tmp = file.with_extension("tmp");
+    let out = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)
+        .unwrap_or_else(|e| {
+            exit_with(format!("cannot open {}: {}", tmp.display(), e))
+        });
+    let trailer = match backup::export(&tao, ns, plaintext.as_ref(), out).await
+    {
+        Ok(trailer) => trailer,
+        Err(e) => {
+            let _ = fs::remove_file(&tmp);
+            exit_with(format!("export failed: {}", e))
+        }
+    };
+    fs::rename(&tmp, &file).unwrap_or_else(|e| {
+        exit_with(format!("cannot write {}: {}", file.display(), e))
+    });
+    println!(
+        "[{}] {} objects and {} associations written to {}",
+        namespace,
+        trailer.objs,
+        trailer.assocs,
+        file.display()
+    );
+}
+
+/*
+ * run_restore(args)
+ *      Verifies the whole backup before loading any of it, and loads it from
+ *      the handle it verified.
+ */
+async fn run_restore(mut args: ServerArgs) {
+    let (file, namespace, verify_only) = match args.command.take() {
+        Some(ServerCommand::Restore {
+            file,
+            namespace,
+            verify_only,
+        }) => (file, namespace, verify_only),
+        _ => return,
+    };
+    let fail = |e: String| format!("{}: {}", file.display(), e);
+    let mut backup =
+        File::open(&file).unwrap_or_else(|e| exit_with(fail(e.to_string())));
+    let (manifest, trailer) =
+        backup::verify(&mut backup).unwrap_or_else(|e| exit_with(fail(e)));
+    println!(
+        "{}: {} objects and {} associations of namespace {}, {}",
+        file.display(),
+        trailer.objs,
+        trailer.assocs,
+        manifest.namespace,
+        match manifest.encrypted {
+            true => "encrypted",
+            false => "plaintext",
+        }
+    );
+    if verify_only {
+        return;
+    }
+    let namespace = namespace.unwrap_or_else(|| manifest.namespace.clone());
+    let config = ServerConfig::from_args(args).unwrap_or_else(|e| exit_with(e));
+    let tao = TaoServer::new(config).unwrap_or_else(|e| exit_with(e));
+    if tao.tier.role != TierRole::Leader {
+        exit_with("restores run on the leader".to_string());
+    }
+    let ns = tao.namespaces.get(&namespace).unwrap_or_else(|| {
+        exit_with(format!("unknown namespace {}", namespace))
+    });
+    let report = backup::restore(&tao, ns, &mut backup, &manifest)
+        .await
+        .unwrap_or_else(|e| exit_with(format!("restore failed: {}", e)));
+    println!(
+        "[{}] {} rows restored, {} already present",
+        namespace, report.restored, report.existing
+    );
+}
+
+/*
+ * drain_on_signal(tao, server, delay, grace)
+ *      On SIGTERM or SIGINT turns the server unready, keeps serving for
+ *      `delay`, then closes the listeners and lets requests in flight run
+ *      for `grace`. A second signal exits at once. Returns the end of the
+ *      grace period once the HTTP server stopped.
+ */
+async fn drain_on_signal(
+    tao: Arc<TaoServer>,
+    server: ServerHandle,
+    delay: Duration,
+    grace: Duration,
+) -> Instant {
+    let signal = match shutdown::terminated().await {
+        Ok(signal) => signal,
+        Err(e) => {
+            error!(error = %e, "cannot handle signals");
+            return std::future::pending().await;
+        }
+    };
+    tao.shutdown.begin();
+    info!(
+        signal,
+        in_flight = tao.shutdown.readiness().in_flight,
+        "draining"
+    );
+    let drain = async {
+        tokio::time::sleep(delay).await;
+        let deadline = Instant::now() + grace;
+        info!("listeners closed, waiting for requests in flight");
+        server.stop(true).await;
+        deadline
+    };
+    tokio::select! {
+        deadline = drain => deadline,
+        _ = shutdown::terminated() => {
+            warn!("exiting without waiting for requests in flight");
+            if let Err(e) = tao.audit.sync() {
+                error!(error = %e, "audit log not synced");
+            }
+            process::exit(1);
+        }
+    }
+}
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let env_path = &args[1];
-    let cache_size: usize = match args[2].parse() {
-        Ok(n) => n,
-        Err(e) => panic!("parse error {}", e),
+    let args = ServerArgs::load().unwrap_or_else(|e| exit_with(e));
+
+    match &args.command {
+        Some(ServerCommand::Keygen {
+            ope_key_file,
+            aes_keyset_file,
+        }) => {
+            CryptKeys::generate()
+                .write(ope_key_file, aes_keyset_file)
+                .unwrap_or_else(|e| exit_with(e));
+            println!(
+                "Wrote {} and {}",
+                ope_key_file.display(),
+                aes_keyset_file.display()
+            );
+            return Ok(());
+        }
+        Some(ServerCommand::Migrate { dry_run }) => {
+            run_migrations(&args, *dry_run).await;
+            return Ok(());
+        }
+        Some(ServerCommand::Token { principal }) => {
+            let token = auth::generate_token().unwrap_or_else(|e| exit_with(e));
+            println!("Token for {}: {}", principal, token);
+            println!("Add to the \"tokens\" of the auth file:");
+            println!("    \"{}\": \"{}\"", principal, auth::hash_token(&token));
+            return Ok(());
+        }
+        Some(ServerCommand::VerifyAudit {
+            audit_log_file,
+            repair,
+        }) => {
+            let path = audit_log_file.display();
+            if *repair {
+                match audit::repair(audit_log_file) {
+                    Ok(Repair::Intact) => {}
+                    Ok(Repair::Completed) => {
+                        println!("{}: completed the last record's line", path)
+                    }
+                    Ok(Repair::Truncated(bytes)) => println!(
+                        "{}: cut off a torn last record, {} bytes",
+                        path, bytes
+                    ),
+                    Err(e) => exit_with(format!("{}: {}", path, e)),
+                }
+            }
+            let (records, last_hash) = audit::verify(audit_log_file)
+                .unwrap_or_else(|e| exit_with(format!("{}: {}", path, e)));
+            println!(
+                "{}: {} records, chain intact, last hash {}",
+                path, records, last_hash
+            );
+            return Ok(());
+        }
+        Some(ServerCommand::Import { .. }) => {
+            run_import(args).await;
+            return Ok(());
+        }
+        Some(ServerCommand::Export { .. }) => {
+            run_export(args).await;
+            return Ok(());
+        }
+        Some(ServerCommand::Restore { .. }) => {
+            run_restore(args).await;
+            return Ok(());
+        }
+        None => (),
+    }
+
+    let config = ServerConfig::from_args(args).unwrap_or_else(|e| exit_with(e));
+    let telemetry = telemetry::init(&config).unwrap_or_else(|e| exit_with(e));
+    let bind_addr = (config.addr.clone(), config.port);
+    let workers = config.workers;
+    let grpc_port = config.grpc_port;
+    let shutdown_delay = Duration::from_secs(config.shutdown_delay_secs);
+    let shutdown_grace = Duration::from_secs(config.shutdown_grace_secs);
+    let max_body_bytes = config.limits.max_body_bytes;
+    let tls = match (&config.tls_cert_file, &config.tls_key_file) {
+        (Some(cert_file), Some(key_file)) => Some(
+            tls::server_acceptor(
+                cert_file,
+                key_file,
+                config.tls_client_ca_file.as_deref(),
+            )
+            .unwrap_or_else(|e| exit_with(e)),
+        ),
+        _ => None,
     };
-    let encrypt: bool = match args[3].as_str() {
-        "yes" => true,
-        _ => false,
+    let grpc_tls = match (&config.tls_cert_file, &config.tls_key_file) {
+        (Some(cert_file), Some(key_file)) if grpc_port.is_some() => Some(
+            grpc::tls_config(
+                cert_file,
+                key_file,
+                config.tls_client_ca_file.as_deref(),
+            )
+            .unwrap_or_else(|e| exit_with(e)),
+        ),
+        _ => None,
     };
-    
-    let mut tao_server = service::tao::TaoServer::new(env_path.to_string(), cache_size, encrypt);
-    let app_data = Data::new(Mutex::new(tao_server));
+    let authenticator = match &config.auth_file {
+        Some(path) => Authenticator::load(path),
+        None => Ok(Authenticator::disabled()),
+    }
+    .unwrap_or_else(|e| exit_with(e));
+    let authenticator = Arc::new(authenticator);
+
+    let tao_server =
+        service::tao::TaoServer::new(config).unwrap_or_else(|e| exit_with(e));
+    let tao_server = Arc::new(tao_server);
+
+    let grpc_server = grpc_port.map(|port| {
+        let threads = workers.unwrap_or_else(|| {
+            thread::available_parallelism().map_or(1, |n| n.get())
+        });
+        let grpc =
+            TaoGrpc::new(tao_server.clone(), authenticator.clone(), threads);
+        let addr = bind_addr.0.clone();
+        let draining = tao_server.shutdown.draining();
+        info!("gRPC API listening on {}:{}", addr, port);
+        actix_web::rt::spawn(async move {
+            let served = grpc::serve(grpc, &addr, port, grpc_tls, draining);
+            if let Err(e) = served.await {
+                exit_with(e);
+            }
+        })
+    });
 
-    HttpServer::new(move || {
+    let app_data = Data::from(tao_server.clone());
+    let authenticator = Data::from(authenticator);
+
+    let mut server = HttpServer::new(move || {
         App::new()
+            .wrap(middleware::from_fn(telemetry::http_request_span))
             .app_data(app_data.clone())
+            .app_data(authenticator.clone())
+            .app_data(JsonConfig::default().limit(max_body_bytes))
             .configure(service::tao::config)
     })
-    .bind(("localhost", 8080))?
-    .run()
-    .await
+    .on_connect(auth::on_connect)
+    .disable_signals()
+    .shutdown_timeout(shutdown_grace.as_secs());
+    if let Some(n) = workers {
+        server = server.workers(n);
+    }
+
+    let scheme = match tls {
+        Some(_) => "https",
+        None => "http",
+    };
+    info!(
+        "TAO server listening on {}://{}:{}",
+        scheme, bind_addr.0, bind_addr.1
+    );
+    let server = match tls {
+        Some(acceptor) => server.bind_openssl(bind_addr, acceptor)?,
+        None => server.bind(bind_addr)?,
+    }
+    .run();
+    let stopping = actix_web::rt::spawn(drain_on_signal(
+        tao_server.clone(),
+        server.handle(),
+        shutdown_delay,
+        shutdown_grace,
+    ));
+    let served = server.await;
+
+    // HTTP requests had the grace period, gRPC calls may still be running
+    let deadline = stopping.await.unwrap_or_else(|_| Instant::now());
+    let running = tao_server.shutdown.drained(deadline).await;
+    if running > 0 {
+        warn!(
+            running,
+            "pipelines cut short by the end of the grace period"
+        );
+    }
+    if let Some(grpc_server) = grpc_server {
+        let _ = tokio::time::timeout_at(deadline, grpc_server).await;
+    }
+    if let Err(e) = tao_server.audit.sync() {
+        error!(error = %e, "audit log not synced");
+    }
+    // Workers release the server as their threads end
+    let released = Instant::now() + CLOSE_WAIT * 10;
+    while Arc::strong_count(&tao_server) > 1 && Instant::now() < released {
+        tokio::time::sleep(CLOSE_WAIT / 10).await;
+    }
+    match Arc::try_unwrap(tao_server) {
+        // Dropping the store closes its connections
+        Ok(tao_server) => drop(tao_server),
+        Err(_) => warn!("database connections left open until exit"),
+    }
+    // Lets the connections' tasks tell the database they are closing
+    tokio::time::sleep(CLOSE_WAIT).await;
+    info!("TAO server stopped");
+    telemetry.shutdown().await;
+    let _ = std::io::stdout().flush();
+    served
 }
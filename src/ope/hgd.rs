@@ -12,17 +12,40 @@
  *          https://www.researchgate.net/publication/220492268_ALGORITHM_668_H2PEC_sampling_from_the_hypergeometric_distribution
  *          https://netlib.org/toms-2014-06-10/668
  *
- *
+ *      This is sampler 2, a faithful port of pyope. Sampler 1, which the
+ *      OPE ciphertexts of the first releases depend on, is in hgd_v1.rs.
  */
 
+use crate::ope::hgd_v1;
 use crate::ope::ope::ope::Range;
 use crate::ope::utils::generate_tape;
 use crypto::symmetriccipher::SynchronousStreamCipher;
 use std::cmp;
 
+/*
+ * Sampler
+ *      How coins are drawn and the hypergeometric distribution sampled.
+ *      Ciphertexts only decrypt with the sampler they were made with.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sampler {
+    V1,
+    V2,
+}
+
+impl Sampler {
+    pub fn version(self) -> u32 {
+        match self {
+            Sampler::V1 => 1,
+            Sampler::V2 => 2,
+        }
+    }
+}
+
 pub struct PRNG {
-    pub tape: [u32; 96],
+    pub tape: [u32; 128],
     pub cipher: Box<dyn SynchronousStreamCipher + 'static>,
+    pub sampler: Sampler,
 }
 
 impl PRNG {
@@ -43,7 +66,10 @@ impl PRNG {
             tmp = (tmp << 1) | coin;
         }
 
-        let ret = tmp as f64 / (u32::max_value() - 1) as f64;
+        let ret = match self.sampler {
+            Sampler::V1 => tmp as f64 / (u32::max_value() - 1) as f64,
+            Sampler::V2 => tmp as f64 / u32::max_value() as f64,
+        };
 
         // sanity check
         assert!(0.0 <= ret && ret <= 1.0);
@@ -83,7 +109,7 @@ pub fn log_gamma(x: u64) -> f64 {
         gl0 *= x2;
         gl0 += v[i];
     }
-    let mut gl = gl0 / x0 + 0.5 * xp.ln() + x0 - 0.5 * x0.ln() - x0;
+    let mut gl = gl0 / x0 + 0.5 * xp.ln() + (x0 - 0.5) * x0.ln() - x0;
 
     if x <= 7.0 {
         for _i in 1..(n + 1) {
@@ -98,16 +124,34 @@ pub fn log_gamma(x: u64) -> f64 {
 /*
  * hypergeo_sample
  *      Sample hypergeometric distribution using coins
- *      as a source of 'randomness'
+ *      as a source of 'randomness', with the sampler the coins are for
  */
 pub fn hypergeo_sample(
+    in_start: u64,
+    in_end: u64,
+    out_start: u64,
+    out_end: u64,
+    seed: u64,
+    coins: PRNG,
+) -> Result<u64, String> {
+    match coins.sampler {
+        Sampler::V1 => Ok(hgd_v1::hypergeo_sample(
+            in_start, in_end, out_start, out_end, seed, coins,
+        )),
+        Sampler::V2 => {
+            hypergeo_sample_v2(in_start, in_end, out_start, out_end, seed, coins)
+        }
+    }
+}
+
+fn hypergeo_sample_v2(
     in_start: u64,
     in_end: u64,
     out_start: u64,
     out_end: u64,
     seed: u64,
     mut coins: PRNG,
-) -> u64 {
+) -> Result<u64, String> {
     let mut in_range = Range {
         start: in_start,
         end: in_end,
@@ -117,7 +161,7 @@ pub fn hypergeo_sample(
         end: out_end,
     };
     let in_size = in_range.size();
-    let mut out_size = out_range.size();
+    let out_size = out_range.size();
     let index: u64 = seed - out_range.start + 1;
     let mut sample = 0;
 
@@ -128,7 +172,7 @@ pub fn hypergeo_sample(
 
     if in_size == out_size {
         /* Input and output range sizes are equal */
-        return in_range.start + index - 1;
+        return Ok(in_range.start + index - 1);
     } else if index > 10 {
         /* If Index > 10, H2PE (Hypergeometric-2 Points-Exponential Tails */
 
@@ -144,7 +188,7 @@ pub fn hypergeo_sample(
         let d4 = min as f64 / size as f64;
         let d5 = 1.0 - d4;
         let d6: f64 = min_sample as f64 * d4 + 0.5;
-        let d7: f64 = ((size - min) as f64 * index as f64 * d4 * d5
+        let d7: f64 = ((size - min_sample) as f64 * index as f64 * d4 * d5
             / (size - 1) as f64
             + 0.5)
             .sqrt();
@@ -177,11 +221,12 @@ pub fn hypergeo_sample(
                     + log_gamma(min_sample - z + 1)
                     + log_gamma(max - min_sample + z + 1));
 
-            if (x * (4.0 - x) - 3.0) as u64 <= t as u64 {
+            // Compared as floats, both sides may be negative
+            if x * (4.0 - x) - 3.0 <= t {
                 break;
             }
 
-            if (x * (x - t)) as u64 >= 1 {
+            if x * (x - t) >= 1.0 {
                 continue;
             }
 
@@ -193,45 +238,47 @@ pub fn hypergeo_sample(
         sample = z;
 
         if in_size > (out_size - in_size) {
-            sample = min_sample - z;
+            sample = min_sample - sample;
         }
 
         if min_sample < index {
-            sample = in_size - z;
+            sample = in_size - sample;
         }
     } else {
         /* If index <= 10, Inverse Transformation */
 
-        out_size = out_size - in_size;
-        let d1 = in_size + (out_size - in_size) - index;
-        let d2 = cmp::min(in_size, out_size - in_size);
+        // The input range holds the `good` items, the rest the `bad` ones
+        let bad = out_size - in_size;
+        let d1 = in_size + bad - index;
+        let d2 = cmp::min(in_size, bad);
 
         let mut y = d2;
         let mut k = index;
 
         while y > 0 {
             let u = coins.draw();
-            y -= (u + y as f64 / (d1 + k) as f64).floor() as u64;
+            let step = (u + y as f64 / (d1 + k) as f64).floor() as u64;
+            y = y.checked_sub(step).ok_or_else(|| {
+                format!("hypergeometric sample underflow at index {}", index)
+            })?;
             k -= 1;
 
             if k == 0 {
                 break;
             }
         }
-        let z = (d2 - y) as u64;
+        sample = d2 - y;
 
-        if in_size >= out_size - in_size {
-            sample = index - z;
+        if in_size > bad {
+            sample = index - sample;
         }
-
-        sample = z;
     }
     if sample == 0 {
-        return in_range.start;
+        return Ok(in_range.start);
     } else {
         sample = in_range.start + sample as u64 - 1;
         assert!(in_range.contains(sample));
 
-        return sample;
+        return Ok(sample);
     }
 }
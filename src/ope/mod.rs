@@ -1,4 +1,5 @@
 pub mod hgd;
+pub mod hgd_v1;
 pub mod ope;
 pub mod stats;
 pub mod utils;
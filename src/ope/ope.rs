@@ -17,7 +17,7 @@ extern crate hmac;
 
 pub mod ope {
 
-    use crate::ope::hgd::{hypergeo_sample, PRNG};
+    use crate::ope::hgd::{hypergeo_sample, Sampler, PRNG};
     use crate::ope::stats::uniform_sample;
     use crate::ope::utils::aes_init;
     use hmac::{Hmac, Mac};
@@ -52,6 +52,7 @@ pub mod ope {
         pub key: String,
         pub in_range: Range,
         pub out_range: Range,
+        pub sampler: Sampler,
     }
 
     impl OPE {
@@ -59,10 +60,9 @@ pub mod ope {
          * encrypt(self, plaintext)
          *  OPE is recursive encryption, check cases then recursive helper
          */
-        pub fn encrypt(&mut self, plaintext: u64) -> u64 {
+        pub fn encrypt(&mut self, plaintext: u64) -> Result<u64, String> {
             if !self.in_range.contains(plaintext) {
-                println!("range does not contain plaintext\n");
-                return 1 as u64;
+                return Err("range does not contain plaintext".to_string());
             }
 
             return self.recursive_encrypt(
@@ -81,7 +81,7 @@ pub mod ope {
             in_end: u64,
             out_start: u64,
             out_end: u64,
-        ) -> u64 {
+        ) -> Result<u64, String> {
             let mut in_range = Range {
                 start: in_start,
                 end: in_end,
@@ -109,14 +109,14 @@ pub mod ope {
             if in_range.size() == 1 {
                 let output = self.tape_gen(plaintext);
                 let ciphertext = uniform_sample(out_range, output);
-                return ciphertext;
+                return Ok(ciphertext);
             }
 
             let output = self.tape_gen(mid);
 
             let mut samples = hypergeo_sample(
                 in_start, in_end, out_start, out_end, mid, output,
-            );
+            )?;
 
             if plaintext <= samples {
                 if in_edge.checked_add(1).is_some() {
@@ -146,10 +146,9 @@ pub mod ope {
                 );
             }
         }
-        pub fn decrypt(&mut self, ciphertext: u64) -> u64 {
+        pub fn decrypt(&mut self, ciphertext: u64) -> Result<u64, String> {
             if !self.out_range.contains(ciphertext) {
-                println!("range does not contain ciphertext\n");
-                return 1 as u64;
+                return Err("range does not contain ciphertext".to_string());
             }
             return self.recursive_decrypt(
                 ciphertext,
@@ -167,7 +166,7 @@ pub mod ope {
             in_end: u64,
             out_start: u64,
             out_end: u64,
-        ) -> u64 {
+        ) -> Result<u64, String> {
             let mut in_range = Range {
                 start: in_start,
                 end: in_end,
@@ -194,14 +193,14 @@ pub mod ope {
             assert!(in_size <= out_size);
 
             if in_range.size() == 1 {
-                return in_range.start;
+                return Ok(in_range.start);
             }
 
             let output = self.tape_gen(mid);
 
             let mut samples = hypergeo_sample(
                 in_start, in_end, out_start, out_end, mid, output,
-            );
+            )?;
 
             if ciphertext <= mid {
                 if in_edge.checked_add(1).is_some() {
@@ -253,7 +252,8 @@ pub mod ope {
 
             let prng = PRNG {
                 cipher: cipher,
-                tape: [0; 96],
+                tape: [0; 128],
+                sampler: self.sampler,
             };
 
             return prng;
@@ -267,74 +267,81 @@ pub mod ope {
 #[cfg(test)]
 mod tests {
 
+    use crate::ope::hgd::Sampler;
     use crate::ope::ope::ope::Range;
     use crate::ope::ope::ope::OPE;
 
     pub const DEFAULT_INPUT_RANGE_END: u64 = u16::max_value() as u64 - 1;
     pub const DEFAULT_OUTPUT_RANGE_END: u64 = u32::max_value() as u64 - 1;
 
-    #[test]
-    fn test_encrypt() {
-        let mut test = OPE {
-            key: "ope-testing-key".to_string(),
+    fn ope(key: &str, start: u64, sampler: Sampler) -> OPE {
+        OPE {
+            key: key.to_string(),
             in_range: Range {
-                start: 1,
+                start,
                 end: DEFAULT_INPUT_RANGE_END,
             },
             out_range: Range {
-                start: 1,
+                start,
                 end: DEFAULT_OUTPUT_RANGE_END,
             },
-        };
+            sampler,
+        }
+    }
+
+    #[test]
+    fn test_encrypt() {
+        let mut test = ope("ope-testing-key", 1, Sampler::V1);
         let tests: [u64; 3] = [10, 100, 1000];
         let res: [u64; 3] = [131086, 4747723, 60293123];
 
         for i in 0..3 {
-            let encrypt = test.encrypt(tests[i]);
+            let encrypt = test.encrypt(tests[i]).unwrap();
+            assert_eq!(res[i], encrypt);
+        }
+    }
+
+    /*
+     * test_encrypt_v2
+     *      Sampler 2 draws other coins for the same key, so the same
+     *      plaintexts have other ciphertexts than with sampler 1.
+     */
+    #[test]
+    fn test_encrypt_v2() {
+        let mut test = ope("ope-testing-key", 1, Sampler::V2);
+        let tests: [u64; 3] = [10, 100, 1000];
+        let res: [u64; 3] = [361274, 5196781, 65549478];
+
+        for i in 0..3 {
+            let encrypt = test.encrypt(tests[i]).unwrap();
             assert_eq!(res[i], encrypt);
         }
     }
 
     #[test]
     fn test_ordering() {
-        let mut test = OPE {
-            key: "testing-key".to_string(),
-            in_range: Range {
-                start: 0 as u64,
-                end: DEFAULT_INPUT_RANGE_END,
-            },
-            out_range: Range {
-                start: 0 as u64,
-                end: DEFAULT_OUTPUT_RANGE_END,
-            },
-        };
-        let a = test.encrypt(25 as u64);
-        let b = test.encrypt(200 as u64);
-        let c = test.encrypt(2500 as u64);
-        println!("result of a: {}, b: {}, c: {}", a, b, c);
-
-        assert!(a < b);
-        assert!(b < c);
+        for sampler in [Sampler::V1, Sampler::V2] {
+            let mut test = ope("testing-key", 0, sampler);
+            let a = test.encrypt(25 as u64).unwrap();
+            let b = test.encrypt(200 as u64).unwrap();
+            let c = test.encrypt(2500 as u64).unwrap();
+            println!("result of a: {}, b: {}, c: {}", a, b, c);
+
+            assert!(a < b);
+            assert!(b < c);
+        }
     }
 
     #[test]
     fn test_decrypt() {
-        let mut test = OPE {
-            key: "ope-testing-key".to_string(),
-            in_range: Range {
-                start: 0,
-                end: DEFAULT_INPUT_RANGE_END,
-            },
-            out_range: Range {
-                start: 0,
-                end: DEFAULT_OUTPUT_RANGE_END,
-            },
-        };
+        for sampler in [Sampler::V1, Sampler::V2] {
+            let mut test = ope("ope-testing-key", 0, sampler);
 
-        let tests: [u64; 4] = [25, 50, 75, 750];
-        for i in 0..4 {
-            let encrypt = test.encrypt(tests[i]);
-            assert_eq!(tests[i], test.decrypt(encrypt));
+            let tests: [u64; 4] = [25, 50, 75, 750];
+            for i in 0..4 {
+                let encrypt = test.encrypt(tests[i]).unwrap();
+                assert_eq!(tests[i], test.decrypt(encrypt).unwrap());
+            }
         }
     }
 }
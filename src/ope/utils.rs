@@ -17,7 +17,7 @@ extern crate crypto; // https://github.com/RustCrypto/traits/tree/master/crypto
 extern crate hmac;
 extern crate sha2;
 
-use crate::ope::hgd::PRNG;
+use crate::ope::hgd::{Sampler, PRNG};
 use crypto::aes::{ctr, KeySize};
 use crypto::symmetriccipher::SynchronousStreamCipher;
 
@@ -32,39 +32,47 @@ pub fn aes_init(
     return aes_cipher;
 }
 
-pub fn generate_tape(prng: &mut PRNG) -> [u32; 96] {
+pub fn generate_tape(prng: &mut PRNG) -> Vec<u32> {
     let mut tape = [b'\x00'; 16];
 
     prng.cipher.process(&[b'\x00'; 16], &mut tape);
 
-    let bin_tape = convert_bitstring(tape);
+    let bin_tape = match prng.sampler {
+        Sampler::V1 => convert_bitstring_v1(tape).to_vec(),
+        Sampler::V2 => convert_bitstring(tape).to_vec(),
+    };
 
     return bin_tape;
 }
 
-pub fn generate_bytes(data: &mut [u8; 16]) -> [Vec<u8>; 16] {
-    let mut bin: [Vec<u8>; 16] = Default::default();
+/*
+ * convert_bitstring(data)
+ *      The bits of the keystream, most significant first, as pyope draws
+ *      its coins.
+ */
+pub fn convert_bitstring(data: [u8; 16]) -> [u32; 128] {
+    let mut ret = [0; 128];
 
-    for i in 0..16 {
-        let tmp: String = data[i].to_string();
-        bin[i] = tmp.clone().as_bytes().to_vec();
+    for (i, byte) in data.iter().enumerate() {
+        for bit in 0..8 {
+            ret[i * 8 + bit] = ((byte >> (7 - bit)) & 1) as u32;
+        }
     }
-
-    return bin;
-}
-pub fn byte_to_bits(byte: &mut Vec<u8>) -> String {
-    return format!("{:b}", byte[0]);
+    return ret;
 }
-pub fn convert_bitstring(mut data: [u8; 16]) -> [u32; 96] {
-    let bytes = generate_bytes(&mut data);
+
+/*
+ * convert_bitstring_v1(data)
+ *      The coins of sampler 1: for each keystream byte, the six bits of the
+ *      ASCII code of its first decimal digit instead of the byte's own.
+ */
+pub fn convert_bitstring_v1(data: [u8; 16]) -> [u32; 96] {
     let mut ret = [0; 96];
-    let mut index = 0;
 
-    for mut byte in bytes {
-        let bit = byte_to_bits(&mut byte);
-        for i in bit.chars() {
-            ret[index] = i.to_digit(2 /* u32 */).unwrap();
-            index += 1;
+    for (i, byte) in data.iter().enumerate() {
+        let digit = byte.to_string().as_bytes()[0];
+        for bit in 0..6 {
+            ret[i * 6 + bit] = ((digit >> (5 - bit)) & 1) as u32;
         }
     }
     return ret;
@@ -17,16 +17,25 @@ extern crate tink_daead;
 extern crate tink_proto;
 extern crate quick_cache;
 
+use crate::ope::hgd::Sampler;
 use crate::ope::ope::ope::Range;
 use crate::ope::ope::ope::OPE;
+use crate::query::error::TaoError;
 use crate::query::results::DBRow;
 use base64::{engine::general_purpose, Engine as _};
 use crypto::aes::{ctr, ecb_decryptor, ecb_encryptor, KeySize};
 use crypto::blockmodes::PkcsPadding;
 use crypto::symmetriccipher::{Decryptor, Encryptor, SynchronousStreamCipher};
+use std::fs::{self, File};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use tink_core::keyset::{insecure, Handle, JsonReader, JsonWriter};
+use tink_core::subtle::random;
 use tink_core::DeterministicAead;
-use tink_proto::KeyTemplate;
 use quick_cache::sync::{Cache};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 use crate::query::query::{Query, TaoArgs, TaoOp};
 use crypto::buffer::{
@@ -35,18 +44,140 @@ use crypto::buffer::{
 
 pub const DEFAULT_INPUT_RANGE_END: u64 = u16::max_value() as u64 - 1;
 pub const DEFAULT_OUTPUT_RANGE_END: u64 = u32::max_value() as u64 - 1;
+/// Encrypted bounds no ciphertext lies between
+pub const EMPTY_OPE_RANGE: (i64, i64) = (1, 0);
+
+/// Starts the OPE key files of sampler 2, files without it use sampler 1
+pub const OPE_KEY_V2_PREFIX: &str = "v2:";
 
 pub struct CryptKeys {
     ope_key: String,
-    aes_key: String,
+    ope_sampler: Sampler,
+    aes_keyset: Handle,
+}
+
+/*
+ * KeyIds
+ *      Names a pair of keys without giving them away: the first 8 bytes of
+ *      the SHA-256 of the OPE key, in hex, the version of the OPE sampler
+ *      the key is used with and the key ids of the keyset.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyIds {
+    pub ope: String,
+    #[serde(default = "first_sampler")]
+    pub ope_sampler: u32,
+    pub aes_primary: u32,
+    pub aes: Vec<u32>,
+}
+
+fn first_sampler() -> u32 {
+    Sampler::V1.version()
 }
 
 impl CryptKeys {
-    fn new(env_path: &String) -> Self {
-        dotenv::from_path(env_path).ok();
-        let ope_key = dotenv::var("OPE_KEY").unwrap();
-        let aes_key = dotenv::var("AES_KEY").unwrap();
-        CryptKeys { ope_key, aes_key }
+    /*
+     * load(ope_key_file, aes_keyset_file)
+     *      The OPE key file holds the key as a single line of text, the AES
+     *      keyset file is a cleartext tink keyset in JSON, as written by
+     *      `tao-server keygen`. Keys that start with OPE_KEY_V2_PREFIX use
+     *      sampler 2, older ones the sampler their ciphertexts were made
+     *      with.
+     */
+    pub fn load(
+        ope_key_file: &Path,
+        aes_keyset_file: &Path,
+    ) -> Result<Self, String> {
+        let ope_key = fs::read_to_string(ope_key_file)
+            .map_err(|e| {
+                format!(
+                    "cannot read OPE key file {}: {}",
+                    ope_key_file.display(),
+                    e
+                )
+            })?
+            .trim()
+            .to_string();
+        let (ope_key, ope_sampler) =
+            match ope_key.strip_prefix(OPE_KEY_V2_PREFIX) {
+                Some(key) => (key.to_string(), Sampler::V2),
+                None => (ope_key, Sampler::V1),
+            };
+        if ope_key.is_empty() {
+            return Err(format!(
+                "OPE key file {} is empty",
+                ope_key_file.display()
+            ));
+        }
+
+        tink_daead::init();
+        let file = File::open(aes_keyset_file).map_err(|e| {
+            format!(
+                "cannot read AES keyset file {}: {}",
+                aes_keyset_file.display(),
+                e
+            )
+        })?;
+        let aes_keyset = insecure::read(&mut JsonReader::new(file))
+            .map_err(|e| {
+                format!(
+                    "invalid AES keyset file {}: {}",
+                    aes_keyset_file.display(),
+                    e
+                )
+            })?;
+
+        Ok(CryptKeys {
+            ope_key,
+            ope_sampler,
+            aes_keyset,
+        })
+    }
+
+    /*
+     * generate()
+     *      Fresh random keys, ciphertexts produced with them cannot be
+     *      decrypted once the keys are dropped.
+     */
+    pub fn generate() -> Self {
+        tink_daead::init();
+        let ope_key = general_purpose::STANDARD_NO_PAD
+            .encode(random::get_random_bytes(32));
+        let aes_keyset = Handle::new(&tink_daead::aes_siv_key_template())
+            .unwrap();
+        CryptKeys {
+            ope_key,
+            ope_sampler: Sampler::V2,
+            aes_keyset,
+        }
+    }
+
+    pub fn ids(&self) -> KeyIds {
+        let digest = Sha256::digest(self.ope_key.as_bytes());
+        let info = self.aes_keyset.keyset_info();
+        KeyIds {
+            ope: digest[..8].iter().map(|b| format!("{:02x}", b)).collect(),
+            ope_sampler: self.ope_sampler.version(),
+            aes_primary: info.primary_key_id,
+            aes: info.key_info.iter().map(|key| key.key_id).collect(),
+        }
+    }
+
+    pub fn write(
+        &self,
+        ope_key_file: &Path,
+        aes_keyset_file: &Path,
+    ) -> Result<(), String> {
+        let prefix = match self.ope_sampler {
+            Sampler::V1 => "",
+            Sampler::V2 => OPE_KEY_V2_PREFIX,
+        };
+        fs::write(ope_key_file, format!("{}{}\n", prefix, self.ope_key))
+            .map_err(|e| format!("{}: {}", ope_key_file.display(), e))?;
+        let file = File::create(aes_keyset_file)
+            .map_err(|e| format!("{}: {}", aes_keyset_file.display(), e))?;
+        insecure::write(&self.aes_keyset, &mut JsonWriter::new(file))
+            .map_err(|e| format!("{}: {}", aes_keyset_file.display(), e))
     }
 }
 
@@ -55,12 +186,8 @@ pub struct Cipher {
 }
 
 impl Cipher {
-    fn mydefault() -> Cipher {
-        tink_daead::init();
-        let aeskey =
-            tink_core::keyset::Handle::new(&tink_daead::aes_siv_key_template())
-                .unwrap();
-        let aescipher = tink_daead::new(&aeskey).unwrap();
+    fn new(keyset: &Handle) -> Cipher {
+        let aescipher = tink_daead::new(keyset).unwrap();
 
         Cipher {
             aes_cipher: aescipher,
@@ -70,6 +197,29 @@ impl Cipher {
 unsafe impl Sync for Cipher {}
 unsafe impl Send for Cipher {}
 
+/*
+ * CryptoStats
+ *      Hit counts of the four caches, and the number and total time of the
+ *      OPE operations they missed.
+ */
+#[derive(Debug, Clone, Serialize)]
+pub struct CryptoStats {
+    pub caches: Vec<CryptoCacheStats>,
+    pub ope_encryptions: u64,
+    pub ope_encrypt_nanos: u64,
+    pub ope_decryptions: u64,
+    pub ope_decrypt_nanos: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CryptoCacheStats {
+    pub cache: &'static str,
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub capacity: u64,
+}
+
 pub struct TaoCrypto {
     keys: CryptKeys,
     cipher: Cipher,
@@ -77,19 +227,39 @@ pub struct TaoCrypto {
     aes_dec_cache: Cache<String, String>,
     ope_enc_cache: Cache<i64, i64>,
     ope_dec_cache: Cache<i64, i64>,
+    ope_encryptions: AtomicU64,
+    ope_encrypt_nanos: AtomicU64,
+    ope_decryptions: AtomicU64,
+    ope_decrypt_nanos: AtomicU64,
 }
 
-impl TaoCrypto {
-    pub fn new(env_path: &String, cache_size: usize) -> Self {
-        let keys = CryptKeys::new(env_path);
+fn cache_stats<K, V>(cache: &'static str, c: &Cache<K, V>) -> CryptoCacheStats
+where
+    K: Eq + std::hash::Hash + Clone,
+    V: Clone,
+{
+    CryptoCacheStats {
+        cache,
+        hits: c.hits(),
+        misses: c.misses(),
+        entries: c.len(),
+        capacity: c.capacity(),
+    }
+}
 
+impl TaoCrypto {
+    pub fn new(
+        keys: CryptKeys,
+        aes_cache_size: usize,
+        ope_cache_size: usize,
+    ) -> Self {
         tink_daead::init();
-        let mycipher: Cipher = Cipher::mydefault();
+        let mycipher: Cipher = Cipher::new(&keys.aes_keyset);
 
-        let aes_enc_cache = Cache::new(cache_size);
-        let aes_dec_cache = Cache::new(cache_size);
-        let ope_enc_cache = Cache::new(cache_size);
-        let ope_dec_cache = Cache::new(cache_size);
+        let aes_enc_cache = Cache::new(aes_cache_size);
+        let aes_dec_cache = Cache::new(aes_cache_size);
+        let ope_enc_cache = Cache::new(ope_cache_size);
+        let ope_dec_cache = Cache::new(ope_cache_size);
 
         TaoCrypto {
             keys,
@@ -98,10 +268,29 @@ impl TaoCrypto {
             aes_dec_cache: aes_dec_cache,
             ope_enc_cache: ope_enc_cache,
             ope_dec_cache: ope_dec_cache,
+            ope_encryptions: AtomicU64::new(0),
+            ope_encrypt_nanos: AtomicU64::new(0),
+            ope_decryptions: AtomicU64::new(0),
+            ope_decrypt_nanos: AtomicU64::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> CryptoStats {
+        CryptoStats {
+            caches: vec![
+                cache_stats("aes_enc", &self.aes_enc_cache),
+                cache_stats("aes_dec", &self.aes_dec_cache),
+                cache_stats("ope_enc", &self.ope_enc_cache),
+                cache_stats("ope_dec", &self.ope_dec_cache),
+            ],
+            ope_encryptions: self.ope_encryptions.load(Ordering::Relaxed),
+            ope_encrypt_nanos: self.ope_encrypt_nanos.load(Ordering::Relaxed),
+            ope_decryptions: self.ope_decryptions.load(Ordering::Relaxed),
+            ope_decrypt_nanos: self.ope_decrypt_nanos.load(Ordering::Relaxed),
         }
     }
 
-    pub fn encrypt_query(&self, query: Query) -> Query {
+    pub fn encrypt_query(&self, query: Query) -> Result<Query, TaoError> {
         let op = query.op;
         let args = match query.args {
             TaoArgs::AssocAddArgs {
@@ -114,7 +303,7 @@ impl TaoCrypto {
                 id1: self.encrypt_string(id1),
                 atype: self.encrypt_string(atype),
                 id2: self.encrypt_string(id2),
-                time: self.encrypt_ope(time),
+                time: self.encrypt_ope(time)?,
                 data: self.encrypt_string(data),
             },
             TaoArgs::AssocGetArgs { id, atype, idset } => {
@@ -130,13 +319,16 @@ impl TaoCrypto {
                 idset,
                 tstart,
                 tend,
-            } => TaoArgs::AssocRangeGetArgs {
-                id: self.encrypt_string(id),
-                atype: self.encrypt_string(atype),
-                idset: self.encrypt_idset(idset),
-                tstart: self.encrypt_ope(tstart),
-                tend: self.encrypt_ope(tend),
-            },
+            } => {
+                let (tstart, tend) = self.encrypt_ope_range(tstart, tend)?;
+                TaoArgs::AssocRangeGetArgs {
+                    id: self.encrypt_string(id),
+                    atype: self.encrypt_string(atype),
+                    idset: self.encrypt_idset(idset),
+                    tstart,
+                    tend,
+                }
+            }
             TaoArgs::AssocCountArgs { id, atype } => TaoArgs::AssocCountArgs {
                 id: self.encrypt_string(id),
                 atype: self.encrypt_string(atype),
@@ -147,13 +339,16 @@ impl TaoCrypto {
                 tstart,
                 tend,
                 lim,
-            } => TaoArgs::AssocRangeArgs {
-                id: self.encrypt_string(id),
-                atype: self.encrypt_string(atype),
-                tstart: self.encrypt_ope(tstart),
-                tend: self.encrypt_ope(tend),
-                lim: lim,
-            },
+            } => {
+                let (tstart, tend) = self.encrypt_ope_range(tstart, tend)?;
+                TaoArgs::AssocRangeArgs {
+                    id: self.encrypt_string(id),
+                    atype: self.encrypt_string(atype),
+                    tstart,
+                    tend,
+                    lim: lim,
+                }
+            }
             TaoArgs::ObjGetArgs { id } => TaoArgs::ObjGetArgs {
                 id: self.encrypt_string(id),
             },
@@ -162,19 +357,67 @@ impl TaoCrypto {
                 otype: self.encrypt_string(otype),
                 data: self.encrypt_string(data),
             },
+            TaoArgs::AssocDeleteArgs { id1, atype, id2 } => {
+                TaoArgs::AssocDeleteArgs {
+                    id1: self.encrypt_string(id1),
+                    atype: self.encrypt_string(atype),
+                    id2: self.encrypt_string(id2),
+                }
+            }
+            TaoArgs::ObjUpdateArgs { id, otype, data } => {
+                TaoArgs::ObjUpdateArgs {
+                    id: self.encrypt_string(id),
+                    otype: self.encrypt_string(otype),
+                    data: self.encrypt_string(data),
+                }
+            }
+            TaoArgs::ObjDeleteArgs { id } => TaoArgs::ObjDeleteArgs {
+                id: self.encrypt_string(id),
+            },
         };
 
-        Query { op: op, args: args }
+        Ok(Query { op: op, args: args })
     }
 
-    pub fn encrypt_ope(&self, data: i64) -> i64 {
+    /*
+     * encrypt_ope_range(self, tstart, tend)
+     *      No stored time lies outside the OPE input range, so a bound past
+     *      it is moved to its end. A range that misses the input range
+     *      entirely matches nothing, and becomes EMPTY_OPE_RANGE.
+     */
+    pub fn encrypt_ope_range(
+        &self,
+        tstart: i64,
+        tend: i64,
+    ) -> Result<(i64, i64), TaoError> {
+        let end = DEFAULT_INPUT_RANGE_END as i64;
+        if tstart > tend || tend < 1 || tstart > end {
+            return Ok(EMPTY_OPE_RANGE);
+        }
+        Ok((
+            self.encrypt_ope(tstart.max(1))?,
+            self.encrypt_ope(tend.min(end))?,
+        ))
+    }
+
+    pub fn encrypt_ope(&self, data: i64) -> Result<i64, TaoError> {
         let _cache_read = match self.ope_enc_cache.get(&data) {
-            Some(val) => return val,
+            Some(val) => return Ok(val),
             _ => (),
         };
 
+        let plaintext = match u64::try_from(data) {
+            Ok(v) if (1..=DEFAULT_INPUT_RANGE_END).contains(&v) => v,
+            _ => {
+                return Err(TaoError::Validation(format!(
+                    "time {} is outside 1..={}",
+                    data, DEFAULT_INPUT_RANGE_END
+                )))
+            }
+        };
+
         let mut ope = OPE {
-            key: "ope-testing-key".to_string(),
+            key: self.keys.ope_key.clone(),
             in_range: Range {
                 start: 1,
                 end: DEFAULT_INPUT_RANGE_END,
@@ -183,14 +426,18 @@ impl TaoCrypto {
                 start: 1,
                 end: DEFAULT_OUTPUT_RANGE_END,
             },
+            sampler: self.keys.ope_sampler,
         };
 
-        let encrypted = ope.encrypt(data.try_into().unwrap())
-                           .try_into()
-                           .unwrap();
+        let started = Instant::now();
+        let encrypted =
+            ope.encrypt(plaintext).map_err(TaoError::Crypto)? as i64;
+        self.ope_encrypt_nanos
+            .fetch_add(started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        self.ope_encryptions.fetch_add(1, Ordering::Relaxed);
         self.ope_enc_cache.insert(data, encrypted);
 
-        return encrypted;
+        return Ok(encrypted);
     }
 
     pub fn encrypt_string(&self, data: String) -> String {
@@ -220,8 +467,8 @@ impl TaoCrypto {
         return encrypt;
     }
 
-    pub fn decrypt_result(&mut self, row: DBRow) -> DBRow {
-        match row {
+    pub fn decrypt_result(&self, row: DBRow) -> Result<DBRow, TaoError> {
+        let row = match row {
             DBRow::AssocRow {
                 id1,
                 atype,
@@ -229,30 +476,41 @@ impl TaoCrypto {
                 t,
                 data,
             } => DBRow::AssocRow {
-                id1: self.decrypt_string(id1),
-                atype: self.decrypt_string(atype),
-                id2: self.decrypt_string(id2),
-                t: self.decrypt_ope(t),
-                data: self.decrypt_string(data),
+                id1: self.decrypt_string(id1)?,
+                atype: self.decrypt_string(atype)?,
+                id2: self.decrypt_string(id2)?,
+                t: self.decrypt_ope(t)?,
+                data: self.decrypt_string(data)?,
             },
             DBRow::ObjRow { id, otype, data } => DBRow::ObjRow {
-                id: self.decrypt_string(id),
-                otype: self.decrypt_string(otype),
-                data: self.decrypt_string(data),
+                id: self.decrypt_string(id)?,
+                otype: self.decrypt_string(otype)?,
+                data: self.decrypt_string(data)?,
             },
             DBRow::Count(n) => DBRow::Count(n),
-            DBRow::NoRes(_) => DBRow::NoRes(true),
-        }
+            DBRow::NoRes(existed) => DBRow::NoRes(existed),
+        };
+        Ok(row)
     }
 
-    pub fn decrypt_ope(&mut self, data: i64) -> i64 {
+    pub fn decrypt_ope(&self, data: i64) -> Result<i64, TaoError> {
         let _cache_read = match self.ope_dec_cache.get(&data) {
-            Some(val) => return val,
+            Some(val) => return Ok(val),
             _ => (),
         };
 
+        let ciphertext = match u64::try_from(data) {
+            Ok(v) if (1..=DEFAULT_OUTPUT_RANGE_END).contains(&v) => v,
+            _ => {
+                return Err(TaoError::Crypto(format!(
+                    "OPE ciphertext {} is out of range",
+                    data
+                )))
+            }
+        };
+
         let mut ope = OPE {
-            key: "ope-testing-key".to_string(),
+            key: self.keys.ope_key.clone(),
             in_range: Range {
                 start: 1,
                 end: DEFAULT_INPUT_RANGE_END,
@@ -261,34 +519,40 @@ impl TaoCrypto {
                 start: 1,
                 end: DEFAULT_OUTPUT_RANGE_END,
             },
+            sampler: self.keys.ope_sampler,
         };
 
-        let decrypted = ope.decrypt(data.try_into().unwrap()).try_into().unwrap();
+        let started = Instant::now();
+        let decrypted =
+            ope.decrypt(ciphertext).map_err(TaoError::Crypto)? as i64;
+        self.ope_decrypt_nanos
+            .fetch_add(started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        self.ope_decryptions.fetch_add(1, Ordering::Relaxed);
         self.ope_dec_cache.insert(data, decrypted);
 
-        return decrypted;
+        return Ok(decrypted);
     }
 
-    pub fn decrypt_string(&mut self, data: String) -> String {
+    pub fn decrypt_string(&self, data: String) -> Result<String, TaoError> {
         let _cache_read = match self.aes_dec_cache.get(&data) {
-            Some(val) => return val,
+            Some(val) => return Ok(val),
             _ => (),
         };
 
         let decoded = match general_purpose::STANDARD_NO_PAD.decode(data.clone()) {
             Ok(v) => v,
-            Err(e) => panic!("Decode Fail: {}", e),
+            Err(e) => return Err(TaoError::Crypto(format!("Decode Fail: {}", e))),
         };
         let decrypted = self
             .cipher
             .aes_cipher
             .decrypt_deterministically(&decoded, b"")
-            .unwrap();
+            .map_err(|e| TaoError::Crypto(e.to_string()))?;
         let plaintext: String = String::from_utf8_lossy(&decrypted).to_string();
 
         self.aes_dec_cache.insert(data.clone(), plaintext.clone());
 
-        return plaintext;
+        return Ok(plaintext);
     }
 }
 
@@ -300,11 +564,15 @@ impl TaoCrypto {
 mod tests {
     use crate::ope::ope::ope::Range;
     use crate::ope::ope::ope::OPE;
-    use crate::query::crypto::TaoCrypto;
+    use crate::query::crypto::{
+        CryptKeys, TaoCrypto, EMPTY_OPE_RANGE, OPE_KEY_V2_PREFIX,
+    };
+    use crate::query::error::TaoError;
+    use crate::service::testing::TempDir;
 
     #[test]
     fn test_encrypt_idset() {
-        let mut taocrypt = TaoCrypto::new(&"./.env".to_string(), 0);
+        let taocrypt = TaoCrypto::new(CryptKeys::generate(), 0, 0);
         let idset = vec!["78".to_string()];
         let encrypt = taocrypt.encrypt_idset(idset);
         println!("Encrypted ID set {:?}\n", encrypt);
@@ -313,11 +581,99 @@ mod tests {
 
     #[test]
     fn test_enccrypt_decrypt_string() {
-        let mut taocrypt = TaoCrypto::new(&"./.env".to_string(), 0);
+        let taocrypt = TaoCrypto::new(CryptKeys::generate(), 0, 0);
         let encrypt = taocrypt.encrypt_string("testing".to_string());
-        let decrypt = taocrypt.decrypt_string(encrypt.clone());
+        let decrypt = taocrypt.decrypt_string(encrypt.clone()).unwrap();
         println!("Encrypted String {:#?}\n", encrypt);
         println!("Decrypted String {:#?}\n", decrypt.as_bytes());
         assert_eq!(decrypt, "testing".to_string());
     }
+
+    #[test]
+    fn test_crypto_errors() {
+        let taocrypt = TaoCrypto::new(CryptKeys::generate(), 0, 0);
+        assert!(matches!(
+            taocrypt.encrypt_ope(-5),
+            Err(TaoError::Validation(_))
+        ));
+        assert!(matches!(
+            taocrypt.decrypt_string("not ciphertext".to_string()),
+            Err(TaoError::Crypto(_))
+        ));
+    }
+
+    #[test]
+    fn test_ope_range() {
+        let taocrypt = TaoCrypto::new(CryptKeys::generate(), 0, 0);
+        let encrypt = |time| taocrypt.encrypt_ope(time).unwrap();
+        let range = |tstart, tend| taocrypt.encrypt_ope_range(tstart, tend);
+        assert_eq!(range(10, 20).unwrap(), (encrypt(10), encrypt(20)));
+        assert_eq!(range(0, 20).unwrap(), (encrypt(1), encrypt(20)));
+        assert_eq!(range(10, 70000).unwrap(), (encrypt(10), encrypt(65534)));
+        assert_eq!(range(70000, 80000).unwrap(), EMPTY_OPE_RANGE);
+        assert_eq!(range(0, 0).unwrap(), EMPTY_OPE_RANGE);
+        assert_eq!(range(20, 10).unwrap(), EMPTY_OPE_RANGE);
+    }
+
+    #[test]
+    fn test_keys_roundtrip() {
+        let dir = TempDir::new("keys-roundtrip");
+        let ope_key_file = dir.join("ope.key");
+        let aes_keyset_file = dir.join("aes-keyset.json");
+        CryptKeys::generate()
+            .write(&ope_key_file, &aes_keyset_file)
+            .unwrap();
+
+        let first = TaoCrypto::new(
+            CryptKeys::load(&ope_key_file, &aes_keyset_file).unwrap(),
+            0,
+            0,
+        );
+        let second = TaoCrypto::new(
+            CryptKeys::load(&ope_key_file, &aes_keyset_file).unwrap(),
+            0,
+            0,
+        );
+        let encrypt = first.encrypt_string("testing".to_string());
+        assert_eq!(encrypt, second.encrypt_string("testing".to_string()));
+        assert_eq!(second.decrypt_string(encrypt).unwrap(), "testing".to_string());
+        assert_eq!(first.encrypt_ope(42).unwrap(), second.encrypt_ope(42).unwrap());
+    }
+
+    #[test]
+    fn test_ope_key_versions() {
+        let dir = TempDir::new("key-versions");
+        let ope_key_file = dir.join("ope.key");
+        let aes_keyset_file = dir.join("aes-keyset.json");
+        CryptKeys::generate()
+            .write(&ope_key_file, &aes_keyset_file)
+            .unwrap();
+        let key = std::fs::read_to_string(&ope_key_file).unwrap();
+        assert!(key.starts_with(OPE_KEY_V2_PREFIX));
+        let keys = CryptKeys::load(&ope_key_file, &aes_keyset_file).unwrap();
+        assert_eq!(keys.ids().ope_sampler, 2);
+
+        // Key files from before the versions keep their ciphertexts
+        std::fs::write(&ope_key_file, "ope-testing-key\n").unwrap();
+        let keys = CryptKeys::load(&ope_key_file, &aes_keyset_file).unwrap();
+        assert_eq!(keys.ids().ope_sampler, 1);
+        let taocrypt = TaoCrypto::new(keys, 0, 0);
+        assert_eq!(taocrypt.encrypt_ope(10).unwrap(), 131086);
+        assert_eq!(taocrypt.decrypt_ope(131086).unwrap(), 10);
+    }
+
+    #[test]
+    fn test_ope_generated_keys() {
+        let times = [1, 2, 10, 11, 42, 100, 1000, 30000, 60000, 65533, 65534];
+        for _ in 0..20 {
+            let taocrypt = TaoCrypto::new(CryptKeys::generate(), 0, 0);
+            let mut last = 0;
+            for time in times {
+                let encrypt = taocrypt.encrypt_ope(time).unwrap();
+                assert!(encrypt > last, "ciphertexts out of order");
+                assert_eq!(taocrypt.decrypt_ope(encrypt).unwrap(), time);
+                last = encrypt;
+            }
+        }
+    }
 }
@@ -0,0 +1,52 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/*
+ * TaoError
+ *      Why a single query in a batch failed. Errors are reported per query,
+ *      the other queries in the batch still run.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "message")]
+pub enum TaoError {
+    Parse(String),
+    Validation(String),
+    Crypto(String),
+    Database(String),
+    Timeout(String),
+    Forbidden(String),
+}
+
+impl fmt::Display for TaoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TaoError::Parse(msg) => write!(f, "parse error: {}", msg),
+            TaoError::Validation(msg) => write!(f, "invalid query: {}", msg),
+            TaoError::Crypto(msg) => write!(f, "crypto error: {}", msg),
+            TaoError::Database(msg) => write!(f, "database error: {}", msg),
+            TaoError::Timeout(msg) => write!(f, "timed out: {}", msg),
+            TaoError::Forbidden(msg) => write!(f, "forbidden: {}", msg),
+        }
+    }
+}
+
+impl TaoError {
+    /*
+     * kind()
+     *      The variant alone, which is what gets logged. Messages may quote
+     *      the query and with it plaintext values.
+     */
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TaoError::Parse(_) => "Parse",
+            TaoError::Validation(_) => "Validation",
+            TaoError::Crypto(_) => "Crypto",
+            TaoError::Database(_) => "Database",
+            TaoError::Timeout(_) => "Timeout",
+            TaoError::Forbidden(_) => "Forbidden",
+        }
+    }
+}
+
+impl std::error::Error for TaoError {}
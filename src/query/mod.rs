@@ -1,4 +1,5 @@
 pub mod crypto;
+pub mod error;
 pub mod parser;
 pub mod query;
 pub mod results;
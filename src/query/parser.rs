@@ -1,5 +1,6 @@
 use pest::{self, Parser};
 
+use crate::query::error::TaoError;
 use crate::query::query::{Query, TaoArgs, TaoOp};
 
 #[derive(pest_derive::Parser)]
@@ -7,129 +8,197 @@ use crate::query::query::{Query, TaoArgs, TaoOp};
 struct TaoParser;
 
 pub fn parse(source: &str) -> Vec<Query> {
+    return parse_batch(source)
+        .into_iter()
+        .map(|q| q.unwrap_or_else(|e| panic!("{}", e)))
+        .collect::<Vec<Query>>();
+}
+
+/*
+ * split_namespace(source)
+ *      Strips a leading `USE name;` and returns the namespace name along
+ *      with the remaining queries.
+ */
+pub fn split_namespace(source: &str) -> (Option<&str>, &str) {
+    let trimmed = source.trim_start();
+    if let Some(rest) = trimmed.strip_prefix("USE") {
+        if rest.starts_with(char::is_whitespace) {
+            if let Some((name, queries)) = rest.split_once(';') {
+                return (Some(name.trim()), queries);
+            }
+        }
+    }
+    (None, source)
+}
+
+/*
+ * parse_batch(source)
+ *      Parses every `;` terminated query on its own, so that one malformed
+ *      query only fails its own slot in the batch.
+ */
+pub fn parse_batch(source: &str) -> Vec<Result<Query, TaoError>> {
+    let statements = source
+        .split(';')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<&str>>();
+
+    if statements.is_empty() {
+        return vec![Err(TaoError::Parse("empty query".to_string()))];
+    }
+
+    statements
+        .into_iter()
+        .map(|s| parse_statement(&format!("{};", s)))
+        .collect::<Vec<Result<Query, TaoError>>>()
+}
+
+fn parse_statement(source: &str) -> Result<Query, TaoError> {
     let mut program = TaoParser::parse(Rule::Queries, source)
-        .unwrap_or_else(|e| panic!("{}", e));
+        .map_err(|e| TaoError::Parse(e.to_string()))?;
     let prog = program.next().unwrap();
-    let qs = match prog.as_rule() {
-        Rule::Queries => parse_queries(prog),
-        _ => panic!("Invalid Query Input!"),
+    let mut qs = match prog.as_rule() {
+        Rule::Queries => parse_queries(prog)?,
+        _ => return Err(TaoError::Parse("Invalid Query Input!".to_string())),
     };
 
-    return qs;
+    Ok(qs.remove(0))
 }
 
-fn parse_queries(queries: pest::iterators::Pair<Rule>) -> Vec<Query> {
+fn parse_queries(
+    queries: pest::iterators::Pair<Rule>,
+) -> Result<Vec<Query>, TaoError> {
     let qs = queries.into_inner().filter(|p| match p.as_rule() {
         Rule::Query => true,
         _ => false,
     });
-    return qs.map(|q| parse_query(q)).collect::<Vec<Query>>();
+    return qs
+        .map(|q| parse_query(q))
+        .collect::<Result<Vec<Query>, TaoError>>();
 }
 
-fn parse_query(query: pest::iterators::Pair<Rule>) -> Query {
+fn parse_query(query: pest::iterators::Pair<Rule>) -> Result<Query, TaoError> {
     let mut query_body = query.into_inner().next().unwrap().into_inner();
     let target = query_body.next().unwrap();
     let op = query_body.next().unwrap();
 
-    let tao_op = parse_tao_op(target.as_str(), op.as_str());
-    let tao_args = parse_tao_args(&tao_op, query_body);
+    let tao_op = parse_tao_op(target.as_str(), op.as_str())?;
+    let tao_args = parse_tao_args(&tao_op, query_body)?;
 
-    return Query {
+    return Ok(Query {
         op: tao_op,
         args: tao_args,
-    };
+    });
 }
 
-fn parse_tao_op(target: &str, op: &str) -> TaoOp {
+/*
+ * parse_op(source)
+ *      Parses an operation as written in a query, e.g. `ASSOC COUNT`.
+ */
+pub fn parse_op(source: &str) -> Result<TaoOp, TaoError> {
+    let words = source.split_whitespace().collect::<Vec<&str>>();
+    match words.as_slice() {
+        [target, op] => parse_tao_op(target, op),
+        _ => Err(TaoError::Parse(format!("{:?} is not an operation", source))),
+    }
+}
+
+fn parse_tao_op(target: &str, op: &str) -> Result<TaoOp, TaoError> {
     let tao_op = match (target, op) {
         ("ASSOC", "ADD") => TaoOp::AssocAdd,
+        ("ASSOC", "DELETE") => TaoOp::AssocDelete,
         ("ASSOC", "GET") => TaoOp::AssocGet,
         ("ASSOC", "RGET") => TaoOp::AssocRangeGet,
         ("ASSOC", "COUNT") => TaoOp::AssocCount,
         ("ASSOC", "RANGE") => TaoOp::AssocRange,
         ("OBJ", "ADD") => TaoOp::ObjAdd,
         ("OBJ", "GET") => TaoOp::ObjGet,
-        _ => panic!("Invalid target and operation combination"),
+        ("OBJ", "UPDATE") => TaoOp::ObjUpdate,
+        ("OBJ", "DELETE") => TaoOp::ObjDelete,
+        _ => {
+            return Err(TaoError::Validation(format!(
+                "{} {} is not supported",
+                target, op
+            )))
+        }
     };
-    return tao_op;
+    return Ok(tao_op);
 }
 
 fn parse_tao_args(
     op: &TaoOp,
     mut args: pest::iterators::Pairs<Rule>,
-) -> TaoArgs {
+) -> Result<TaoArgs, TaoError> {
     match op {
         TaoOp::AssocAdd => {
             let (a1, a2, a3, a4, a5) = unwrap_five_args(args);
             let id1: String = a1.to_string();
             let atype: String = a2.to_string();
             let id2: String = a3.to_string();
-            let time: i64 = a4.parse().unwrap();
+            let time: i64 = parse_number(a4, "time")?;
             let data: String = a5.to_string();
 
-            return TaoArgs::AssocAddArgs {
+            return Ok(TaoArgs::AssocAddArgs {
                 id1: id1,
                 atype: atype,
                 id2: id2,
                 time: time,
                 data: data,
-            };
+            });
         }
-        TaoOp::AssocDelete => panic!("Operation not supported"),
-        TaoOp::AssocChangeType => panic!("Operation not supported"),
         TaoOp::AssocGet => {
             let (a1, a2, a3) = unwrap_three_args(args);
             let id: String = a1.to_string();
             let atype: String = a2.to_string();
-            let idset: Vec<String> = parse_id_set(a3);
+            let idset: Vec<String> = parse_id_set(a3)?;
 
-            return TaoArgs::AssocGetArgs {
+            return Ok(TaoArgs::AssocGetArgs {
                 id: id,
                 atype: atype,
                 idset: idset,
-            };
+            });
         }
         TaoOp::AssocRangeGet => {
             let (a1, a2, a3, a4, a5) = unwrap_five_args(args);
             let id: String = a1.to_string();
             let atype: String = a2.to_string();
-            let idset: Vec<String> = parse_id_set(a3);
-            let tstart: i64 = a4.parse().unwrap();
-            let tend: i64 = a5.parse().unwrap();
+            let idset: Vec<String> = parse_id_set(a3)?;
+            let tstart: i64 = parse_number(a4, "time-lo")?;
+            let tend: i64 = parse_number(a5, "time-hi")?;
 
-            return TaoArgs::AssocRangeGetArgs {
+            return Ok(TaoArgs::AssocRangeGetArgs {
                 id: id,
                 atype: atype,
                 idset: idset,
                 tstart: tstart,
                 tend: tend,
-            };
+            });
         }
         TaoOp::AssocCount => {
             let (a1, a2) = unwrap_two_args(args);
             let id: String = a1.to_string();
             let atype: String = a2.to_string();
 
-            return TaoArgs::AssocCountArgs {
+            return Ok(TaoArgs::AssocCountArgs {
                 id: id,
                 atype: atype,
-            };
+            });
         }
         TaoOp::AssocRange => {
             let (a1, a2, a3, a4, a5) = unwrap_five_args(args);
             let id1: String = a1.to_string();
             let atype = a2.to_string();
-            let t1: i64 = a3.parse().unwrap();
-            let t2: i64 = a4.parse().unwrap();
-            let lim: i64 = a5.parse().unwrap();
+            let t1: i64 = parse_number(a3, "time-lo")?;
+            let t2: i64 = parse_number(a4, "time-hi")?;
+            let lim: i64 = parse_number(a5, "lim")?;
 
-            return TaoArgs::AssocRangeArgs {
+            return Ok(TaoArgs::AssocRangeArgs {
                 id: id1,
                 atype: atype,
                 tstart: t1,
                 tend: t2,
                 lim: lim,
-            };
+            });
         }
         TaoOp::ObjAdd => {
             let (a1, a2, a3) = unwrap_three_args(args);
@@ -137,34 +206,148 @@ fn parse_tao_args(
             let otype = a2.to_string();
             let data = a3.to_string();
 
-            return TaoArgs::ObjAddArgs {
+            return Ok(TaoArgs::ObjAddArgs {
                 id: id,
                 otype: otype,
                 data: data,
-            };
+            });
         }
         TaoOp::ObjGet => {
             let id: String = args.next().unwrap().as_str().to_string();
 
-            return TaoArgs::ObjGetArgs { id: id };
+            return Ok(TaoArgs::ObjGetArgs { id: id });
+        }
+        TaoOp::AssocDelete => {
+            let (a1, a2, a3) = unwrap_three_args(args);
+            Ok(TaoArgs::AssocDeleteArgs {
+                id1: a1.to_string(),
+                atype: a2.to_string(),
+                id2: a3.to_string(),
+            })
+        }
+        TaoOp::ObjUpdate => {
+            let (a1, a2, a3) = unwrap_three_args(args);
+            Ok(TaoArgs::ObjUpdateArgs {
+                id: a1.to_string(),
+                otype: a2.to_string(),
+                data: a3.to_string(),
+            })
+        }
+        TaoOp::ObjDelete => {
+            let id = args.next().unwrap().as_str().to_string();
+            Ok(TaoArgs::ObjDeleteArgs { id })
+        }
+        _ => Err(TaoError::Validation(format!("{:?} is not supported", op))),
+    }
+}
+
+/*
+ * validate_args(args)
+ *      Checks arguments that were not parsed from query text, e.g. those of
+ *      gRPC requests, against the grammar rules parsed ones went through.
+ */
+pub fn validate_args(args: &TaoArgs) -> Result<(), TaoError> {
+    let check = |rule: Rule, value: &str, name: &str| {
+        let matched = TaoParser::parse(rule, value)
+            .ok()
+            .and_then(|mut pairs| pairs.next())
+            .is_some_and(|pair| pair.as_str() == value);
+        match matched {
+            true => Ok(()),
+            false => Err(TaoError::Validation(format!(
+                "{} {:?} is not valid",
+                name, value
+            ))),
         }
-        TaoOp::ObjDelete => panic!("Operation not supported"),
-        _ => panic!("Operation not supported"),
     };
+    let natural = |value: i64, name: &str| match value >= 0 {
+        true => Ok(()),
+        false => Err(TaoError::Validation(format!(
+            "{} {} is negative",
+            name, value
+        ))),
+    };
+    match args {
+        TaoArgs::AssocAddArgs {
+            id1,
+            atype,
+            id2,
+            time,
+            data,
+        } => {
+            check(Rule::Number, id1, "id1")?;
+            check(Rule::AssocType, atype, "atype")?;
+            check(Rule::Number, id2, "id2")?;
+            natural(*time, "time")?;
+            check(Rule::RawString, data, "data")
+        }
+        TaoArgs::AssocGetArgs { id, atype, idset }
+        | TaoArgs::AssocRangeGetArgs { id, atype, idset, .. } => {
+            check(Rule::Number, id, "id1")?;
+            check(Rule::AssocType, atype, "atype")?;
+            if idset.is_empty() {
+                return Err(TaoError::Validation("no id2s given".to_string()));
+            }
+            for id2 in idset {
+                check(Rule::Number, id2, "id2")?;
+            }
+            if let TaoArgs::AssocRangeGetArgs { tstart, tend, .. } = args {
+                natural(*tstart, "tstart")?;
+                natural(*tend, "tend")?;
+            }
+            Ok(())
+        }
+        TaoArgs::AssocCountArgs { id, atype } => {
+            check(Rule::Number, id, "id1")?;
+            check(Rule::AssocType, atype, "atype")
+        }
+        TaoArgs::AssocRangeArgs {
+            id,
+            atype,
+            tstart,
+            tend,
+            lim,
+        } => {
+            check(Rule::Number, id, "id1")?;
+            check(Rule::AssocType, atype, "atype")?;
+            natural(*tstart, "tstart")?;
+            natural(*tend, "tend")?;
+            natural(*lim, "limit")
+        }
+        TaoArgs::ObjGetArgs { id } | TaoArgs::ObjDeleteArgs { id } => {
+            check(Rule::Number, id, "id")
+        }
+        TaoArgs::ObjAddArgs { id, otype, data }
+        | TaoArgs::ObjUpdateArgs { id, otype, data } => {
+            check(Rule::Number, id, "id")?;
+            check(Rule::ObjType, otype, "otype")?;
+            check(Rule::RawString, data, "data")
+        }
+        TaoArgs::AssocDeleteArgs { id1, atype, id2 } => {
+            check(Rule::Number, id1, "id1")?;
+            check(Rule::AssocType, atype, "atype")?;
+            check(Rule::Number, id2, "id2")
+        }
+    }
+}
+
+fn parse_number(arg: &str, name: &str) -> Result<i64, TaoError> {
+    arg.parse().map_err(|_| {
+        TaoError::Validation(format!("{} {} is out of range", name, arg))
+    })
 }
 
-fn parse_id_set(lst: &str) -> Vec<String> {
+fn parse_id_set(lst: &str) -> Result<Vec<String>, TaoError> {
     let mut ids = TaoParser::parse(Rule::NumList, lst)
-        .unwrap_or_else(|e| panic!("{}", e));
+        .map_err(|e| TaoError::Parse(e.to_string()))?;
     let ids = ids.next().unwrap();
-    let ids = ids.into_inner().filter(|p| match p.as_rule() {
-        Rule::Number => true,
-        _ => panic!("Set of IDs should only contain numbers"),
-    });
-    let idset = ids.map(|n| n.as_str().parse().unwrap());
-    let idset = idset.collect::<Vec<String>>();
+    let idset = ids
+        .into_inner()
+        .filter(|p| matches!(p.as_rule(), Rule::Number))
+        .map(|n| n.as_str().to_string())
+        .collect::<Vec<String>>();
 
-    return idset;
+    return Ok(idset);
 }
 
 // not really clean
@@ -206,3 +389,90 @@ fn unwrap_five_args(
 
     return (a1, a2, a3, a4, a5);
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::query::error::TaoError;
+    use crate::query::parser::{parse_batch, split_namespace, validate_args};
+    use crate::query::query::{Query, TaoArgs, TaoOp};
+
+    #[test]
+    fn test_validate_args() {
+        let obj = |id: &str, otype: &str, data: &str| TaoArgs::ObjAddArgs {
+            id: id.to_string(),
+            otype: otype.to_string(),
+            data: data.to_string(),
+        };
+        assert!(validate_args(&obj("42", "USER", "alice smith")).is_ok());
+        assert!(validate_args(&obj("4a", "USER", "alice")).is_err());
+        assert!(validate_args(&obj("42", "USERS", "alice")).is_err());
+        assert!(validate_args(&obj("42", "USER", "x\"; OBJ GET 1")).is_err());
+
+        let range = |lim: i64| TaoArgs::AssocRangeArgs {
+            id: "42".to_string(),
+            atype: "LIKES".to_string(),
+            tstart: 0,
+            tend: 100,
+            lim,
+        };
+        assert!(validate_args(&range(10)).is_ok());
+        assert!(validate_args(&range(-1)).is_err());
+    }
+
+    #[test]
+    fn test_parse_batch_partial() {
+        let res = parse_batch(
+            "OBJ GET 1; ASSOC FOO 1 LIKES; ASSOC CHTYPE 1 LIKES 2 FRIEND; \
+             OBJ GET 2;",
+        );
+        assert_eq!(res.len(), 4);
+        assert!(matches!(res[0], Ok(ref q) if matches!(q.op, TaoOp::ObjGet)));
+        assert!(matches!(res[1], Err(TaoError::Parse(_))));
+        assert!(matches!(res[2], Err(TaoError::Validation(_))));
+        assert!(res[3].is_ok());
+    }
+
+    #[test]
+    fn test_parse_updates_and_deletes() {
+        let res = parse_batch(
+            r#"OBJ UPDATE 1 USER "bob"; OBJ DELETE 1; ASSOC DELETE 1 LIKES 2;
+               OBJ UPDATE 1 "bob";"#,
+        );
+        assert!(matches!(
+            res[0],
+            Ok(Query { args: TaoArgs::ObjUpdateArgs { ref data, .. }, .. })
+                if data == "bob"
+        ));
+        assert!(matches!(
+            res[1],
+            Ok(Query { args: TaoArgs::ObjDeleteArgs { .. }, .. })
+        ));
+        assert!(matches!(
+            res[2],
+            Ok(Query { args: TaoArgs::AssocDeleteArgs { ref id2, .. }, .. })
+                if id2 == "2"
+        ));
+        assert!(matches!(res[3], Err(TaoError::Parse(_))));
+    }
+
+    #[test]
+    fn test_parse_batch_out_of_range() {
+        let res = parse_batch("ASSOC RANGE 1 LIKES 0 99999999999999999999 10;");
+        assert!(matches!(res[0], Err(TaoError::Validation(_))));
+    }
+
+    #[test]
+    fn test_split_namespace() {
+        assert_eq!(
+            split_namespace("USE acme; OBJ GET 1;"),
+            (Some("acme"), " OBJ GET 1;")
+        );
+        assert_eq!(split_namespace("OBJ GET 1;"), (None, "OBJ GET 1;"));
+        assert_eq!(split_namespace("USEFUL;"), (None, "USEFUL;"));
+    }
+
+    #[test]
+    fn test_parse_batch_empty() {
+        assert!(matches!(parse_batch(" ")[0], Err(TaoError::Parse(_))));
+    }
+}
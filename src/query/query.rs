@@ -93,7 +93,7 @@ impl fmt::Display for AssocType {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum TaoArgs {
     AssocAddArgs {
         id1: String,
@@ -133,14 +133,40 @@ pub enum TaoArgs {
         otype: String,
         data: String,
     },
+    AssocDeleteArgs {
+        id1: String,
+        atype: String,
+        id2: String,
+    },
+    ObjUpdateArgs {
+        id: String,
+        otype: String,
+        data: String,
+    },
+    ObjDeleteArgs {
+        id: String,
+    },
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Query {
     pub op: TaoOp,
     pub args: TaoArgs,
 }
 
+impl Query {
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self.args,
+            TaoArgs::ObjAddArgs { .. }
+                | TaoArgs::AssocAddArgs { .. }
+                | TaoArgs::AssocDeleteArgs { .. }
+                | TaoArgs::ObjUpdateArgs { .. }
+                | TaoArgs::ObjDeleteArgs { .. }
+        )
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct SqlQuery {
     pub op: TaoOp,
@@ -0,0 +1,459 @@
+/*
+ * File: audit.rs
+ *      Append-only audit log of writes. Every mutating query that reaches
+ *      the server is recorded as one JSON line with the principal, time,
+ *      operation, arguments and outcome, including writes the policy
+ *      denied. Arguments are logged as stored, i.e. as ciphertext when
+ *      encryption is on, so the log holds no plaintext. A write whose
+ *      arguments do not encrypt is recorded without them, and errors by
+ *      their kind alone, as their messages may quote the arguments.
+ *
+ *      Each record carries the hash of the one before it and its own hash,
+ *      the SHA-256 of the record without that field. Changing, removing or
+ *      reordering records breaks the chain, which `tao-server verify-audit`
+ *      detects. Truncating the end of the log is only detectable by
+ *      comparing with a record count or last hash kept elsewhere.
+ *
+ *      A write whose record cannot be appended fails, and so does every
+ *      write after it until the server is restarted. An append cut short
+ *      leaves a torn last line, which `tao-server verify-audit --repair`
+ *      mends before the server will start on the log again.
+ */
+use std::fs::{self, File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::query::error::TaoError;
+use crate::query::query::{Query, TaoArgs, TaoOp};
+
+/// `prev` of the first record
+pub const GENESIS: &str =
+    "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub seq: u64,
+    /// Unix time in milliseconds
+    pub time: u64,
+    pub principal: String,
+    pub namespace: String,
+    pub op: TaoOp,
+    /// None if the arguments could not be encrypted
+    pub args: Option<TaoArgs>,
+    pub ok: bool,
+    /// The kind of error only, messages may quote plaintext values
+    pub error: Option<String>,
+    pub prev: String,
+}
+
+impl AuditEntry {
+    fn hash(&self) -> Result<String, String> {
+        let bytes = serde_json::to_vec(self).map_err(|e| e.to_string())?;
+        Ok(hex(&Sha256::digest(&bytes)))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AuditRecord {
+    #[serde(flatten)]
+    entry: AuditEntry,
+    hash: String,
+}
+
+struct Chain {
+    file: File,
+    seq: u64,
+    last_hash: String,
+    /// Why an append failed, after which the chain takes no more records
+    failed: Option<String>,
+}
+
+/// What `repair` did to the end of a log
+#[derive(Debug, PartialEq, Eq)]
+pub enum Repair {
+    Intact,
+    /// The last record was whole but for its newline
+    Completed,
+    /// The torn last record was cut off, this many bytes
+    Truncated(usize),
+}
+
+pub struct AuditLog {
+    chain: Option<Mutex<Chain>>,
+}
+
+impl AuditLog {
+    pub fn disabled() -> Self {
+        AuditLog { chain: None }
+    }
+
+    /*
+     * open(path)
+     *      Verifies what is already in the log and continues its chain, a
+     *      server never appends to a log that does not verify.
+     */
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let (seq, last_hash) = match path.exists() {
+            true => verify(path)?,
+            false => (0, GENESIS.to_string()),
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| {
+                format!("cannot open audit log {}: {}", path.display(), e)
+            })?;
+        Ok(AuditLog {
+            chain: Some(Mutex::new(Chain {
+                file,
+                seq,
+                last_hash,
+                failed: None,
+            })),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.chain.is_some()
+    }
+
+    /*
+     * check()
+     *      Fails once an append has, writes are refused from then on since
+     *      they could not be recorded.
+     */
+    pub fn check(&self) -> Result<(), String> {
+        let chain = match &self.chain {
+            Some(chain) => chain,
+            None => return Ok(()),
+        };
+        let chain = chain.lock().map_err(|e| e.to_string())?;
+        match &chain.failed {
+            Some(e) => Err(e.clone()),
+            None => Ok(()),
+        }
+    }
+
+    /*
+     * record(principal, namespace, query, outcome)
+     *      `query` is the write as stored. The line is written with a single
+     *      append before the write is answered.
+     */
+    pub fn record<T>(
+        &self,
+        principal: &str,
+        namespace: &str,
+        query: &Query,
+        outcome: &Result<T, TaoError>,
+    ) -> Result<(), String> {
+        let args = Some(query.args.clone());
+        self.append(principal, namespace, &query.op, args, outcome)
+    }
+
+    /*
+     * record_op(principal, namespace, op, outcome)
+     *      A write that failed before it could be encrypted, recorded
+     *      without its plaintext arguments.
+     */
+    pub fn record_op<T>(
+        &self,
+        principal: &str,
+        namespace: &str,
+        op: &TaoOp,
+        outcome: &Result<T, TaoError>,
+    ) -> Result<(), String> {
+        self.append(principal, namespace, op, None, outcome)
+    }
+
+    fn append<T>(
+        &self,
+        principal: &str,
+        namespace: &str,
+        op: &TaoOp,
+        args: Option<TaoArgs>,
+        outcome: &Result<T, TaoError>,
+    ) -> Result<(), String> {
+        let chain = match &self.chain {
+            Some(chain) => chain,
+            None => return Ok(()),
+        };
+        let mut chain = chain.lock().map_err(|e| e.to_string())?;
+        if let Some(e) = &chain.failed {
+            return Err(e.clone());
+        }
+        let entry = AuditEntry {
+            seq: chain.seq + 1,
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            principal: principal.to_string(),
+            namespace: namespace.to_string(),
+            op: op.clone(),
+            args,
+            ok: outcome.is_ok(),
+            error: outcome.as_ref().err().map(|e| e.kind().to_string()),
+            prev: chain.last_hash.clone(),
+        };
+        let hash = entry.hash()?;
+        let mut line = serde_json::to_string(&AuditRecord {
+            entry,
+            hash: hash.clone(),
+        })
+        .map_err(|e| e.to_string())?;
+        line.push('\n');
+        if let Err(e) = chain.file.write_all(line.as_bytes()) {
+            let e = format!("cannot write audit log: {}", e);
+            chain.failed = Some(e.clone());
+            return Err(e);
+        }
+        chain.seq += 1;
+        chain.last_hash = hash;
+        Ok(())
+    }
+
+    /*
+     * sync()
+     *      Records are appended unbuffered, this makes sure they reached the
+     *      disk before the server exits.
+     */
+    pub fn sync(&self) -> Result<(), String> {
+        let chain = match &self.chain {
+            Some(chain) => chain,
+            None => return Ok(()),
+        };
+        let chain = chain.lock().map_err(|e| e.to_string())?;
+        chain
+            .file
+            .sync_all()
+            .map_err(|e| format!("cannot sync audit log: {}", e))
+    }
+}
+
+/*
+ * verify(path)
+ *      Checks every record's hash and its link to the record before it.
+ *      Returns the number of records and the hash of the last one.
+ */
+pub fn verify(path: &Path) -> Result<(u64, String), String> {
+    let contents = read(path)?;
+    let (records, torn) = split_torn(&contents);
+    let records = std::str::from_utf8(records).map_err(|e| e.to_string())?;
+    if !torn.is_empty() {
+        return Err(format!(
+            "line {}: torn record at the end, from an append that did not \
+             finish; `tao-server verify-audit --repair` mends it",
+            records.lines().count() + 1
+        ));
+    }
+    verify_records(records)
+}
+
+/*
+ * repair(path)
+ *      Mends a torn last line once the records before it verify. A record
+ *      that only lacks its newline gets one, anything else is cut off.
+ */
+pub fn repair(path: &Path) -> Result<Repair, String> {
+    let contents = read(path)?;
+    let (records, torn) = split_torn(&contents);
+    let records = std::str::from_utf8(records).map_err(|e| e.to_string())?;
+    verify_records(records)?;
+    if torn.is_empty() {
+        return Ok(Repair::Intact);
+    }
+    let whole = std::str::from_utf8(&contents)
+        .map_err(|e| e.to_string())
+        .and_then(verify_records)
+        .is_ok();
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .map_err(|e| format!("cannot open {}: {}", path.display(), e))?;
+    let repair = match whole {
+        true => {
+            file.seek(SeekFrom::End(0)).map_err(|e| e.to_string())?;
+            file.write_all(b"\n").map_err(|e| e.to_string())?;
+            Repair::Completed
+        }
+        false => {
+            file.set_len(records.len() as u64)
+                .map_err(|e| e.to_string())?;
+            Repair::Truncated(torn.len())
+        }
+    };
+    file.sync_all().map_err(|e| e.to_string())?;
+    Ok(repair)
+}
+
+/*
+ * unaudited(e)
+ *      The error of a write the audit log could not take.
+ */
+pub fn unaudited(e: String) -> TaoError {
+    TaoError::Database(format!("write not audited: {}", e))
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, String> {
+    fs::read(path)
+        .map_err(|e| format!("cannot read audit log {}: {}", path.display(), e))
+}
+
+/*
+ * split_torn(contents)
+ *      The complete lines, and what follows the last newline.
+ */
+fn split_torn(contents: &[u8]) -> (&[u8], &[u8]) {
+    let end = contents
+        .iter()
+        .rposition(|b| *b == b'\n')
+        .map_or(0, |i| i + 1);
+    contents.split_at(end)
+}
+
+fn verify_records(contents: &str) -> Result<(u64, String), String> {
+    let mut seq = 0;
+    let mut last_hash = GENESIS.to_string();
+    for (i, line) in contents.lines().enumerate() {
+        let at = |msg: String| format!("line {}: {}", i + 1, msg);
+        let record: AuditRecord =
+            serde_json::from_str(line).map_err(|e| at(e.to_string()))?;
+        if record.entry.seq != seq + 1 {
+            return Err(at(format!(
+                "expected record {}, found {}",
+                seq + 1,
+                record.entry.seq
+            )));
+        }
+        if record.entry.prev != last_hash {
+            return Err(at("does not follow the previous record".to_string()));
+        }
+        if record.entry.hash().map_err(at)? != record.hash {
+            return Err(
+                at("hash mismatch, the record was changed".to_string()),
+            );
+        }
+        seq = record.entry.seq;
+        last_hash = record.hash;
+    }
+    Ok((seq, last_hash))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use std::fs::OpenOptions;
+    use std::sync::Mutex;
+
+    use super::{repair, verify, AuditLog, Chain, Repair, GENESIS};
+    use crate::query::error::TaoError;
+    use crate::query::parser::parse;
+    use crate::service::testing::TempDir;
+
+    #[test]
+    fn test_chain() {
+        let dir = TempDir::new("audit");
+        let path = dir.join("audit.log");
+
+        let writes = parse(r#"OBJ ADD 1 USER "a"; ASSOC ADD 1 LIKES 2 3 "x";"#);
+        let log = AuditLog::open(&path).unwrap();
+        log.record("etl", "default", &writes[0], &Ok::<(), TaoError>(()))
+            .unwrap();
+        drop(log);
+
+        let (seq, hash) = verify(&path).unwrap();
+        assert_eq!(seq, 1);
+        assert_ne!(hash, GENESIS);
+
+        let log = AuditLog::open(&path).unwrap();
+        let denied: Result<(), TaoError> =
+            Err(TaoError::Forbidden("etl may not run AssocAdd".to_string()));
+        log.record("etl", "default", &writes[1], &denied).unwrap();
+        assert_eq!(verify(&path).unwrap().0, 2);
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let forged = contents.replacen("\"etl\"", "\"root\"", 1);
+        fs::write(&path, forged).unwrap();
+        assert!(verify(&path).unwrap_err().starts_with("line 1: hash"));
+        assert!(AuditLog::open(&path).is_err());
+
+        let lines = contents.lines().collect::<Vec<&str>>();
+        fs::write(&path, format!("{}\n", lines[1])).unwrap();
+        assert!(verify(&path).is_err());
+    }
+
+    #[test]
+    fn test_torn_last_record() {
+        let dir = TempDir::new("audit-torn");
+        let path = dir.join("audit.log");
+
+        let writes = parse(r#"OBJ ADD 1 USER "a"; OBJ ADD 2 USER "b";"#);
+        let log = AuditLog::open(&path).unwrap();
+        for write in &writes {
+            log.record("etl", "default", write, &Ok::<(), TaoError>(()))
+                .unwrap();
+        }
+        drop(log);
+        let contents = fs::read_to_string(&path).unwrap();
+        let (seq, hash) = verify(&path).unwrap();
+
+        // The newline alone is missing, the record is kept
+        fs::write(&path, contents.trim_end()).unwrap();
+        assert!(verify(&path).unwrap_err().contains("torn"));
+        assert!(AuditLog::open(&path).is_err());
+        assert_eq!(repair(&path).unwrap(), Repair::Completed);
+        assert_eq!(verify(&path).unwrap(), (seq, hash));
+
+        let torn = &contents[..contents.len() - 20];
+        fs::write(&path, torn).unwrap();
+        assert!(verify(&path).unwrap_err().starts_with("line 2: torn"));
+        let first = contents.find('\n').unwrap() + 1;
+        let cut = Repair::Truncated(torn.len() - first);
+        assert_eq!(repair(&path).unwrap(), cut);
+        assert_eq!(verify(&path).unwrap().0, 1);
+        assert_eq!(repair(&path).unwrap(), Repair::Intact);
+        assert!(AuditLog::open(&path).is_ok());
+
+        // Nothing is cut off a log whose chain is broken
+        let forged = contents.replacen("\"etl\"", "\"root\"", 1);
+        fs::write(&path, &forged[..forged.len() - 20]).unwrap();
+        assert!(repair(&path).is_err());
+    }
+
+    #[test]
+    fn test_failed_append() {
+        let file = OpenOptions::new().append(true).open("/dev/full").unwrap();
+        let log = AuditLog {
+            chain: Some(Mutex::new(Chain {
+                file,
+                seq: 0,
+                last_hash: GENESIS.to_string(),
+                failed: None,
+            })),
+        };
+        assert!(log.check().is_ok());
+        let writes = parse(r#"OBJ ADD 1 USER "a";"#);
+        let ok = Ok::<(), TaoError>(());
+        assert!(log.record("etl", "default", &writes[0], &ok).is_err());
+        // Later writes are refused even if the disk recovered
+        assert!(log.check().is_err());
+        let dir = TempDir::new("audit-failed");
+        let path = dir.join("audit.log");
+        let mut chain = log.chain.as_ref().unwrap().lock().unwrap();
+        chain.file = fs::File::create(&path).unwrap();
+        drop(chain);
+        assert!(log.record("etl", "default", &writes[0], &ok).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+    }
+}
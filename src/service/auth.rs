@@ -0,0 +1,279 @@
+/*
+ * File: auth.rs
+ *      Authentication of /query callers. A caller proves who it is with a
+ *      bearer token, or with the client certificate it presented in the TLS
+ *      handshake. The resulting Principal is handed to the rest of the
+ *      request for logging and authorization.
+ *
+ *      Tokens are stored as SHA-256 digests, so the auth file never holds a
+ *      usable secret. Without an auth file every caller is `anonymous`.
+ */
+use std::any::Any;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use actix_tls::accept::openssl::TlsStream;
+use actix_web::{
+    dev::{Extensions, Payload},
+    error::InternalError,
+    http::header::{AUTHORIZATION, WWW_AUTHENTICATE},
+    rt::net::TcpStream,
+    web::Data,
+    FromRequest, HttpRequest, HttpResponse,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use futures::future::{ready, Ready};
+use openssl::nid::Nid;
+use openssl::x509::X509Ref;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+pub const ANONYMOUS: &str = "anonymous";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Principal {
+    pub name: String,
+}
+
+/*
+ * ClientIdentity
+ *      Common name of a verified client certificate, attached to the
+ *      connection by `on_connect`.
+ */
+#[derive(Debug, Clone)]
+pub struct ClientIdentity(pub String);
+
+/*
+ * AuthFile
+ *      {
+ *        "tokens": { "analytics": "<sha256 hex of the token>" },
+ *        "certificates": { "etl.internal": "etl" }
+ *      }
+ *      `tokens` maps principals to token digests, `certificates` maps the
+ *      common name of a client certificate to a principal.
+ */
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AuthFile {
+    #[serde(default)]
+    tokens: HashMap<String, String>,
+    #[serde(default)]
+    certificates: HashMap<String, String>,
+}
+
+pub struct Authenticator {
+    enabled: bool,
+    tokens: HashMap<String, String>,
+    certificates: HashMap<String, String>,
+}
+
+impl Authenticator {
+    pub fn disabled() -> Self {
+        Authenticator {
+            enabled: false,
+            tokens: HashMap::new(),
+            certificates: HashMap::new(),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path).map_err(|e| {
+            format!("cannot read auth file {}: {}", path.display(), e)
+        })?;
+        Authenticator::parse(&contents)
+            .map_err(|e| format!("invalid auth file {}: {}", path.display(), e))
+    }
+
+    fn parse(contents: &str) -> Result<Self, String> {
+        let file: AuthFile =
+            serde_json::from_str(contents).map_err(|e| e.to_string())?;
+
+        let mut tokens = HashMap::new();
+        for (principal, digest) in file.tokens {
+            let digest = digest.to_ascii_lowercase();
+            if digest.len() != 64
+                || !digest.chars().all(|c| c.is_ascii_hexdigit())
+            {
+                return Err(format!(
+                    "token of {} is not a SHA-256 hex digest",
+                    principal
+                ));
+            }
+            if let Some(other) = tokens.insert(digest, principal.clone()) {
+                return Err(format!(
+                    "{} and {} share a token",
+                    other, principal
+                ));
+            }
+        }
+        Ok(Authenticator {
+            enabled: true,
+            tokens,
+            certificates: file.certificates,
+        })
+    }
+
+    /*
+     * authenticate(bearer, identity)
+     *      A bearer token wins over the client certificate, and a wrong
+     *      token is rejected even when the certificate would have passed.
+     */
+    pub fn authenticate(
+        &self,
+        bearer: Option<&str>,
+        identity: Option<&ClientIdentity>,
+    ) -> Result<Principal, String> {
+        if !self.enabled {
+            return Ok(Principal {
+                name: ANONYMOUS.to_string(),
+            });
+        }
+        if let Some(token) = bearer {
+            return match self.tokens.get(&hash_token(token)) {
+                Some(name) => Ok(Principal { name: name.clone() }),
+                None => Err("invalid token".to_string()),
+            };
+        }
+        if let Some(ClientIdentity(common_name)) = identity {
+            if let Some(name) = self.certificates.get(common_name) {
+                return Ok(Principal { name: name.clone() });
+            }
+        }
+        Err("authentication required".to_string())
+    }
+}
+
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>()
+}
+
+pub fn generate_token() -> Result<String, String> {
+    let mut bytes = [0u8; 32];
+    openssl::rand::rand_bytes(&mut bytes).map_err(|e| e.to_string())?;
+    Ok(URL_SAFE_NO_PAD.encode(bytes))
+}
+
+/*
+ * on_connect(conn, ext)
+ *      Passed to HttpServer::on_connect. The handshake already verified the
+ *      certificate against the client CA, here we only remember its name.
+ */
+pub fn on_connect(conn: &dyn Any, ext: &mut Extensions) {
+    let stream = match conn.downcast_ref::<TlsStream<TcpStream>>() {
+        Some(stream) => stream,
+        None => return,
+    };
+    let common_name = stream
+        .ssl()
+        .peer_certificate()
+        .and_then(|cert| common_name(&cert));
+    if let Some(common_name) = common_name {
+        ext.insert(ClientIdentity(common_name));
+    }
+}
+
+pub fn common_name(cert: &X509Ref) -> Option<String> {
+    cert.subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .and_then(|entry| entry.data().to_string().ok())
+        .map(|name| name.to_string())
+}
+
+fn unauthorized(message: String) -> actix_web::Error {
+    let response = HttpResponse::Unauthorized()
+        .insert_header((WWW_AUTHENTICATE, "Bearer"))
+        .json(&message);
+    InternalError::from_response(message, response).into()
+}
+
+/*
+ * Principal as an extractor
+ *      Listed before the body in a handler, a failed authentication answers
+ *      401 before the body is read or the handler runs.
+ */
+impl FromRequest for Principal {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let authenticator = match req.app_data::<Data<Authenticator>>() {
+            Some(authenticator) => authenticator,
+            None => {
+                return ready(Err(unauthorized(
+                    "authentication is not configured".to_string(),
+                )))
+            }
+        };
+        let bearer = match req.headers().get(AUTHORIZATION) {
+            Some(value) => match value
+                .to_str()
+                .ok()
+                .and_then(|v| v.strip_prefix("Bearer "))
+            {
+                Some(token) => Some(token.trim()),
+                None => {
+                    return ready(Err(unauthorized(
+                        "malformed Authorization header".to_string(),
+                    )))
+                }
+            },
+            None => None,
+        };
+        let identity = req.conn_data::<ClientIdentity>();
+        ready(
+            authenticator
+                .authenticate(bearer, identity)
+                .map_err(unauthorized),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash_token, Authenticator, ClientIdentity, ANONYMOUS};
+
+    fn authenticator() -> Authenticator {
+        let contents = format!(
+            r#"{{ "tokens": {{ "analytics": "{}" }},
+                  "certificates": {{ "etl.internal": "etl" }} }}"#,
+            hash_token("s3cret")
+        );
+        Authenticator::parse(&contents).unwrap()
+    }
+
+    #[test]
+    fn test_tokens() {
+        let auth = authenticator();
+        let principal = auth.authenticate(Some("s3cret"), None).unwrap();
+        assert_eq!(principal.name, "analytics");
+        assert!(auth.authenticate(Some("guess"), None).is_err());
+        assert!(auth.authenticate(None, None).is_err());
+    }
+
+    #[test]
+    fn test_client_certificates() {
+        let auth = authenticator();
+        let etl = ClientIdentity("etl.internal".to_string());
+        let stranger = ClientIdentity("stranger".to_string());
+        assert_eq!(auth.authenticate(None, Some(&etl)).unwrap().name, "etl");
+        assert!(auth.authenticate(None, Some(&stranger)).is_err());
+        assert!(auth.authenticate(Some("guess"), Some(&etl)).is_err());
+    }
+
+    #[test]
+    fn test_disabled_is_anonymous() {
+        let auth = Authenticator::disabled();
+        assert_eq!(auth.authenticate(None, None).unwrap().name, ANONYMOUS);
+    }
+
+    #[test]
+    fn test_invalid_digest() {
+        let contents = r#"{ "tokens": { "analytics": "s3cret" } }"#;
+        assert!(Authenticator::parse(contents).is_err());
+    }
+}
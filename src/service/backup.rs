@@ -0,0 +1,534 @@
+/*
+ * File: backup.rs
+ *      Backups of a namespace, written by `tao-server export` and loaded by
+ *      `tao-server restore`. A backup is a file of JSON lines: a manifest,
+ *      the objects and the associations, in the shapes `tao-server import`
+ *      reads, and a trailer with their counts and the SHA-256 of their lines
+ *          {"manifest": {"format": "tao-backup", "version": 1, ...}}
+ *          {"id": "...", "otype": "...", "data": "..."}
+ *          {"id1": "...", "atype": "...", "id2": "...", "time": 7, ...}
+ *          {"trailer": {"objs": 1, "assocs": 1, "sha256": "..."}}
+ *
+ *      Rows are exported as stored, i.e. as ciphertext when encryption is
+ *      on, and the manifest names the keys they were encrypted with by
+ *      their KeyIds, next to the OPE ranges and the schema version. With
+ *      `--plaintext` they are decrypted instead, which takes the key files
+ *      of the namespace given once more, so that a server's config alone
+ *      never exports plaintext. Each shard is read in a transaction of its
+ *      own, so the backup of a single shard is one consistent snapshot.
+ *
+ *      A restore reads the whole backup first and loads nothing unless the
+ *      trailer matches. Ciphertext is only restored into a namespace with
+ *      the same keys, plaintext is checked and encrypted as imports are.
+ *      Keys already taken are skipped, so a restore may be run again.
+ */
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+
+use crate::query::crypto::{
+    KeyIds, DEFAULT_INPUT_RANGE_END, DEFAULT_OUTPUT_RANGE_END,
+};
+use crate::query::error::TaoError;
+use crate::query::parser::validate_args;
+use crate::query::query::{Query, TaoArgs, TaoOp};
+use crate::query::results::DBRow;
+use crate::service::import::{encrypt_all, store_queries};
+use crate::service::migrate::MIGRATIONS;
+use crate::service::namespace::Namespace;
+use crate::service::tao::{unix_millis, TaoServer};
+
+pub const BACKUP_FORMAT: &str = "tao-backup";
+pub const BACKUP_VERSION: u32 = 1;
+/// Who the audit log records for the writes of `tao-server restore`
+pub const RESTORE_PRINCIPAL: &str = "tao-server restore";
+/// Rows on their way from the store to the file
+const EXPORT_BUFFER: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OpeParams {
+    pub input_range: (u64, u64),
+    pub output_range: (u64, u64),
+}
+
+impl OpeParams {
+    pub fn current() -> Self {
+        OpeParams {
+            input_range: (1, DEFAULT_INPUT_RANGE_END),
+            output_range: (1, DEFAULT_OUTPUT_RANGE_END),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format: String,
+    pub version: u32,
+    /// Unix time in milliseconds
+    pub created: u64,
+    pub namespace: String,
+    pub schema_version: i32,
+    /// Whether the rows are ciphertext
+    pub encrypted: bool,
+    /// The keys of the ciphertext, None for plaintext or unknown keys
+    pub keys: Option<KeyIds>,
+    pub ope: OpeParams,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Trailer {
+    pub objs: u64,
+    pub assocs: u64,
+    pub sha256: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Line {
+    Manifest {
+        manifest: Manifest,
+    },
+    Trailer {
+        trailer: Trailer,
+    },
+    Obj {
+        id: String,
+        otype: String,
+        data: String,
+    },
+    Assoc {
+        id1: String,
+        atype: String,
+        id2: String,
+        time: i64,
+        data: String,
+    },
+}
+
+impl Line {
+    fn of_row(row: DBRow) -> Option<Line> {
+        match row {
+            DBRow::ObjRow { id, otype, data } => {
+                Some(Line::Obj { id, otype, data })
+            }
+            DBRow::AssocRow {
+                id1,
+                atype,
+                id2,
+                t,
+                data,
+            } => Some(Line::Assoc {
+                id1,
+                atype,
+                id2,
+                time: t,
+                data,
+            }),
+            _ => None,
+        }
+    }
+
+    fn into_query(self) -> Option<Query> {
+        match self {
+            Line::Obj { id, otype, data } => Some(Query {
+                op: TaoOp::ObjAdd,
+                args: TaoArgs::ObjAddArgs { id, otype, data },
+            }),
+            Line::Assoc {
+                id1,
+                atype,
+                id2,
+                time,
+                data,
+            } => Some(Query {
+                op: TaoOp::AssocAdd,
+                args: TaoArgs::AssocAddArgs {
+                    id1,
+                    atype,
+                    id2,
+                    time,
+                    data,
+                },
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct RestoreReport {
+    pub restored: u64,
+    /// Rows whose key was taken already
+    pub existing: u64,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn schema_version() -> i32 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+/*
+ * export(tao, ns, plaintext, out)
+ *      Writes a backup of the namespace, decrypted when `plaintext` names
+ *      the namespace's keys. The trailer is only written once every row
+ *      is, so a backup cut short never verifies.
+ */
+pub async fn export<W: Write>(
+    tao: &TaoServer,
+    ns: &Namespace,
+    plaintext: Option<&KeyIds>,
+    out: W,
+) -> Result<Trailer, String> {
+    let decrypt = plaintext.is_some() && tao.encrypted;
+    if decrypt && ns.key_ids.as_ref() != plaintext {
+        return Err(format!(
+            "the keys given to --plaintext are not those of namespace {}",
+            ns.config.name
+        ));
+    }
+    let encrypted = tao.encrypted && !decrypt;
+    let manifest = Manifest {
+        format: BACKUP_FORMAT.to_string(),
+        version: BACKUP_VERSION,
+        created: unix_millis(),
+        namespace: ns.config.name.clone(),
+        schema_version: schema_version(),
+        encrypted,
+        keys: ns.key_ids.clone().filter(|_| encrypted),
+        ope: OpeParams::current(),
+    };
+    let write_line = |out: &mut BufWriter<W>, line: &Line| {
+        let line = serde_json::to_string(line).map_err(|e| e.to_string())?;
+        writeln!(out, "{}", line).map_err(|e| e.to_string())?;
+        Ok::<_, String>(line)
+    };
+    let mut out = BufWriter::new(out);
+    write_line(&mut out, &Line::Manifest { manifest })?;
+
+    let (sender, mut receiver) = mpsc::channel(EXPORT_BUFFER);
+    let reading = tao.store.export(&ns.config, sender);
+    let writing = async move {
+        let mut digest = Sha256::new();
+        let (mut objs, mut assocs) = (0, 0);
+        while let Some(row) = receiver.recv().await {
+            let row = match decrypt {
+                true => ns
+                    .tao_crypto
+                    .decrypt_result(row)
+                    .map_err(|e| e.to_string())?,
+                false => row,
+            };
+            match row {
+                DBRow::ObjRow { .. } => objs += 1,
+                _ => assocs += 1,
+            }
+            let line = match Line::of_row(row) {
+                Some(line) => write_line(&mut out, &line)?,
+                None => return Err("the store sent a result row".to_string()),
+            };
+            digest.update(line.as_bytes());
+            digest.update(b"\n");
+        }
+        let trailer = Trailer {
+            objs,
+            assocs,
+            sha256: hex(&digest.finalize()),
+        };
+        Ok::<_, String>((out, trailer))
+    };
+    // A failed write closes the channel, which ends the read early
+    let (read, written) = futures::join!(reading, writing);
+    let (mut out, trailer) = written?;
+    read.map_err(|e| e.to_string())?;
+
+    let trailer = Line::Trailer { trailer };
+    write_line(&mut out, &trailer)?;
+    out.flush().map_err(|e| e.to_string())?;
+    match trailer {
+        Line::Trailer { trailer } => Ok(trailer),
+        _ => unreachable!(),
+    }
+}
+
+/*
+ * verify(file)
+ *      Reads a whole backup and returns its manifest and trailer, when the
+ *      rows before the trailer are the ones it counts and hashes.
+ */
+pub fn verify<R: Read>(file: R) -> Result<(Manifest, Trailer), String> {
+    let mut lines = BufReader::new(file).lines();
+    let manifest = match lines.next() {
+        Some(Ok(first)) => match serde_json::from_str::<Line>(&first) {
+            Ok(Line::Manifest { manifest }) => manifest,
+            _ => return Err("no manifest, not a backup".to_string()),
+        },
+        Some(Err(e)) => return Err(e.to_string()),
+        None => return Err("empty file, not a backup".to_string()),
+    };
+    if manifest.format != BACKUP_FORMAT || manifest.version != BACKUP_VERSION {
+        return Err(format!(
+            "unknown backup format {} version {}",
+            manifest.format, manifest.version
+        ));
+    }
+
+    let mut digest = Sha256::new();
+    let (mut objs, mut assocs) = (0, 0);
+    let mut trailer = None;
+    for (n, line) in lines.enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        let number = n + 2;
+        if trailer.is_some() {
+            return Err(format!("line {} follows the trailer", number));
+        }
+        match serde_json::from_str::<Line>(&line) {
+            Ok(Line::Obj { .. }) => objs += 1,
+            Ok(Line::Assoc { .. }) => assocs += 1,
+            Ok(Line::Trailer { trailer: t }) => {
+                trailer = Some(t);
+                continue;
+            }
+            _ => return Err(format!("line {} is not a row", number)),
+        }
+        digest.update(line.as_bytes());
+        digest.update(b"\n");
+    }
+    let trailer =
+        trailer.ok_or("no trailer, the backup is incomplete".to_string())?;
+    if (trailer.objs, trailer.assocs) != (objs, assocs) {
+        return Err(format!(
+            "the trailer counts {} objects and {} associations, \
+             the backup holds {} and {}",
+            trailer.objs, trailer.assocs, objs, assocs
+        ));
+    }
+    if trailer.sha256 != hex(&digest.finalize()) {
+        return Err("the rows do not match the trailer's digest".to_string());
+    }
+    Ok((manifest, trailer))
+}
+
+/*
+ * check_restorable(tao, ns, manifest)
+ *      Whether the namespace can take the backup's rows: ciphertext needs
+ *      the very keys and OPE ranges it was made with.
+ */
+pub fn check_restorable(
+    tao: &TaoServer,
+    ns: &Namespace,
+    manifest: &Manifest,
+) -> Result<(), String> {
+    if manifest.schema_version > schema_version() {
+        return Err(format!(
+            "the backup is of schema version {}, this server knows {}",
+            manifest.schema_version,
+            schema_version()
+        ));
+    }
+    if !manifest.encrypted {
+        return Ok(());
+    }
+    if !tao.encrypted {
+        return Err("the backup is encrypted, encryption is off".to_string());
+    }
+    if manifest.ope != OpeParams::current() {
+        return Err("the backup's OPE ranges differ from the server's".into());
+    }
+    match (&manifest.keys, &ns.key_ids) {
+        (Some(backup), Some(keys)) if backup == keys => Ok(()),
+        (Some(_), Some(_)) => Err(format!(
+            "the backup was encrypted with other keys than namespace {}'s",
+            ns.config.name
+        )),
+        (Some(_), None) => Err(format!(
+            "namespace {} has no key files to check the backup's keys with",
+            ns.config.name
+        )),
+        (None, _) => Err("the backup does not name its keys".to_string()),
+    }
+}
+
+/*
+ * restore(tao, ns, file, manifest)
+ *      Loads a backup that verified into the namespace, in batches of
+ *      IMPORT_BATCH_SIZE rows. Reads from the start of the very file that
+ *      verified, so pass the handle it was verified with.
+ */
+pub async fn restore<R: Read + Seek>(
+    tao: &TaoServer,
+    ns: &Namespace,
+    mut file: R,
+    manifest: &Manifest,
+) -> Result<RestoreReport, String> {
+    check_restorable(tao, ns, manifest)?;
+    file.seek(SeekFrom::Start(0)).map_err(|e| e.to_string())?;
+    let mut lines = BufReader::new(file).lines().skip(1);
+    let mut report = RestoreReport::default();
+    loop {
+        let mut batch = Vec::with_capacity(tao.import_batch_size);
+        for line in lines.by_ref() {
+            let line = line.map_err(|e| e.to_string())?;
+            let line = serde_json::from_str::<Line>(&line)
+                .map_err(|e| e.to_string())?;
+            batch.extend(line.into_query());
+            if batch.len() == tao.import_batch_size {
+                break;
+            }
+        }
+        if batch.is_empty() {
+            return Ok(report);
+        }
+        let batch = match manifest.encrypted {
+            true => batch,
+            false => {
+                prepare(tao, ns, batch).await.map_err(|e| e.to_string())?
+            }
+        };
+        let restored = store_queries(tao, RESTORE_PRINCIPAL, ns, &batch)
+            .await
+            .map_err(|e| e.to_string())?;
+        report.restored += restored;
+        report.existing += batch.len() as u64 - restored;
+    }
+}
+
+/*
+ * prepare(tao, ns, queries)
+ *      Checks plaintext rows and encrypts them when encryption is on.
+ */
+async fn prepare(
+    tao: &TaoServer,
+    ns: &Namespace,
+    queries: Vec<Query>,
+) -> Result<Vec<Query>, TaoError> {
+    for query in &queries {
+        validate_args(&query.args)?;
+    }
+    if !tao.encrypted {
+        return Ok(queries);
+    }
+    let crypto = ns.tao_crypto.clone();
+    tokio::task::spawn_blocking(move || encrypt_all(&crypto, queries))
+        .await
+        .map_err(|e| TaoError::Crypto(e.to_string()))?
+        .into_iter()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{check_restorable, export, restore, verify};
+    use crate::query::results::DBRow;
+    use crate::service::import::{parse_record, store_queries, ImportFormat};
+    use crate::service::namespace::DEFAULT_NAMESPACE;
+    use crate::service::shard::Route;
+    use crate::service::tao::TaoServer;
+    use crate::service::testing;
+
+    /*
+     * server(keys)
+     *      An encrypted server on the in-memory store. Servers given the
+     *      same `keys` share their key files.
+     */
+    fn server(keys: &str) -> TaoServer {
+        testing::server(Some(&format!("backup-{}", keys)))
+    }
+
+    async fn add(tao: &TaoServer, records: &[&str]) {
+        let ns = &tao.namespaces[DEFAULT_NAMESPACE];
+        let queries = records
+            .iter()
+            .map(|r| parse_record(ImportFormat::Csv, r).unwrap())
+            .map(|q| ns.tao_crypto.encrypt_query(q).unwrap())
+            .collect::<Vec<_>>();
+        store_queries(tao, "test", ns, &queries).await.unwrap();
+    }
+
+    async fn data(tao: &TaoServer, id: &str) -> Option<String> {
+        let ns = &tao.namespaces[DEFAULT_NAMESPACE];
+        let id = ns.tao_crypto.encrypt_string(id.to_string());
+        let row = tao.store.obj_get(&ns.config, &id, Route::Primary).await;
+        match ns.tao_crypto.decrypt_result(row.unwrap()?).unwrap() {
+            DBRow::ObjRow { data, .. } => Some(data),
+            _ => None,
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_export_and_restore() {
+        let source = server("a");
+        add(&source, &["obj,1,USER,alice", "assoc,1,LIKES,2,5,recent"]).await;
+        let ns = &source.namespaces[DEFAULT_NAMESPACE];
+        let mut backup = Vec::new();
+        let trailer = export(&source, ns, None, &mut backup).await.unwrap();
+        assert_eq!((trailer.objs, trailer.assocs), (1, 1));
+        assert!(!String::from_utf8_lossy(&backup).contains("alice"));
+
+        let mut file = Cursor::new(backup);
+        let (manifest, _) = verify(&mut file).unwrap();
+        assert!(manifest.encrypted);
+        let target = server("a");
+        let ns = &target.namespaces[DEFAULT_NAMESPACE];
+        add(&target, &["obj,1,USER,kept"]).await;
+        let report = restore(&target, ns, &mut file, &manifest).await.unwrap();
+        assert_eq!((report.restored, report.existing), (1, 1));
+        assert_eq!(data(&target, "1").await.unwrap(), "kept");
+        // The same key with another OPE sampler gives other times
+        let mut older = manifest.clone();
+        older.keys.as_mut().unwrap().ope_sampler = 1;
+        assert!(check_restorable(&target, ns, &older).is_err());
+
+        // Ciphertext needs the keys it was made with
+        let other = server("b");
+        let ns = &other.namespaces[DEFAULT_NAMESPACE];
+        assert!(check_restorable(&other, ns, &manifest).is_err());
+
+        // Plaintext takes the namespace's keys once more
+        let keys = other.namespaces[DEFAULT_NAMESPACE].key_ids.clone();
+        let ns = &source.namespaces[DEFAULT_NAMESPACE];
+        let refused = export(&source, ns, keys.as_ref(), Vec::new()).await;
+        assert!(refused.unwrap_err().contains("--plaintext"));
+        let mut backup = Vec::new();
+        export(&source, ns, ns.key_ids.as_ref(), &mut backup)
+            .await
+            .unwrap();
+        assert!(String::from_utf8_lossy(&backup).contains("alice"));
+
+        // and is encrypted anew, with any keys
+        let mut file = Cursor::new(backup);
+        let (manifest, _) = verify(&mut file).unwrap();
+        let ns = &other.namespaces[DEFAULT_NAMESPACE];
+        restore(&other, ns, &mut file, &manifest).await.unwrap();
+        assert_eq!(data(&other, "1").await.unwrap(), "alice");
+    }
+
+    #[actix_web::test]
+    async fn test_verify() {
+        let tao = server("verify");
+        add(&tao, &["obj,1,USER,alice", "obj,2,USER,bob"]).await;
+        let ns = &tao.namespaces[DEFAULT_NAMESPACE];
+        let mut backup = Vec::new();
+        export(&tao, ns, ns.key_ids.as_ref(), &mut backup)
+            .await
+            .unwrap();
+        let backup = String::from_utf8(backup).unwrap();
+        let check = |text: &str| verify(text.as_bytes());
+        assert!(check(&backup).is_ok());
+
+        let lines = backup.lines().collect::<Vec<_>>();
+        let cut = lines[..lines.len() - 1].join("\n");
+        assert!(check(&cut).unwrap_err().contains("incomplete"));
+        let dropped = [lines[0], lines[2], lines[3]].join("\n");
+        assert!(check(&dropped).unwrap_err().contains("counts"));
+        let changed = backup.replace("alice", "mallory");
+        assert!(check(&changed).unwrap_err().contains("digest"));
+        assert!(check("").is_err());
+    }
+}
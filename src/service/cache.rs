@@ -0,0 +1,572 @@
+/*
+ * File: cache.rs
+ *      The read-through cache in front of Postgres. Objects are cached by
+ *      id, and association lists by (id1, atype) as the whole time ordered
+ *      list, so that ASSOC GET, RGET, COUNT and RANGE on a cached list are
+ *      answered without a database round trip.
+ *
+ *      Entries hold the rows exactly as stored, i.e. ciphertext when
+ *      encryption is on. Filtering a cached list works on ciphertext too:
+ *      ids are encrypted deterministically and times with OPE, so equality
+ *      and order are the same as on plaintext.
+ */
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::mem::size_of;
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use quick_cache::{sync::Cache, Weighter};
+use serde::{Deserialize, Serialize};
+
+use crate::query::query::{Query, TaoArgs};
+use crate::query::results::DBRow;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum CacheKey {
+    Obj {
+        namespace: String,
+        id: String,
+    },
+    AssocList {
+        namespace: String,
+        id1: String,
+        atype: String,
+    },
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    weight: NonZeroU32,
+    rows: Arc<Vec<DBRow>>,
+}
+
+impl Entry {
+    fn new(rows: Vec<DBRow>) -> Self {
+        let bytes =
+            size_of::<Entry>() + rows.iter().map(row_bytes).sum::<usize>();
+        let weight = u32::try_from(bytes).unwrap_or(u32::MAX);
+        Entry {
+            weight: NonZeroU32::new(weight).unwrap_or(NonZeroU32::MIN),
+            rows: Arc::new(rows),
+        }
+    }
+}
+
+fn row_bytes(row: &DBRow) -> usize {
+    let heap = match row {
+        DBRow::AssocRow {
+            id1,
+            atype,
+            id2,
+            data,
+            ..
+        } => id1.len() + atype.len() + id2.len() + data.len(),
+        DBRow::ObjRow { id, otype, data } => {
+            id.len() + otype.len() + data.len()
+        }
+        DBRow::Count(_) | DBRow::NoRes(_) => 0,
+    };
+    size_of::<DBRow>() + heap
+}
+
+/*
+ * EntryWeighter
+ *      Weighs entries by their approximate size in bytes, computed once
+ *      when the entry is created.
+ */
+#[derive(Debug, Clone)]
+struct EntryWeighter;
+
+impl Weighter<CacheKey, (), Entry> for EntryWeighter {
+    fn weight(&self, _: &CacheKey, _: &(), entry: &Entry) -> NonZeroU32 {
+        entry.weight
+    }
+}
+
+/*
+ * Invalidation
+ *      The entry a write makes stale, named by ciphertext keys. Followers
+ *      receive these from the leader.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Invalidation {
+    Obj {
+        namespace: String,
+        id: String,
+    },
+    AssocList {
+        namespace: String,
+        id1: String,
+        atype: String,
+    },
+}
+
+impl Invalidation {
+    /*
+     * of_write(ns, query)
+     *      None for reads.
+     */
+    pub fn of_write(ns: &str, query: &Query) -> Option<Invalidation> {
+        match &query.args {
+            TaoArgs::ObjAddArgs { id, .. }
+            | TaoArgs::ObjUpdateArgs { id, .. }
+            | TaoArgs::ObjDeleteArgs { id } => Some(Invalidation::Obj {
+                namespace: ns.to_string(),
+                id: id.clone(),
+            }),
+            TaoArgs::AssocAddArgs { id1, atype, .. }
+            | TaoArgs::AssocDeleteArgs { id1, atype, .. } => {
+                Some(Invalidation::AssocList {
+                    namespace: ns.to_string(),
+                    id1: id1.clone(),
+                    atype: atype.clone(),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub enabled: bool,
+    pub obj_hits: u64,
+    pub obj_misses: u64,
+    pub assoc_hits: u64,
+    pub assoc_misses: u64,
+    pub entries: usize,
+    pub bytes: u64,
+    pub capacity_bytes: u64,
+}
+
+/// Keys whose last write is remembered, for replica routing
+const RECENT_WRITES: usize = 65536;
+/// Write epochs, each shared by the keys that hash to it
+const EPOCHS: usize = 4096;
+
+pub struct GraphCache {
+    cache: Option<Cache<CacheKey, Entry, EntryWeighter>>,
+    recent_writes: Option<Cache<CacheKey, Instant>>,
+    max_assoc_list: usize,
+    epochs: Box<[AtomicU64]>,
+    obj_hits: AtomicU64,
+    obj_misses: AtomicU64,
+    assoc_hits: AtomicU64,
+    assoc_misses: AtomicU64,
+}
+
+/*
+ * Filling the cache
+ *      A read that missed takes the epoch of its key before going to the
+ *      database and hands it back with the rows. Every write bumps the
+ *      epoch of its key before it touches the cache, so a fill that raced
+ *      a write is dropped again instead of caching what the write replaced.
+ *      Keys share EPOCHS epochs by hash, so a write only drops the fills of
+ *      its own key and of the few keys hashing alike.
+ *
+ *      With read replicas a fill may also come from a replica that has not
+ *      replayed a write yet. The time of the last write to each key is
+ *      kept, so that misses on recently written keys read the primary.
+ */
+impl GraphCache {
+    /*
+     * new(capacity_bytes, max_assoc_list)
+     *      A capacity of 0 disables the cache. Association lists longer
+     *      than max_assoc_list are never cached.
+     */
+    pub fn new(capacity_bytes: u64, max_assoc_list: usize) -> Self {
+        let cache = match capacity_bytes {
+            0 => None,
+            _ => {
+                let estimated_items = (capacity_bytes / 512).max(16) as usize;
+                Some(Cache::with_weighter(
+                    estimated_items,
+                    capacity_bytes,
+                    EntryWeighter,
+                ))
+            }
+        };
+        let recent_writes = cache.as_ref().map(|_| Cache::new(RECENT_WRITES));
+        GraphCache {
+            cache,
+            recent_writes,
+            max_assoc_list,
+            epochs: (0..EPOCHS).map(|_| AtomicU64::new(0)).collect(),
+            obj_hits: AtomicU64::new(0),
+            obj_misses: AtomicU64::new(0),
+            assoc_hits: AtomicU64::new(0),
+            assoc_misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.cache.is_some()
+    }
+
+    pub fn max_assoc_list(&self) -> usize {
+        self.max_assoc_list
+    }
+
+    fn epoch(&self, key: &CacheKey) -> &AtomicU64 {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.epochs[(hasher.finish() % EPOCHS as u64) as usize]
+    }
+
+    pub fn obj_epoch(&self, ns: &str, id: &str) -> u64 {
+        self.epoch(&obj_key(ns, id)).load(Ordering::SeqCst)
+    }
+
+    pub fn assoc_list_epoch(&self, ns: &str, id1: &str, atype: &str) -> u64 {
+        self.epoch(&assoc_key(ns, id1, atype))
+            .load(Ordering::SeqCst)
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<Arc<Vec<DBRow>>> {
+        let (hits, misses) = match key {
+            CacheKey::Obj { .. } => (&self.obj_hits, &self.obj_misses),
+            CacheKey::AssocList { .. } => {
+                (&self.assoc_hits, &self.assoc_misses)
+            }
+        };
+        let entry = self.cache.as_ref()?.get(key);
+        match entry {
+            Some(entry) => {
+                hits.fetch_add(1, Ordering::Relaxed);
+                Some(entry.rows)
+            }
+            None => {
+                misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    fn fill(&self, key: CacheKey, rows: Vec<DBRow>, epoch: u64) {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return,
+        };
+        let current = self.epoch(&key);
+        if current.load(Ordering::SeqCst) != epoch {
+            return;
+        }
+        cache.insert(key.clone(), Entry::new(rows));
+        if current.load(Ordering::SeqCst) != epoch {
+            cache.remove(&key);
+        }
+    }
+
+    fn write(&self, key: &CacheKey) {
+        self.epoch(key).fetch_add(1, Ordering::SeqCst);
+        if let Some(recent_writes) = &self.recent_writes {
+            recent_writes.insert(key.clone(), Instant::now());
+        }
+        if let Some(cache) = &self.cache {
+            cache.remove(key);
+        }
+    }
+
+    /*
+     * written_within(key, window)
+     *      Whether the key was written in the last `window`. Keys pushed out
+     *      of the bounded set count as not written.
+     */
+    fn written_within(&self, key: &CacheKey, window: Duration) -> bool {
+        match self.recent_writes.as_ref().and_then(|w| w.get(key)) {
+            Some(written) => written.elapsed() < window,
+            None => false,
+        }
+    }
+
+    pub fn obj_written_within(
+        &self,
+        ns: &str,
+        id: &str,
+        window: Duration,
+    ) -> bool {
+        self.written_within(&obj_key(ns, id), window)
+    }
+
+    pub fn assoc_list_written_within(
+        &self,
+        ns: &str,
+        id1: &str,
+        atype: &str,
+        window: Duration,
+    ) -> bool {
+        self.written_within(&assoc_key(ns, id1, atype), window)
+    }
+
+    pub fn get_obj(&self, ns: &str, id: &str) -> Option<Arc<Vec<DBRow>>> {
+        self.get(&obj_key(ns, id))
+    }
+
+    pub fn fill_obj(&self, ns: &str, id: &str, rows: Vec<DBRow>, epoch: u64) {
+        self.fill(obj_key(ns, id), rows, epoch)
+    }
+
+    /*
+     * put_obj(ns, id, row)
+     *      After a successful OBJ ADD the new object is cached right away.
+     */
+    pub fn put_obj(&self, ns: &str, id: &str, row: DBRow) {
+        let key = obj_key(ns, id);
+        self.write(&key);
+        let epoch = self.epoch(&key).load(Ordering::SeqCst);
+        self.fill(key, vec![row], epoch);
+    }
+
+    pub fn invalidate_obj(&self, ns: &str, id: &str) {
+        self.write(&obj_key(ns, id))
+    }
+
+    pub fn get_assoc_list(
+        &self,
+        ns: &str,
+        id1: &str,
+        atype: &str,
+    ) -> Option<Arc<Vec<DBRow>>> {
+        self.get(&assoc_key(ns, id1, atype))
+    }
+
+    pub fn fill_assoc_list(
+        &self,
+        ns: &str,
+        id1: &str,
+        atype: &str,
+        rows: Vec<DBRow>,
+        epoch: u64,
+    ) {
+        if rows.len() > self.max_assoc_list {
+            return;
+        }
+        self.fill(assoc_key(ns, id1, atype), rows, epoch)
+    }
+
+    pub fn invalidate_assoc_list(&self, ns: &str, id1: &str, atype: &str) {
+        self.write(&assoc_key(ns, id1, atype))
+    }
+
+    pub fn invalidate(&self, invalidation: &Invalidation) {
+        match invalidation {
+            Invalidation::Obj { namespace, id } => {
+                self.invalidate_obj(namespace, id)
+            }
+            Invalidation::AssocList {
+                namespace,
+                id1,
+                atype,
+            } => self.invalidate_assoc_list(namespace, id1, atype),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let (entries, bytes, capacity_bytes) = match &self.cache {
+            Some(cache) => (cache.len(), cache.weight(), cache.capacity()),
+            None => (0, 0, 0),
+        };
+        CacheStats {
+            enabled: self.is_enabled(),
+            obj_hits: self.obj_hits.load(Ordering::Relaxed),
+            obj_misses: self.obj_misses.load(Ordering::Relaxed),
+            assoc_hits: self.assoc_hits.load(Ordering::Relaxed),
+            assoc_misses: self.assoc_misses.load(Ordering::Relaxed),
+            entries,
+            bytes,
+            capacity_bytes,
+        }
+    }
+}
+
+fn obj_key(ns: &str, id: &str) -> CacheKey {
+    CacheKey::Obj {
+        namespace: ns.to_string(),
+        id: id.to_string(),
+    }
+}
+
+fn assoc_key(ns: &str, id1: &str, atype: &str) -> CacheKey {
+    CacheKey::AssocList {
+        namespace: ns.to_string(),
+        id1: id1.to_string(),
+        atype: atype.to_string(),
+    }
+}
+
+/*
+ * read_assoc_list(list, args)
+ *      Answers an association read from the full list of (id1, atype),
+ *      which is ordered by time, newest first. Returns None for queries
+ *      that are not association reads.
+ */
+pub fn read_assoc_list(list: &[DBRow], args: &TaoArgs) -> Option<Vec<DBRow>> {
+    let in_range = |row: &DBRow, tstart: i64, tend: i64| match row {
+        DBRow::AssocRow { t, .. } => tstart <= *t && *t <= tend,
+        _ => false,
+    };
+    let in_set = |row: &DBRow, idset: &Vec<String>| match row {
+        DBRow::AssocRow { id2, .. } => idset.contains(id2),
+        _ => false,
+    };
+
+    let rows = match args {
+        TaoArgs::AssocGetArgs { idset, .. } => list
+            .iter()
+            .filter(|row| in_set(row, idset))
+            .cloned()
+            .collect::<Vec<DBRow>>(),
+        TaoArgs::AssocRangeGetArgs {
+            idset,
+            tstart,
+            tend,
+            ..
+        } => list
+            .iter()
+            .filter(|row| in_set(row, idset) && in_range(row, *tstart, *tend))
+            .cloned()
+            .collect::<Vec<DBRow>>(),
+        TaoArgs::AssocCountArgs { .. } => {
+            vec![DBRow::Count(list.len() as i64)]
+        }
+        TaoArgs::AssocRangeArgs {
+            tstart, tend, lim, ..
+        } => list
+            .iter()
+            .filter(|row| in_range(row, *tstart, *tend))
+            .take(usize::try_from(*lim).ok()?)
+            .cloned()
+            .collect::<Vec<DBRow>>(),
+        _ => return None,
+    };
+    Some(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{read_assoc_list, GraphCache};
+    use crate::query::query::TaoArgs;
+    use crate::query::results::DBRow;
+
+    fn assoc(id2: &str, t: i64) -> DBRow {
+        DBRow::AssocRow {
+            id1: "1".to_string(),
+            atype: "LIKES".to_string(),
+            id2: id2.to_string(),
+            t,
+            data: "x".to_string(),
+        }
+    }
+
+    fn obj(id: &str) -> DBRow {
+        DBRow::ObjRow {
+            id: id.to_string(),
+            otype: "USER".to_string(),
+            data: "alice".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_read_assoc_list() {
+        let list = vec![assoc("4", 40), assoc("3", 30), assoc("2", 20)];
+        let range = TaoArgs::AssocRangeArgs {
+            id: "1".to_string(),
+            atype: "LIKES".to_string(),
+            tstart: 20,
+            tend: 35,
+            lim: 1,
+        };
+        let rows = read_assoc_list(&list, &range).unwrap();
+        assert!(matches!(&rows[..], [DBRow::AssocRow { t: 30, .. }]));
+
+        let get = TaoArgs::AssocRangeGetArgs {
+            id: "1".to_string(),
+            atype: "LIKES".to_string(),
+            idset: vec!["2".to_string(), "4".to_string()],
+            tstart: 0,
+            tend: 30,
+        };
+        assert_eq!(read_assoc_list(&list, &get).unwrap().len(), 1);
+
+        let count = TaoArgs::AssocCountArgs {
+            id: "1".to_string(),
+            atype: "LIKES".to_string(),
+        };
+        let rows = read_assoc_list(&list, &count).unwrap();
+        assert!(matches!(rows[..], [DBRow::Count(3)]));
+    }
+
+    #[test]
+    fn test_hits_and_writes() {
+        let cache = GraphCache::new(1 << 20, 100);
+        assert!(cache.get_obj("default", "1").is_none());
+        let epoch = cache.obj_epoch("default", "1");
+        cache.fill_obj("default", "1", vec![obj("1")], epoch);
+        assert_eq!(cache.get_obj("default", "1").unwrap().len(), 1);
+        assert!(cache.get_obj("staging", "1").is_none());
+
+        let epoch = cache.assoc_list_epoch("default", "1", "LIKES");
+        cache.fill_assoc_list("default", "1", "LIKES", vec![], epoch);
+        cache.invalidate_assoc_list("default", "1", "LIKES");
+        assert!(cache.get_assoc_list("default", "1", "LIKES").is_none());
+
+        let stats = cache.stats();
+        assert_eq!((stats.obj_hits, stats.obj_misses), (1, 2));
+        assert_eq!((stats.assoc_hits, stats.assoc_misses), (0, 1));
+    }
+
+    #[test]
+    fn test_stale_fill_is_dropped() {
+        let cache = GraphCache::new(1 << 20, 100);
+        let epoch = cache.obj_epoch("default", "1");
+        cache.put_obj("default", "1", obj("1"));
+        cache.fill_obj("default", "1", vec![], epoch);
+        assert_eq!(cache.get_obj("default", "1").unwrap().len(), 1);
+
+        // Writes to other keys leave the fill be
+        let epoch = cache.assoc_list_epoch("default", "1", "LIKES");
+        cache.put_obj("default", "2", obj("2"));
+        cache.invalidate_assoc_list("default", "1", "FRIEND");
+        cache.fill_assoc_list("default", "1", "LIKES", vec![], epoch);
+        assert!(cache.get_assoc_list("default", "1", "LIKES").is_some());
+    }
+
+    #[test]
+    fn test_recent_writes() {
+        let cache = GraphCache::new(1 << 20, 100);
+        let window = Duration::from_secs(60);
+        assert!(!cache.obj_written_within("default", "1", window));
+        cache.put_obj("default", "1", obj("1"));
+        cache.invalidate_assoc_list("default", "1", "LIKES");
+        assert!(cache.obj_written_within("default", "1", window));
+        assert!(!cache.obj_written_within("default", "1", Duration::ZERO));
+        assert!(
+            cache.assoc_list_written_within("default", "1", "LIKES", window)
+        );
+        assert!(
+            !cache.assoc_list_written_within("default", "1", "FRIEND", window)
+        );
+    }
+
+    #[test]
+    fn test_bounded() {
+        let cache = GraphCache::new(4096, 100);
+        for id in 0..1000 {
+            let id = id.to_string();
+            let epoch = cache.obj_epoch("default", &id);
+            cache.fill_obj("default", &id, vec![obj("1")], epoch);
+        }
+        assert!(cache.stats().bytes <= 4096);
+
+        let long = (0..5).map(|t| assoc("2", t)).collect::<Vec<DBRow>>();
+        let cache = GraphCache::new(1 << 20, 4);
+        let epoch = cache.assoc_list_epoch("default", "1", "LIKES");
+        cache.fill_assoc_list("default", "1", "LIKES", long, epoch);
+        assert!(cache.get_assoc_list("default", "1", "LIKES").is_none());
+    }
+}
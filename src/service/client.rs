@@ -0,0 +1,135 @@
+/*
+ * File: client.rs
+ *      HTTP client for the TAO query endpoint, shared by tao-cli and
+ *      tao-interactive
+ */
+use std::cell::RefCell;
+use std::path::PathBuf;
+
+use awc::{Client, Connector};
+use openssl::ssl::SslFiletype;
+
+use crate::service::tao::{QueryRequest, QueryResponse, SESSION_HEADER};
+use crate::service::tls::pinned_connector;
+
+#[derive(Debug, Default)]
+pub struct ClientOptions {
+    pub tls: bool,
+    /// Only trust server certificates issued by this CA
+    pub ca_file: Option<PathBuf>,
+    /// Client certificate and key for mutual TLS
+    pub cert_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
+    /// Bearer token sent with every query
+    pub token: Option<String>,
+}
+
+/*
+ * TaoClient
+ *      Keeps the read-your-writes session token of its last write, so that
+ *      its reads see what it wrote even when the server reads replicas.
+ */
+pub struct TaoClient {
+    client: Client,
+    endpoint: String,
+    session: RefCell<Option<String>>,
+}
+
+impl TaoClient {
+    /*
+     * new(host, port, options)
+     *      A CA file or a client certificate implies https. The CA file pins
+     *      the server certificate to that CA.
+     */
+    pub fn new(
+        host: &str,
+        port: &str,
+        options: ClientOptions,
+    ) -> Result<Self, String> {
+        let tls = options.tls
+            || options.ca_file.is_some()
+            || options.cert_file.is_some();
+        let connector = match tls {
+            true => {
+                let mut connector =
+                    pinned_connector(options.ca_file.as_deref())?;
+                match (&options.cert_file, &options.key_file) {
+                    (Some(cert_file), Some(key_file)) => {
+                        connector
+                            .set_certificate_chain_file(cert_file)
+                            .map_err(|e| {
+                                format!("{}: {}", cert_file.display(), e)
+                            })?;
+                        connector
+                            .set_private_key_file(key_file, SslFiletype::PEM)
+                            .map_err(|e| {
+                                format!("{}: {}", key_file.display(), e)
+                            })?;
+                    }
+                    (None, None) => (),
+                    _ => {
+                        return Err("a client certificate needs its key, and \
+                                    the key its certificate"
+                            .to_string())
+                    }
+                }
+                Some(connector.build())
+            }
+            false => None,
+        };
+
+        let mut builder = Client::builder();
+        if let Some(token) = &options.token {
+            builder = builder.bearer_auth(token);
+        }
+        let client = match connector {
+            Some(connector) => builder
+                .connector(Connector::new().openssl(connector))
+                .finish(),
+            None => builder.finish(),
+        };
+        let scheme = match tls {
+            true => "https",
+            false => "http",
+        };
+        let endpoint = format!("{}://{}:{}/query", scheme, host, port);
+        Ok(TaoClient {
+            client,
+            endpoint,
+            session: RefCell::new(None),
+        })
+    }
+
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    pub async fn query(&self, query: String) -> Result<QueryResponse, String> {
+        let mut request = self.client.post(&self.endpoint);
+        if let Some(session) = self.session.borrow().as_deref() {
+            request = request.insert_header((SESSION_HEADER, session));
+        }
+        let mut resp = request
+            .send_json(&QueryRequest { query })
+            .await
+            .map_err(|e| {
+                format!("request to {} failed: {}", self.endpoint, e)
+            })?;
+        if !resp.status().is_success() {
+            let body = resp.body().await.unwrap_or_default();
+            return Err(format!(
+                "server returned {}: {}",
+                resp.status(),
+                String::from_utf8_lossy(&body)
+            ));
+        }
+        if let Some(session) = resp.headers().get(SESSION_HEADER) {
+            if let Ok(session) = session.to_str() {
+                self.session.replace(Some(session.to_string()));
+            }
+        }
+        resp.json::<QueryResponse>()
+            .await
+            .map_err(|e| format!("invalid response: {}", e))
+    }
+}
@@ -0,0 +1,877 @@
+/*
+ * File: config.rs
+ *      TAO server configuration
+ *
+ *      Settings are layered, later sources win:
+ *          config file < environment variables < command line flags
+ *      The config file uses the same KEY=value lines as the environment
+ *      variables, so an existing .env file works as a config file.
+ */
+use std::path::{Path, PathBuf};
+
+use clap::{Parser, Subcommand, ValueEnum};
+use tracing_subscriber::EnvFilter;
+
+use crate::service::import::ImportFormat;
+use crate::service::limits::Limits;
+use crate::service::namespace::{
+    load_tenants, NamespaceConfig, DEFAULT_NAMESPACE,
+};
+use crate::service::shard::load_shards;
+use crate::service::tao::DBConfig;
+use crate::service::tier::TierRole;
+use crate::service::tls::DBSslMode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum EncryptionMode {
+    On,
+    Off,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum StorageBackend {
+    /// Postgres, possibly sharded and replicated, see DATABASE_*
+    Postgres,
+    /// A single local SQLite file, for one server on one machine
+    Sqlite,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// One human readable line per event
+    Text,
+    /// One JSON object per event, with the fields of its spans
+    Json,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ServerCommand {
+    /// Generate a fresh OPE key and AES keyset
+    Keygen {
+        /// Where to write the OPE key
+        #[arg(long, default_value = "ope.key")]
+        ope_key_file: PathBuf,
+        /// Where to write the AES keyset
+        #[arg(long, default_value = "aes_keyset.json")]
+        aes_keyset_file: PathBuf,
+    },
+    /// Create or upgrade the database schema
+    Migrate {
+        /// Print the pending migrations without applying them
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Generate an API token and the digest to put in the auth file
+    Token {
+        /// Principal the token identifies
+        principal: String,
+    },
+    /// Check the hash chain of an audit log
+    VerifyAudit {
+        /// Audit log to check
+        audit_log_file: PathBuf,
+        /// Mend a torn last record left by an append that did not finish
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Load objects and associations from a JSONL or CSV file
+    Import {
+        /// File to import, one record per line
+        file: PathBuf,
+        /// Format of the records
+        #[arg(long, value_enum, default_value = "jsonl")]
+        format: ImportFormat,
+        /// Namespace to import into
+        #[arg(long, default_value = DEFAULT_NAMESPACE)]
+        namespace: String,
+        /// Where to keep the lines done, the import resumes after them
+        #[arg(long)]
+        progress_file: Option<PathBuf>,
+        /// Where to append the records that failed, as JSON lines
+        #[arg(long)]
+        errors_file: Option<PathBuf>,
+    },
+    /// Write a backup of a namespace, as stored ciphertext by default
+    Export {
+        /// Where to write the backup
+        file: PathBuf,
+        /// Namespace to back up
+        #[arg(long, default_value = DEFAULT_NAMESPACE)]
+        namespace: String,
+        /// Decrypt the rows, given the namespace's OPE key and AES keyset
+        /// files once more
+        #[arg(
+            long,
+            num_args = 2,
+            value_names = ["OPE_KEY_FILE", "AES_KEYSET_FILE"]
+        )]
+        plaintext: Option<Vec<PathBuf>>,
+    },
+    /// Check a backup and load it into a namespace
+    Restore {
+        /// Backup to restore
+        file: PathBuf,
+        /// Namespace to restore into [default: the backup's]
+        #[arg(long)]
+        namespace: Option<String>,
+        /// Check the backup without loading it
+        #[arg(long)]
+        verify_only: bool,
+    },
+}
+
+#[derive(Debug, Parser)]
+#[command(name = "tao-server", about = "Encrypted TAO server")]
+pub struct ServerArgs {
+    /// Config file of KEY=value lines [default: ./.env if present]
+    #[arg(long, short, env = "TAO_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Address to bind the HTTP listener to
+    #[arg(long, env = "SERVER_ADDR", default_value = "localhost")]
+    pub addr: String,
+    /// Port to bind the HTTP listener to
+    #[arg(long, env = "SERVER_PORT", default_value_t = 8080)]
+    pub port: u16,
+    /// Number of HTTP worker threads [default: number of CPUs]
+    #[arg(long, env = "SERVER_WORKERS")]
+    pub workers: Option<usize>,
+    /// Port to serve the gRPC API on, which is off unless set
+    #[arg(long, env = "GRPC_PORT")]
+    pub grpc_port: Option<u16>,
+    /// Seconds between turning unready and closing the listeners on SIGTERM
+    #[arg(long, env = "SHUTDOWN_DELAY_SECS", default_value_t = 0)]
+    pub shutdown_delay_secs: u64,
+    /// Seconds in-flight requests get to finish once the listeners close
+    #[arg(long, env = "SHUTDOWN_GRACE_SECS", default_value_t = 30)]
+    pub shutdown_grace_secs: u64,
+
+    /// PEM certificate chain, serves https when set
+    #[arg(long, env = "TLS_CERT_FILE")]
+    pub tls_cert_file: Option<PathBuf>,
+    /// PEM private key of the certificate
+    #[arg(long, env = "TLS_KEY_FILE")]
+    pub tls_key_file: Option<PathBuf>,
+    /// CA that issues client certificates, enables mutual TLS
+    #[arg(long, env = "TLS_CLIENT_CA_FILE")]
+    pub tls_client_ca_file: Option<PathBuf>,
+    /// Token digests and client certificate names of allowed callers
+    #[arg(long, env = "AUTH_FILE")]
+    pub auth_file: Option<PathBuf>,
+    /// Operations and types each principal may use
+    #[arg(long, env = "POLICY_FILE")]
+    pub policy_file: Option<PathBuf>,
+    /// Append-only, hash-chained log of every write
+    #[arg(long, env = "AUDIT_LOG_FILE")]
+    pub audit_log_file: Option<PathBuf>,
+
+    /// Encrypt queries before they reach the database
+    #[arg(long, env = "ENCRYPTION", value_enum, default_value = "on")]
+    pub encryption: EncryptionMode,
+    /// File holding the OPE key, required when encryption is on
+    #[arg(long, env = "OPE_KEY_FILE")]
+    pub ope_key_file: Option<PathBuf>,
+    /// Tink AES-SIV keyset in JSON, required when encryption is on
+    #[arg(long, env = "AES_KEYSET_FILE")]
+    pub aes_keyset_file: Option<PathBuf>,
+    /// Entries in each of the AES encrypt/decrypt caches
+    #[arg(long, env = "AES_CACHE_SIZE", default_value_t = 1024)]
+    pub aes_cache_size: usize,
+    /// Entries in each of the OPE encrypt/decrypt caches
+    #[arg(long, env = "OPE_CACHE_SIZE", default_value_t = 1024)]
+    pub ope_cache_size: usize,
+
+    /// Memory for cached objects and association lists, 0 disables it
+    #[arg(long, env = "GRAPH_CACHE_MB", default_value_t = 64)]
+    pub graph_cache_mb: u64,
+    /// Longer association lists are always read from the database
+    #[arg(long, env = "GRAPH_CACHE_MAX_ASSOC_LIST", default_value_t = 1000)]
+    pub graph_cache_max_assoc_list: usize,
+    /// Change feed events a slow subscriber may fall behind before skipping
+    #[arg(long, env = "FEED_BUFFER", default_value_t = 1024)]
+    pub feed_buffer: usize,
+    /// Milliseconds a request may take, all of its queries included
+    #[arg(long, env = "REQUEST_TIMEOUT_MS", default_value_t = 30_000)]
+    pub request_timeout_ms: u64,
+    /// Milliseconds each query of a request may take
+    #[arg(long, env = "QUERY_TIMEOUT_MS", default_value_t = 10_000)]
+    pub query_timeout_ms: u64,
+    /// Largest request body in bytes
+    #[arg(long, env = "MAX_BODY_BYTES", default_value_t = 1024 * 1024)]
+    pub max_body_bytes: usize,
+    /// Most queries in one batch
+    #[arg(long, env = "MAX_BATCH_QUERIES", default_value_t = 100)]
+    pub max_batch_queries: usize,
+    /// Most ids in the id set of an ASSOC GET or RGET
+    #[arg(long, env = "MAX_ID_SET", default_value_t = 1000)]
+    pub max_id_set: usize,
+    /// Largest limit of an ASSOC RANGE
+    #[arg(long, env = "MAX_RANGE_LIMIT", default_value_t = 10_000)]
+    pub max_range_limit: i64,
+    /// Queries per second each principal may run, 0 for no limit
+    #[arg(long, env = "RATE_LIMIT_QPS", default_value_t = 0)]
+    pub rate_limit_qps: u32,
+    /// Queries a principal may run at once after idling
+    #[arg(long, env = "RATE_LIMIT_BURST", default_value_t = 200)]
+    pub rate_limit_burst: u32,
+    /// Largest body of a POST /import in bytes
+    #[arg(long, env = "MAX_IMPORT_BYTES", default_value_t = 1 << 30)]
+    pub max_import_bytes: u64,
+    /// Lines of an import loaded at once
+    #[arg(long, env = "IMPORT_BATCH_SIZE", default_value_t = 1000)]
+    pub import_batch_size: usize,
+
+    /// Levels to log, e.g. "info" or "warn,encrypted_tao=debug"
+    #[arg(long, env = "LOG_LEVEL", default_value = "info")]
+    pub log_level: String,
+    /// Format of the log lines on stdout
+    #[arg(long, env = "LOG_FORMAT", value_enum, default_value = "text")]
+    pub log_format: LogFormat,
+    /// OTLP/gRPC endpoint to export spans to, e.g. http://localhost:4317
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+    /// File to append spans to as OTLP JSON lines
+    #[arg(long, env = "TRACE_FILE")]
+    pub trace_file: Option<PathBuf>,
+
+    /// Leader applies writes, followers forward them to the leader
+    #[arg(long, env = "TIER_ROLE", value_enum, default_value = "leader")]
+    pub tier_role: TierRole,
+    /// Base URL of the leader, required for followers
+    #[arg(long, env = "TIER_LEADER_URL")]
+    pub tier_leader_url: Option<String>,
+    /// Base URLs of the followers the leader sends invalidations to
+    #[arg(long, env = "TIER_FOLLOWER_URLS", value_delimiter = ',')]
+    pub tier_follower_urls: Vec<String>,
+    /// Only read from the environment, shared by leader and followers
+    #[arg(skip)]
+    pub tier_token: Option<String>,
+
+    /// Where the graph is stored
+    #[arg(long, env = "STORAGE", value_enum, default_value = "postgres")]
+    pub storage: StorageBackend,
+    /// Database file of the sqlite storage, created if missing
+    #[arg(long, env = "SQLITE_FILE", default_value = "tao.db")]
+    pub sqlite_file: PathBuf,
+
+    #[arg(long, env = "DATABASE_HOST")]
+    pub db_host: Option<String>,
+    #[arg(long, env = "DATABASE_PORT_NUM", default_value = "5432")]
+    pub db_port: String,
+    #[arg(long, env = "DATABASE_NAME")]
+    pub db_name: Option<String>,
+    #[arg(long, env = "DATABASE_USERNAME")]
+    pub db_user: Option<String>,
+    /// Only read from the environment, so it never shows up in `ps`
+    #[arg(skip)]
+    pub db_password: Option<String>,
+    /// TLS towards Postgres
+    #[arg(
+        long,
+        env = "DATABASE_SSLMODE",
+        value_enum,
+        default_value = "disable"
+    )]
+    pub db_sslmode: DBSslMode,
+    /// CA certificate(s) the Postgres server certificate must chain to
+    #[arg(long, env = "DATABASE_SSLROOTCERT")]
+    pub db_sslrootcert: Option<PathBuf>,
+    /// Read replicas of the database as host or host:port
+    #[arg(long, env = "DATABASE_REPLICAS", value_delimiter = ',')]
+    pub db_replicas: Vec<String>,
+    /// Seconds a client's reads go to the primary after it wrote
+    #[arg(long, env = "READ_YOUR_WRITES_SECS", default_value_t = 5)]
+    pub read_your_writes_secs: u64,
+    #[arg(long, env = "DATABASE_POOL_MIN_SIZE", default_value_t = 1)]
+    pub db_pool_min_size: u32,
+    #[arg(long, env = "DATABASE_POOL_MAX_SIZE", default_value_t = 16)]
+    pub db_pool_max_size: u32,
+    /// Seconds an idle pooled connection is kept open
+    #[arg(long, env = "DATABASE_POOL_IDLE_TIMEOUT", default_value_t = 600)]
+    pub db_pool_idle_timeout: u64,
+    /// Seconds to wait for a pooled connection before failing
+    #[arg(long, env = "DATABASE_POOL_CHECKOUT_TIMEOUT", default_value_t = 30)]
+    pub db_pool_checkout_timeout: u64,
+
+    /// Postgres schema of the default namespace
+    #[arg(long, env = "DATABASE_SCHEMA", default_value = "public")]
+    pub db_schema: String,
+    /// Association table of the default namespace
+    #[arg(long, env = "ASSOC_TABLE", default_value = "assoc_test")]
+    pub assoc_table: String,
+    /// Object table of the default namespace
+    #[arg(long, env = "OBJ_TABLE", default_value = "obj_test")]
+    pub obj_table: String,
+    /// JSON file describing additional namespaces
+    #[arg(long, env = "TENANTS_FILE")]
+    pub tenants_file: Option<PathBuf>,
+    /// JSON list of database shards, DATABASE_* fill in what they omit
+    #[arg(long, env = "SHARDS_FILE")]
+    pub shards_file: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<ServerCommand>,
+}
+
+impl ServerArgs {
+    /*
+     * load()
+     *      Flags are parsed twice: once to find the config file, and again
+     *      after its values have been added to the environment. dotenv never
+     *      overwrites variables that are already set, which gives the
+     *      environment precedence over the file.
+     */
+    pub fn load() -> Result<Self, String> {
+        let args = ServerArgs::parse();
+        match &args.config {
+            Some(path) => {
+                dotenv::from_path(path).map_err(|e| {
+                    format!("cannot read config file {}: {}", path.display(), e)
+                })?;
+            }
+            None => {
+                dotenv::dotenv().ok();
+            }
+        }
+
+        let mut args = ServerArgs::parse();
+        args.db_password = dotenv::var("DATABASE_PASSWORD").ok();
+        args.tier_token = dotenv::var("TIER_TOKEN").ok();
+        Ok(args)
+    }
+
+    pub fn db_config(&self) -> Result<DBConfig, String> {
+        let db_config = DBConfig {
+            host: require(&self.db_host, "DATABASE_HOST", "--db-host")?,
+            user: require(&self.db_user, "DATABASE_USERNAME", "--db-user")?,
+            password: self.db_password.clone().ok_or(
+                "missing database password, set DATABASE_PASSWORD".to_string(),
+            )?,
+            db_name: require(&self.db_name, "DATABASE_NAME", "--db-name")?,
+            port: self.db_port.clone(),
+            pool_min_size: self.db_pool_min_size,
+            pool_max_size: self.db_pool_max_size,
+            pool_idle_timeout: self.db_pool_idle_timeout,
+            pool_checkout_timeout: self.db_pool_checkout_timeout,
+            ssl_mode: self.db_sslmode,
+            ssl_root_cert: self.db_sslrootcert.clone(),
+            replicas: self.db_replicas.clone(),
+        };
+        if db_config.port.parse::<u16>().is_err() {
+            return Err(format!(
+                "DATABASE_PORT_NUM must be a port number, got {:?}",
+                db_config.port
+            ));
+        }
+        if let Some(path) = &db_config.ssl_root_cert {
+            if !path.is_file() {
+                return Err(format!(
+                    "DATABASE_SSLROOTCERT {} does not exist",
+                    path.display()
+                ));
+            }
+        }
+        for replica in &db_config.replicas {
+            db_config
+                .replica(replica)
+                .map_err(|e| format!("DATABASE_REPLICAS: {}", e))?;
+        }
+        if db_config.pool_max_size == 0 {
+            return Err("DATABASE_POOL_MAX_SIZE must be at least 1".to_string());
+        }
+        if db_config.pool_min_size > db_config.pool_max_size {
+            return Err(format!(
+                "DATABASE_POOL_MIN_SIZE ({}) exceeds DATABASE_POOL_MAX_SIZE ({})",
+                db_config.pool_min_size, db_config.pool_max_size
+            ));
+        }
+        Ok(db_config)
+    }
+
+    /*
+     * shards()
+     *      Without a shards file the graph lives in the single database
+     *      described by DATABASE_*.
+     */
+    pub fn shards(&self) -> Result<Vec<DBConfig>, String> {
+        let db_config = self.db_config()?;
+        match &self.shards_file {
+            Some(path) => load_shards(path, &db_config),
+            None => Ok(vec![db_config]),
+        }
+    }
+
+    /*
+     * namespaces()
+     *      The default namespace first, followed by the tenants file.
+     */
+    pub fn namespaces(&self) -> Result<Vec<NamespaceConfig>, String> {
+        let mut namespaces = vec![NamespaceConfig {
+            name: DEFAULT_NAMESPACE.to_string(),
+            schema: self.db_schema.clone(),
+            assoc_table: self.assoc_table.clone(),
+            obj_table: self.obj_table.clone(),
+            ope_key_file: None,
+            aes_keyset_file: None,
+        }];
+        if let Some(path) = &self.tenants_file {
+            for tenant in load_tenants(path)? {
+                if tenant.name == DEFAULT_NAMESPACE {
+                    return Err(format!(
+                        "tenants file {} redefines the {} namespace",
+                        path.display(),
+                        DEFAULT_NAMESPACE
+                    ));
+                }
+                namespaces.push(tenant);
+            }
+        }
+        for namespace in &namespaces {
+            namespace.validate()?;
+        }
+        Ok(namespaces)
+    }
+}
+
+#[derive(Debug)]
+pub struct ServerConfig {
+    pub addr: String,
+    pub port: u16,
+    pub workers: Option<usize>,
+    pub grpc_port: Option<u16>,
+    pub shutdown_delay_secs: u64,
+    pub shutdown_grace_secs: u64,
+    pub tls_cert_file: Option<PathBuf>,
+    pub tls_key_file: Option<PathBuf>,
+    pub tls_client_ca_file: Option<PathBuf>,
+    pub auth_file: Option<PathBuf>,
+    pub policy_file: Option<PathBuf>,
+    pub audit_log_file: Option<PathBuf>,
+    pub encrypted: bool,
+    pub ope_key_file: Option<PathBuf>,
+    pub aes_keyset_file: Option<PathBuf>,
+    pub aes_cache_size: usize,
+    pub ope_cache_size: usize,
+    pub graph_cache_mb: u64,
+    pub graph_cache_max_assoc_list: usize,
+    pub feed_buffer: usize,
+    pub request_timeout_ms: u64,
+    pub query_timeout_ms: u64,
+    pub limits: Limits,
+    pub rate_limit_qps: u32,
+    pub rate_limit_burst: u32,
+    pub import_batch_size: usize,
+    pub log_level: String,
+    pub log_format: LogFormat,
+    pub otlp_endpoint: Option<String>,
+    pub trace_file: Option<PathBuf>,
+    pub tier_role: TierRole,
+    pub tier_leader_url: Option<String>,
+    pub tier_follower_urls: Vec<String>,
+    pub tier_token: Option<String>,
+    pub storage: StorageBackend,
+    pub sqlite_file: PathBuf,
+    /// Empty unless the storage is Postgres
+    pub shards: Vec<DBConfig>,
+    pub read_your_writes_secs: u64,
+    pub namespaces: Vec<NamespaceConfig>,
+}
+
+impl ServerConfig {
+    pub fn from_args(args: ServerArgs) -> Result<Self, String> {
+        if args.workers == Some(0) {
+            return Err("SERVER_WORKERS must be at least 1".to_string());
+        }
+        if args.grpc_port == Some(args.port) {
+            return Err(format!(
+                "GRPC_PORT and SERVER_PORT are both {}",
+                args.port
+            ));
+        }
+
+        match (&args.tls_cert_file, &args.tls_key_file) {
+            (Some(_), None) | (None, Some(_)) => {
+                return Err(
+                    "set both TLS_CERT_FILE and TLS_KEY_FILE, or neither"
+                        .to_string(),
+                )
+            }
+            (Some(cert), Some(key)) => {
+                for (env, path) in
+                    [("TLS_CERT_FILE", cert), ("TLS_KEY_FILE", key)]
+                {
+                    if !path.is_file() {
+                        return Err(format!(
+                            "{} {} does not exist",
+                            env,
+                            path.display()
+                        ));
+                    }
+                }
+            }
+            (None, None) => (),
+        }
+        if let Some(ca_file) = &args.tls_client_ca_file {
+            if args.tls_cert_file.is_none() {
+                return Err(
+                    "TLS_CLIENT_CA_FILE needs TLS_CERT_FILE and TLS_KEY_FILE"
+                        .to_string(),
+                );
+            }
+            if !ca_file.is_file() {
+                return Err(format!(
+                    "TLS_CLIENT_CA_FILE {} does not exist",
+                    ca_file.display()
+                ));
+            }
+        }
+        for (env, path) in [
+            ("AUTH_FILE", &args.auth_file),
+            ("POLICY_FILE", &args.policy_file),
+        ] {
+            if let Some(path) = path {
+                if !path.is_file() {
+                    return Err(format!(
+                        "{} {} does not exist",
+                        env,
+                        path.display()
+                    ));
+                }
+            }
+        }
+
+        if args.feed_buffer == 0 {
+            return Err("FEED_BUFFER must be at least 1".to_string());
+        }
+        if args.request_timeout_ms == 0 || args.query_timeout_ms == 0 {
+            return Err(
+                "REQUEST_TIMEOUT_MS and QUERY_TIMEOUT_MS must be at least 1"
+                    .to_string(),
+            );
+        }
+        if args.max_body_bytes == 0
+            || args.max_batch_queries == 0
+            || args.max_id_set == 0
+            || args.max_range_limit <= 0
+            || args.max_import_bytes == 0
+        {
+            return Err("MAX_BODY_BYTES, MAX_BATCH_QUERIES, MAX_ID_SET, \
+                        MAX_RANGE_LIMIT and MAX_IMPORT_BYTES must be at least 1"
+                .to_string());
+        }
+        if args.rate_limit_qps > 0
+            && (args.rate_limit_burst as usize) < args.max_batch_queries
+        {
+            return Err(format!(
+                "RATE_LIMIT_BURST must be at least MAX_BATCH_QUERIES ({})",
+                args.max_batch_queries
+            ));
+        }
+        if args.import_batch_size == 0 {
+            return Err("IMPORT_BATCH_SIZE must be at least 1".to_string());
+        }
+
+        if let Err(e) = EnvFilter::try_new(&args.log_level) {
+            return Err(format!("LOG_LEVEL: {}", e));
+        }
+        if let Some(endpoint) = &args.otlp_endpoint {
+            if !endpoint.starts_with("http://")
+                && !endpoint.starts_with("https://")
+            {
+                return Err(format!(
+                    "OTEL_EXPORTER_OTLP_ENDPOINT must be an http(s) URL, got {}",
+                    endpoint
+                ));
+            }
+        }
+
+        if args.tier_role == TierRole::Follower {
+            if args.tier_leader_url.is_none() {
+                return Err("a follower needs TIER_LEADER_URL".to_string());
+            }
+            if !args.tier_follower_urls.is_empty() {
+                return Err(
+                    "TIER_FOLLOWER_URLS is only for the leader".to_string()
+                );
+            }
+        }
+        let tiered = args.tier_role == TierRole::Follower
+            || !args.tier_follower_urls.is_empty();
+        if tiered && args.tier_token.as_deref().unwrap_or("").is_empty() {
+            return Err(
+                "leader and followers need a shared TIER_TOKEN".to_string()
+            );
+        }
+
+        let encrypted = args.encryption == EncryptionMode::On;
+        if encrypted {
+            require_file(&args.ope_key_file, "OPE_KEY_FILE", "--ope-key-file")?;
+            require_file(
+                &args.aes_keyset_file,
+                "AES_KEYSET_FILE",
+                "--aes-keyset-file",
+            )?;
+        }
+
+        let shards = match args.storage {
+            StorageBackend::Postgres => args.shards()?,
+            StorageBackend::Sqlite => {
+                if args.shards_file.is_some() || !args.db_replicas.is_empty() {
+                    return Err("SHARDS_FILE and DATABASE_REPLICAS need \
+                                postgres storage"
+                        .to_string());
+                }
+                Vec::new()
+            }
+        };
+        let namespaces = args.namespaces()?;
+
+        Ok(ServerConfig {
+            addr: args.addr,
+            port: args.port,
+            workers: args.workers,
+            grpc_port: args.grpc_port,
+            shutdown_delay_secs: args.shutdown_delay_secs,
+            shutdown_grace_secs: args.shutdown_grace_secs,
+            tls_cert_file: args.tls_cert_file,
+            tls_key_file: args.tls_key_file,
+            tls_client_ca_file: args.tls_client_ca_file,
+            auth_file: args.auth_file,
+            policy_file: args.policy_file,
+            audit_log_file: args.audit_log_file,
+            encrypted,
+            ope_key_file: args.ope_key_file,
+            aes_keyset_file: args.aes_keyset_file,
+            aes_cache_size: args.aes_cache_size,
+            ope_cache_size: args.ope_cache_size,
+            graph_cache_mb: args.graph_cache_mb,
+            graph_cache_max_assoc_list: args.graph_cache_max_assoc_list,
+            feed_buffer: args.feed_buffer,
+            request_timeout_ms: args.request_timeout_ms,
+            query_timeout_ms: args.query_timeout_ms,
+            limits: Limits {
+                max_body_bytes: args.max_body_bytes,
+                max_batch_queries: args.max_batch_queries,
+                max_id_set: args.max_id_set,
+                max_range_limit: args.max_range_limit,
+                max_import_bytes: args.max_import_bytes,
+            },
+            rate_limit_qps: args.rate_limit_qps,
+            rate_limit_burst: args.rate_limit_burst,
+            import_batch_size: args.import_batch_size,
+            log_level: args.log_level,
+            log_format: args.log_format,
+            otlp_endpoint: args.otlp_endpoint,
+            trace_file: args.trace_file,
+            tier_role: args.tier_role,
+            tier_leader_url: args.tier_leader_url,
+            tier_follower_urls: args.tier_follower_urls,
+            tier_token: args.tier_token,
+            storage: args.storage,
+            sqlite_file: args.sqlite_file,
+            shards,
+            read_your_writes_secs: args.read_your_writes_secs,
+            namespaces,
+        })
+    }
+}
+
+fn require(
+    val: &Option<String>,
+    env: &str,
+    flag: &str,
+) -> Result<String, String> {
+    match val {
+        Some(v) if !v.is_empty() => Ok(v.clone()),
+        _ => Err(format!("missing {}, set it or pass {}", env, flag)),
+    }
+}
+
+fn require_file(
+    path: &Option<PathBuf>,
+    env: &str,
+    flag: &str,
+) -> Result<(), String> {
+    let path: &Path = match path {
+        Some(p) => p,
+        None => {
+            return Err(format!(
+                "encryption is on but {} is not set, pass {} or run \
+                 `tao-server keygen` to create keys",
+                env, flag
+            ))
+        }
+    };
+    if !path.is_file() {
+        return Err(format!("{} {} does not exist", env, path.display()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LogFormat, ServerArgs, ServerConfig};
+    use clap::Parser;
+
+    fn args(flags: &[&str]) -> ServerArgs {
+        let mut argv = vec!["tao-server"];
+        argv.extend_from_slice(flags);
+        let mut args = ServerArgs::parse_from(argv);
+        args.db_password = Some("secret".to_string());
+        args
+    }
+
+    #[test]
+    fn test_valid_config() {
+        let config = ServerConfig::from_args(args(&[
+            "--encryption=off",
+            "--db-host=localhost",
+            "--db-user=tao",
+            "--db-name=tao",
+            "--port=9090",
+        ]))
+        .unwrap();
+        assert_eq!(config.port, 9090);
+        assert!(!config.encrypted);
+        assert_eq!(config.shards.len(), 1);
+        assert_eq!(config.shards[0].port, "5432");
+        assert_eq!(config.namespaces.len(), 1);
+        assert_eq!(
+            config.namespaces[0].assoc_table(),
+            "\"public\".\"assoc_test\""
+        );
+    }
+
+    #[test]
+    fn test_invalid_table_name() {
+        let err = ServerConfig::from_args(args(&[
+            "--encryption=off",
+            "--db-host=localhost",
+            "--db-user=tao",
+            "--db-name=tao",
+            "--assoc-table=assoc;drop",
+        ]))
+        .unwrap_err();
+        assert!(err.contains("not a valid SQL identifier"));
+    }
+
+    #[test]
+    fn test_missing_db_host() {
+        let err = ServerConfig::from_args(args(&[
+            "--encryption=off",
+            "--db-user=tao",
+            "--db-name=tao",
+        ]))
+        .unwrap_err();
+        assert!(err.contains("DATABASE_HOST"));
+    }
+
+    #[test]
+    fn test_sqlite_storage() {
+        let config = ServerConfig::from_args(args(&[
+            "--encryption=off",
+            "--storage=sqlite",
+            "--sqlite-file=graph.db",
+        ]))
+        .unwrap();
+        assert_eq!(config.sqlite_file.to_str(), Some("graph.db"));
+        assert!(config.shards.is_empty());
+
+        let err = ServerConfig::from_args(args(&[
+            "--encryption=off",
+            "--storage=sqlite",
+            "--db-replicas=replica",
+        ]))
+        .unwrap_err();
+        assert!(err.contains("postgres storage"));
+    }
+
+    #[test]
+    fn test_telemetry_settings() {
+        let flags = [
+            "--encryption=off",
+            "--storage=sqlite",
+            "--log-format=json",
+            "--log-level=warn,encrypted_tao=debug",
+        ];
+        let config = ServerConfig::from_args(args(&flags)).unwrap();
+        assert_eq!(config.log_format, LogFormat::Json);
+
+        let err = ServerConfig::from_args(args(&[
+            "--encryption=off",
+            "--storage=sqlite",
+            "--log-level=info,=[",
+        ]))
+        .unwrap_err();
+        assert!(err.contains("LOG_LEVEL"));
+
+        let err = ServerConfig::from_args(args(&[
+            "--encryption=off",
+            "--storage=sqlite",
+            "--otlp-endpoint=localhost:4317",
+        ]))
+        .unwrap_err();
+        assert!(err.contains("OTEL_EXPORTER_OTLP_ENDPOINT"));
+    }
+
+    #[test]
+    fn test_encryption_requires_keys() {
+        let err = ServerConfig::from_args(args(&[
+            "--encryption=on",
+            "--db-host=localhost",
+            "--db-user=tao",
+            "--db-name=tao",
+        ]))
+        .unwrap_err();
+        assert!(err.contains("OPE_KEY_FILE"));
+    }
+
+    #[test]
+    fn test_follower_needs_leader_and_token() {
+        let flags = [
+            "--encryption=off",
+            "--db-host=localhost",
+            "--db-user=tao",
+            "--db-name=tao",
+            "--tier-role=follower",
+        ];
+        let err = ServerConfig::from_args(args(&flags)).unwrap_err();
+        assert!(err.contains("TIER_LEADER_URL"));
+
+        let mut flags = flags.to_vec();
+        flags.push("--tier-leader-url=http://localhost:8080");
+        let err = ServerConfig::from_args(args(&flags)).unwrap_err();
+        assert!(err.contains("TIER_TOKEN"));
+
+        let mut follower = args(&flags);
+        follower.tier_token = Some("s3cret".to_string());
+        assert!(ServerConfig::from_args(follower).is_ok());
+    }
+
+    #[test]
+    fn test_client_ca_requires_tls() {
+        let err = ServerConfig::from_args(args(&[
+            "--encryption=off",
+            "--db-host=localhost",
+            "--db-user=tao",
+            "--db-name=tao",
+            "--tls-client-ca-file=Cargo.toml",
+        ]))
+        .unwrap_err();
+        assert!(err.contains("TLS_CERT_FILE"));
+    }
+
+    #[test]
+    fn test_pool_bounds() {
+        let err = ServerConfig::from_args(args(&[
+            "--encryption=off",
+            "--db-host=localhost",
+            "--db-user=tao",
+            "--db-name=tao",
+            "--db-pool-min-size=8",
+            "--db-pool-max-size=4",
+        ]))
+        .unwrap_err();
+        assert!(err.contains("DATABASE_POOL_MIN_SIZE"));
+    }
+}
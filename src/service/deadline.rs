@@ -0,0 +1,199 @@
+/*
+ * File: deadline.rs
+ *      How long requests and their queries may run. A request must be done
+ *      REQUEST_TIMEOUT_MS after it arrives, and each of its queries within
+ *      QUERY_TIMEOUT_MS of starting, or by the request's deadline if that
+ *      comes first. Clients may ask for less time, over HTTP with the
+ *      X-Tao-Timeout-Ms and X-Tao-Query-Timeout-Ms headers and over gRPC
+ *      with grpc-timeout and x-tao-query-timeout-ms, but not for more.
+ *
+ *      Only the store call of a query is bounded. Once its deadline passes
+ *      the call is dropped, which cancels it in the store, and the query
+ *      fails with a timeout. What follows the call still runs, the cache
+ *      invalidation and the audit log included, so a write that timed out
+ *      is handled as one that failed, although it may have been applied.
+ */
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use crate::query::error::TaoError;
+
+pub const TIMEOUT_HEADER: &str = "x-tao-timeout-ms";
+pub const QUERY_TIMEOUT_HEADER: &str = "x-tao-query-timeout-ms";
+pub const GRPC_TIMEOUT_METADATA: &str = "grpc-timeout";
+
+/*
+ * Timeouts
+ *      The time a request and each of its queries may take.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    pub request: Duration,
+    pub query: Duration,
+}
+
+impl Timeouts {
+    pub fn from_millis(request: u64, query: u64) -> Self {
+        Timeouts {
+            request: Duration::from_millis(request),
+            query: Duration::from_millis(query),
+        }
+    }
+
+    /*
+     * limit(request, query)
+     *      These timeouts, shortened to those a client asked for.
+     */
+    pub fn limit(
+        &self,
+        request: Option<Duration>,
+        query: Option<Duration>,
+    ) -> Timeouts {
+        Timeouts {
+            request: request.map_or(self.request, |t| t.min(self.request)),
+            query: query.map_or(self.query, |t| t.min(self.query)),
+        }
+    }
+
+    /*
+     * start()
+     *      The deadlines of a request arriving now.
+     */
+    pub fn start(&self) -> Deadlines {
+        Deadlines::new(Some(self.request), self.query)
+    }
+}
+
+/*
+ * Deadlines
+ *      When a request must be done by, None for the open ended gRPC
+ *      streams, and how long each of its queries may take.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Deadlines {
+    request: Option<Instant>,
+    query: Duration,
+}
+
+impl Deadlines {
+    pub fn new(request: Option<Duration>, query: Duration) -> Self {
+        Deadlines {
+            request: request.map(|t| Instant::now() + t),
+            query,
+        }
+    }
+
+    /*
+     * query()
+     *      The deadline of a query starting now.
+     */
+    pub fn query(&self) -> Instant {
+        let query = Instant::now() + self.query;
+        match self.request {
+            Some(request) => request.min(query),
+            None => query,
+        }
+    }
+}
+
+/*
+ * within(deadline, call)
+ *      Runs `call` until the deadline, dropping it once that passes.
+ */
+pub async fn within<T, F>(deadline: Instant, call: F) -> Result<T, TaoError>
+where
+    F: Future<Output = Result<T, TaoError>>,
+{
+    match tokio::time::timeout_at(deadline.into(), call).await {
+        Ok(res) => res,
+        Err(_) => Err(TaoError::Timeout("query deadline exceeded".to_string())),
+    }
+}
+
+/*
+ * parse_millis(name, value)
+ *      A timeout header, in whole milliseconds.
+ */
+pub fn parse_millis(name: &str, value: &str) -> Result<Duration, TaoError> {
+    match value.trim().parse::<u64>() {
+        Ok(millis) if millis > 0 => Ok(Duration::from_millis(millis)),
+        _ => Err(TaoError::Validation(format!(
+            "{} must be a number of milliseconds above 0",
+            name
+        ))),
+    }
+}
+
+/*
+ * parse_grpc_timeout(value)
+ *      The grpc-timeout of the gRPC over HTTP/2 spec: up to 8 digits and a
+ *      unit, H, M, S, m, u or n.
+ */
+pub fn parse_grpc_timeout(value: &str) -> Result<Duration, TaoError> {
+    let malformed =
+        || TaoError::Validation(format!("malformed {}", GRPC_TIMEOUT_METADATA));
+    if value.len() < 2 || value.len() > 9 || !value.is_ascii() {
+        return Err(malformed());
+    }
+    let (digits, unit) = value.split_at(value.len() - 1);
+    let amount = digits.parse::<u64>().map_err(|_| malformed())?;
+    let timeout = match unit {
+        "H" => Duration::from_secs(amount * 3600),
+        "M" => Duration::from_secs(amount * 60),
+        "S" => Duration::from_secs(amount),
+        "m" => Duration::from_millis(amount),
+        "u" => Duration::from_micros(amount),
+        "n" => Duration::from_nanos(amount),
+        _ => return Err(malformed()),
+    };
+    Ok(timeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{parse_grpc_timeout, parse_millis, within, Timeouts};
+    use crate::query::error::TaoError;
+
+    #[test]
+    fn test_limits() {
+        let timeouts = Timeouts::from_millis(30_000, 10_000);
+        assert_eq!(timeouts.limit(None, None), timeouts);
+        let limited = timeouts.limit(
+            Some(Duration::from_millis(500)),
+            Some(Duration::from_secs(60)),
+        );
+        assert_eq!(limited, Timeouts::from_millis(500, 10_000));
+
+        let deadlines = limited.start();
+        let query = deadlines.query();
+        assert!(query <= Instant::now() + Duration::from_millis(500));
+
+        assert_eq!(parse_millis("h", " 250").unwrap().as_millis(), 250);
+        for bad in ["0", "-5", "1.5", ""] {
+            assert!(parse_millis("h", bad).is_err(), "{}", bad);
+        }
+        assert_eq!(parse_grpc_timeout("2S").unwrap(), Duration::from_secs(2));
+        assert_eq!(
+            parse_grpc_timeout("1500m").unwrap(),
+            Duration::from_millis(1500)
+        );
+        for bad in ["", "S", "10", "5s", "123456789S"] {
+            assert!(parse_grpc_timeout(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_within() {
+        let soon = Instant::now() + Duration::from_millis(20);
+        let stuck = within(soon, async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok(())
+        });
+        assert!(matches!(stuck.await, Err(TaoError::Timeout(_))));
+
+        let done = within(soon, async { Ok(7) });
+        assert_eq!(done.await.unwrap(), 7);
+    }
+}
@@ -0,0 +1,397 @@
+/*
+ * File: feed.rs
+ *      Change feed. Instead of polling ASSOC RANGE, clients subscribe to
+ *      association lists (id1, atype) and object ids and receive an event,
+ *      as server-sent events, for every write applied to them: ASSOC ADD
+ *      and DELETE, OBJ ADD, UPDATE and DELETE. ASSOC CHTYPE is refused by
+ *      the parser, so it has no events.
+ *
+ *      Writes are published as stored, i.e. as ciphertext when encryption
+ *      is on. Each subscriber decrypts and policy checks the events it
+ *      receives, unless it asked for ciphertext to decrypt them end to end.
+ *      Events are published by the server that applies the write, so feeds
+ *      are served by the leader, followers refuse subscriptions.
+ */
+use std::collections::HashSet;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::web::Bytes;
+use futures::stream::{self, Stream};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError, Receiver, Sender};
+
+use crate::query::error::TaoError;
+use crate::query::query::{Query, TaoArgs, TaoOp};
+use crate::query::results::DBRow;
+
+/// Comment line sent on idle feeds so that proxies keep them open
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/*
+ * Topic
+ *      What a subscription names and a write touches. Topics hold the ids
+ *      as stored, so subscriptions are encrypted before they are compared.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Topic {
+    Obj { id: String },
+    AssocList { id1: String, atype: String },
+}
+
+impl Topic {
+    pub fn of(args: &TaoArgs) -> Topic {
+        match args {
+            TaoArgs::ObjGetArgs { id }
+            | TaoArgs::ObjAddArgs { id, .. }
+            | TaoArgs::ObjUpdateArgs { id, .. }
+            | TaoArgs::ObjDeleteArgs { id } => Topic::Obj { id: id.clone() },
+            TaoArgs::AssocAddArgs { id1, atype, .. }
+            | TaoArgs::AssocDeleteArgs { id1, atype, .. } => Topic::AssocList {
+                id1: id1.clone(),
+                atype: atype.clone(),
+            },
+            TaoArgs::AssocGetArgs { id, atype, .. }
+            | TaoArgs::AssocRangeGetArgs { id, atype, .. }
+            | TaoArgs::AssocCountArgs { id, atype }
+            | TaoArgs::AssocRangeArgs { id, atype, .. } => Topic::AssocList {
+                id1: id.clone(),
+                atype: atype.clone(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FeedMode {
+    /// Events are decrypted and policy checked by the server
+    #[default]
+    Plaintext,
+    /// Events are passed on as stored, for clients holding the keys
+    Ciphertext,
+}
+
+/*
+ * FeedParams
+ *      Query string of GET /feed, e.g.
+ *          ?assocs=42:LIKES,42:COMMENT&objs=7&mode=ciphertext
+ */
+#[derive(Debug, Deserialize)]
+pub struct FeedParams {
+    pub assocs: Option<String>,
+    pub objs: Option<String>,
+    #[serde(default)]
+    pub mode: FeedMode,
+    pub namespace: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FeedEvent {
+    pub namespace: String,
+    pub topic: Topic,
+    pub op: TaoOp,
+    pub row: DBRow,
+}
+
+impl FeedEvent {
+    /*
+     * of_write(ns, query, rows)
+     *      The event for a write that was applied, given the rows it was
+     *      answered with. Updates and deletes carry the row they changed,
+     *      and have no event when they found nothing. None for reads.
+     */
+    pub fn of_write(
+        ns: &str,
+        query: &Query,
+        rows: &[DBRow],
+    ) -> Option<FeedEvent> {
+        let row = match &query.args {
+            TaoArgs::AssocAddArgs {
+                id1,
+                atype,
+                id2,
+                time,
+                data,
+            } => DBRow::AssocRow {
+                id1: id1.clone(),
+                atype: atype.clone(),
+                id2: id2.clone(),
+                t: *time,
+                data: data.clone(),
+            },
+            TaoArgs::ObjAddArgs { id, otype, data } => DBRow::ObjRow {
+                id: id.clone(),
+                otype: otype.clone(),
+                data: data.clone(),
+            },
+            TaoArgs::AssocDeleteArgs { .. }
+            | TaoArgs::ObjUpdateArgs { .. }
+            | TaoArgs::ObjDeleteArgs { .. } => rows.first()?.clone(),
+            _ => return None,
+        };
+        Some(FeedEvent {
+            namespace: ns.to_string(),
+            topic: Topic::of(&query.args),
+            op: query.op.clone(),
+            row,
+        })
+    }
+
+    /*
+     * of_load(ns, row)
+     *      The event for a row a bulk load added, as the OBJ ADD or ASSOC
+     *      ADD it was imported as.
+     */
+    pub fn of_load(ns: &str, row: DBRow) -> Option<FeedEvent> {
+        let (topic, op) = match &row {
+            DBRow::ObjRow { id, .. } => {
+                (Topic::Obj { id: id.clone() }, TaoOp::ObjAdd)
+            }
+            DBRow::AssocRow { id1, atype, .. } => (
+                Topic::AssocList {
+                    id1: id1.clone(),
+                    atype: atype.clone(),
+                },
+                TaoOp::AssocAdd,
+            ),
+            _ => return None,
+        };
+        Some(FeedEvent {
+            namespace: ns.to_string(),
+            topic,
+            op,
+            row,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct EventData<'a> {
+    pub op: &'a TaoOp,
+    pub row: &'a DBRow,
+}
+
+pub struct Feed {
+    sender: Sender<Arc<FeedEvent>>,
+}
+
+impl Feed {
+    /*
+     * new(capacity)
+     *      Subscribers that fall more than `capacity` events behind skip
+     *      ahead and are told how many they missed.
+     */
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Feed { sender }
+    }
+
+    pub fn publish(&self, event: FeedEvent) {
+        // Fails only when nobody is subscribed
+        let _ = self.sender.send(Arc::new(event));
+    }
+
+    pub fn subscribe(&self) -> Receiver<Arc<FeedEvent>> {
+        self.sender.subscribe()
+    }
+
+    pub fn subscribers(&self) -> usize {
+        self.sender.receiver_count()
+    }
+}
+
+/*
+ * subscriptions(assocs, objs)
+ *      The plaintext reads that stand for each subscription, "id1:ATYPE"
+ *      for association lists and "id" for objects. They are never run, but
+ *      are policy checked and encrypted like the reads they stand for.
+ */
+pub fn subscriptions(
+    assocs: Option<&str>,
+    objs: Option<&str>,
+) -> Result<Vec<Query>, TaoError> {
+    let split = |list: Option<&str>| -> Vec<String> {
+        list.unwrap_or("")
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect()
+    };
+
+    let mut queries = Vec::new();
+    for assoc in split(assocs) {
+        let (id, atype) = match assoc.rsplit_once(':') {
+            Some((id, atype)) if !id.is_empty() && !atype.is_empty() => {
+                (id.to_string(), atype.to_string())
+            }
+            _ => {
+                return Err(TaoError::Validation(format!(
+                    "subscription {:?} is not id1:ATYPE",
+                    assoc
+                )))
+            }
+        };
+        queries.push(Query {
+            op: TaoOp::AssocRange,
+            args: TaoArgs::AssocRangeArgs {
+                id,
+                atype,
+                tstart: 0,
+                tend: i64::MAX,
+                lim: 0,
+            },
+        });
+    }
+    for id in split(objs) {
+        queries.push(Query {
+            op: TaoOp::ObjGet,
+            args: TaoArgs::ObjGetArgs { id },
+        });
+    }
+    if queries.is_empty() {
+        return Err(TaoError::Validation(
+            "subscribe to at least one of assocs or objs".to_string(),
+        ));
+    }
+    Ok(queries)
+}
+
+/*
+ * event_stream(receiver, topics, namespace, render)
+ *      Server-sent events for the subscribed topics. `render` turns an
+ *      event into its data line, or None to drop it.
+ */
+pub fn event_stream<F>(
+    receiver: Receiver<Arc<FeedEvent>>,
+    topics: HashSet<Topic>,
+    namespace: String,
+    render: F,
+) -> impl Stream<Item = Result<Bytes, actix_web::Error>>
+where
+    F: Fn(&FeedEvent) -> Option<String> + 'static,
+{
+    let filter = Rc::new((topics, namespace, render));
+    stream::unfold((receiver, filter), |(mut receiver, filter)| async move {
+        let (topics, namespace, render) = &*filter;
+        loop {
+            let chunk =
+                match tokio::time::timeout(KEEP_ALIVE, receiver.recv()).await {
+                    Err(_) => ": keep-alive\n\n".to_string(),
+                    Ok(Ok(event)) => {
+                        if event.namespace != *namespace
+                            || !topics.contains(&event.topic)
+                        {
+                            continue;
+                        }
+                        match render(&event) {
+                            Some(data) => format!("data: {}\n\n", data),
+                            None => continue,
+                        }
+                    }
+                    Ok(Err(RecvError::Lagged(missed))) => format!(
+                        "event: lagged\ndata: {{\"missed\":{}}}\n\n",
+                        missed
+                    ),
+                    Ok(Err(RecvError::Closed)) => return None,
+                };
+            return Some((Ok(Bytes::from(chunk)), (receiver, filter)));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use futures::StreamExt;
+
+    use super::{event_stream, subscriptions, Feed, FeedEvent, Topic};
+    use crate::query::parser::parse;
+    use crate::query::results::DBRow;
+
+    #[test]
+    fn test_subscriptions() {
+        let queries =
+            subscriptions(Some("42:LIKES, a:b:COMMENT"), Some("7")).unwrap();
+        assert_eq!(queries.len(), 3);
+        assert_eq!(
+            Topic::of(&queries[1].args),
+            Topic::AssocList {
+                id1: "a:b".to_string(),
+                atype: "COMMENT".to_string()
+            }
+        );
+        assert!(subscriptions(Some("42"), None).is_err());
+        assert!(subscriptions(None, Some(" , ")).is_err());
+    }
+
+    #[test]
+    fn test_writes_match_subscriptions() {
+        let writes =
+            parse(r#"ASSOC ADD 42 LIKES 9 100 "x"; OBJ ADD 7 USER "a";"#);
+        let subs = subscriptions(Some("42:LIKES"), Some("7")).unwrap();
+        for (write, sub) in writes.iter().zip(&subs) {
+            let event = FeedEvent::of_write("default", write, &[]).unwrap();
+            assert_eq!(event.topic, Topic::of(&sub.args));
+        }
+        let read = &parse("OBJ GET 7;")[0];
+        assert!(FeedEvent::of_write("default", read, &[]).is_none());
+    }
+
+    #[test]
+    fn test_updates_and_deletes() {
+        let writes = parse(
+            r#"OBJ UPDATE 7 USER "b"; OBJ DELETE 7; ASSOC DELETE 42 LIKES 9;"#,
+        );
+        let rows = [DBRow::ObjRow {
+            id: "7".to_string(),
+            otype: "USER".to_string(),
+            data: "b".to_string(),
+        }];
+        let subs = subscriptions(Some("42:LIKES"), Some("7")).unwrap();
+        for (write, sub) in writes.iter().zip([&subs[1], &subs[1], &subs[0]]) {
+            let event = FeedEvent::of_write("default", write, &rows).unwrap();
+            assert_eq!(event.topic, Topic::of(&sub.args));
+            assert_eq!(format!("{:?}", event.row), format!("{:?}", rows[0]));
+            // Nothing changed, nothing to publish
+            assert!(FeedEvent::of_write("default", write, &[]).is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_event_stream() {
+        let feed = Feed::new(2);
+        let subs = subscriptions(Some("42:LIKES"), None).unwrap();
+        let topics = subs
+            .iter()
+            .map(|q| Topic::of(&q.args))
+            .collect::<HashSet<Topic>>();
+        let stream = event_stream(
+            feed.subscribe(),
+            topics,
+            "default".to_string(),
+            |event| Some(format!("{:?}", event.op)),
+        );
+        futures::pin_mut!(stream);
+
+        let writes = parse(
+            r#"ASSOC ADD 42 FRIEND 9 1 "x"; ASSOC ADD 42 LIKES 9 2 "x";"#,
+        );
+        for write in &writes {
+            feed.publish(FeedEvent::of_write("default", write, &[]).unwrap());
+        }
+        let chunk = stream.next().await.unwrap().unwrap();
+        assert_eq!(&chunk[..], b"data: AssocAdd\n\n");
+
+        for _ in 0..3 {
+            feed.publish(
+                FeedEvent::of_write("default", &writes[1], &[]).unwrap(),
+            );
+        }
+        let chunk = stream.next().await.unwrap().unwrap();
+        assert!(chunk.starts_with(b"event: lagged\n"));
+        assert_eq!(feed.subscribers(), 1);
+    }
+}
@@ -0,0 +1,800 @@
+/*
+ * File: grpc.rs
+ *      gRPC API, the TAO operations of proto/tao.proto as typed calls. Each
+ *      call is turned into the Query the query language would give and
+ *      runs through the same executor as POST /query, so the policy,
+ *      encryption, graph cache, audit log and change feed all apply.
+ *
+ *      The executor's futures are not Send, as awc clients are not, while
+ *      tonic needs Send handlers. Calls therefore run on a pool of single
+ *      threaded runtimes and only their results cross back.
+ */
+use std::collections::HashSet;
+use std::fs;
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::Stream;
+use openssl::x509::X509;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::task::LocalPoolHandle;
+use tonic::codegen::http;
+use tonic::metadata::MetadataValue;
+use tonic::transport::{Certificate, Identity, Server, ServerTlsConfig};
+use tonic::{Request, Response, Status};
+use tower_http::request_id::{
+    MakeRequestId, PropagateRequestIdLayer, RequestId, SetRequestIdLayer,
+};
+use tower_http::trace::{DefaultOnFailure, DefaultOnResponse, TraceLayer};
+use tracing::{Instrument, Level, Span};
+
+use crate::query::error::TaoError;
+use crate::query::parser::validate_args;
+use crate::query::query::{Query, TaoArgs, TaoOp};
+use crate::query::results::DBRow;
+use crate::service::auth::{self, Authenticator, ClientIdentity, Principal};
+use crate::service::deadline::{
+    parse_grpc_timeout, parse_millis, Deadlines, GRPC_TIMEOUT_METADATA,
+    QUERY_TIMEOUT_HEADER,
+};
+use crate::service::tao::{unix_millis, TaoServer};
+use crate::service::telemetry;
+
+pub mod proto {
+    tonic::include_proto!("tao.v1");
+}
+
+use proto::tao_server::{Tao, TaoServer as TaoService};
+use proto::{
+    Assoc, AssocAddRequest, AssocCountRequest, AssocCountResponse,
+    AssocGetRequest, AssocList, AssocRangeGetRequest, AssocRangeRequest,
+    AssocRangeStreamRequest, Obj, ObjAddRequest, ObjGetRequest, ObjGetResponse,
+    WriteResponse,
+};
+
+const NAMESPACE_METADATA: &str = "x-tao-namespace";
+const SESSION_METADATA: &str = "x-tao-session";
+
+/// Page size of AssocRangeStream when the request leaves it at 0
+pub const DEFAULT_PAGE_SIZE: i64 = 1000;
+pub const MAX_PAGE_SIZE: i64 = 10_000;
+
+fn status(e: TaoError) -> Status {
+    let message = e.to_string();
+    match e {
+        TaoError::Parse(_) | TaoError::Validation(_) => {
+            Status::invalid_argument(message)
+        }
+        TaoError::Forbidden(_) => Status::permission_denied(message),
+        TaoError::Timeout(_) => Status::deadline_exceeded(message),
+        TaoError::Crypto(_) | TaoError::Database(_) => {
+            Status::internal(message)
+        }
+    }
+}
+
+fn to_assoc(row: DBRow) -> Option<Assoc> {
+    match row {
+        DBRow::AssocRow {
+            id1,
+            atype,
+            id2,
+            t,
+            data,
+        } => Some(Assoc {
+            id1,
+            atype,
+            id2,
+            time: t,
+            data,
+        }),
+        _ => None,
+    }
+}
+
+fn assoc_list(rows: Vec<DBRow>) -> AssocList {
+    AssocList {
+        assocs: rows.into_iter().filter_map(to_assoc).collect(),
+    }
+}
+
+/*
+ * written(message)
+ *      Answers a write with a session, as X-Tao-Session does over HTTP.
+ */
+fn written<T>(message: T) -> Response<T> {
+    let mut response = Response::new(message);
+    if let Ok(value) = MetadataValue::try_from(unix_millis().to_string()) {
+        response.metadata_mut().insert(SESSION_METADATA, value);
+    }
+    response
+}
+
+/*
+ * Caller
+ *      Who made a call, and the namespace, session and timeouts it asked
+ *      for.
+ */
+struct Caller {
+    principal: Principal,
+    namespace: Option<String>,
+    session: Option<u64>,
+    request_timeout: Option<Duration>,
+    query_timeout: Option<Duration>,
+}
+
+pub struct TaoGrpc {
+    tao: Arc<TaoServer>,
+    authenticator: Arc<Authenticator>,
+    pool: LocalPoolHandle,
+}
+
+impl TaoGrpc {
+    /*
+     * new(tao, authenticator, threads)
+     *      `threads` is the size of the pool the calls run on.
+     */
+    pub fn new(
+        tao: Arc<TaoServer>,
+        authenticator: Arc<Authenticator>,
+        threads: usize,
+    ) -> Self {
+        TaoGrpc {
+            tao,
+            authenticator,
+            pool: LocalPoolHandle::new(threads.max(1)),
+        }
+    }
+
+    pub fn into_service(self) -> TaoService<TaoGrpc> {
+        let max_body_bytes = self.tao.limits.max_body_bytes;
+        TaoService::new(self).max_decoding_message_size(max_body_bytes)
+    }
+
+    /*
+     * caller(request)
+     *      Authenticates like POST /query, by bearer token or by the common
+     *      name of a verified client certificate.
+     */
+    #[allow(clippy::result_large_err)]
+    fn caller<T>(&self, request: &Request<T>) -> Result<Caller, Status> {
+        let metadata = request.metadata();
+        let text = |key: &str| {
+            metadata.get(key).map(|value| {
+                value.to_str().map_err(|_| {
+                    Status::invalid_argument(format!("malformed {}", key))
+                })
+            })
+        };
+        let bearer = match text("authorization").transpose()? {
+            Some(value) => match value.strip_prefix("Bearer ") {
+                Some(token) => Some(token.trim()),
+                None => {
+                    return Err(Status::unauthenticated(
+                        "malformed authorization metadata",
+                    ))
+                }
+            },
+            None => None,
+        };
+        let identity = request
+            .peer_certs()
+            .and_then(|certs| certs.first().cloned())
+            .and_then(|cert| X509::from_der(&cert).ok())
+            .and_then(|cert| auth::common_name(&cert))
+            .map(ClientIdentity);
+        let principal = self
+            .authenticator
+            .authenticate(bearer, identity.as_ref())
+            .map_err(Status::unauthenticated)?;
+
+        let session = match text(SESSION_METADATA).transpose()? {
+            Some(value) => Some(value.parse::<u64>().map_err(|_| {
+                Status::invalid_argument(format!(
+                    "malformed {}",
+                    SESSION_METADATA
+                ))
+            })?),
+            None => None,
+        };
+        let request_timeout = text(GRPC_TIMEOUT_METADATA)
+            .transpose()?
+            .map(parse_grpc_timeout)
+            .transpose()
+            .map_err(status)?;
+        let query_timeout = text(QUERY_TIMEOUT_HEADER)
+            .transpose()?
+            .map(|value| parse_millis(QUERY_TIMEOUT_HEADER, value))
+            .transpose()
+            .map_err(status)?;
+        Ok(Caller {
+            principal,
+            namespace: text(NAMESPACE_METADATA)
+                .transpose()?
+                .map(|s| s.to_string()),
+            session,
+            request_timeout,
+            query_timeout,
+        })
+    }
+
+    async fn run(
+        &self,
+        caller: Caller,
+        op: TaoOp,
+        args: TaoArgs,
+    ) -> Result<Vec<DBRow>, Status> {
+        validate_args(&args).map_err(status)?;
+        self.tao
+            .admit(&caller.principal, 1, &[&args])
+            .map_err(|rejected| rejected.status())?;
+        let tao = self.tao.clone();
+        let deadline = tao
+            .timeouts
+            .limit(caller.request_timeout, caller.query_timeout)
+            .start()
+            .query();
+        let span = Span::current();
+        self.pool
+            .spawn_pinned(move || async move {
+                let _running = tao.shutdown.enter();
+                let query = Query { op, args };
+                run_query(&tao, &caller, query, deadline)
+                    .instrument(span)
+                    .await
+            })
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(status)
+    }
+}
+
+/*
+ * NewRequestId
+ *      Request ids of calls that come without an x-request-id, which is
+ *      set on the call and returned with its response.
+ */
+#[derive(Clone)]
+struct NewRequestId;
+
+impl MakeRequestId for NewRequestId {
+    fn make_request_id<B>(
+        &mut self,
+        _request: &http::Request<B>,
+    ) -> Option<RequestId> {
+        let id = telemetry::request_id(None);
+        http::HeaderValue::from_str(&id).ok().map(RequestId::new)
+    }
+}
+
+/*
+ * tls_config(cert_file, key_file, client_ca_file)
+ *      The gRPC listener uses the certificate of the HTTP one. Client
+ *      certificates are optional there too, tokens still work without.
+ */
+pub fn tls_config(
+    cert_file: &Path,
+    key_file: &Path,
+    client_ca_file: Option<&Path>,
+) -> Result<ServerTlsConfig, String> {
+    let read = |path: &Path| {
+        fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))
+    };
+    let identity = Identity::from_pem(read(cert_file)?, read(key_file)?);
+    let mut config = ServerTlsConfig::new().identity(identity);
+    if let Some(ca_file) = client_ca_file {
+        config = config
+            .client_ca_root(Certificate::from_pem(read(ca_file)?))
+            .client_auth_optional(true);
+    }
+    Ok(config)
+}
+
+/*
+ * serve(grpc, addr, port, tls, shutdown)
+ *      Serves the gRPC API until the listener fails, or until `shutdown`
+ *      resolves and the calls in flight are answered.
+ */
+pub async fn serve(
+    grpc: TaoGrpc,
+    addr: &str,
+    port: u16,
+    tls: Option<ServerTlsConfig>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), String> {
+    let socket_addr = tokio::net::lookup_host((addr, port))
+        .await
+        .map_err(|e| format!("cannot resolve {}: {}", addr, e))?
+        .next()
+        .ok_or_else(|| format!("cannot resolve {}", addr))?;
+    let mut server = Server::builder();
+    if let Some(tls) = tls {
+        server = server.tls_config(tls).map_err(|e| e.to_string())?;
+    }
+    server
+        .layer(SetRequestIdLayer::x_request_id(NewRequestId))
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(
+            TraceLayer::new_for_grpc()
+                .make_span_with(telemetry::grpc_request_span)
+                .on_response(DefaultOnResponse::new().level(Level::INFO))
+                .on_failure(DefaultOnFailure::new().level(Level::WARN)),
+        )
+        .add_service(grpc.into_service())
+        .serve_with_shutdown(socket_addr, shutdown)
+        .await
+        .map_err(|e| format!("gRPC server on {}: {}", socket_addr, e))
+}
+
+async fn run_query(
+    tao: &TaoServer,
+    caller: &Caller,
+    query: Query,
+    deadline: Instant,
+) -> Result<Vec<DBRow>, TaoError> {
+    let ns = tao.resolve_namespace(caller.namespace.as_deref(), None)?;
+    let route = tao.read_route(caller.session);
+    tao.run_query(&caller.principal, ns, query, route, deadline)
+        .await
+}
+
+/*
+ * RangePages
+ *      Walks a time range newest first, one ASSOC RANGE per page. Each page
+ *      after the first ends at the time of the last association sent, so
+ *      that associations sharing that time are not lost at the boundary,
+ *      and those of them already sent are skipped. A page asks for
+ *      page_size associations more than it skips, so a stream whose pages
+ *      outgrow max_range_limit fails rather than growing sent_at_tend.
+ */
+struct RangePages {
+    id1: String,
+    atype: String,
+    tstart: i64,
+    tend: i64,
+    page_size: i64,
+    remaining: Option<i64>,
+    /// id2s sent with t == tend
+    sent_at_tend: HashSet<String>,
+    done: bool,
+}
+
+impl RangePages {
+    fn new(request: AssocRangeStreamRequest) -> Result<Self, TaoError> {
+        let page_size = match request.page_size {
+            0 => DEFAULT_PAGE_SIZE,
+            n if (1..=MAX_PAGE_SIZE).contains(&n) => n,
+            n => {
+                return Err(TaoError::Validation(format!(
+                    "page_size {} is outside 1..={}",
+                    n, MAX_PAGE_SIZE
+                )))
+            }
+        };
+        let remaining = match request.limit {
+            0 => None,
+            n if n > 0 => Some(n),
+            n => {
+                return Err(TaoError::Validation(format!(
+                    "limit {} is negative",
+                    n
+                )))
+            }
+        };
+        Ok(RangePages {
+            id1: request.id1,
+            atype: request.atype,
+            tstart: request.tstart,
+            tend: request.tend,
+            page_size,
+            remaining,
+            sent_at_tend: HashSet::new(),
+            done: false,
+        })
+    }
+
+    fn lim(&self) -> i64 {
+        self.page_size + self.sent_at_tend.len() as i64
+    }
+
+    fn next_page(&self) -> Option<TaoArgs> {
+        if self.done || self.remaining == Some(0) {
+            return None;
+        }
+        Some(TaoArgs::AssocRangeArgs {
+            id: self.id1.clone(),
+            atype: self.atype.clone(),
+            tstart: self.tstart,
+            tend: self.tend,
+            lim: self.lim(),
+        })
+    }
+
+    /*
+     * accept(rows)
+     *      Takes the answer to the last page and returns what is left to
+     *      send. A full page holds at least page_size new associations, so
+     *      every page makes progress.
+     */
+    fn accept(&mut self, rows: Vec<DBRow>) -> Vec<Assoc> {
+        let full = rows.len() as i64 == self.lim();
+        let mut fresh = rows
+            .into_iter()
+            .filter_map(to_assoc)
+            .filter(|a| {
+                !(a.time == self.tend && self.sent_at_tend.contains(&a.id2))
+            })
+            .collect::<Vec<Assoc>>();
+        if let Some(remaining) = &mut self.remaining {
+            fresh.truncate(*remaining as usize);
+            *remaining -= fresh.len() as i64;
+        }
+        match fresh.last() {
+            Some(last) if full => {
+                if last.time != self.tend {
+                    self.tend = last.time;
+                    self.sent_at_tend.clear();
+                }
+                for assoc in fresh.iter().filter(|a| a.time == self.tend) {
+                    self.sent_at_tend.insert(assoc.id2.clone());
+                }
+            }
+            _ => self.done = true,
+        }
+        fresh
+    }
+}
+
+type AssocStream = Pin<Box<dyn Stream<Item = Result<Assoc, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Tao for TaoGrpc {
+    async fn obj_get(
+        &self,
+        request: Request<ObjGetRequest>,
+    ) -> Result<Response<ObjGetResponse>, Status> {
+        let caller = self.caller(&request)?;
+        let ObjGetRequest { id } = request.into_inner();
+        let rows = self
+            .run(caller, TaoOp::ObjGet, TaoArgs::ObjGetArgs { id })
+            .await?;
+        let obj = rows.into_iter().find_map(|row| match row {
+            DBRow::ObjRow { id, otype, data } => Some(Obj { id, otype, data }),
+            _ => None,
+        });
+        Ok(Response::new(ObjGetResponse { obj }))
+    }
+
+    async fn obj_add(
+        &self,
+        request: Request<ObjAddRequest>,
+    ) -> Result<Response<WriteResponse>, Status> {
+        let caller = self.caller(&request)?;
+        let ObjAddRequest { id, otype, data } = request.into_inner();
+        let args = TaoArgs::ObjAddArgs { id, otype, data };
+        self.run(caller, TaoOp::ObjAdd, args).await?;
+        Ok(written(WriteResponse {}))
+    }
+
+    async fn assoc_add(
+        &self,
+        request: Request<AssocAddRequest>,
+    ) -> Result<Response<WriteResponse>, Status> {
+        let caller = self.caller(&request)?;
+        let AssocAddRequest {
+            id1,
+            atype,
+            id2,
+            time,
+            data,
+        } = request.into_inner();
+        let args = TaoArgs::AssocAddArgs {
+            id1,
+            atype,
+            id2,
+            time,
+            data,
+        };
+        self.run(caller, TaoOp::AssocAdd, args).await?;
+        Ok(written(WriteResponse {}))
+    }
+
+    async fn assoc_get(
+        &self,
+        request: Request<AssocGetRequest>,
+    ) -> Result<Response<AssocList>, Status> {
+        let caller = self.caller(&request)?;
+        let AssocGetRequest { id1, atype, id2s } = request.into_inner();
+        let args = TaoArgs::AssocGetArgs {
+            id: id1,
+            atype,
+            idset: id2s,
+        };
+        let rows = self.run(caller, TaoOp::AssocGet, args).await?;
+        Ok(Response::new(assoc_list(rows)))
+    }
+
+    async fn assoc_range_get(
+        &self,
+        request: Request<AssocRangeGetRequest>,
+    ) -> Result<Response<AssocList>, Status> {
+        let caller = self.caller(&request)?;
+        let AssocRangeGetRequest {
+            id1,
+            atype,
+            id2s,
+            tstart,
+            tend,
+        } = request.into_inner();
+        let args = TaoArgs::AssocRangeGetArgs {
+            id: id1,
+            atype,
+            idset: id2s,
+            tstart,
+            tend,
+        };
+        let rows = self.run(caller, TaoOp::AssocRangeGet, args).await?;
+        Ok(Response::new(assoc_list(rows)))
+    }
+
+    async fn assoc_count(
+        &self,
+        request: Request<AssocCountRequest>,
+    ) -> Result<Response<AssocCountResponse>, Status> {
+        let caller = self.caller(&request)?;
+        let AssocCountRequest { id1, atype } = request.into_inner();
+        let args = TaoArgs::AssocCountArgs { id: id1, atype };
+        let rows = self.run(caller, TaoOp::AssocCount, args).await?;
+        let count = rows
+            .into_iter()
+            .find_map(|row| match row {
+                DBRow::Count(count) => Some(count),
+                _ => None,
+            })
+            .unwrap_or(0);
+        Ok(Response::new(AssocCountResponse { count }))
+    }
+
+    async fn assoc_range(
+        &self,
+        request: Request<AssocRangeRequest>,
+    ) -> Result<Response<AssocList>, Status> {
+        let caller = self.caller(&request)?;
+        let AssocRangeRequest {
+            id1,
+            atype,
+            tstart,
+            tend,
+            limit,
+        } = request.into_inner();
+        let args = TaoArgs::AssocRangeArgs {
+            id: id1,
+            atype,
+            tstart,
+            tend,
+            lim: limit,
+        };
+        let rows = self.run(caller, TaoOp::AssocRange, args).await?;
+        Ok(Response::new(assoc_list(rows)))
+    }
+
+    type AssocRangeStreamStream = AssocStream;
+
+    /*
+     * assoc_range_stream(request)
+     *      Pages are fetched as the client reads, at most one page ahead.
+     *      Each page is a separate read, so writes made while the stream
+     *      runs may or may not show up in it.
+     */
+    async fn assoc_range_stream(
+        &self,
+        request: Request<AssocRangeStreamRequest>,
+    ) -> Result<Response<AssocStream>, Status> {
+        let caller = self.caller(&request)?;
+        let pages = RangePages::new(request.into_inner()).map_err(status)?;
+        let first = pages.next_page();
+        if let Some(args) = &first {
+            validate_args(args).map_err(status)?;
+        }
+        self.tao
+            .admit(&caller.principal, 1, &Vec::from_iter(&first))
+            .map_err(|rejected| rejected.status())?;
+        let (sender, receiver) = mpsc::channel(pages.page_size as usize);
+        let tao = self.tao.clone();
+        let span = Span::current();
+        // Detached, it ends once the range is sent or the client is gone
+        drop(self.pool.spawn_pinned(move || {
+            send_pages(tao, caller, pages, sender).instrument(span)
+        }));
+        Ok(Response::new(Box::pin(ReceiverStream::new(receiver))))
+    }
+}
+
+/*
+ * send_pages(tao, caller, pages, sender)
+ *      Feeds an AssocRangeStream, see assoc_range_stream(). Each page must
+ *      be read within the query timeout, the stream as a whole only within
+ *      the grpc-timeout of the call, if it has one.
+ */
+async fn send_pages(
+    tao: Arc<TaoServer>,
+    caller: Caller,
+    mut pages: RangePages,
+    sender: mpsc::Sender<Result<Assoc, Status>>,
+) {
+    let query_timeout = tao.timeouts.limit(None, caller.query_timeout).query;
+    let deadlines = Deadlines::new(caller.request_timeout, query_timeout);
+    while let Some(args) = pages.next_page() {
+        // A page grows by the associations already sent at its tend, so
+        // the range limit is checked again on every page
+        if let Err(rejected) = tao.limits.check_query(&args) {
+            let _ = sender.send(Err(rejected.status())).await;
+            return;
+        }
+        let query = Query {
+            op: TaoOp::AssocRange,
+            args,
+        };
+        let deadline = deadlines.query();
+        let rows = match run_query(&tao, &caller, query, deadline).await {
+            Ok(rows) => rows,
+            Err(e) => {
+                let _ = sender.send(Err(status(e))).await;
+                return;
+            }
+        };
+        for assoc in pages.accept(rows) {
+            if sender.send(Ok(assoc)).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use clap::Parser;
+    use futures::StreamExt;
+    use tonic::{Code, Request};
+
+    use super::proto::tao_server::Tao;
+    use super::proto::{
+        AssocAddRequest, AssocRangeStreamRequest, ObjAddRequest, ObjGetRequest,
+    };
+    use super::TaoGrpc;
+    use crate::service::auth::Authenticator;
+    use crate::service::config::{ServerArgs, ServerConfig};
+    use crate::service::store::MemoryStore;
+    use crate::service::tao::TaoServer;
+
+    fn grpc() -> TaoGrpc {
+        TaoGrpc::new(Arc::new(server()), Arc::new(Authenticator::disabled()), 2)
+    }
+
+    fn server() -> TaoServer {
+        let args = ServerArgs::parse_from([
+            "tao-server",
+            "--encryption=off",
+            "--storage=sqlite",
+        ]);
+        let config = ServerConfig::from_args(args).unwrap();
+        TaoServer::with_store(config, Box::new(MemoryStore::new())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_objects() {
+        let grpc = grpc();
+        let add = |otype: &str| {
+            Request::new(ObjAddRequest {
+                id: "7".to_string(),
+                otype: otype.to_string(),
+                data: "alice".to_string(),
+            })
+        };
+        let err = grpc.obj_add(add("PERSON")).await.unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+        let resp = grpc.obj_add(add("USER")).await.unwrap();
+        assert!(resp.metadata().get("x-tao-session").is_some());
+
+        let get = |id: &str| Request::new(ObjGetRequest { id: id.to_string() });
+        let obj = grpc.obj_get(get("7")).await.unwrap().into_inner().obj;
+        assert_eq!(obj.unwrap().data, "alice");
+        let obj = grpc.obj_get(get("8")).await.unwrap().into_inner().obj;
+        assert!(obj.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_assoc_range_stream() {
+        let grpc = grpc();
+        // Three associations share time 20 and straddle a page boundary
+        let times = [("1", 10), ("2", 20), ("3", 20), ("4", 20), ("5", 30)];
+        for (id2, time) in times {
+            let request = Request::new(AssocAddRequest {
+                id1: "42".to_string(),
+                atype: "LIKES".to_string(),
+                id2: id2.to_string(),
+                time,
+                data: "x".to_string(),
+            });
+            grpc.assoc_add(request).await.unwrap();
+        }
+
+        let stream = |limit, page_size| {
+            grpc.assoc_range_stream(Request::new(AssocRangeStreamRequest {
+                id1: "42".to_string(),
+                atype: "LIKES".to_string(),
+                tstart: 0,
+                tend: 100,
+                limit,
+                page_size,
+            }))
+        };
+        let id2s = |limit, page_size| async move {
+            stream(limit, page_size)
+                .await
+                .unwrap()
+                .into_inner()
+                .map(|assoc| assoc.unwrap().id2)
+                .collect::<Vec<String>>()
+                .await
+        };
+        assert_eq!(id2s(0, 2).await, ["5", "4", "3", "2", "1"]);
+        assert_eq!(id2s(0, 1).await, ["5", "4", "3", "2", "1"]);
+        assert_eq!(id2s(3, 2).await, ["5", "4", "3"]);
+        let err = stream(0, -1).await.err().unwrap();
+        assert_eq!(err.code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_assoc_range_stream_limits() {
+        let mut tao = server();
+        tao.limits.max_range_limit = 3;
+        let grpc =
+            TaoGrpc::new(Arc::new(tao), Arc::new(Authenticator::disabled()), 2);
+        for (id2, time) in [("1", 20), ("2", 20), ("3", 20), ("4", 30)] {
+            let request = Request::new(AssocAddRequest {
+                id1: "42".to_string(),
+                atype: "LIKES".to_string(),
+                id2: id2.to_string(),
+                time,
+                data: "x".to_string(),
+            });
+            grpc.assoc_add(request).await.unwrap();
+        }
+        let stream = |page_size| {
+            grpc.assoc_range_stream(Request::new(AssocRangeStreamRequest {
+                id1: "42".to_string(),
+                atype: "LIKES".to_string(),
+                tstart: 0,
+                tend: 100,
+                limit: 0,
+                page_size,
+            }))
+        };
+        let err = stream(4).await.err().unwrap();
+        assert_eq!(err.code(), Code::ResourceExhausted);
+
+        // The third page would skip the three associations sent at time 20,
+        // so it asks for more than the range limit
+        let items = stream(2)
+            .await
+            .unwrap()
+            .into_inner()
+            .collect::<Vec<_>>()
+            .await;
+        let (sent, failed) = items.split_at(items.len() - 1);
+        let id2s = sent
+            .iter()
+            .map(|assoc| assoc.as_ref().unwrap().id2.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(id2s, ["4", "3", "2", "1"]);
+        let err = failed[0].as_ref().err().unwrap();
+        assert_eq!(err.code(), Code::ResourceExhausted);
+    }
+}
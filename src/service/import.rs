@@ -0,0 +1,851 @@
+/*
+ * File: import.rs
+ *      Bulk import of objects and associations, by `tao-server import` and
+ *      POST /import. Records come one per line, as JSON lines
+ *          {"id": "1", "otype": "USER", "data": "alice"}
+ *          {"id1": "1", "atype": "LIKES", "id2": "2", "time": 5,
+ *           "data": "recent"}
+ *      or as CSV rows led by the kind of record
+ *          obj,1,USER,alice
+ *          assoc,1,LIKES,2,5,recent
+ *
+ *      Each record is checked as the OBJ ADD or ASSOC ADD it stands for,
+ *      against the policy too over HTTP, and loaded in batches of
+ *      IMPORT_BATCH_SIZE lines. A batch is encrypted on every core, then
+ *      added with the store's bulk load, COPY on Postgres. Keys that are
+ *      taken are skipped, not overwritten, so an import may be run again.
+ *      The rows added go to the change feed like those of single writes.
+ *
+ *      Records that do not parse or pass the checks are reported with their
+ *      line numbers and do not stop the import, a failing store does. The
+ *      report then says how many lines were done, those of the batches that
+ *      were loaded, and the import resumes after them with `?skip=` or the
+ *      progress file of `tao-server import`.
+ */
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::pin::pin;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use actix_web::web::{Bytes, BytesMut};
+use clap::ValueEnum;
+use futures::future;
+use futures::stream::{self, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::query::crypto::TaoCrypto;
+use crate::query::error::TaoError;
+use crate::query::parser::validate_args;
+use crate::query::query::{Query, TaoArgs, TaoOp};
+use crate::query::results::DBRow;
+use crate::service::audit::unaudited;
+use crate::service::auth::Principal;
+use crate::service::cache::Invalidation;
+use crate::service::deadline;
+use crate::service::feed::FeedEvent;
+use crate::service::namespace::Namespace;
+use crate::service::tao::TaoServer;
+
+/// Who the audit log records for the writes of `tao-server import`
+pub const IMPORT_PRINCIPAL: &str = "tao-server import";
+/// Row errors a report lists, the others are only counted
+pub const MAX_REPORTED_ERRORS: usize = 1000;
+/// Invalidations sent to the followers at once
+const BROADCASTS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportFormat {
+    /// One JSON object per line
+    Jsonl,
+    /// obj,id,otype,data and assoc,id1,atype,id2,time,data rows
+    Csv,
+}
+
+/*
+ * ImportParams
+ *      The query string of POST /import.
+ */
+#[derive(Debug, Deserialize)]
+pub struct ImportParams {
+    #[serde(default = "default_format")]
+    pub format: ImportFormat,
+    pub namespace: Option<String>,
+    /// Lines already imported
+    #[serde(default)]
+    pub skip: u64,
+}
+
+fn default_format() -> ImportFormat {
+    ImportFormat::Jsonl
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ImportOptions {
+    pub format: ImportFormat,
+    pub skip: u64,
+    pub batch_size: usize,
+}
+
+/*
+ * Record
+ *      A JSON line, told apart by its fields.
+ */
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Record {
+    Obj {
+        id: String,
+        otype: String,
+        data: String,
+    },
+    Assoc {
+        id1: String,
+        atype: String,
+        id2: String,
+        time: i64,
+        data: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RowError {
+    pub line: u64,
+    pub error: String,
+    pub record: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ImportReport {
+    /// Lines done, skipped ones included, to resume after
+    pub done: u64,
+    pub imported: u64,
+    /// Records whose key was taken already
+    pub existing: u64,
+    pub failed: u64,
+    pub errors: Vec<RowError>,
+    /// Why the import stopped early
+    pub error: Option<String>,
+}
+
+/*
+ * Checked
+ *      A record that parsed, with the policy's verdict.
+ */
+struct Checked {
+    line: u64,
+    record: String,
+    op: TaoOp,
+    allowed: Result<(), TaoError>,
+}
+
+struct Loaded {
+    imported: u64,
+    existing: u64,
+    errors: Vec<RowError>,
+}
+
+/*
+ * parse_record(format, line)
+ *      The OBJ ADD or ASSOC ADD a line stands for, checked like the queries
+ *      of gRPC requests.
+ */
+pub fn parse_record(
+    format: ImportFormat,
+    line: &str,
+) -> Result<Query, TaoError> {
+    let args = match format {
+        ImportFormat::Jsonl => json_args(line)?,
+        ImportFormat::Csv => csv_args(line)?,
+    };
+    validate_args(&args)?;
+    let op = match args {
+        TaoArgs::ObjAddArgs { .. } => TaoOp::ObjAdd,
+        _ => TaoOp::AssocAdd,
+    };
+    Ok(Query { op, args })
+}
+
+fn json_args(line: &str) -> Result<TaoArgs, TaoError> {
+    let value = serde_json::from_str::<serde_json::Value>(line)
+        .map_err(|e| TaoError::Parse(e.to_string()))?;
+    let record = Record::deserialize(value).map_err(|_| {
+        TaoError::Parse("not an object or association record".to_string())
+    })?;
+    Ok(match record {
+        Record::Obj { id, otype, data } => {
+            TaoArgs::ObjAddArgs { id, otype, data }
+        }
+        Record::Assoc {
+            id1,
+            atype,
+            id2,
+            time,
+            data,
+        } => TaoArgs::AssocAddArgs {
+            id1,
+            atype,
+            id2,
+            time,
+            data,
+        },
+    })
+}
+
+fn csv_args(line: &str) -> Result<TaoArgs, TaoError> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(line.as_bytes());
+    let mut record = csv::StringRecord::new();
+    reader
+        .read_record(&mut record)
+        .map_err(|e| TaoError::Parse(e.to_string()))?;
+    let fields = record.iter().collect::<Vec<&str>>();
+    match fields.as_slice() {
+        ["obj", id, otype, data] => Ok(TaoArgs::ObjAddArgs {
+            id: id.to_string(),
+            otype: otype.to_string(),
+            data: data.to_string(),
+        }),
+        ["assoc", id1, atype, id2, time, data] => Ok(TaoArgs::AssocAddArgs {
+            id1: id1.to_string(),
+            atype: atype.to_string(),
+            id2: id2.to_string(),
+            time: time.parse::<i64>().map_err(|_| {
+                TaoError::Parse(format!("time {:?} is not a number", time))
+            })?,
+            data: data.to_string(),
+        }),
+        _ => Err(TaoError::Parse(
+            "expected obj,id,otype,data or assoc,id1,atype,id2,time,data"
+                .to_string(),
+        )),
+    }
+}
+
+/*
+ * lines(chunks, max_line)
+ *      Splits a stream of bytes into lines, without their line ends. A line
+ *      over `max_line` bytes ends the stream with an error, as does a chunk
+ *      that could not be read.
+ */
+pub fn lines<S, E>(
+    chunks: S,
+    max_line: usize,
+) -> impl Stream<Item = Result<Bytes, String>>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: fmt::Display,
+{
+    let too_long = move || format!("a line is over {} bytes", max_line);
+    let state = Some((chunks, BytesMut::new(), false));
+    stream::unfold(state, move |state| async move {
+        let (mut chunks, mut buf, mut eof) = state?;
+        loop {
+            if let Some(end) = buf.iter().position(|b| *b == b'\n') {
+                if end > max_line {
+                    return Some((Err(too_long()), None));
+                }
+                let line = buf.split_to(end + 1).freeze().slice(..end);
+                return Some((Ok(line), Some((chunks, buf, eof))));
+            }
+            if buf.len() > max_line {
+                return Some((Err(too_long()), None));
+            }
+            if eof {
+                return match buf.is_empty() {
+                    true => None,
+                    false => Some((Ok(buf.freeze()), None)),
+                };
+            }
+            match chunks.next().await {
+                Some(Ok(chunk)) => buf.extend_from_slice(&chunk),
+                Some(Err(e)) => return Some((Err(e.to_string()), None)),
+                None => eof = true,
+            }
+        }
+    })
+}
+
+/*
+ * capped(chunks, max_bytes)
+ *      Passes the chunks on until they add up to over `max_bytes`, then
+ *      ends the stream with an error.
+ */
+pub fn capped<S, E>(
+    chunks: S,
+    max_bytes: u64,
+) -> impl Stream<Item = Result<Bytes, String>>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: fmt::Display,
+{
+    let mut total = 0u64;
+    chunks.scan(false, move |over, chunk| {
+        let item = match (*over, chunk) {
+            (true, _) => None,
+            (false, Ok(chunk)) => {
+                total += chunk.len() as u64;
+                *over = total > max_bytes;
+                Some(match *over {
+                    true => {
+                        Err(format!("the body is over {} bytes", max_bytes))
+                    }
+                    false => Ok(chunk),
+                })
+            }
+            (false, Err(e)) => Some(Err(e.to_string())),
+        };
+        future::ready(item)
+    })
+}
+
+/*
+ * import(tao, principal, ns, options, lines, on_batch)
+ *      Imports the records of `lines` after the first `options.skip`.
+ *      `principal` is None for `tao-server import`, which the policy does
+ *      not apply to. Others are charged a token of their rate limit per
+ *      line, waited for before each batch. on_batch(done, errors) runs once
+ *      a batch is loaded, with the lines done and the batch's row errors,
+ *      an error from it stops the import.
+ */
+pub async fn import<S, F>(
+    tao: &TaoServer,
+    principal: Option<&Principal>,
+    ns: &Namespace,
+    options: ImportOptions,
+    lines: S,
+    mut on_batch: F,
+) -> ImportReport
+where
+    S: Stream<Item = Result<Bytes, String>>,
+    F: FnMut(u64, &[RowError]) -> Result<(), String>,
+{
+    let mut lines = pin!(lines.skip(options.skip as usize));
+    let mut report = ImportReport {
+        done: options.skip,
+        ..ImportReport::default()
+    };
+    let mut line = options.skip;
+    let mut eof = false;
+    while !eof && report.error.is_none() {
+        let mut batch = Vec::with_capacity(options.batch_size);
+        while batch.len() < options.batch_size {
+            match lines.next().await {
+                Some(Ok(text)) => {
+                    line += 1;
+                    batch.push((line, text));
+                }
+                Some(Err(e)) => {
+                    report.error = Some(e);
+                    break;
+                }
+                None => {
+                    eof = true;
+                    break;
+                }
+            }
+        }
+        if batch.is_empty() {
+            break;
+        }
+        if let Some(principal) = principal {
+            let tokens = tao.rate_limiter.wait(&principal.name, batch.len());
+            tokio::select! {
+                _ = tokens => (),
+                _ = tao.shutdown.draining() => {
+                    report.error =
+                        Some("the server is shutting down".to_string());
+                    break;
+                }
+            }
+        }
+
+        let loaded =
+            match load_batch(tao, principal, ns, options.format, batch).await {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    error!(
+                        namespace = %ns.config.name,
+                        after = report.done,
+                        error = %e,
+                        "import stopped"
+                    );
+                    report.error = Some(e.to_string());
+                    break;
+                }
+            };
+        report.done = line;
+        report.imported += loaded.imported;
+        report.existing += loaded.existing;
+        report.failed += loaded.errors.len() as u64;
+        let room = MAX_REPORTED_ERRORS.saturating_sub(report.errors.len());
+        report
+            .errors
+            .extend(loaded.errors.iter().take(room).cloned());
+        info!(
+            namespace = %ns.config.name,
+            done = report.done,
+            imported = report.imported,
+            failed = report.failed,
+            "import batch loaded"
+        );
+        if let Err(e) = on_batch(report.done, &loaded.errors) {
+            report.error = Some(e);
+        }
+        // Stops between batches, the caller resumes after `done`
+        if !eof && report.error.is_none() && tao.shutdown.is_draining() {
+            report.error = Some("the server is shutting down".to_string());
+        }
+    }
+    report
+}
+
+/*
+ * load_batch(tao, principal, ns, format, batch)
+ *      Checks, encrypts and loads the lines of a batch. Like single writes,
+ *      every record checked is audited, denied ones included, and the
+ *      cached entries loading may have made stale are invalidated on this
+ *      server and its followers.
+ */
+async fn load_batch(
+    tao: &TaoServer,
+    principal: Option<&Principal>,
+    ns: &Namespace,
+    format: ImportFormat,
+    batch: Vec<(u64, Bytes)>,
+) -> Result<Loaded, TaoError> {
+    let mut errors = Vec::new();
+    let mut checked = Vec::with_capacity(batch.len());
+    let mut queries = Vec::with_capacity(batch.len());
+    for (line, bytes) in batch {
+        let text = match std::str::from_utf8(&bytes) {
+            Ok(text) => text.trim_end_matches('\r'),
+            Err(_) => {
+                errors.push(RowError {
+                    line,
+                    error: "not UTF-8".to_string(),
+                    record: String::from_utf8_lossy(&bytes).into_owned(),
+                });
+                continue;
+            }
+        };
+        if text.trim().is_empty() {
+            continue;
+        }
+        match parse_record(format, text) {
+            Ok(query) => {
+                let allowed = match principal {
+                    Some(principal) => tao.policy.check(principal, &query),
+                    None => Ok(()),
+                };
+                checked.push(Checked {
+                    line,
+                    record: text.to_string(),
+                    op: query.op.clone(),
+                    allowed,
+                });
+                queries.push(query);
+            }
+            Err(e) => errors.push(RowError {
+                line,
+                error: e.to_string(),
+                record: text.to_string(),
+            }),
+        }
+    }
+
+    let encrypted = match tao.encrypted {
+        true => {
+            let crypto = ns.tao_crypto.clone();
+            tokio::task::spawn_blocking(move || encrypt_all(&crypto, queries))
+                .await
+                .map_err(|e| TaoError::Crypto(e.to_string()))?
+        }
+        false => queries.into_iter().map(Ok).collect(),
+    };
+
+    let principal = principal.map_or(IMPORT_PRINCIPAL, |p| p.name.as_str());
+    let mut allowed = Vec::with_capacity(checked.len());
+    for (checked, query) in checked.into_iter().zip(encrypted) {
+        match (query, &checked.allowed) {
+            (Ok(query), Ok(())) => allowed.push(query),
+            (Ok(query), Err(e)) => {
+                // Nothing was written, so the denial stands either way
+                let denied = Err::<(), _>(e.clone());
+                let _ = audit(tao, principal, ns, &query, &denied);
+                errors.push(checked.row_error(e.clone()));
+            }
+            // Denied stays denied, also when the record does not encrypt
+            (Err(_), Err(e)) => {
+                let denied = Err::<(), _>(e.clone());
+                let (ns_name, op) = (&ns.config.name, &checked.op);
+                let recorded =
+                    tao.audit.record_op(principal, ns_name, op, &denied);
+                if let Err(e) = recorded {
+                    error!(error = %e, "cannot write to the audit log");
+                }
+                errors.push(checked.row_error(e.clone()));
+            }
+            (Err(e), Ok(())) => errors.push(checked.row_error(e)),
+        }
+    }
+
+    let res = store_queries(tao, principal, ns, &allowed).await;
+    errors.sort_by_key(|e| e.line);
+    let imported = res?;
+    Ok(Loaded {
+        imported,
+        existing: allowed.len() as u64 - imported,
+        errors,
+    })
+}
+
+/*
+ * store_queries(tao, principal, ns, queries)
+ *      Loads OBJ ADD and ASSOC ADD queries, as stored, with the store's bulk
+ *      load. Like single writes, what they may have made stale is then
+ *      invalidated here and on the followers, each is audited, and the rows
+ *      added are published to the change feed. Returns the rows added.
+ */
+pub async fn store_queries(
+    tao: &TaoServer,
+    principal: &str,
+    ns: &Namespace,
+    queries: &[Query],
+) -> Result<u64, TaoError> {
+    let rows = queries
+        .iter()
+        .filter_map(|query| db_row(&query.args))
+        .collect::<Vec<DBRow>>();
+    let invalidations = queries
+        .iter()
+        .filter_map(|query| Invalidation::of_write(&ns.config.name, query))
+        .collect::<HashSet<Invalidation>>();
+
+    tao.audit.check().map_err(unaudited)?;
+    let deadline = Instant::now() + tao.timeouts.request;
+    let load = tao.store.load(&ns.config, &rows);
+    let res = deadline::within(deadline, load).await;
+    for invalidation in &invalidations {
+        tao.cache.invalidate(invalidation);
+    }
+    stream::iter(&invalidations)
+        .for_each_concurrent(BROADCASTS, |inv| tao.tier.broadcast(inv))
+        .await;
+    for query in queries {
+        audit(tao, principal, ns, query, &res)?;
+    }
+    let added = res?;
+    let count = added.len() as u64;
+    for row in added {
+        if let Some(event) = FeedEvent::of_load(&ns.config.name, row) {
+            tao.feed.publish(event);
+        }
+    }
+    Ok(count)
+}
+
+impl Checked {
+    fn row_error(&self, error: TaoError) -> RowError {
+        RowError {
+            line: self.line,
+            error: error.to_string(),
+            record: self.record.clone(),
+        }
+    }
+}
+
+/*
+ * encrypt_all(crypto, queries)
+ *      Encrypts the queries on as many threads as there are cores, keeping
+ *      their order.
+ */
+pub fn encrypt_all(
+    crypto: &Arc<TaoCrypto>,
+    queries: Vec<Query>,
+) -> Vec<Result<Query, TaoError>> {
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk = queries.len().div_ceil(threads).max(1);
+    thread::scope(|scope| {
+        let workers = queries
+            .chunks(chunk)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|query| crypto.encrypt_query(query.clone()))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("encryption panicked"))
+            .collect()
+    })
+}
+
+fn db_row(args: &TaoArgs) -> Option<DBRow> {
+    match args {
+        TaoArgs::ObjAddArgs { id, otype, data } => Some(DBRow::ObjRow {
+            id: id.clone(),
+            otype: otype.clone(),
+            data: data.clone(),
+        }),
+        TaoArgs::AssocAddArgs {
+            id1,
+            atype,
+            id2,
+            time,
+            data,
+        } => Some(DBRow::AssocRow {
+            id1: id1.clone(),
+            atype: atype.clone(),
+            id2: id2.clone(),
+            t: *time,
+            data: data.clone(),
+        }),
+        _ => None,
+    }
+}
+
+fn audit<T>(
+    tao: &TaoServer,
+    principal: &str,
+    ns: &Namespace,
+    query: &Query,
+    outcome: &Result<T, TaoError>,
+) -> Result<(), TaoError> {
+    let recorded = tao.audit.record(principal, &ns.config.name, query, outcome);
+    recorded.map_err(|e| {
+        error!(error = %e, "cannot write to the audit log");
+        unaudited(e)
+    })
+}
+
+/*
+ * read_progress(path)
+ *      The lines a previous `tao-server import` did, 0 without a file.
+ */
+pub fn read_progress(path: &Path) -> Result<u64, String> {
+    if !path.exists() {
+        return Ok(0);
+    }
+    fs::read_to_string(path)
+        .map_err(|e| e.to_string())?
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| "does not hold a line count".to_string())
+}
+
+/*
+ * write_progress(path, done)
+ *      Replaces the progress file in one rename, so that it is never seen
+ *      half written.
+ */
+pub fn write_progress(path: &Path, done: u64) -> Result<(), String> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, format!("{}\n", done))
+        .and_then(|_| fs::rename(&tmp, path))
+        .map_err(|e| format!("cannot write {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::time::{Duration, Instant};
+
+    use actix_web::web::Bytes;
+    use futures::stream::{self, StreamExt};
+
+    use super::{
+        capped, import, lines, parse_record, ImportFormat, ImportOptions,
+        RowError,
+    };
+    use crate::query::query::TaoArgs;
+    use crate::query::results::DBRow;
+    use crate::service::auth::Principal;
+    use crate::service::limits::RateLimiter;
+    use crate::service::namespace::DEFAULT_NAMESPACE;
+    use crate::service::shard::Route;
+    use crate::service::tao::TaoServer;
+    use crate::service::testing;
+
+    fn server(encrypted: bool) -> TaoServer {
+        testing::server(encrypted.then_some("import"))
+    }
+
+    fn body(text: &str) -> impl futures::Stream<Item = Result<Bytes, String>> {
+        let chunks = text
+            .as_bytes()
+            .chunks(7)
+            .map(|c| Ok::<_, Infallible>(Bytes::copy_from_slice(c)))
+            .collect::<Vec<_>>();
+        lines(stream::iter(chunks), 1024)
+    }
+
+    #[test]
+    fn test_parse_record() {
+        let obj = r#"{"id": "1", "otype": "USER", "data": "alice"}"#;
+        let query = parse_record(ImportFormat::Jsonl, obj).unwrap();
+        assert!(matches!(query.args, TaoArgs::ObjAddArgs { .. }));
+        let assoc =
+            r#"{"id1":"1","atype":"LIKES","id2":"2","time":5,"data":"x"}"#;
+        let query = parse_record(ImportFormat::Jsonl, assoc).unwrap();
+        assert!(matches!(query.args, TaoArgs::AssocAddArgs { time: 5, .. }));
+
+        let query =
+            parse_record(ImportFormat::Csv, r#"obj,1,USER,"a b""#).unwrap();
+        match query.args {
+            TaoArgs::ObjAddArgs { data, .. } => assert_eq!(data, "a b"),
+            other => panic!("{:?}", other),
+        }
+        let query = parse_record(ImportFormat::Csv, "assoc,1,LIKES,2,5,recent")
+            .unwrap();
+        assert!(matches!(query.args, TaoArgs::AssocAddArgs { .. }));
+
+        for (format, bad) in [
+            (ImportFormat::Jsonl, r#"{"id": "1"}"#),
+            (ImportFormat::Jsonl, r#"{"id": "x", "otype": "USER"}"#),
+            (ImportFormat::Jsonl, "{"),
+            (ImportFormat::Csv, "obj,1,user"),
+            (ImportFormat::Csv, "assoc,1,LIKES,2,soon,recent"),
+            (ImportFormat::Csv, "edge,1,2"),
+        ] {
+            assert!(parse_record(format, bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_lines() {
+        let split = body("a\r\n\nbc\nlast").collect::<Vec<_>>().await;
+        let split = split.into_iter().map(|l| l.unwrap()).collect::<Vec<_>>();
+        assert_eq!(split, ["a\r", "", "bc", "last"]);
+
+        let long = format!("short\n{}\nnever", "x".repeat(2000));
+        let split = body(&long).collect::<Vec<_>>().await;
+        assert_eq!(split.len(), 2);
+        assert!(split[1].is_err());
+
+        let chunks = ["abc", "def", "ghi"]
+            .map(|c| Ok::<_, Infallible>(Bytes::from_static(c.as_bytes())));
+        let split = capped(stream::iter(chunks), 7).collect::<Vec<_>>().await;
+        assert_eq!(split.len(), 3);
+        assert!(split[1].is_ok());
+        assert!(split[2].is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_import_rate_limit() {
+        let mut tao = server(false);
+        tao.rate_limiter = RateLimiter::new(100, 5);
+        let ns = &tao.namespaces[DEFAULT_NAMESPACE];
+        let principal = Principal {
+            name: "test".to_string(),
+        };
+        let text = (1..=12)
+            .map(|id| format!("obj,{},USER,x", id))
+            .collect::<Vec<String>>()
+            .join("\n");
+        let options = ImportOptions {
+            format: ImportFormat::Csv,
+            skip: 0,
+            batch_size: 4,
+        };
+        // A burst of 5 right away, the 7 other lines at 100 per second
+        let start = Instant::now();
+        let report =
+            import(&tao, Some(&principal), ns, options, body(&text), |_, _| {
+                Ok(())
+            })
+            .await;
+        assert_eq!((report.imported, report.error), (12, None));
+        assert!(start.elapsed() >= Duration::from_millis(60));
+        assert!(tao.rate_limiter.take("test", 1).is_err());
+    }
+
+    async fn check_import(tao: &TaoServer) {
+        let ns = &tao.namespaces[DEFAULT_NAMESPACE];
+        let mut events = tao.feed.subscribe();
+        let records = [
+            r#"{"id": "1", "otype": "USER", "data": "alice"}"#,
+            r#"{"id": "2", "otype": "USER", "data": "bob"}"#,
+            "not json",
+            r#"{"id1":"1","atype":"LIKES","id2":"2","time":5,"data":"x"}"#,
+            "",
+            r#"{"id1":"1","atype":"LIKES","id2":"3","time":7,"data":"x"}"#,
+            r#"{"id": "1", "otype": "USER", "data": "again"}"#,
+        ];
+        let text = records.join("\n");
+        let options = ImportOptions {
+            format: ImportFormat::Jsonl,
+            skip: 0,
+            batch_size: 3,
+        };
+        let mut batches = Vec::new();
+        let on_batch = |done: u64, errors: &[RowError]| {
+            batches.push((done, errors.len()));
+            Ok(())
+        };
+        let report =
+            import(tao, None, ns, options, body(&text), on_batch).await;
+        assert_eq!(report.error, None);
+        assert_eq!(batches, [(3, 1), (6, 0), (7, 0)]);
+        assert_eq!(
+            (report.done, report.imported, report.existing, report.failed),
+            (7, 4, 1, 1)
+        );
+        assert_eq!(report.errors[0].line, 3);
+        assert_eq!(report.errors[0].record, "not json");
+        // An event per row added, none for the key that was taken
+        let ops = std::iter::from_fn(|| events.try_recv().ok())
+            .map(|event| format!("{:?}", event.op))
+            .collect::<Vec<String>>();
+        assert_eq!(ops, ["ObjAdd", "ObjAdd", "AssocAdd", "AssocAdd"]);
+
+        // Resuming after the first batch adds nothing twice
+        let options = ImportOptions { skip: 3, ..options };
+        let report =
+            import(tao, None, ns, options, body(&text), |_, _| Ok(())).await;
+        assert_eq!((report.done, report.imported, report.existing), (7, 0, 3));
+        assert!(events.try_recv().is_err());
+
+        let query = parse_record(ImportFormat::Jsonl, records[0]).unwrap();
+        let query = match tao.encrypted {
+            true => ns.tao_crypto.encrypt_query(query).unwrap(),
+            false => query,
+        };
+        let id = match &query.args {
+            TaoArgs::ObjAddArgs { id, .. } => id.clone(),
+            _ => unreachable!(),
+        };
+        let row = tao
+            .store
+            .obj_get(&ns.config, &id, Route::Primary)
+            .await
+            .unwrap()
+            .unwrap();
+        let row = match tao.encrypted {
+            true => ns.tao_crypto.decrypt_result(row).unwrap(),
+            false => row,
+        };
+        assert!(matches!(row, DBRow::ObjRow { data, .. } if data == "alice"));
+    }
+
+    #[actix_web::test]
+    async fn test_plaintext_import() {
+        check_import(&server(false)).await;
+    }
+
+    #[actix_web::test]
+    async fn test_encrypted_import() {
+        check_import(&server(true)).await;
+    }
+}
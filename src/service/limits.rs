@@ -0,0 +1,295 @@
+/*
+ * File: limits.rs
+ *      Admission control. Requests are turned away before any query runs
+ *      when they are too large, with 413 (RESOURCE_EXHAUSTED over gRPC):
+ *      a body over MAX_BODY_BYTES, a batch of more than MAX_BATCH_QUERIES
+ *      queries, an ASSOC GET or RGET listing more than MAX_ID_SET ids, or
+ *      an ASSOC RANGE asking for more than MAX_RANGE_LIMIT associations.
+ *
+ *      With RATE_LIMIT_QPS set, each principal also gets a token bucket of
+ *      RATE_LIMIT_BURST queries, refilled at RATE_LIMIT_QPS per second. A
+ *      batch takes a token per query, a gRPC call or stream one. Requests
+ *      finding too few tokens get 429 and a Retry-After.
+ */
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::HttpResponse;
+use tonic::Status;
+
+use crate::query::query::TaoArgs;
+
+/// Buckets kept before the full ones, which lose nothing, are dropped
+const MAX_BUCKETS: usize = 10_000;
+
+/*
+ * Rejected
+ *      Why a request was turned away as a whole.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rejected {
+    TooLarge(String),
+    RateLimited(Duration),
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rejected::TooLarge(msg) => write!(f, "request too large: {}", msg),
+            Rejected::RateLimited(wait) => write!(
+                f,
+                "rate limit exceeded, retry in {} ms",
+                wait.as_millis()
+            ),
+        }
+    }
+}
+
+impl Rejected {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Rejected::TooLarge(_) => "TooLarge",
+            Rejected::RateLimited(_) => "RateLimited",
+        }
+    }
+
+    pub fn response(&self) -> HttpResponse {
+        match self {
+            Rejected::TooLarge(_) => {
+                HttpResponse::PayloadTooLarge().json(self.to_string())
+            }
+            Rejected::RateLimited(wait) => {
+                let secs = wait.as_secs() + (wait.subsec_nanos() > 0) as u64;
+                HttpResponse::TooManyRequests()
+                    .insert_header((RETRY_AFTER, secs.to_string()))
+                    .json(self.to_string())
+            }
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        Status::resource_exhausted(self.to_string())
+    }
+}
+
+/*
+ * Limits
+ *      The largest request the server takes.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_body_bytes: usize,
+    pub max_batch_queries: usize,
+    pub max_id_set: usize,
+    pub max_range_limit: i64,
+    pub max_import_bytes: u64,
+}
+
+impl Limits {
+    pub fn check_batch(&self, queries: usize) -> Result<(), Rejected> {
+        match queries > self.max_batch_queries {
+            true => Err(Rejected::TooLarge(format!(
+                "{} queries in a batch, at most {} are allowed",
+                queries, self.max_batch_queries
+            ))),
+            false => Ok(()),
+        }
+    }
+
+    pub fn check_import(&self, bytes: u64) -> Result<(), Rejected> {
+        match bytes > self.max_import_bytes {
+            true => Err(Rejected::TooLarge(format!(
+                "an import of {} bytes, at most {} are allowed",
+                bytes, self.max_import_bytes
+            ))),
+            false => Ok(()),
+        }
+    }
+
+    pub fn check_query(&self, args: &TaoArgs) -> Result<(), Rejected> {
+        match args {
+            TaoArgs::AssocGetArgs { idset, .. }
+            | TaoArgs::AssocRangeGetArgs { idset, .. }
+                if idset.len() > self.max_id_set =>
+            {
+                Err(Rejected::TooLarge(format!(
+                    "{} ids in an id set, at most {} are allowed",
+                    idset.len(),
+                    self.max_id_set
+                )))
+            }
+            TaoArgs::AssocRangeArgs { lim, .. }
+                if *lim > self.max_range_limit =>
+            {
+                Err(Rejected::TooLarge(format!(
+                    "a range limit of {}, at most {} is allowed",
+                    lim, self.max_range_limit
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/*
+ * RateLimiter
+ *      A token bucket per principal, or no limit at all when the rate is 0.
+ */
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32, burst: u32) -> Self {
+        RateLimiter {
+            rate: rate as f64,
+            burst: burst as f64,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /*
+     * wait(principal, queries)
+     *      Takes a token per query like take(), but waits for them instead
+     *      of refusing. They are taken a burst at most at a time, so that
+     *      more queries than a burst can be waited for too.
+     */
+    pub async fn wait(&self, principal: &str, queries: usize) {
+        let mut left = queries;
+        while left > 0 {
+            let wanted = left.min(self.burst as usize);
+            match self.take(principal, wanted) {
+                Ok(()) => left -= wanted,
+                Err(Rejected::RateLimited(wait)) => {
+                    tokio::time::sleep(wait).await
+                }
+                Err(Rejected::TooLarge(_)) => return,
+            }
+        }
+    }
+
+    /*
+     * take(principal, queries)
+     *      Takes a token per query from the principal's bucket, or none and
+     *      says how long until there are enough.
+     */
+    pub fn take(
+        &self,
+        principal: &str,
+        queries: usize,
+    ) -> Result<(), Rejected> {
+        if self.rate == 0.0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut buckets =
+            self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(principal) {
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, bucket| {
+                let idle = now.duration_since(bucket.updated).as_secs_f64();
+                bucket.tokens + idle * rate < burst
+            });
+        }
+        let bucket = buckets.entry(principal.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let idle = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + idle * self.rate).min(self.burst);
+        bucket.updated = now;
+
+        let wanted = queries as f64;
+        if bucket.tokens >= wanted {
+            bucket.tokens -= wanted;
+            return Ok(());
+        }
+        let wait = (wanted.min(self.burst) - bucket.tokens) / self.rate;
+        Err(Rejected::RateLimited(Duration::from_secs_f64(wait)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Limits, RateLimiter, Rejected};
+    use crate::query::query::TaoArgs;
+
+    #[test]
+    fn test_limits() {
+        let limits = Limits {
+            max_body_bytes: 1024,
+            max_batch_queries: 2,
+            max_id_set: 3,
+            max_range_limit: 100,
+            max_import_bytes: 4096,
+        };
+        assert!(limits.check_batch(2).is_ok());
+        assert!(matches!(limits.check_batch(3), Err(Rejected::TooLarge(_))));
+        assert!(limits.check_import(4096).is_ok());
+        assert!(limits.check_import(4097).is_err());
+
+        let get = |n: usize| TaoArgs::AssocGetArgs {
+            id: "1".to_string(),
+            atype: "LIKES".to_string(),
+            idset: (0..n).map(|i| i.to_string()).collect(),
+        };
+        assert!(limits.check_query(&get(3)).is_ok());
+        assert!(limits.check_query(&get(4)).is_err());
+        let range = |lim: i64| TaoArgs::AssocRangeArgs {
+            id: "1".to_string(),
+            atype: "LIKES".to_string(),
+            tstart: 0,
+            tend: 10,
+            lim,
+        };
+        assert!(limits.check_query(&range(100)).is_ok());
+        let err = limits.check_query(&range(101)).unwrap_err();
+        assert_eq!(err.response().status().as_u16(), 413);
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        assert!(RateLimiter::new(0, 0).take("a", 1_000_000).is_ok());
+
+        let limiter = RateLimiter::new(10, 5);
+        assert!(limiter.take("a", 3).is_ok());
+        assert!(limiter.take("a", 2).is_ok());
+        let wait = match limiter.take("a", 2) {
+            Err(Rejected::RateLimited(wait)) => wait,
+            other => panic!("{:?}", other),
+        };
+        assert!(wait > Duration::from_millis(100));
+        assert!(wait <= Duration::from_millis(200));
+        // Every principal has a bucket of its own
+        assert!(limiter.take("b", 5).is_ok());
+
+        let resp = limiter.take("a", 5).unwrap_err().response();
+        assert_eq!(resp.status().as_u16(), 429);
+        assert_eq!(resp.headers().get("retry-after").unwrap(), "1");
+
+        std::thread::sleep(Duration::from_millis(250));
+        assert!(limiter.take("a", 2).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter_wait() {
+        // A burst at once, then the rest at 100 per second
+        let limiter = RateLimiter::new(100, 5);
+        let start = Instant::now();
+        limiter.wait("a", 12).await;
+        assert!(start.elapsed() >= Duration::from_millis(60));
+        assert!(limiter.take("a", 1).is_err());
+    }
+}
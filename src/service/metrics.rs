@@ -0,0 +1,444 @@
+/*
+ * File: metrics.rs
+ *      Prometheus metrics, served by GET /metrics in the text exposition
+ *      format. Query and database latencies are recorded as queries run,
+ *      the pool, cache and OPE figures are read from their owners on each
+ *      scrape.
+ */
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::query::crypto::{CryptoCacheStats, CryptoStats};
+use crate::query::error::TaoError;
+use crate::query::query::TaoOp;
+use crate::service::cache::CacheStats;
+use crate::service::pool::PoolMetrics;
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Upper bounds of the latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 14] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1,
+    0.25, 0.5, 1.0, 2.5,
+];
+
+#[derive(Default)]
+struct Histogram {
+    /// Cumulative, as Prometheus wants them
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if secs <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+}
+
+/*
+ * Metrics
+ *      Counters and latency histograms of the queries, labelled by TaoOp.
+ *      Queries are counted by outcome, "ok" or the kind of TaoError.
+ */
+#[derive(Default)]
+pub struct Metrics {
+    queries: Mutex<BTreeMap<(String, &'static str), u64>>,
+    query_latency: Mutex<BTreeMap<String, Histogram>>,
+    db_latency: Mutex<BTreeMap<String, Histogram>>,
+}
+
+/*
+ * Scrape
+ *      What is read from elsewhere for one scrape. `crypto` holds the
+ *      stats of each set of keys, empty when encryption is off.
+ */
+pub struct Scrape<'a> {
+    pub pools: &'a [Vec<PoolMetrics>],
+    pub graph_cache: &'a CacheStats,
+    pub crypto: &'a [(String, CryptoStats)],
+}
+
+fn op_label(op: &TaoOp) -> String {
+    format!("{:?}", op)
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    /*
+     * observe_query(op, res, elapsed)
+     *      One query through the whole executor: policy, encryption, cache
+     *      or database, and decryption.
+     */
+    pub fn observe_query<T>(
+        &self,
+        op: &TaoOp,
+        res: &Result<T, TaoError>,
+        elapsed: Duration,
+    ) {
+        let outcome = match res {
+            Ok(_) => "ok",
+            Err(e) => e.kind(),
+        };
+        let op = op_label(op);
+        *self
+            .queries
+            .lock()
+            .unwrap()
+            .entry((op.clone(), outcome))
+            .or_default() += 1;
+        self.query_latency
+            .lock()
+            .unwrap()
+            .entry(op)
+            .or_default()
+            .observe(elapsed);
+    }
+
+    /*
+     * observe_db(op, elapsed)
+     *      One call to the store, cache misses only.
+     */
+    pub fn observe_db(&self, op: &TaoOp, elapsed: Duration) {
+        self.db_latency
+            .lock()
+            .unwrap()
+            .entry(op_label(op))
+            .or_default()
+            .observe(elapsed);
+    }
+
+    pub fn render(&self, scrape: &Scrape) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "tao_queries_total",
+            "counter",
+            "Queries run, by operation and outcome",
+        );
+        for ((op, outcome), n) in self.queries.lock().unwrap().iter() {
+            sample(
+                &mut out,
+                "tao_queries_total",
+                &[("op", op), ("outcome", outcome)],
+                n,
+            );
+        }
+        histograms(
+            &mut out,
+            "tao_query_duration_seconds",
+            "Time to run a query, including encryption and the cache",
+            &self.query_latency.lock().unwrap(),
+        );
+        histograms(
+            &mut out,
+            "tao_db_query_duration_seconds",
+            "Time the store takes for a query",
+            &self.db_latency.lock().unwrap(),
+        );
+
+        render_pools(&mut out, scrape.pools);
+        render_graph_cache(&mut out, scrape.graph_cache);
+        render_crypto(&mut out, scrape.crypto);
+        out
+    }
+}
+
+fn render_pools(out: &mut String, pools: &[Vec<PoolMetrics>]) {
+    let mut rows = Vec::new();
+    for (shard, pools) in pools.iter().enumerate() {
+        for (i, pool) in pools.iter().enumerate() {
+            let role = match i {
+                0 => "primary",
+                _ => "replica",
+            };
+            rows.push((shard.to_string(), role, pool));
+        }
+    }
+    type PoolValue = fn(&PoolMetrics) -> u64;
+    let families: [(&str, &str, PoolValue); 4] = [
+        ("tao_db_connections", "Open database connections", |p| {
+            p.connections as u64
+        }),
+        (
+            "tao_db_idle_connections",
+            "Idle database connections",
+            |p| p.idle_connections as u64,
+        ),
+        ("tao_db_max_connections", "Size limit of the pool", |p| {
+            p.max_size as u64
+        }),
+        (
+            "tao_db_connections_created_total",
+            "Database connections opened",
+            |p| p.connections_created,
+        ),
+    ];
+    for (name, help, value) in families {
+        let kind = match name.ends_with("_total") {
+            true => "counter",
+            false => "gauge",
+        };
+        header(out, name, kind, help);
+        for (shard, role, pool) in &rows {
+            let labels = [("shard", shard.as_str()), ("role", role)];
+            sample(out, name, &with_host(&labels, pool), value(pool));
+        }
+    }
+
+    header(
+        out,
+        "tao_db_checkouts_total",
+        "counter",
+        "Connection checkouts, by whether they had to wait or timed out",
+    );
+    for (shard, role, pool) in &rows {
+        for (result, n) in [
+            ("direct", pool.get_direct),
+            ("waited", pool.get_waited),
+            ("timed_out", pool.get_timed_out),
+        ] {
+            let labels = [("shard", shard.as_str()), ("role", role)];
+            let mut labels = with_host(&labels, pool);
+            labels.push(("result", result));
+            sample(out, "tao_db_checkouts_total", &labels, n);
+        }
+    }
+    header(
+        out,
+        "tao_db_checkout_wait_seconds_total",
+        "counter",
+        "Time spent waiting for a connection",
+    );
+    for (shard, role, pool) in &rows {
+        let labels = [("shard", shard.as_str()), ("role", role)];
+        sample(
+            out,
+            "tao_db_checkout_wait_seconds_total",
+            &with_host(&labels, pool),
+            pool.get_wait_time_ms as f64 / 1000.0,
+        );
+    }
+}
+
+fn with_host<'a>(
+    labels: &[(&'a str, &'a str)],
+    pool: &'a PoolMetrics,
+) -> Vec<(&'a str, &'a str)> {
+    let mut labels = labels.to_vec();
+    labels.push(("host", &pool.host));
+    labels
+}
+
+fn render_graph_cache(out: &mut String, stats: &CacheStats) {
+    for (name, help, obj, assoc) in [
+        (
+            "tao_graph_cache_hits_total",
+            "Graph cache hits",
+            stats.obj_hits,
+            stats.assoc_hits,
+        ),
+        (
+            "tao_graph_cache_misses_total",
+            "Graph cache misses",
+            stats.obj_misses,
+            stats.assoc_misses,
+        ),
+    ] {
+        header(out, name, "counter", help);
+        sample(out, name, &[("kind", "obj")], obj);
+        sample(out, name, &[("kind", "assoc_list")], assoc);
+    }
+    header(out, "tao_graph_cache_bytes", "gauge", "Graph cache size");
+    sample(out, "tao_graph_cache_bytes", &[], stats.bytes);
+    header(
+        out,
+        "tao_graph_cache_capacity_bytes",
+        "gauge",
+        "Graph cache size limit",
+    );
+    sample(
+        out,
+        "tao_graph_cache_capacity_bytes",
+        &[],
+        stats.capacity_bytes,
+    );
+}
+
+fn render_crypto(out: &mut String, crypto: &[(String, CryptoStats)]) {
+    type CacheValue = fn(&CryptoCacheStats) -> f64;
+    let families: [(&str, &str, &str, CacheValue); 4] = [
+        (
+            "tao_crypto_cache_hits_total",
+            "counter",
+            "Encryption cache hits",
+            |c| c.hits as f64,
+        ),
+        (
+            "tao_crypto_cache_misses_total",
+            "counter",
+            "Encryption cache misses",
+            |c| c.misses as f64,
+        ),
+        (
+            "tao_crypto_cache_hit_ratio",
+            "gauge",
+            "Share of lookups that hit, 0 before the first one",
+            |c| match c.hits + c.misses {
+                0 => 0.0,
+                n => c.hits as f64 / n as f64,
+            },
+        ),
+        (
+            "tao_crypto_cache_entries",
+            "gauge",
+            "Entries in the encryption cache",
+            |c| c.entries as f64,
+        ),
+    ];
+    for (name, kind, help, value) in families {
+        header(out, name, kind, help);
+        for (keys, stats) in crypto {
+            for cache in &stats.caches {
+                let labels = [("keys", keys.as_str()), ("cache", cache.cache)];
+                sample(out, name, &labels, value(cache));
+            }
+        }
+    }
+
+    header(
+        out,
+        "tao_ope_duration_seconds",
+        "summary",
+        "Time spent in OPE encryption and decryption, cache misses only",
+    );
+    for (keys, stats) in crypto {
+        for (op, count, nanos) in [
+            ("encrypt", stats.ope_encryptions, stats.ope_encrypt_nanos),
+            ("decrypt", stats.ope_decryptions, stats.ope_decrypt_nanos),
+        ] {
+            let labels = [("keys", keys.as_str()), ("op", op)];
+            sample(
+                out,
+                "tao_ope_duration_seconds_sum",
+                &labels,
+                nanos as f64 / 1e9,
+            );
+            sample(out, "tao_ope_duration_seconds_count", &labels, count);
+        }
+    }
+}
+
+fn histograms(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    histograms: &BTreeMap<String, Histogram>,
+) {
+    header(out, name, "histogram", help);
+    let bucket = format!("{}_bucket", name);
+    for (op, histogram) in histograms {
+        for (bound, n) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+            let le = bound.to_string();
+            sample(out, &bucket, &[("op", op), ("le", &le)], n);
+        }
+        let count = histogram.count;
+        sample(out, &bucket, &[("op", op), ("le", "+Inf")], count);
+        let labels = [("op", op.as_str())];
+        sample(out, &format!("{}_sum", name), &labels, histogram.sum);
+        sample(out, &format!("{}_count", name), &labels, count);
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample(
+    out: &mut String,
+    name: &str,
+    labels: &[(&str, &str)],
+    value: impl Display,
+) {
+    let _ = write!(out, "{}", name);
+    if !labels.is_empty() {
+        let labels = labels
+            .iter()
+            .map(|(key, value)| format!("{}=\"{}\"", key, escape(value)))
+            .collect::<Vec<String>>();
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+    let _ = writeln!(out, " {}", value);
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Metrics, Scrape};
+    use crate::query::crypto::{CryptKeys, TaoCrypto};
+    use crate::query::error::TaoError;
+    use crate::query::query::TaoOp;
+    use crate::service::cache::GraphCache;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        let ok: Result<(), TaoError> = Ok(());
+        let denied: Result<(), TaoError> =
+            Err(TaoError::Forbidden("no".to_string()));
+        metrics.observe_query(&TaoOp::ObjGet, &ok, Duration::from_micros(300));
+        metrics.observe_query(&TaoOp::ObjGet, &ok, Duration::from_millis(30));
+        metrics.observe_query(&TaoOp::ObjAdd, &denied, Duration::ZERO);
+        metrics.observe_db(&TaoOp::ObjGet, Duration::from_secs(5));
+
+        let crypto = TaoCrypto::new(CryptKeys::generate(), 16, 16);
+        crypto.encrypt_string("a".to_string());
+        crypto.encrypt_string("a".to_string());
+        let text = metrics.render(&Scrape {
+            pools: &[],
+            graph_cache: &GraphCache::new(0, 0).stats(),
+            crypto: &[("server".to_string(), crypto.stats())],
+        });
+
+        for line in [
+            "# TYPE tao_query_duration_seconds histogram",
+            r#"tao_queries_total{op="ObjGet",outcome="ok"} 2"#,
+            r#"tao_queries_total{op="ObjAdd",outcome="Forbidden"} 1"#,
+            r#"tao_query_duration_seconds_bucket{op="ObjGet",le="0.00025"} 0"#,
+            r#"tao_query_duration_seconds_bucket{op="ObjGet",le="0.0005"} 1"#,
+            r#"tao_query_duration_seconds_bucket{op="ObjGet",le="+Inf"} 2"#,
+            r#"tao_query_duration_seconds_count{op="ObjGet"} 2"#,
+            r#"tao_db_query_duration_seconds_bucket{op="ObjGet",le="2.5"} 0"#,
+            r#"tao_db_query_duration_seconds_count{op="ObjGet"} 1"#,
+            r#"tao_crypto_cache_hits_total{keys="server",cache="aes_enc"} 1"#,
+            r#"tao_crypto_cache_hit_ratio{keys="server",cache="aes_enc"} 0.5"#,
+            r#"tao_crypto_cache_hit_ratio{keys="server",cache="ope_dec"} 0"#,
+            r#"tao_ope_duration_seconds_count{keys="server",op="encrypt"} 0"#,
+        ] {
+            assert!(text.lines().any(|l| l == line), "{} in\n{}", line, text);
+        }
+        assert_eq!(super::escape("a\"b\\"), "a\\\"b\\\\");
+    }
+}
@@ -0,0 +1,155 @@
+/*
+ * File: migrate.rs
+ *      Versioned schema migrations, embedded in the binary
+ *
+ *      Migrations are templates over a namespace's schema and tables, and
+ *      are applied to each namespace separately. Applied versions are
+ *      recorded per namespace in tao_schema_version. New migrations are
+ *      appended to MIGRATIONS with the next version number, existing ones
+ *      are never edited once released.
+ */
+use tokio_postgres::Client;
+use tracing::error;
+
+use crate::service::namespace::NamespaceConfig;
+use crate::service::tao::DBConfig;
+
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+pub const MIGRATIONS: &[Migration] = &[Migration {
+    version: 1,
+    name: "create_graph_tables",
+    sql: include_str!("migrations/0001_create_graph_tables.sql"),
+}];
+
+impl Migration {
+    pub fn render(&self, namespace: &NamespaceConfig) -> String {
+        self.sql
+            .replace("{schema}", &format!("\"{}\"", namespace.schema))
+            .replace("{assoc_table}", &namespace.assoc_table())
+            .replace("{obj_table}", &namespace.obj_table())
+            .replace(
+                "{assoc_range_idx}",
+                &format!("\"{}_range_idx\"", namespace.assoc_table),
+            )
+    }
+}
+
+const SCHEMA_VERSION_TABLE: &str = "\
+    CREATE TABLE IF NOT EXISTS public.tao_schema_version ( \
+        namespace TEXT NOT NULL, \
+        version INTEGER NOT NULL, \
+        name TEXT NOT NULL, \
+        applied_at TIMESTAMPTZ NOT NULL DEFAULT now(), \
+        PRIMARY KEY (namespace, version) \
+    )";
+
+// serializes concurrent `tao-server migrate` runs against one database
+const MIGRATION_LOCK_ID: i64 = 0x0074_616f_5f6d_6967;
+
+pub async fn db_connect(config: &DBConfig) -> Result<Client, String> {
+    let (client, conn) = config
+        .pg_config()?
+        .connect(config.tls_connector()?)
+        .await
+        .map_err(|e| format!("cannot connect to database: {}", e))?;
+    tokio::spawn(async move {
+        if let Err(e) = conn.await {
+            error!(error = %e, "database connection failed");
+        }
+    });
+    Ok(client)
+}
+
+pub fn pending_migrations(applied: &[i32]) -> Vec<&'static Migration> {
+    MIGRATIONS
+        .iter()
+        .filter(|m| !applied.contains(&m.version))
+        .collect()
+}
+
+/*
+ * migrate(client, namespace, dry_run)
+ *      Applies every pending migration of the namespace in a single
+ *      transaction and returns them. With dry_run the transaction is rolled
+ *      back, so nothing, including the version table, is left behind.
+ */
+pub async fn migrate(
+    client: &mut Client,
+    namespace: &NamespaceConfig,
+    dry_run: bool,
+) -> Result<Vec<&'static Migration>, tokio_postgres::Error> {
+    let tx = client.transaction().await?;
+    tx.execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK_ID])
+        .await?;
+    tx.batch_execute(SCHEMA_VERSION_TABLE).await?;
+
+    let applied = tx
+        .query(
+            "SELECT version FROM public.tao_schema_version \
+             WHERE namespace = $1",
+            &[&namespace.name],
+        )
+        .await?
+        .iter()
+        .map(|row| row.get(0))
+        .collect::<Vec<i32>>();
+    let pending = pending_migrations(&applied);
+
+    if dry_run {
+        tx.rollback().await?;
+        return Ok(pending);
+    }
+
+    for migration in &pending {
+        tx.batch_execute(&migration.render(namespace)).await?;
+        tx.execute(
+            "INSERT INTO public.tao_schema_version(namespace, version, name) \
+             VALUES ($1, $2, $3)",
+            &[&namespace.name, &migration.version, &migration.name],
+        )
+        .await?;
+    }
+    tx.commit().await?;
+
+    Ok(pending)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pending_migrations, MIGRATIONS};
+    use crate::service::namespace::NamespaceConfig;
+
+    #[test]
+    fn test_versions_are_sequential() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, i as i32 + 1);
+            assert!(!migration.sql.trim().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_pending_migrations() {
+        assert_eq!(pending_migrations(&[]).len(), MIGRATIONS.len());
+        let applied = MIGRATIONS.iter().map(|m| m.version).collect::<Vec<_>>();
+        assert!(pending_migrations(&applied).is_empty());
+    }
+
+    #[test]
+    fn test_render_namespace() {
+        let mut ns: NamespaceConfig = serde_json::from_str(
+            r#"{ "schema": "acme", "assoc_table": "likes" }"#,
+        )
+        .unwrap();
+        ns.name = "acme".to_string();
+        let sql = MIGRATIONS[0].render(&ns);
+        assert!(sql.contains("CREATE SCHEMA IF NOT EXISTS \"acme\";"));
+        assert!(sql.contains("CREATE TABLE IF NOT EXISTS \"acme\".\"likes\""));
+        assert!(sql.contains("\"likes_range_idx\""));
+        assert!(!sql.contains('{'));
+    }
+}
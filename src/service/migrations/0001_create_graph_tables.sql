@@ -0,0 +1,21 @@
+CREATE SCHEMA IF NOT EXISTS {schema};
+
+CREATE TABLE IF NOT EXISTS {obj_table} (
+    id TEXT NOT NULL,
+    otype TEXT NOT NULL,
+    data TEXT NOT NULL,
+    PRIMARY KEY (id)
+);
+
+CREATE TABLE IF NOT EXISTS {assoc_table} (
+    id1 TEXT NOT NULL,
+    atype TEXT NOT NULL,
+    id2 TEXT NOT NULL,
+    t BIGINT NOT NULL,
+    data TEXT NOT NULL,
+    PRIMARY KEY (id1, atype, id2)
+);
+
+-- ASSOC RANGE scans the newest associations of (id1, atype) first
+CREATE INDEX IF NOT EXISTS {assoc_range_idx}
+    ON {assoc_table} (id1, atype, t DESC);
@@ -1 +1,25 @@
+pub mod audit;
+pub mod auth;
+pub mod backup;
+pub mod cache;
+pub mod client;
+pub mod config;
+pub mod deadline;
+pub mod feed;
+pub mod grpc;
+pub mod import;
+pub mod limits;
+pub mod metrics;
+pub mod migrate;
+pub mod namespace;
+pub mod policy;
+pub mod pool;
+pub mod shard;
+pub mod shutdown;
+pub mod store;
 pub mod tao;
+#[cfg(test)]
+pub mod testing;
+pub mod telemetry;
+pub mod tier;
+pub mod tls;
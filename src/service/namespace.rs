@@ -0,0 +1,185 @@
+/*
+ * File: namespace.rs
+ *      Namespaces let several graphs (staging and production, or one per
+ *      tenant) live in one database. Each namespace names a Postgres schema
+ *      and a pair of tables, and may bring its own encryption keys so that
+ *      ciphertexts from one namespace never match another's.
+ *
+ *      A request picks its namespace with the X-Tao-Namespace header or a
+ *      leading `USE name;`, and falls back to the default namespace.
+ */
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use crate::query::crypto::{CryptKeys, KeyIds, TaoCrypto};
+
+pub const DEFAULT_NAMESPACE: &str = "default";
+pub const NAMESPACE_HEADER: &str = "X-Tao-Namespace";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NamespaceConfig {
+    #[serde(skip)]
+    pub name: String,
+    #[serde(default = "default_schema")]
+    pub schema: String,
+    #[serde(default = "default_assoc_table")]
+    pub assoc_table: String,
+    #[serde(default = "default_obj_table")]
+    pub obj_table: String,
+    pub ope_key_file: Option<PathBuf>,
+    pub aes_keyset_file: Option<PathBuf>,
+}
+
+fn default_schema() -> String {
+    "public".to_string()
+}
+
+fn default_assoc_table() -> String {
+    "assoc_test".to_string()
+}
+
+fn default_obj_table() -> String {
+    "obj_test".to_string()
+}
+
+impl NamespaceConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        for ident in [&self.schema, &self.assoc_table, &self.obj_table] {
+            if !is_identifier(ident) {
+                return Err(format!(
+                    "namespace {}: {:?} is not a valid SQL identifier",
+                    self.name, ident
+                ));
+            }
+        }
+        match (&self.ope_key_file, &self.aes_keyset_file) {
+            (Some(_), None) | (None, Some(_)) => Err(format!(
+                "namespace {}: set both ope_key_file and aes_keyset_file, \
+                 or neither",
+                self.name
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn assoc_table(&self) -> String {
+        format!("\"{}\".\"{}\"", self.schema, self.assoc_table)
+    }
+
+    pub fn obj_table(&self) -> String {
+        format!("\"{}\".\"{}\"", self.schema, self.obj_table)
+    }
+}
+
+/*
+ * load_tenants(path)
+ *      Tenants are read from a JSON object keyed by namespace name, e.g.
+ *          { "staging": { "schema": "staging" },
+ *            "acme": { "assoc_table": "acme_assoc", "obj_table": "acme_obj",
+ *                      "ope_key_file": "acme/ope.key",
+ *                      "aes_keyset_file": "acme/aes_keyset.json" } }
+ */
+pub fn load_tenants(path: &Path) -> Result<Vec<NamespaceConfig>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| {
+        format!("cannot read tenants file {}: {}", path.display(), e)
+    })?;
+    let tenants: HashMap<String, NamespaceConfig> =
+        serde_json::from_str(&contents).map_err(|e| {
+            format!("invalid tenants file {}: {}", path.display(), e)
+        })?;
+
+    let mut tenants = tenants
+        .into_iter()
+        .map(|(name, mut tenant)| {
+            tenant.name = name;
+            tenant
+        })
+        .collect::<Vec<NamespaceConfig>>();
+    tenants.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(tenants)
+}
+
+fn is_identifier(ident: &str) -> bool {
+    let mut chars = ident.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => (),
+        _ => return false,
+    }
+    ident.len() <= 63 && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+pub struct Namespace {
+    pub config: NamespaceConfig,
+    pub tao_crypto: Arc<TaoCrypto>,
+    /// The keys' ids, None for keys generated at startup
+    pub key_ids: Option<KeyIds>,
+}
+
+impl Namespace {
+    /*
+     * new(config, shared_crypto, shared_key_ids)
+     *      Namespaces without keys of their own share the server's keys.
+     */
+    pub fn new(
+        config: NamespaceConfig,
+        shared_crypto: &Arc<TaoCrypto>,
+        shared_key_ids: Option<&KeyIds>,
+        aes_cache_size: usize,
+        ope_cache_size: usize,
+    ) -> Result<Self, String> {
+        let key_files = (&config.ope_key_file, &config.aes_keyset_file);
+        let (tao_crypto, key_ids) = match key_files {
+            (Some(ope_key_file), Some(aes_keyset_file)) => {
+                let keys = CryptKeys::load(ope_key_file, aes_keyset_file)
+                    .map_err(|e| format!("namespace {}: {}", config.name, e))?;
+                let ids = keys.ids();
+                let tao_crypto =
+                    TaoCrypto::new(keys, aes_cache_size, ope_cache_size);
+                (Arc::new(tao_crypto), Some(ids))
+            }
+            _ => (shared_crypto.clone(), shared_key_ids.cloned()),
+        };
+        Ok(Namespace {
+            config,
+            tao_crypto,
+            key_ids,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_identifier, NamespaceConfig};
+
+    #[test]
+    fn test_identifiers() {
+        assert!(is_identifier("assoc_test"));
+        assert!(is_identifier("_tenant2"));
+        assert!(!is_identifier("2tenant"));
+        assert!(!is_identifier("assoc\"; DROP TABLE obj_test; --"));
+        assert!(!is_identifier(""));
+    }
+
+    #[test]
+    fn test_defaults_and_quoting() {
+        let mut ns: NamespaceConfig =
+            serde_json::from_str(r#"{ "schema": "staging" }"#).unwrap();
+        ns.name = "staging".to_string();
+        assert!(ns.validate().is_ok());
+        assert_eq!(ns.assoc_table(), "\"staging\".\"assoc_test\"");
+        assert_eq!(ns.obj_table(), "\"staging\".\"obj_test\"");
+    }
+
+    #[test]
+    fn test_keys_come_in_pairs() {
+        let mut ns: NamespaceConfig =
+            serde_json::from_str(r#"{ "ope_key_file": "ope.key" }"#).unwrap();
+        ns.name = "acme".to_string();
+        assert!(ns.validate().is_err());
+    }
+}
@@ -0,0 +1,256 @@
+/*
+ * File: policy.rs
+ *      Per-principal authorization. A policy file lists, for every
+ *      principal, the operations it may run and the object and association
+ *      types it may touch:
+ *          { "analytics": { "ops": ["ASSOC COUNT", "ASSOC RANGE"],
+ *                           "types": ["LIKES"] },
+ *            "etl": { "ops": ["OBJ ADD", "ASSOC ADD"] } }
+ *      Leaving out `ops` or `types` allows all of them. Principals missing
+ *      from the file may do nothing. Without a policy file every
+ *      authenticated caller may do everything.
+ *
+ *      Queries are checked on plaintext, after parsing and before
+ *      encryption. OBJ GET names no type, so its type is checked on the
+ *      decrypted object instead.
+ */
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::query::error::TaoError;
+use crate::query::parser;
+use crate::query::query::{Query, TaoArgs};
+use crate::query::results::DBRow;
+use crate::service::auth::Principal;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    ops: Option<Vec<String>>,
+    types: Option<Vec<String>>,
+}
+
+/*
+ * Rule
+ *      Ops are kept by their Debug name, TaoOp is neither Hash nor Eq.
+ */
+#[derive(Debug)]
+struct Rule {
+    ops: Option<HashSet<String>>,
+    types: Option<HashSet<String>>,
+}
+
+pub struct Policy {
+    enabled: bool,
+    rules: HashMap<String, Rule>,
+}
+
+impl Policy {
+    pub fn allow_all() -> Self {
+        Policy {
+            enabled: false,
+            rules: HashMap::new(),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path).map_err(|e| {
+            format!("cannot read policy file {}: {}", path.display(), e)
+        })?;
+        Policy::parse(&contents).map_err(|e| {
+            format!("invalid policy file {}: {}", path.display(), e)
+        })
+    }
+
+    fn parse(contents: &str) -> Result<Self, String> {
+        let config: HashMap<String, RuleConfig> =
+            serde_json::from_str(contents).map_err(|e| e.to_string())?;
+
+        let mut rules = HashMap::new();
+        for (principal, rule) in config {
+            let ops = match rule.ops {
+                Some(ops) => Some(
+                    ops.iter()
+                        .map(|op| {
+                            parser::parse_op(op)
+                                .map(|op| format!("{:?}", op))
+                                .map_err(|e| format!("{}: {}", principal, e))
+                        })
+                        .collect::<Result<HashSet<String>, String>>()?,
+                ),
+                None => None,
+            };
+            let types = rule
+                .types
+                .map(|types| types.into_iter().collect::<HashSet<String>>());
+            rules.insert(principal, Rule { ops, types });
+        }
+        Ok(Policy {
+            enabled: true,
+            rules,
+        })
+    }
+
+    fn rule(&self, principal: &Principal) -> Result<Option<&Rule>, TaoError> {
+        if !self.enabled {
+            return Ok(None);
+        }
+        match self.rules.get(&principal.name) {
+            Some(rule) => Ok(Some(rule)),
+            None => Err(TaoError::Forbidden(format!(
+                "{} has no policy",
+                principal.name
+            ))),
+        }
+    }
+
+    /*
+     * check(principal, query)
+     *      Runs on the parsed plaintext query, before it is encrypted.
+     */
+    pub fn check(
+        &self,
+        principal: &Principal,
+        query: &Query,
+    ) -> Result<(), TaoError> {
+        let rule = match self.rule(principal)? {
+            Some(rule) => rule,
+            None => return Ok(()),
+        };
+        if let Some(ops) = &rule.ops {
+            let op = format!("{:?}", query.op);
+            if !ops.contains(&op) {
+                return Err(TaoError::Forbidden(format!(
+                    "{} may not run {}",
+                    principal.name, op
+                )));
+            }
+        }
+        match query_type(&query.args) {
+            Some(ty) => check_type(principal, rule, ty),
+            None => Ok(()),
+        }
+    }
+
+    /*
+     * check_rows(principal, rows)
+     *      Checks the types of decrypted objects, for queries that could not
+     *      name a type up front.
+     */
+    pub fn check_rows(
+        &self,
+        principal: &Principal,
+        rows: &[DBRow],
+    ) -> Result<(), TaoError> {
+        let rule = match self.rule(principal)? {
+            Some(rule) => rule,
+            None => return Ok(()),
+        };
+        for row in rows {
+            if let DBRow::ObjRow { otype, .. } = row {
+                check_type(principal, rule, otype)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn check_type(
+    principal: &Principal,
+    rule: &Rule,
+    ty: &str,
+) -> Result<(), TaoError> {
+    match &rule.types {
+        Some(types) if !types.contains(ty) => Err(TaoError::Forbidden(
+            format!("{} may not access {}", principal.name, ty),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn query_type(args: &TaoArgs) -> Option<&str> {
+    match args {
+        TaoArgs::AssocAddArgs { atype, .. }
+        | TaoArgs::AssocGetArgs { atype, .. }
+        | TaoArgs::AssocRangeGetArgs { atype, .. }
+        | TaoArgs::AssocCountArgs { atype, .. }
+        | TaoArgs::AssocRangeArgs { atype, .. }
+        | TaoArgs::AssocDeleteArgs { atype, .. } => Some(atype),
+        TaoArgs::ObjAddArgs { otype, .. }
+        | TaoArgs::ObjUpdateArgs { otype, .. } => Some(otype),
+        TaoArgs::ObjGetArgs { .. } | TaoArgs::ObjDeleteArgs { .. } => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Policy;
+    use crate::query::error::TaoError;
+    use crate::query::parser::parse;
+    use crate::query::results::DBRow;
+    use crate::service::auth::Principal;
+
+    fn principal(name: &str) -> Principal {
+        Principal {
+            name: name.to_string(),
+        }
+    }
+
+    fn policy() -> Policy {
+        Policy::parse(
+            r#"{ "analytics": { "ops": ["ASSOC COUNT", "ASSOC RANGE"],
+                                "types": ["LIKES"] },
+                 "reader": { "types": ["USER"] } }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_ops_and_types() {
+        let policy = policy();
+        let analytics = principal("analytics");
+        let queries = parse(
+            r#"ASSOC COUNT 1 LIKES; ASSOC COUNT 1 FRIEND; OBJ ADD 1 USER "a";"#,
+        );
+        assert!(policy.check(&analytics, &queries[0]).is_ok());
+        assert!(matches!(
+            policy.check(&analytics, &queries[1]),
+            Err(TaoError::Forbidden(_))
+        ));
+        assert!(matches!(
+            policy.check(&analytics, &queries[2]),
+            Err(TaoError::Forbidden(_))
+        ));
+    }
+
+    #[test]
+    fn test_unknown_principal_is_denied() {
+        let queries = parse("OBJ GET 1;");
+        assert!(policy().check(&principal("stranger"), &queries[0]).is_err());
+        assert!(Policy::allow_all()
+            .check(&principal("stranger"), &queries[0])
+            .is_ok());
+    }
+
+    #[test]
+    fn test_obj_get_checks_rows() {
+        let policy = policy();
+        let reader = principal("reader");
+        let row = |otype: &str| DBRow::ObjRow {
+            id: "1".to_string(),
+            otype: otype.to_string(),
+            data: "x".to_string(),
+        };
+        assert!(policy.check(&reader, &parse("OBJ GET 1;")[0]).is_ok());
+        assert!(policy.check_rows(&reader, &[row("USER")]).is_ok());
+        assert!(policy.check_rows(&reader, &[row("POST")]).is_err());
+    }
+
+    #[test]
+    fn test_unknown_op() {
+        assert!(Policy::parse(r#"{ "a": { "ops": ["OBJ DROP"] } }"#).is_err());
+    }
+}
@@ -0,0 +1,76 @@
+/*
+ * File: pool.rs
+ *      Bounded Postgres connection pool shared by the TAO handlers
+ */
+use std::time::Duration;
+
+use bb8::{Pool, PooledConnection};
+use bb8_postgres::PostgresConnectionManager;
+use postgres_openssl::MakeTlsConnector;
+use serde::{Deserialize, Serialize};
+
+use crate::service::tao::DBConfig;
+
+pub type DBPool = Pool<PostgresConnectionManager<MakeTlsConnector>>;
+pub type DBConn =
+    PooledConnection<'static, PostgresConnectionManager<MakeTlsConnector>>;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PoolMetrics {
+    pub host: String,
+    pub connections: u32,
+    pub idle_connections: u32,
+    pub min_size: u32,
+    pub max_size: u32,
+    pub get_direct: u64,
+    pub get_waited: u64,
+    pub get_timed_out: u64,
+    pub get_wait_time_ms: u128,
+    pub connections_created: u64,
+    pub connections_closed_broken: u64,
+    pub connections_closed_invalid: u64,
+    pub connections_closed_idle_timeout: u64,
+}
+
+/*
+ * build_pool
+ *      Connections are established lazily in the background, so a
+ *      database that is down at startup does not keep the server from
+ *      booting. Every checkout runs an empty query first so that a
+ *      connection dropped by Postgres is replaced instead of handed out.
+ */
+pub fn build_pool(config: &DBConfig) -> Result<DBPool, String> {
+    let manager = PostgresConnectionManager::new(
+        config.pg_config()?,
+        config.tls_connector()?,
+    );
+
+    let pool = Pool::builder()
+        .min_idle(Some(config.pool_min_size))
+        .max_size(config.pool_max_size)
+        .idle_timeout(Some(Duration::from_secs(config.pool_idle_timeout)))
+        .connection_timeout(Duration::from_secs(config.pool_checkout_timeout))
+        .test_on_check_out(true)
+        .build_unchecked(manager);
+    Ok(pool)
+}
+
+pub fn pool_metrics(pool: &DBPool, config: &DBConfig) -> PoolMetrics {
+    let state = pool.state();
+    let stats = state.statistics;
+    PoolMetrics {
+        host: format!("{}:{}", config.host, config.port),
+        connections: state.connections,
+        idle_connections: state.idle_connections,
+        min_size: config.pool_min_size,
+        max_size: config.pool_max_size,
+        get_direct: stats.get_direct,
+        get_waited: stats.get_waited,
+        get_timed_out: stats.get_timed_out,
+        get_wait_time_ms: stats.get_wait_time.as_millis(),
+        connections_created: stats.connections_created,
+        connections_closed_broken: stats.connections_closed_broken,
+        connections_closed_invalid: stats.connections_closed_invalid,
+        connections_closed_idle_timeout: stats.connections_closed_idle_timeout,
+    }
+}
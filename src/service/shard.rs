@@ -0,0 +1,311 @@
+/*
+ * File: shard.rs
+ *      Spreads the graph over several Postgres databases. As in TAO an
+ *      object lives on the shard its id hashes to, and an association on
+ *      the shard of its id1, so all of an object's outgoing edges share a
+ *      database with it.
+ *
+ *      Routing hashes the id as stored, i.e. the AES-SIV ciphertext when
+ *      encryption is on. The ciphertext of an id never changes, so neither
+ *      does its shard. Changing the number of shards moves most ids, so
+ *      the shard map is fixed once data is written.
+ */
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use postgres_openssl::MakeTlsConnector;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::query::results::DBRow;
+use crate::service::pool::{build_pool, pool_metrics, DBPool, PoolMetrics};
+use crate::service::tao::DBConfig;
+
+/*
+ * Route
+ *      Where a query may run. Writes and reads that must see recent writes
+ *      go to the primary, other reads to a replica when there is one.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    Primary,
+    Replica,
+}
+
+/*
+ * Shard
+ *      One database of the graph, with a pool for the primary and one for
+ *      each of its replicas. `tls` connects to any of them, to cancel
+ *      queries.
+ */
+pub struct Shard {
+    pub config: DBConfig,
+    pub pool: DBPool,
+    pub tls: MakeTlsConnector,
+    replicas: Vec<(DBConfig, DBPool)>,
+    next_replica: AtomicUsize,
+}
+
+impl Shard {
+    pub fn new(config: DBConfig) -> Result<Self, String> {
+        let pool = build_pool(&config)?;
+        let replicas = config
+            .replicas
+            .iter()
+            .map(|addr| {
+                let replica = config.replica(addr)?;
+                let pool = build_pool(&replica)?;
+                Ok((replica, pool))
+            })
+            .collect::<Result<Vec<(DBConfig, DBPool)>, String>>()?;
+        let tls = config.tls_connector()?;
+        Ok(Shard {
+            config,
+            pool,
+            tls,
+            replicas,
+            next_replica: AtomicUsize::new(0),
+        })
+    }
+
+    /*
+     * pool(route)
+     *      Replicas take turns, a shard without replicas reads from its
+     *      primary.
+     */
+    pub fn pool(&self, route: Route) -> &DBPool {
+        if route == Route::Primary || self.replicas.is_empty() {
+            return &self.pool;
+        }
+        let next = self.next_replica.fetch_add(1, Ordering::Relaxed);
+        &self.replicas[next % self.replicas.len()].1
+    }
+
+    pub fn metrics(&self) -> Vec<PoolMetrics> {
+        let mut metrics = vec![pool_metrics(&self.pool, &self.config)];
+        for (config, pool) in &self.replicas {
+            metrics.push(pool_metrics(pool, config));
+        }
+        metrics
+    }
+}
+
+/*
+ * ShardConfig
+ *      One entry of the shards file, a JSON list such as
+ *          [ { "host": "db0", "replicas": ["db0-replica"] },
+ *            { "host": "db1", "db_name": "tao1",
+ *              "password_env": "SHARD1_PASSWORD" } ]
+ *      Settings left out are taken from the DATABASE_* settings.
+ */
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ShardConfig {
+    host: Option<String>,
+    port: Option<String>,
+    db_name: Option<String>,
+    user: Option<String>,
+    password_env: Option<String>,
+    replicas: Option<Vec<String>>,
+}
+
+pub fn load_shards(
+    path: &Path,
+    defaults: &DBConfig,
+) -> Result<Vec<DBConfig>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| {
+        format!("cannot read shards file {}: {}", path.display(), e)
+    })?;
+    let shards: Vec<ShardConfig> =
+        serde_json::from_str(&contents).map_err(|e| {
+            format!("invalid shards file {}: {}", path.display(), e)
+        })?;
+    if shards.is_empty() {
+        return Err(format!("shards file {} lists no shards", path.display()));
+    }
+
+    shards
+        .into_iter()
+        .enumerate()
+        .map(|(i, shard)| {
+            let mut config = defaults.clone();
+            if let Some(host) = shard.host {
+                config.host = host;
+            }
+            if let Some(port) = shard.port {
+                config.port = port;
+            }
+            if let Some(db_name) = shard.db_name {
+                config.db_name = db_name;
+            }
+            if let Some(user) = shard.user {
+                config.user = user;
+            }
+            if let Some(replicas) = shard.replicas {
+                config.replicas = replicas;
+            }
+            for replica in &config.replicas {
+                config
+                    .replica(replica)
+                    .map_err(|e| format!("shard {}: {}", i, e))?;
+            }
+            if let Some(env) = shard.password_env {
+                config.password = dotenv::var(&env)
+                    .map_err(|_| format!("shard {}: {} is not set", i, env))?;
+            }
+            if config.port.parse::<u16>().is_err() {
+                return Err(format!(
+                    "shard {}: {} is not a valid port",
+                    i, config.port
+                ));
+            }
+            Ok(config)
+        })
+        .collect::<Result<Vec<DBConfig>, String>>()
+}
+
+/*
+ * shard_for(key, shards)
+ *      The first 8 bytes of SHA-256 rather than the std hasher, which is
+ *      not guaranteed to stay the same across Rust releases.
+ */
+pub fn shard_for(key: &str, shards: usize) -> usize {
+    if shards <= 1 {
+        return 0;
+    }
+    let digest = Sha256::digest(key.as_bytes());
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    (u64::from_be_bytes(bytes) % shards as u64) as usize
+}
+
+/*
+ * shard_key(row)
+ *      The id a row is stored by: an object's id, an association's id1,
+ *      so that a list lives on the shard of the object it hangs off. None
+ *      for results.
+ */
+pub fn shard_key(row: &DBRow) -> Option<&str> {
+    match row {
+        DBRow::ObjRow { id, .. } => Some(id),
+        DBRow::AssocRow { id1, .. } => Some(id1),
+        DBRow::Count(_) | DBRow::NoRes(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{load_shards, shard_for, shard_key, Route, Shard};
+    use crate::query::results::DBRow;
+    use crate::service::tao::DBConfig;
+    use crate::service::testing::TempDir;
+    use crate::service::tls::DBSslMode;
+
+    fn defaults() -> DBConfig {
+        DBConfig {
+            host: "localhost".to_string(),
+            user: "tao".to_string(),
+            password: "secret".to_string(),
+            db_name: "tao".to_string(),
+            port: "5432".to_string(),
+            pool_min_size: 1,
+            pool_max_size: 16,
+            pool_idle_timeout: 600,
+            pool_checkout_timeout: 30,
+            ssl_mode: DBSslMode::Disable,
+            ssl_root_cert: None,
+            replicas: vec![],
+        }
+    }
+
+    #[test]
+    fn test_shard_for() {
+        assert_eq!(shard_for("42", 1), 0);
+        assert_eq!(shard_for("42", 4), shard_for("42", 4));
+
+        let mut counts = [0; 4];
+        for id in 0..4000 {
+            counts[shard_for(&id.to_string(), 4)] += 1;
+        }
+        assert!(counts.iter().all(|&n| n > 800), "{:?}", counts);
+    }
+
+    #[test]
+    fn test_replica_address() {
+        let primary = defaults();
+        let replica = primary.replica("replica-1").unwrap();
+        assert_eq!(
+            (replica.host.as_str(), replica.port.as_str()),
+            ("replica-1", "5432")
+        );
+        let replica = primary.replica("10.0.0.2:5433").unwrap();
+        assert_eq!(
+            (replica.host.as_str(), replica.port.as_str()),
+            ("10.0.0.2", "5433")
+        );
+        assert!(primary.replica(":5433").is_err());
+        assert!(primary.replica("replica-1:pg").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reads_round_robin_over_replicas() {
+        let mut config = defaults();
+        config.replicas = vec!["r0".to_string(), "r1:5433".to_string()];
+        let shard = Shard::new(config).unwrap();
+        let hosts = (0..4)
+            .map(|_| shard.pool(Route::Replica) as *const _)
+            .collect::<Vec<_>>();
+        assert_ne!(hosts[0], hosts[1]);
+        assert_eq!((hosts[0], hosts[1]), (hosts[2], hosts[3]));
+        assert!(!hosts.contains(&(shard.pool(Route::Primary) as *const _)));
+
+        let shard = Shard::new(defaults()).unwrap();
+        assert!(std::ptr::eq(
+            shard.pool(Route::Replica),
+            shard.pool(Route::Primary)
+        ));
+        assert_eq!(shard.metrics().len(), 1);
+    }
+
+    #[test]
+    fn test_assocs_follow_id1() {
+        let assoc = DBRow::AssocRow {
+            id1: "7".to_string(),
+            atype: "LIKES".to_string(),
+            id2: "9".to_string(),
+            t: 1,
+            data: "x".to_string(),
+        };
+        let obj = DBRow::ObjRow {
+            id: "7".to_string(),
+            otype: "USER".to_string(),
+            data: "x".to_string(),
+        };
+        assert_eq!(shard_key(&assoc), Some("7"));
+        assert_eq!(shard_key(&obj), Some("7"));
+        assert_eq!(shard_key(&DBRow::Count(1)), None);
+    }
+
+    #[test]
+    fn test_load_shards() {
+        let dir = TempDir::new("shards");
+        let path = dir.join("shards.json");
+        fs::write(&path, r#"[ {}, { "host": "db1", "port": "5433" } ]"#)
+            .unwrap();
+
+        let shards = load_shards(&path, &defaults()).unwrap();
+        assert_eq!(shards.len(), 2);
+        assert_eq!(shards[0].host, "localhost");
+        assert_eq!(
+            (shards[1].host.as_str(), shards[1].port.as_str()),
+            ("db1", "5433")
+        );
+        assert_eq!(shards[1].db_name, "tao");
+
+        fs::write(&path, "[]").unwrap();
+        assert!(load_shards(&path, &defaults()).is_err());
+    }
+}
@@ -0,0 +1,164 @@
+/*
+ * File: shutdown.rs
+ *      Draining on SIGTERM or SIGINT. The server turns unready first, so
+ *      GET /ready answers 503 while load balancers take it out, then closes
+ *      its listeners and gives the requests in flight SHUTDOWN_GRACE_SECS
+ *      to finish. A batch of writes runs without a transaction, so cutting
+ *      a pipeline short would leave it half applied. Only then are the
+ *      audit log and span exporters flushed and the database connections
+ *      closed. A second signal skips the wait.
+ */
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde::Serialize;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, Notify};
+use tokio::time::Instant;
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub draining: bool,
+    /// Pipelines still running
+    pub in_flight: usize,
+}
+
+pub struct Shutdown {
+    draining: watch::Sender<bool>,
+    in_flight: AtomicUsize,
+    idle: Notify,
+}
+
+/*
+ * InFlight
+ *      Counts a pipeline as running until dropped, also when its request
+ *      is cancelled.
+ */
+pub struct InFlight<'a> {
+    shutdown: &'a Shutdown,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if self.shutdown.in_flight.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shutdown.idle.notify_waiters();
+        }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Shutdown {
+            draining: watch::Sender::new(false),
+            in_flight: AtomicUsize::new(0),
+            idle: Notify::new(),
+        }
+    }
+
+    pub fn begin(&self) {
+        self.draining.send_replace(true);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        *self.draining.borrow()
+    }
+
+    /*
+     * draining()
+     *      Resolves once the server starts draining, for the streams that
+     *      would otherwise hold it up until the grace period ends.
+     */
+    pub fn draining(&self) -> impl Future<Output = ()> + 'static {
+        let mut draining = self.draining.subscribe();
+        async move {
+            let _ = draining.wait_for(|draining| *draining).await;
+        }
+    }
+
+    pub fn enter(&self) -> InFlight<'_> {
+        self.in_flight.fetch_add(1, Ordering::AcqRel);
+        InFlight { shutdown: self }
+    }
+
+    pub fn readiness(&self) -> Readiness {
+        let draining = self.is_draining();
+        Readiness {
+            ready: !draining,
+            draining,
+            in_flight: self.in_flight.load(Ordering::Acquire),
+        }
+    }
+
+    /*
+     * drained(deadline)
+     *      Waits for the running pipelines until `deadline`. Returns how
+     *      many were still running, 0 once all finished.
+     */
+    pub async fn drained(&self, deadline: Instant) -> usize {
+        loop {
+            // Registered before the check, so no wake-up is missed
+            let idle = self.idle.notified();
+            let running = self.in_flight.load(Ordering::Acquire);
+            if running == 0 {
+                return 0;
+            }
+            if tokio::time::timeout_at(deadline, idle).await.is_err() {
+                return self.in_flight.load(Ordering::Acquire);
+            }
+        }
+    }
+}
+
+/*
+ * terminated()
+ *      Waits for SIGTERM or SIGINT and returns its name.
+ */
+pub async fn terminated() -> Result<&'static str, String> {
+    let mut term =
+        signal(SignalKind::terminate()).map_err(|e| e.to_string())?;
+    let mut int = signal(SignalKind::interrupt()).map_err(|e| e.to_string())?;
+    tokio::select! {
+        _ = term.recv() => Ok("SIGTERM"),
+        _ = int.recv() => Ok("SIGINT"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::Shutdown;
+
+    #[actix_web::test]
+    async fn test_drained() {
+        let shutdown = Shutdown::new();
+        assert!(shutdown.readiness().ready);
+        let draining = shutdown.draining();
+        let first = shutdown.enter();
+        let second = shutdown.enter();
+        shutdown.begin();
+        draining.await;
+        let readiness = shutdown.readiness();
+        assert!(!readiness.ready && readiness.draining);
+        assert_eq!(readiness.in_flight, 2);
+
+        let soon = Instant::now() + Duration::from_millis(20);
+        drop(first);
+        assert_eq!(shutdown.drained(soon).await, 1);
+        let later = Instant::now() + Duration::from_secs(5);
+        let (left, _) = futures::join!(shutdown.drained(later), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(second);
+        });
+        assert_eq!(left, 0);
+    }
+}
@@ -0,0 +1,441 @@
+/*
+ * File: store/memory.rs
+ *      GraphStore in process memory, for tests and for trying the server
+ *      without a database. Nothing survives a restart.
+ *
+ *      Tables are keyed by their qualified name, as in Postgres, so
+ *      namespaces sharing tables share data. Each association list keeps
+ *      its edges by id2 and an index by (t, id2) for newest-first scans.
+ */
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound::{Excluded, Included, Unbounded};
+use std::sync::RwLock;
+
+use async_trait::async_trait;
+use tokio::sync::mpsc::Sender;
+
+use crate::query::error::TaoError;
+use crate::query::results::DBRow;
+use crate::service::namespace::NamespaceConfig;
+use crate::service::shard::Route;
+use crate::service::store::GraphStore;
+
+#[derive(Default)]
+struct AssocList {
+    /// id2 -> (t, data)
+    edges: BTreeMap<String, (i64, String)>,
+    by_time: BTreeSet<(i64, String)>,
+}
+
+/// (table, id) -> (otype, data)
+type Objects = BTreeMap<(String, String), (String, String)>;
+/// (table, id1, atype) -> list
+type Assocs = BTreeMap<(String, String, String), AssocList>;
+
+#[derive(Default)]
+pub struct MemoryStore {
+    objs: RwLock<Objects>,
+    assocs: RwLock<Assocs>,
+}
+
+fn poisoned<T>(_: T) -> TaoError {
+    TaoError::Database("memory store lock poisoned".to_string())
+}
+
+fn assoc_row(id1: &str, atype: &str, id2: &str, t: i64, data: &str) -> DBRow {
+    DBRow::AssocRow {
+        id1: id1.to_string(),
+        atype: atype.to_string(),
+        id2: id2.to_string(),
+        t,
+        data: data.to_string(),
+    }
+}
+
+fn list_key(
+    ns: &NamespaceConfig,
+    id1: &str,
+    atype: &str,
+) -> (String, String, String) {
+    (ns.assoc_table(), id1.to_string(), atype.to_string())
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+}
+
+#[async_trait]
+impl GraphStore for MemoryStore {
+    async fn obj_add(
+        &self,
+        ns: &NamespaceConfig,
+        id: &str,
+        otype: &str,
+        data: &str,
+    ) -> Result<(), TaoError> {
+        let mut objs = self.objs.write().map_err(poisoned)?;
+        let key = (ns.obj_table(), id.to_string());
+        if objs.contains_key(&key) {
+            return Err(TaoError::Database(format!(
+                "duplicate key: object {} exists",
+                id
+            )));
+        }
+        objs.insert(key, (otype.to_string(), data.to_string()));
+        Ok(())
+    }
+
+    async fn obj_get(
+        &self,
+        ns: &NamespaceConfig,
+        id: &str,
+        _: Route,
+    ) -> Result<Option<DBRow>, TaoError> {
+        let objs = self.objs.read().map_err(poisoned)?;
+        let key = (ns.obj_table(), id.to_string());
+        Ok(objs.get(&key).map(|(otype, data)| DBRow::ObjRow {
+            id: id.to_string(),
+            otype: otype.clone(),
+            data: data.clone(),
+        }))
+    }
+
+    async fn obj_update(
+        &self,
+        ns: &NamespaceConfig,
+        id: &str,
+        otype: &str,
+        data: &str,
+    ) -> Result<bool, TaoError> {
+        let mut objs = self.objs.write().map_err(poisoned)?;
+        match objs.get_mut(&(ns.obj_table(), id.to_string())) {
+            Some(obj) => {
+                *obj = (otype.to_string(), data.to_string());
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn obj_delete(
+        &self,
+        ns: &NamespaceConfig,
+        id: &str,
+    ) -> Result<Option<DBRow>, TaoError> {
+        let mut objs = self.objs.write().map_err(poisoned)?;
+        let obj = objs.remove(&(ns.obj_table(), id.to_string()));
+        Ok(obj.map(|(otype, data)| DBRow::ObjRow {
+            id: id.to_string(),
+            otype,
+            data,
+        }))
+    }
+
+    async fn assoc_add(
+        &self,
+        ns: &NamespaceConfig,
+        id1: &str,
+        atype: &str,
+        id2: &str,
+        t: i64,
+        data: &str,
+    ) -> Result<(), TaoError> {
+        let mut assocs = self.assocs.write().map_err(poisoned)?;
+        let list = assocs.entry(list_key(ns, id1, atype)).or_default();
+        if list.edges.contains_key(id2) {
+            return Err(TaoError::Database(format!(
+                "duplicate key: association {} {} {} exists",
+                id1, atype, id2
+            )));
+        }
+        list.edges.insert(id2.to_string(), (t, data.to_string()));
+        list.by_time.insert((t, id2.to_string()));
+        Ok(())
+    }
+
+    async fn assoc_get(
+        &self,
+        ns: &NamespaceConfig,
+        id1: &str,
+        atype: &str,
+        idset: &[String],
+        range: Option<(i64, i64)>,
+        _: Route,
+    ) -> Result<Vec<DBRow>, TaoError> {
+        let assocs = self.assocs.read().map_err(poisoned)?;
+        let list = match assocs.get(&list_key(ns, id1, atype)) {
+            Some(list) => list,
+            None => return Ok(Vec::new()),
+        };
+        let in_range = |t: i64| match range {
+            Some((tstart, tend)) => tstart <= t && t <= tend,
+            None => true,
+        };
+        let ids = idset.iter().collect::<BTreeSet<&String>>();
+        Ok(ids
+            .into_iter()
+            .filter_map(|id2| {
+                let (t, data) = list.edges.get(id2)?;
+                match in_range(*t) {
+                    true => Some(assoc_row(id1, atype, id2, *t, data)),
+                    false => None,
+                }
+            })
+            .collect())
+    }
+
+    async fn assoc_range(
+        &self,
+        ns: &NamespaceConfig,
+        id1: &str,
+        atype: &str,
+        tstart: i64,
+        tend: i64,
+        lim: i64,
+        _: Route,
+    ) -> Result<Vec<DBRow>, TaoError> {
+        if lim < 0 {
+            return Err(TaoError::Database(
+                "LIMIT must not be negative".to_string(),
+            ));
+        }
+        if tstart > tend {
+            return Ok(Vec::new());
+        }
+        let assocs = self.assocs.read().map_err(poisoned)?;
+        let list = match assocs.get(&list_key(ns, id1, atype)) {
+            Some(list) => list,
+            None => return Ok(Vec::new()),
+        };
+        let upper = match tend.checked_add(1) {
+            Some(end) => Excluded((end, String::new())),
+            None => Unbounded,
+        };
+        Ok(list
+            .by_time
+            .range((Included((tstart, String::new())), upper))
+            .rev()
+            .take(lim as usize)
+            .map(|(t, id2)| {
+                let (_, data) = &list.edges[id2];
+                assoc_row(id1, atype, id2, *t, data)
+            })
+            .collect())
+    }
+
+    async fn assoc_count(
+        &self,
+        ns: &NamespaceConfig,
+        id1: &str,
+        atype: &str,
+        _: Route,
+    ) -> Result<i64, TaoError> {
+        let assocs = self.assocs.read().map_err(poisoned)?;
+        Ok(assocs
+            .get(&list_key(ns, id1, atype))
+            .map(|list| list.edges.len() as i64)
+            .unwrap_or(0))
+    }
+
+    async fn assoc_delete(
+        &self,
+        ns: &NamespaceConfig,
+        id1: &str,
+        atype: &str,
+        id2: &str,
+    ) -> Result<Option<DBRow>, TaoError> {
+        let mut assocs = self.assocs.write().map_err(poisoned)?;
+        let key = list_key(ns, id1, atype);
+        let list = match assocs.get_mut(&key) {
+            Some(list) => list,
+            None => return Ok(None),
+        };
+        let (t, data) = match list.edges.remove(id2) {
+            Some(edge) => edge,
+            None => return Ok(None),
+        };
+        list.by_time.remove(&(t, id2.to_string()));
+        if list.edges.is_empty() {
+            assocs.remove(&key);
+        }
+        Ok(Some(DBRow::AssocRow {
+            id1: id1.to_string(),
+            atype: atype.to_string(),
+            id2: id2.to_string(),
+            t,
+            data,
+        }))
+    }
+
+    async fn load(
+        &self,
+        ns: &NamespaceConfig,
+        rows: &[DBRow],
+    ) -> Result<Vec<DBRow>, TaoError> {
+        let mut objs = self.objs.write().map_err(poisoned)?;
+        let mut assocs = self.assocs.write().map_err(poisoned)?;
+        let mut added = Vec::new();
+        for row in rows {
+            match row {
+                DBRow::ObjRow { id, otype, data } => {
+                    let key = (ns.obj_table(), id.clone());
+                    if let Entry::Vacant(obj) = objs.entry(key) {
+                        obj.insert((otype.clone(), data.clone()));
+                        added.push(row.clone());
+                    }
+                }
+                DBRow::AssocRow {
+                    id1,
+                    atype,
+                    id2,
+                    t,
+                    data,
+                } => {
+                    let list =
+                        assocs.entry(list_key(ns, id1, atype)).or_default();
+                    if let Entry::Vacant(edge) = list.edges.entry(id2.clone()) {
+                        edge.insert((*t, data.clone()));
+                        list.by_time.insert((*t, id2.clone()));
+                        added.push(row.clone());
+                    }
+                }
+                _ => (),
+            }
+        }
+        Ok(added)
+    }
+
+    async fn export(
+        &self,
+        ns: &NamespaceConfig,
+        rows: Sender<DBRow>,
+    ) -> Result<u64, TaoError> {
+        let snapshot = {
+            let objs = self.objs.read().map_err(poisoned)?;
+            let assocs = self.assocs.read().map_err(poisoned)?;
+            let (obj_table, assoc_table) = (ns.obj_table(), ns.assoc_table());
+            let mut snapshot = objs
+                .iter()
+                .filter(|((table, _), _)| *table == obj_table)
+                .map(|((_, id), (otype, data))| DBRow::ObjRow {
+                    id: id.clone(),
+                    otype: otype.clone(),
+                    data: data.clone(),
+                })
+                .collect::<Vec<DBRow>>();
+            for ((table, id1, atype), list) in assocs.iter() {
+                if *table != assoc_table {
+                    continue;
+                }
+                snapshot.extend(list.edges.iter().map(|(id2, (t, data))| {
+                    assoc_row(id1, atype, id2, *t, data)
+                }));
+            }
+            snapshot
+        };
+        let mut sent = 0;
+        for row in snapshot {
+            if rows.send(row).await.is_err() {
+                break;
+            }
+            sent += 1;
+        }
+        Ok(sent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryStore;
+    use crate::query::results::DBRow;
+    use crate::service::namespace::NamespaceConfig;
+    use crate::service::shard::Route;
+    use crate::service::store::GraphStore;
+
+    fn ns() -> NamespaceConfig {
+        serde_json::from_str::<NamespaceConfig>("{}").unwrap()
+    }
+
+    fn times(rows: &[DBRow]) -> Vec<i64> {
+        rows.iter()
+            .map(|row| match row {
+                DBRow::AssocRow { t, .. } => *t,
+                _ => panic!("not an association"),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_assoc_range_is_newest_first() {
+        let (store, ns) = (MemoryStore::new(), ns());
+        for (id2, t) in [("a", 10), ("b", 30), ("c", 20), ("d", i64::MAX)] {
+            store
+                .assoc_add(&ns, "1", "LIKES", id2, t, "x")
+                .await
+                .unwrap();
+        }
+        let range = |tstart, tend, lim| {
+            store.assoc_range(
+                &ns,
+                "1",
+                "LIKES",
+                tstart,
+                tend,
+                lim,
+                Route::Replica,
+            )
+        };
+        assert_eq!(times(&range(0, 30, 10).await.unwrap()), [30, 20, 10]);
+        assert_eq!(times(&range(15, 30, 1).await.unwrap()), [30]);
+        assert_eq!(
+            times(&range(i64::MIN, i64::MAX, 1).await.unwrap()),
+            [i64::MAX]
+        );
+        assert!(range(0, 30, -1).await.is_err());
+        assert_eq!(
+            store
+                .assoc_count(&ns, "1", "LIKES", Route::Primary)
+                .await
+                .unwrap(),
+            4
+        );
+    }
+
+    #[tokio::test]
+    async fn test_writes() {
+        let (store, ns) = (MemoryStore::new(), ns());
+        store.obj_add(&ns, "1", "USER", "a").await.unwrap();
+        assert!(store.obj_add(&ns, "1", "USER", "b").await.is_err());
+        assert!(store.obj_update(&ns, "1", "USER", "b").await.unwrap());
+        assert!(matches!(
+            store.obj_get(&ns, "1", Route::Primary).await.unwrap(),
+            Some(DBRow::ObjRow { data, .. }) if data == "b"
+        ));
+        assert!(store.obj_delete(&ns, "1").await.unwrap().is_some());
+        assert!(store.obj_delete(&ns, "1").await.unwrap().is_none());
+
+        store
+            .assoc_add(&ns, "1", "LIKES", "2", 5, "x")
+            .await
+            .unwrap();
+        assert!(store
+            .assoc_add(&ns, "1", "LIKES", "2", 6, "y")
+            .await
+            .is_err());
+        let idset = vec!["2".to_string(), "3".to_string()];
+        let get = |range| {
+            store.assoc_get(&ns, "1", "LIKES", &idset, range, Route::Primary)
+        };
+        assert_eq!(times(&get(None).await.unwrap()), [5]);
+        assert!(get(Some((6, 10))).await.unwrap().is_empty());
+        assert!(store
+            .assoc_delete(&ns, "1", "LIKES", "2")
+            .await
+            .unwrap()
+            .is_some());
+        assert!(get(None).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,153 @@
+/*
+ * File: store/mod.rs
+ *      The storage layer under TaoServer. A GraphStore keeps the objects and
+ *      associations of every namespace and answers the primitive TAO reads
+ *      and writes. Values arrive as stored, i.e. already encrypted when
+ *      encryption is on, so stores never see plaintext. Association times
+ *      are OPE ciphertexts, whose order is the order of the plaintexts.
+ *
+ *      Objects are unique by id, associations by (id1, atype, id2), and
+ *      association lists are read newest first.
+ */
+use async_trait::async_trait;
+use tokio::sync::mpsc::Sender;
+
+use crate::query::error::TaoError;
+use crate::query::results::DBRow;
+use crate::service::namespace::NamespaceConfig;
+use crate::service::pool::PoolMetrics;
+use crate::service::shard::Route;
+
+pub mod memory;
+pub mod postgres;
+pub mod sqlite;
+
+pub use memory::MemoryStore;
+pub use postgres::PostgresStore;
+pub use sqlite::SqliteStore;
+
+/*
+ * GraphStore
+ *      Reads take the route the server picked for them, stores without
+ *      replicas ignore it.
+ */
+#[async_trait]
+pub trait GraphStore: Send + Sync {
+    async fn obj_add(
+        &self,
+        ns: &NamespaceConfig,
+        id: &str,
+        otype: &str,
+        data: &str,
+    ) -> Result<(), TaoError>;
+
+    async fn obj_get(
+        &self,
+        ns: &NamespaceConfig,
+        id: &str,
+        route: Route,
+    ) -> Result<Option<DBRow>, TaoError>;
+
+    /// Whether the object existed
+    async fn obj_update(
+        &self,
+        ns: &NamespaceConfig,
+        id: &str,
+        otype: &str,
+        data: &str,
+    ) -> Result<bool, TaoError>;
+
+    /// The object deleted, None if there was none
+    async fn obj_delete(
+        &self,
+        ns: &NamespaceConfig,
+        id: &str,
+    ) -> Result<Option<DBRow>, TaoError>;
+
+    async fn assoc_add(
+        &self,
+        ns: &NamespaceConfig,
+        id1: &str,
+        atype: &str,
+        id2: &str,
+        t: i64,
+        data: &str,
+    ) -> Result<(), TaoError>;
+
+    /*
+     * assoc_get(ns, id1, atype, idset, range, route)
+     *      The associations from id1 to any of idset, limited to times in
+     *      `range` (inclusive) when given.
+     */
+    async fn assoc_get(
+        &self,
+        ns: &NamespaceConfig,
+        id1: &str,
+        atype: &str,
+        idset: &[String],
+        range: Option<(i64, i64)>,
+        route: Route,
+    ) -> Result<Vec<DBRow>, TaoError>;
+
+    /*
+     * assoc_range(ns, id1, atype, tstart, tend, lim, route)
+     *      Up to `lim` associations with tstart <= t <= tend, newest first.
+     */
+    #[allow(clippy::too_many_arguments)]
+    async fn assoc_range(
+        &self,
+        ns: &NamespaceConfig,
+        id1: &str,
+        atype: &str,
+        tstart: i64,
+        tend: i64,
+        lim: i64,
+        route: Route,
+    ) -> Result<Vec<DBRow>, TaoError>;
+
+    async fn assoc_count(
+        &self,
+        ns: &NamespaceConfig,
+        id1: &str,
+        atype: &str,
+        route: Route,
+    ) -> Result<i64, TaoError>;
+
+    /// The association deleted, None if there was none
+    async fn assoc_delete(
+        &self,
+        ns: &NamespaceConfig,
+        id1: &str,
+        atype: &str,
+        id2: &str,
+    ) -> Result<Option<DBRow>, TaoError>;
+
+    /*
+     * load(ns, rows)
+     *      Adds objects and associations in bulk, skipping those whose key
+     *      is taken, so that loading rows twice adds them once. Returns the
+     *      rows added.
+     */
+    async fn load(
+        &self,
+        ns: &NamespaceConfig,
+        rows: &[DBRow],
+    ) -> Result<Vec<DBRow>, TaoError>;
+
+    /*
+     * export(ns, rows)
+     *      Sends the objects and then the associations of the namespace, as
+     *      of one snapshot per shard. Stops early, without an error, once
+     *      `rows` is closed. Returns the number of rows sent.
+     */
+    async fn export(
+        &self,
+        ns: &NamespaceConfig,
+        rows: Sender<DBRow>,
+    ) -> Result<u64, TaoError>;
+
+    /// Connection pools behind the store, per shard
+    fn pools(&self) -> Vec<Vec<PoolMetrics>> {
+        Vec::new()
+    }
+}
@@ -0,0 +1,548 @@
+/*
+ * File: store/postgres.rs
+ *      GraphStore on Postgres, spread over the shards in shard.rs. Objects
+ *      live on the shard of their id and associations on the shard of their
+ *      id1. The tables are set up by `tao-server migrate`.
+ */
+use std::collections::BTreeMap;
+use std::ops::{Deref, DerefMut};
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use async_trait::async_trait;
+use bb8::RunError;
+use core::marker::Sync;
+use futures::TryStreamExt;
+use postgres_openssl::MakeTlsConnector;
+use tokio::sync::mpsc::Sender;
+use tokio_postgres::binary_copy::BinaryCopyInWriter;
+use tokio_postgres::types::{ToSql, Type};
+use tokio_postgres::IsolationLevel;
+use tracing::{error, warn};
+
+use crate::query::error::TaoError;
+use crate::query::query::{format_in_clause, TaoOp};
+use crate::query::results::{deserialize_rows, DBRow};
+use crate::service::namespace::NamespaceConfig;
+use crate::service::pool::{DBConn, PoolMetrics};
+use crate::service::shard::{shard_for, shard_key, Route, Shard};
+use crate::service::store::GraphStore;
+use crate::service::tao::DBConfig;
+
+fn db_error(e: tokio_postgres::Error) -> TaoError {
+    TaoError::Database(e.to_string())
+}
+
+/*
+ * Conn
+ *      A pooled connection that cancels its query if dropped before
+ *      finish(), as when the query's deadline passes. Dropping the future
+ *      of a query only stops waiting for it, Postgres would carry on. The
+ *      connection goes back to the pool once the cancelled query is over,
+ *      so that the cancel cannot hit the next query run on it.
+ */
+struct Conn {
+    client: Option<DBConn>,
+    tls: MakeTlsConnector,
+    running: AtomicBool,
+}
+
+impl Conn {
+    /*
+     * finish(res)
+     *      The outcome of the query, which needs no cancelling anymore.
+     */
+    fn finish<T>(
+        &self,
+        res: Result<T, tokio_postgres::Error>,
+    ) -> Result<T, TaoError> {
+        self.running.store(false, Ordering::Relaxed);
+        res.map_err(db_error)
+    }
+}
+
+impl Deref for Conn {
+    type Target = tokio_postgres::Client;
+
+    fn deref(&self) -> &Self::Target {
+        self.client.as_ref().expect("connection already released")
+    }
+}
+
+impl DerefMut for Conn {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.client.as_mut().expect("connection already released")
+    }
+}
+
+impl Drop for Conn {
+    fn drop(&mut self) {
+        let client = match self.client.take() {
+            Some(client) if self.running.load(Ordering::Relaxed) => client,
+            _ => return,
+        };
+        let tls = self.tls.clone();
+        tokio::spawn(async move {
+            if let Err(e) = client.cancel_token().cancel_query(tls).await {
+                warn!(error = %e, "cannot cancel a query past its deadline");
+            }
+            // Answered once the cancelled query is over
+            let _ = client.simple_query("").await;
+        });
+    }
+}
+
+type Param<'a> = &'a (dyn ToSql + Sync);
+
+/*
+ * copy_rows(client, ns, rows)
+ *      Copies the rows into temporary tables with COPY, then inserts those
+ *      whose key is free, all in one transaction. Returns the rows added.
+ */
+async fn copy_rows(
+    client: &mut tokio_postgres::Client,
+    ns: &NamespaceConfig,
+    rows: &[&DBRow],
+) -> Result<Vec<DBRow>, tokio_postgres::Error> {
+    let tx = client.transaction().await?;
+    let mut added = Vec::new();
+
+    let objs: Vec<[Param; 3]> = rows
+        .iter()
+        .filter_map(|row| match row {
+            DBRow::ObjRow { id, otype, data } => {
+                Some::<[Param; 3]>([id, otype, data])
+            }
+            _ => None,
+        })
+        .collect();
+    if !objs.is_empty() {
+        let obj_table = ns.obj_table();
+        tx.batch_execute(&format!(
+            "CREATE TEMP TABLE tao_import_obj \
+             (LIKE {obj_table} INCLUDING DEFAULTS) ON COMMIT DROP"
+        ))
+        .await?;
+        let sink = tx
+            .copy_in("COPY tao_import_obj (id, otype, data) FROM STDIN BINARY")
+            .await?;
+        let mut writer = pin!(BinaryCopyInWriter::new(sink, &[Type::TEXT; 3]));
+        for row in &objs {
+            writer.as_mut().write(row).await?;
+        }
+        writer.as_mut().finish().await?;
+        let inserted = tx
+            .query(
+                &format!(
+                    "INSERT INTO {obj_table} \
+                     SELECT * FROM tao_import_obj \
+                     ON CONFLICT DO NOTHING RETURNING *"
+                ),
+                &[],
+            )
+            .await?;
+        added.extend(deserialize_rows(&TaoOp::ObjGet, &inserted));
+    }
+
+    let assocs: Vec<[Param; 5]> = rows
+        .iter()
+        .filter_map(|row| match row {
+            DBRow::AssocRow {
+                id1,
+                atype,
+                id2,
+                t,
+                data,
+            } => Some::<[Param; 5]>([id1, atype, id2, t, data]),
+            _ => None,
+        })
+        .collect();
+    if !assocs.is_empty() {
+        let assoc_table = ns.assoc_table();
+        tx.batch_execute(&format!(
+            "CREATE TEMP TABLE tao_import_assoc \
+             (LIKE {assoc_table} INCLUDING DEFAULTS) ON COMMIT DROP"
+        ))
+        .await?;
+        let sink = tx
+            .copy_in(
+                "COPY tao_import_assoc (id1, atype, id2, t, data) \
+                 FROM STDIN BINARY",
+            )
+            .await?;
+        let types =
+            [Type::TEXT, Type::TEXT, Type::TEXT, Type::INT8, Type::TEXT];
+        let mut writer = pin!(BinaryCopyInWriter::new(sink, &types));
+        for row in &assocs {
+            writer.as_mut().write(row).await?;
+        }
+        writer.as_mut().finish().await?;
+        let inserted = tx
+            .query(
+                &format!(
+                    "INSERT INTO {assoc_table} \
+                     SELECT * FROM tao_import_assoc \
+                     ON CONFLICT DO NOTHING RETURNING *"
+                ),
+                &[],
+            )
+            .await?;
+        added.extend(deserialize_rows(&TaoOp::AssocRange, &inserted));
+    }
+
+    tx.commit().await?;
+    Ok(added)
+}
+
+/*
+ * snapshot(client, ns, rows)
+ *      Sends the namespace's rows on a shard, read in one repeatable read
+ *      transaction so that they are of the same moment.
+ */
+async fn snapshot(
+    client: &mut tokio_postgres::Client,
+    ns: &NamespaceConfig,
+    rows: &Sender<DBRow>,
+) -> Result<u64, tokio_postgres::Error> {
+    let tx = client
+        .build_transaction()
+        .isolation_level(IsolationLevel::RepeatableRead)
+        .read_only(true)
+        .start()
+        .await?;
+    let mut sent = 0;
+    let tables = [
+        (TaoOp::ObjGet, ns.obj_table()),
+        (TaoOp::AssocRange, ns.assoc_table()),
+    ];
+    for (op, table) in tables {
+        let sql_query = format!("SELECT * FROM {}", table);
+        let params: [Param; 0] = [];
+        let mut stream = pin!(tx.query_raw(&sql_query, params).await?);
+        while let Some(row) = stream.try_next().await? {
+            for row in deserialize_rows(&op, &vec![row]) {
+                if rows.send(row).await.is_err() {
+                    return Ok(sent);
+                }
+                sent += 1;
+            }
+        }
+    }
+    tx.commit().await?;
+    Ok(sent)
+}
+
+pub struct PostgresStore {
+    shards: Vec<Shard>,
+}
+
+impl PostgresStore {
+    pub fn new(shards: Vec<DBConfig>) -> Result<Self, String> {
+        let shards = shards
+            .into_iter()
+            .map(Shard::new)
+            .collect::<Result<Vec<Shard>, String>>()?;
+        Ok(PostgresStore { shards })
+    }
+
+    /*
+     * connect(key, route)
+     *      Connects to the shard that holds `key`, an object id or an id1,
+     *      on its primary or one of its replicas.
+     */
+    async fn connect(&self, key: &str, route: Route) -> Result<Conn, TaoError> {
+        self.connect_shard(shard_for(key, self.shards.len()), route)
+            .await
+    }
+
+    async fn connect_shard(
+        &self,
+        shard: usize,
+        route: Route,
+    ) -> Result<Conn, TaoError> {
+        let shard = &self.shards[shard];
+        match shard.pool(route).get_owned().await {
+            Ok(client) => Ok(Conn {
+                client: Some(client),
+                tls: shard.tls.clone(),
+                running: AtomicBool::new(true),
+            }),
+            Err(RunError::TimedOut) => Err(TaoError::Timeout(
+                "no database connection available".to_string(),
+            )),
+            Err(RunError::User(e)) => {
+                error!(error = %e, "database connection failed");
+                Err(db_error(e))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl GraphStore for PostgresStore {
+    async fn obj_add(
+        &self,
+        ns: &NamespaceConfig,
+        id: &str,
+        otype: &str,
+        data: &str,
+    ) -> Result<(), TaoError> {
+        let client = self.connect(id, Route::Primary).await?;
+
+        let sql_query = format!(
+            "INSERT INTO {}(id, otype, data) \
+             VALUES ($1, $2, $3)",
+            ns.obj_table()
+        );
+
+        let added = client.execute(&sql_query, &[&id, &otype, &data]).await;
+        client.finish(added)?;
+        return Ok(());
+    }
+
+    async fn obj_get(
+        &self,
+        ns: &NamespaceConfig,
+        id: &str,
+        route: Route,
+    ) -> Result<Option<DBRow>, TaoError> {
+        let client = self.connect(id, route).await?;
+
+        let sql_query = format!(
+            "SELECT * \
+             FROM {} \
+             WHERE id = $1",
+            ns.obj_table()
+        );
+
+        let resp = &client.finish(client.query(&sql_query, &[&id]).await)?;
+
+        let res = deserialize_rows(&TaoOp::ObjGet, resp);
+        return Ok(res.into_iter().next());
+    }
+
+    async fn obj_update(
+        &self,
+        ns: &NamespaceConfig,
+        id: &str,
+        otype: &str,
+        data: &str,
+    ) -> Result<bool, TaoError> {
+        let client = self.connect(id, Route::Primary).await?;
+
+        let sql_query = format!(
+            "UPDATE {} \
+             SET otype = $2, data = $3 \
+             WHERE id = $1",
+            ns.obj_table()
+        );
+
+        let updated = client.execute(&sql_query, &[&id, &otype, &data]).await;
+        let updated = client.finish(updated)?;
+        return Ok(updated > 0);
+    }
+
+    async fn obj_delete(
+        &self,
+        ns: &NamespaceConfig,
+        id: &str,
+    ) -> Result<Option<DBRow>, TaoError> {
+        let client = self.connect(id, Route::Primary).await?;
+
+        let sql_query = format!(
+            "DELETE FROM {} \
+             WHERE id = $1 \
+             RETURNING *",
+            ns.obj_table()
+        );
+
+        let deleted = client.query(&sql_query, &[&id]).await;
+        let deleted = client.finish(deleted)?;
+        return Ok(deserialize_rows(&TaoOp::ObjGet, &deleted).pop());
+    }
+
+    async fn assoc_add(
+        &self,
+        ns: &NamespaceConfig,
+        id1: &str,
+        atype: &str,
+        id2: &str,
+        t: i64,
+        data: &str,
+    ) -> Result<(), TaoError> {
+        let client = self.connect(id1, Route::Primary).await?;
+
+        let sql_query = format!(
+            "INSERT INTO {}(id1, atype, id2, t, data) \
+             VALUES ($1, $2, $3, $4, $5)",
+            ns.assoc_table()
+        );
+
+        let added = client
+            .execute(&sql_query, &[&id1, &atype, &id2, &t, &data])
+            .await;
+        client.finish(added)?;
+        return Ok(());
+    }
+
+    async fn assoc_get(
+        &self,
+        ns: &NamespaceConfig,
+        id1: &str,
+        atype: &str,
+        idset: &[String],
+        range: Option<(i64, i64)>,
+        route: Route,
+    ) -> Result<Vec<DBRow>, TaoError> {
+        let client = self.connect(id1, route).await?;
+
+        let (tstart, tend) = range.unwrap_or((0, 0));
+        let mut params =
+            vec![&id1 as &(dyn ToSql + Sync), &atype as &(dyn ToSql + Sync)];
+        let in_range = match range {
+            Some(_) => {
+                params.push(&tstart as &(dyn ToSql + Sync));
+                params.push(&tend as &(dyn ToSql + Sync));
+                "AND t >= $3 AND t <= $4 "
+            }
+            None => "",
+        };
+        let in_set = format_in_clause(&idset.to_vec(), params.len() as i32);
+        let assoc_table = ns.assoc_table();
+        let sql_query = format!(
+            "SELECT * \
+             FROM {assoc_table} \
+             WHERE id1 = $1 \
+             AND atype = $2 \
+             {in_range}\
+             AND id2 in {in_set}"
+        );
+        params.extend(idset.iter().map(|x| x as &(dyn ToSql + Sync)));
+
+        let resp = &client.finish(client.query(&sql_query, &params).await)?;
+
+        let res = deserialize_rows(&TaoOp::AssocGet, resp);
+        return Ok(res);
+    }
+
+    async fn assoc_range(
+        &self,
+        ns: &NamespaceConfig,
+        id1: &str,
+        atype: &str,
+        tstart: i64,
+        tend: i64,
+        lim: i64,
+        route: Route,
+    ) -> Result<Vec<DBRow>, TaoError> {
+        let client = self.connect(id1, route).await?;
+
+        let sql_query = format!(
+            "SELECT * \
+             FROM {} \
+             WHERE id1 = $1 \
+               AND atype = $2 \
+               AND t >= $3 \
+               AND t <= $4 \
+             ORDER BY t DESC \
+             LIMIT $5",
+            ns.assoc_table()
+        );
+
+        let resp = client
+            .query(&sql_query, &[&id1, &atype, &tstart, &tend, &lim])
+            .await;
+        let resp = &client.finish(resp)?;
+
+        let res = deserialize_rows(&TaoOp::AssocRange, resp);
+        return Ok(res);
+    }
+
+    async fn assoc_count(
+        &self,
+        ns: &NamespaceConfig,
+        id1: &str,
+        atype: &str,
+        route: Route,
+    ) -> Result<i64, TaoError> {
+        let client = self.connect(id1, route).await?;
+
+        let sql_query = format!(
+            "SELECT COUNT(*) \
+             FROM {} \
+             WHERE id1 = $1 \
+               AND atype = $2",
+            ns.assoc_table()
+        );
+
+        let row = client.query_one(&sql_query, &[&id1, &atype]).await;
+        let row = client.finish(row)?;
+        return Ok(row.get(0));
+    }
+
+    async fn assoc_delete(
+        &self,
+        ns: &NamespaceConfig,
+        id1: &str,
+        atype: &str,
+        id2: &str,
+    ) -> Result<Option<DBRow>, TaoError> {
+        let client = self.connect(id1, Route::Primary).await?;
+
+        let sql_query = format!(
+            "DELETE FROM {} \
+             WHERE id1 = $1 \
+               AND atype = $2 \
+               AND id2 = $3 \
+             RETURNING *",
+            ns.assoc_table()
+        );
+
+        let deleted = client.query(&sql_query, &[&id1, &atype, &id2]).await;
+        let deleted = client.finish(deleted)?;
+        return Ok(deserialize_rows(&TaoOp::AssocRange, &deleted).pop());
+    }
+
+    async fn load(
+        &self,
+        ns: &NamespaceConfig,
+        rows: &[DBRow],
+    ) -> Result<Vec<DBRow>, TaoError> {
+        let mut by_shard: BTreeMap<usize, Vec<&DBRow>> = BTreeMap::new();
+        for row in rows {
+            if let Some(key) = shard_key(row) {
+                let shard = shard_for(key, self.shards.len());
+                by_shard.entry(shard).or_default().push(row);
+            }
+        }
+
+        let mut added = Vec::new();
+        for (shard, rows) in &by_shard {
+            let mut client = self.connect_shard(*shard, Route::Primary).await?;
+            let copied = copy_rows(&mut client, ns, rows).await;
+            added.extend(client.finish(copied)?);
+        }
+        Ok(added)
+    }
+
+    async fn export(
+        &self,
+        ns: &NamespaceConfig,
+        rows: Sender<DBRow>,
+    ) -> Result<u64, TaoError> {
+        let mut sent = 0;
+        for shard in 0..self.shards.len() {
+            let mut client = self.connect_shard(shard, Route::Primary).await?;
+            let read = snapshot(&mut client, ns, &rows).await;
+            sent += client.finish(read)?;
+            if rows.is_closed() {
+                break;
+            }
+        }
+        Ok(sent)
+    }
+
+    fn pools(&self) -> Vec<Vec<PoolMetrics>> {
+        self.shards.iter().map(|shard| shard.metrics()).collect()
+    }
+}
@@ -0,0 +1,657 @@
+/*
+ * File: store/sqlite.rs
+ *      GraphStore in a single SQLite file, for running one server without
+ *      Postgres, e.g. on a laptop or at the edge. The tables of every
+ *      namespace are created when the file is opened.
+ *
+ *      SQLite has no schemas, so a namespace's tables are named
+ *      "schema.table". Times are 64-bit integers as in Postgres, and the
+ *      (id1, atype, t) index serves ASSOC RANGE in the order of the OPE
+ *      ciphertexts, which is the order of the plaintexts.
+ *
+ *      All queries share one connection and run on tokio's blocking
+ *      threads, so a slow disk never stalls the HTTP workers. A query
+ *      dropped at its deadline is interrupted, or skipped if it is still
+ *      waiting for the connection.
+ */
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rusqlite::types::Value;
+use rusqlite::{
+    params, params_from_iter, Connection, InterruptHandle, OptionalExtension,
+};
+use tokio::sync::mpsc::Sender;
+
+use crate::query::error::TaoError;
+use crate::query::results::DBRow;
+use crate::service::namespace::NamespaceConfig;
+use crate::service::shard::Route;
+use crate::service::store::GraphStore;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS {obj_table} (
+        id TEXT NOT NULL,
+        otype TEXT NOT NULL,
+        data TEXT NOT NULL,
+        PRIMARY KEY (id)
+    );
+
+    CREATE TABLE IF NOT EXISTS {assoc_table} (
+        id1 TEXT NOT NULL,
+        atype TEXT NOT NULL,
+        id2 TEXT NOT NULL,
+        t INTEGER NOT NULL,
+        data TEXT NOT NULL,
+        PRIMARY KEY (id1, atype, id2)
+    );
+
+    CREATE INDEX IF NOT EXISTS {assoc_range_idx}
+        ON {assoc_table} (id1, atype, t DESC);
+";
+
+/// Time a query waits on a file locked by another process
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn db_error(e: rusqlite::Error) -> TaoError {
+    TaoError::Database(e.to_string())
+}
+
+fn obj_table(ns: &NamespaceConfig) -> String {
+    format!("\"{}.{}\"", ns.schema, ns.obj_table)
+}
+
+fn assoc_table(ns: &NamespaceConfig) -> String {
+    format!("\"{}.{}\"", ns.schema, ns.assoc_table)
+}
+
+fn assoc_row(row: &rusqlite::Row) -> rusqlite::Result<DBRow> {
+    Ok(DBRow::AssocRow {
+        id1: row.get(0)?,
+        atype: row.get(1)?,
+        id2: row.get(2)?,
+        t: row.get(3)?,
+        data: row.get(4)?,
+    })
+}
+
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+    interrupt: InterruptHandle,
+}
+
+/*
+ * Call
+ *      Where a query run on the connection is at.
+ */
+#[derive(Debug, PartialEq, Eq)]
+enum Call {
+    Waiting,
+    Running,
+    Over,
+}
+
+/*
+ * Interrupt
+ *      Interrupts its query if dropped while the query is running, as when
+ *      its deadline passes, and keeps it from starting if it has not yet.
+ *      The query is only Running while it holds the connection, so no
+ *      other query can be interrupted instead.
+ */
+struct Interrupt<'a> {
+    call: Arc<Mutex<Call>>,
+    handle: &'a InterruptHandle,
+}
+
+impl Drop for Interrupt<'_> {
+    fn drop(&mut self) {
+        let mut call = self.call.lock().unwrap_or_else(|e| e.into_inner());
+        if *call == Call::Running {
+            self.handle.interrupt();
+        }
+        *call = Call::Over;
+    }
+}
+
+impl SqliteStore {
+    /*
+     * open(path, namespaces)
+     *      Opens or creates the file and the tables of each namespace.
+     */
+    pub fn open(
+        path: &Path,
+        namespaces: &[NamespaceConfig],
+    ) -> Result<Self, String> {
+        let at = |e: rusqlite::Error| {
+            format!("sqlite file {}: {}", path.display(), e)
+        };
+        let conn = Connection::open(path).map_err(at)?;
+        conn.busy_timeout(BUSY_TIMEOUT).map_err(at)?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;",
+        )
+        .map_err(at)?;
+        for ns in namespaces {
+            let schema = SCHEMA
+                .replace("{obj_table}", &obj_table(ns))
+                .replace("{assoc_table}", &assoc_table(ns))
+                .replace(
+                    "{assoc_range_idx}",
+                    &format!("\"{}.{}_range_idx\"", ns.schema, ns.assoc_table),
+                );
+            conn.execute_batch(&schema).map_err(at)?;
+        }
+        Ok(SqliteStore {
+            interrupt: conn.get_interrupt_handle(),
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /*
+     * run(f)
+     *      Runs `f` on the connection on a blocking thread.
+     */
+    async fn run<T, F>(&self, f: F) -> Result<T, TaoError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        let call = Arc::new(Mutex::new(Call::Waiting));
+        let _interrupt = Interrupt {
+            call: call.clone(),
+            handle: &self.interrupt,
+        };
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.lock().map_err(|_| {
+                TaoError::Database("sqlite connection lock poisoned".into())
+            })?;
+            let state = || call.lock().unwrap_or_else(|e| e.into_inner());
+            {
+                let mut state = state();
+                if *state == Call::Over {
+                    return Err(TaoError::Timeout(
+                        "query deadline exceeded".into(),
+                    ));
+                }
+                *state = Call::Running;
+            }
+            let res = f(&conn).map_err(db_error);
+            *state() = Call::Over;
+            res
+        })
+        .await
+        .map_err(|e| TaoError::Database(e.to_string()))?
+    }
+}
+
+#[async_trait]
+impl GraphStore for SqliteStore {
+    async fn obj_add(
+        &self,
+        ns: &NamespaceConfig,
+        id: &str,
+        otype: &str,
+        data: &str,
+    ) -> Result<(), TaoError> {
+        let sql_query = format!(
+            "INSERT INTO {}(id, otype, data) VALUES (?1, ?2, ?3)",
+            obj_table(ns)
+        );
+        let values = [id.to_string(), otype.to_string(), data.to_string()];
+        self.run(move |conn| {
+            conn.execute(&sql_query, params_from_iter(values))
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn obj_get(
+        &self,
+        ns: &NamespaceConfig,
+        id: &str,
+        _: Route,
+    ) -> Result<Option<DBRow>, TaoError> {
+        let sql_query = format!(
+            "SELECT id, otype, data FROM {} WHERE id = ?1",
+            obj_table(ns)
+        );
+        let id = id.to_string();
+        self.run(move |conn| {
+            conn.query_row(&sql_query, [id], |row| {
+                Ok(DBRow::ObjRow {
+                    id: row.get(0)?,
+                    otype: row.get(1)?,
+                    data: row.get(2)?,
+                })
+            })
+            .optional()
+        })
+        .await
+    }
+
+    async fn obj_update(
+        &self,
+        ns: &NamespaceConfig,
+        id: &str,
+        otype: &str,
+        data: &str,
+    ) -> Result<bool, TaoError> {
+        let sql_query = format!(
+            "UPDATE {} SET otype = ?2, data = ?3 WHERE id = ?1",
+            obj_table(ns)
+        );
+        let values = [id.to_string(), otype.to_string(), data.to_string()];
+        let updated = self
+            .run(move |conn| conn.execute(&sql_query, params_from_iter(values)))
+            .await?;
+        Ok(updated > 0)
+    }
+
+    async fn obj_delete(
+        &self,
+        ns: &NamespaceConfig,
+        id: &str,
+    ) -> Result<Option<DBRow>, TaoError> {
+        let sql_query = format!(
+            "DELETE FROM {} WHERE id = ?1 RETURNING id, otype, data",
+            obj_table(ns)
+        );
+        let id = id.to_string();
+        self.run(move |conn| {
+            conn.query_row(&sql_query, [id], |row| {
+                Ok(DBRow::ObjRow {
+                    id: row.get(0)?,
+                    otype: row.get(1)?,
+                    data: row.get(2)?,
+                })
+            })
+            .optional()
+        })
+        .await
+    }
+
+    async fn assoc_add(
+        &self,
+        ns: &NamespaceConfig,
+        id1: &str,
+        atype: &str,
+        id2: &str,
+        t: i64,
+        data: &str,
+    ) -> Result<(), TaoError> {
+        let sql_query = format!(
+            "INSERT INTO {}(id1, atype, id2, t, data) \
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            assoc_table(ns)
+        );
+        let (id1, atype) = (id1.to_string(), atype.to_string());
+        let (id2, data) = (id2.to_string(), data.to_string());
+        self.run(move |conn| {
+            conn.execute(&sql_query, params![id1, atype, id2, t, data])
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn assoc_get(
+        &self,
+        ns: &NamespaceConfig,
+        id1: &str,
+        atype: &str,
+        idset: &[String],
+        range: Option<(i64, i64)>,
+        _: Route,
+    ) -> Result<Vec<DBRow>, TaoError> {
+        if idset.is_empty() {
+            return Ok(Vec::new());
+        }
+        let (tstart, tend) = range.unwrap_or((i64::MIN, i64::MAX));
+        let in_set = vec!["?"; idset.len()].join(", ");
+        let sql_query = format!(
+            "SELECT id1, atype, id2, t, data \
+             FROM {} \
+             WHERE id1 = ?1 \
+               AND atype = ?2 \
+               AND t >= ?3 \
+               AND t <= ?4 \
+               AND id2 IN ({})",
+            assoc_table(ns),
+            in_set
+        );
+        let (id1, atype) = (id1.to_string(), atype.to_string());
+        let idset = idset.to_vec();
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(&sql_query)?;
+            let mut values = vec![
+                Value::Text(id1),
+                Value::Text(atype),
+                Value::Integer(tstart),
+                Value::Integer(tend),
+            ];
+            values.extend(idset.into_iter().map(Value::Text));
+            let rows = stmt.query_map(params_from_iter(values), assoc_row)?;
+            rows.collect()
+        })
+        .await
+    }
+
+    async fn assoc_range(
+        &self,
+        ns: &NamespaceConfig,
+        id1: &str,
+        atype: &str,
+        tstart: i64,
+        tend: i64,
+        lim: i64,
+        _: Route,
+    ) -> Result<Vec<DBRow>, TaoError> {
+        // SQLite reads a negative LIMIT as no limit, Postgres rejects it
+        if lim < 0 {
+            return Err(TaoError::Database(
+                "LIMIT must not be negative".to_string(),
+            ));
+        }
+        let sql_query = format!(
+            "SELECT id1, atype, id2, t, data \
+             FROM {} \
+             WHERE id1 = ?1 \
+               AND atype = ?2 \
+               AND t >= ?3 \
+               AND t <= ?4 \
+             ORDER BY t DESC, id2 DESC \
+             LIMIT ?5",
+            assoc_table(ns)
+        );
+        let (id1, atype) = (id1.to_string(), atype.to_string());
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(&sql_query)?;
+            let rows = stmt
+                .query_map(params![id1, atype, tstart, tend, lim], assoc_row)?;
+            rows.collect()
+        })
+        .await
+    }
+
+    async fn assoc_count(
+        &self,
+        ns: &NamespaceConfig,
+        id1: &str,
+        atype: &str,
+        _: Route,
+    ) -> Result<i64, TaoError> {
+        let sql_query = format!(
+            "SELECT COUNT(*) FROM {} WHERE id1 = ?1 AND atype = ?2",
+            assoc_table(ns)
+        );
+        let (id1, atype) = (id1.to_string(), atype.to_string());
+        self.run(move |conn| {
+            conn.query_row(&sql_query, [id1, atype], |row| row.get(0))
+        })
+        .await
+    }
+
+    async fn assoc_delete(
+        &self,
+        ns: &NamespaceConfig,
+        id1: &str,
+        atype: &str,
+        id2: &str,
+    ) -> Result<Option<DBRow>, TaoError> {
+        let sql_query = format!(
+            "DELETE FROM {} WHERE id1 = ?1 AND atype = ?2 AND id2 = ?3 \
+             RETURNING id1, atype, id2, t, data",
+            assoc_table(ns)
+        );
+        let values = [id1.to_string(), atype.to_string(), id2.to_string()];
+        self.run(move |conn| {
+            conn.query_row(&sql_query, params_from_iter(values), assoc_row)
+                .optional()
+        })
+        .await
+    }
+
+    async fn load(
+        &self,
+        ns: &NamespaceConfig,
+        rows: &[DBRow],
+    ) -> Result<Vec<DBRow>, TaoError> {
+        let add_obj = format!(
+            "INSERT OR IGNORE INTO {}(id, otype, data) VALUES (?1, ?2, ?3)",
+            obj_table(ns)
+        );
+        let add_assoc = format!(
+            "INSERT OR IGNORE INTO {}(id1, atype, id2, t, data) \
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            assoc_table(ns)
+        );
+        let rows = rows.to_vec();
+        self.run(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            let mut added = Vec::new();
+            {
+                let mut add_obj = tx.prepare(&add_obj)?;
+                let mut add_assoc = tx.prepare(&add_assoc)?;
+                for row in rows {
+                    let inserted = match &row {
+                        DBRow::ObjRow { id, otype, data } => {
+                            add_obj.execute(params![id, otype, data])?
+                        }
+                        DBRow::AssocRow {
+                            id1,
+                            atype,
+                            id2,
+                            t,
+                            data,
+                        } => add_assoc
+                            .execute(params![id1, atype, id2, t, data])?,
+                        _ => 0,
+                    };
+                    if inserted > 0 {
+                        added.push(row);
+                    }
+                }
+            }
+            tx.commit()?;
+            Ok(added)
+        })
+        .await
+    }
+
+    async fn export(
+        &self,
+        ns: &NamespaceConfig,
+        rows: Sender<DBRow>,
+    ) -> Result<u64, TaoError> {
+        let objs = format!("SELECT id, otype, data FROM {}", obj_table(ns));
+        let assocs =
+            format!("SELECT id1, atype, id2, t, data FROM {}", assoc_table(ns));
+        self.run(move |conn| {
+            // Both reads see the snapshot the first one starts
+            let tx = conn.unchecked_transaction()?;
+            let mut sent = 0;
+            let mut objs = tx.prepare(&objs)?;
+            let mut assocs = tx.prepare(&assocs)?;
+            let obj_rows = objs.query_map([], |row| {
+                Ok(DBRow::ObjRow {
+                    id: row.get(0)?,
+                    otype: row.get(1)?,
+                    data: row.get(2)?,
+                })
+            })?;
+            for row in obj_rows.chain(assocs.query_map([], assoc_row)?) {
+                if rows.blocking_send(row?).is_err() {
+                    break;
+                }
+                sent += 1;
+            }
+            Ok(sent)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::SqliteStore;
+    use crate::query::error::TaoError;
+    use crate::query::results::DBRow;
+    use crate::service::deadline::within;
+    use crate::service::namespace::NamespaceConfig;
+    use crate::service::shard::Route;
+    use crate::service::store::GraphStore;
+    use crate::service::testing::TempDir;
+
+    fn namespace(name: &str, schema: &str) -> NamespaceConfig {
+        let mut ns = serde_json::from_str::<NamespaceConfig>(&format!(
+            "{{\"schema\": \"{}\"}}",
+            schema
+        ))
+        .unwrap();
+        ns.name = name.to_string();
+        ns
+    }
+
+    fn times(rows: &[DBRow]) -> Vec<i64> {
+        rows.iter()
+            .map(|row| match row {
+                DBRow::AssocRow { t, .. } => *t,
+                _ => panic!("not an association"),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_store() {
+        let dir = TempDir::new("sqlite");
+        let path = dir.join("tao.db");
+        let namespaces = [namespace("default", "public"), namespace("b", "b")];
+        let (ns, other) = (&namespaces[0], &namespaces[1]);
+
+        let store = SqliteStore::open(&path, &namespaces).unwrap();
+        store.obj_add(ns, "1", "USER", "a").await.unwrap();
+        assert!(store.obj_add(ns, "1", "USER", "b").await.is_err());
+        for (id2, t) in [("a", 10), ("b", 30), ("c", 20), ("d", i64::MAX)] {
+            store
+                .assoc_add(ns, "1", "LIKES", id2, t, "x")
+                .await
+                .unwrap();
+        }
+        drop(store);
+
+        let store = SqliteStore::open(&path, &namespaces).unwrap();
+        let range = |tstart, tend, lim| {
+            store.assoc_range(
+                ns,
+                "1",
+                "LIKES",
+                tstart,
+                tend,
+                lim,
+                Route::Primary,
+            )
+        };
+        assert_eq!(times(&range(0, 30, 10).await.unwrap()), [30, 20, 10]);
+        assert_eq!(times(&range(15, 30, 1).await.unwrap()), [30]);
+        assert_eq!(
+            times(&range(i64::MIN, i64::MAX, 1).await.unwrap()),
+            [i64::MAX]
+        );
+        assert!(range(0, 30, -1).await.is_err());
+
+        let idset = vec!["b".to_string(), "c".to_string(), "z".to_string()];
+        let get = |range| {
+            store.assoc_get(ns, "1", "LIKES", &idset, range, Route::Primary)
+        };
+        assert_eq!(get(None).await.unwrap().len(), 2);
+        assert_eq!(times(&get(Some((25, 40))).await.unwrap()), [30]);
+
+        assert!(store
+            .assoc_delete(ns, "1", "LIKES", "b")
+            .await
+            .unwrap()
+            .is_some());
+        assert_eq!(
+            store
+                .assoc_count(ns, "1", "LIKES", Route::Primary)
+                .await
+                .unwrap(),
+            3
+        );
+        assert!(store.obj_update(ns, "1", "USER", "c").await.unwrap());
+        assert!(matches!(
+            store.obj_get(ns, "1", Route::Primary).await.unwrap(),
+            Some(DBRow::ObjRow { data, .. }) if data == "c"
+        ));
+        assert!(store
+            .obj_get(other, "1", Route::Primary)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            store.obj_delete(ns, "1").await.unwrap(),
+            Some(DBRow::ObjRow { data, .. }) if data == "c"
+        ));
+
+        // Loading skips taken keys, those in the same batch included
+        let obj = |id: &str| DBRow::ObjRow {
+            id: id.to_string(),
+            otype: "USER".to_string(),
+            data: "d".to_string(),
+        };
+        let assoc = |id2: &str| DBRow::AssocRow {
+            id1: "1".to_string(),
+            atype: "LIKES".to_string(),
+            id2: id2.to_string(),
+            t: 40,
+            data: "x".to_string(),
+        };
+        let rows = [obj("1"), obj("2"), obj("2"), assoc("a"), assoc("e")];
+        assert_eq!(store.load(ns, &rows).await.unwrap().len(), 3);
+        assert!(store.load(ns, &rows).await.unwrap().is_empty());
+        assert_eq!(
+            store
+                .assoc_count(ns, "1", "LIKES", Route::Primary)
+                .await
+                .unwrap(),
+            4
+        );
+    }
+
+    #[tokio::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn test_deadlines() {
+        let dir = TempDir::new("sqlite-deadline");
+        let namespaces = [namespace("default", "public")];
+        let ns = &namespaces[0];
+        let store =
+            SqliteStore::open(&dir.join("tao.db"), &namespaces).unwrap();
+        let soon = || Instant::now() + Duration::from_millis(50);
+
+        // Still waiting for the connection, it must never run
+        let held = store.conn.lock().unwrap();
+        let add = within(soon(), store.obj_add(ns, "1", "USER", "a")).await;
+        assert!(matches!(add, Err(TaoError::Timeout(_))));
+        drop(held);
+
+        // Running, it must be interrupted and free the connection
+        let count = "WITH RECURSIVE c(x) AS \
+                     (SELECT 1 UNION ALL SELECT x + 1 FROM c) \
+                     SELECT count(*) FROM c";
+        let endless = store.run(move |conn| {
+            conn.query_row(count, [], |row| row.get::<_, i64>(0))
+        });
+        let started = Instant::now();
+        assert!(matches!(
+            within(soon(), endless).await,
+            Err(TaoError::Timeout(_))
+        ));
+        let get = store.obj_get(ns, "1", Route::Primary).await.unwrap();
+        assert!(get.is_none());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -1,350 +1,1132 @@
-use std::sync::Mutex;
-
 use actix_web::{
-    get, post,
-    web::{scope, Data, Json, ServiceConfig},
-    HttpResponse, Responder,
+    get,
+    http::header::{CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE},
+    post,
+    web::{self, scope, Data, Json, ServiceConfig},
+    HttpRequest, HttpResponse, Responder,
 };
-use core::marker::Sync;
-use futures::future::join_all;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use futures::{future::join_all, StreamExt};
 use serde::{Deserialize, Serialize};
-use tokio_postgres::{connect, types::ToSql, Client, NoTls};
+use postgres_openssl::MakeTlsConnector;
+use tokio_postgres::config::SslMode;
+use tracing::{error, field::Empty, info, info_span, warn, Instrument, Span};
 
 use crate::query::{
-    crypto::TaoCrypto,
+    crypto::{CryptKeys, TaoCrypto},
+    error::TaoError,
     parser,
-    query::{format_in_clause, Query, TaoArgs, TaoOp},
-    results::{deserialize_rows, DBRow},
+    query::{Query, TaoArgs, TaoOp},
+    results::DBRow,
+};
+use crate::service::audit::{unaudited, AuditLog};
+use crate::service::auth::Principal;
+use crate::service::cache::{read_assoc_list, GraphCache, Invalidation};
+use crate::service::feed::{
+    self, event_stream, EventData, Feed, FeedEvent, FeedMode, FeedParams,
+    Topic,
+};
+use crate::service::tier::{Tier, TierRole, WriteRequest};
+use crate::service::config::{ServerConfig, StorageBackend};
+use crate::service::deadline::{
+    self, parse_millis, Timeouts, QUERY_TIMEOUT_HEADER, TIMEOUT_HEADER,
+};
+use crate::service::import::{self, ImportOptions, ImportParams};
+use crate::service::limits::{Limits, RateLimiter, Rejected};
+use crate::service::metrics::{self, Metrics, Scrape};
+use crate::service::namespace::{
+    Namespace, NamespaceConfig, DEFAULT_NAMESPACE, NAMESPACE_HEADER,
 };
+use crate::service::policy::Policy;
+use crate::service::shard::Route;
+use crate::service::shutdown::Shutdown;
+use crate::service::store::{GraphStore, PostgresStore, SqliteStore};
+use crate::service::tls::{db_connector, DBSslMode};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct QueryRequest {
     pub query: String,
 }
 
+/*
+ * QueryResult
+ *      Outcome of one query in a batch, serialized as
+ *      {"Ok": [rows]} or {"Err": {"kind": ..., "message": ...}}
+ */
+pub type QueryResult = Result<Vec<DBRow>, TaoError>;
+
+/*
+ * SESSION_HEADER
+ *      Read-your-writes session token, the unix time in milliseconds of the
+ *      client's last write. Responses to batches with a write carry a new
+ *      one, and clients send back the latest they have seen.
+ */
+pub const SESSION_HEADER: &str = "X-Tao-Session";
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct QueryResponse {
-    pub response: Vec<Vec<DBRow>>,
+    pub response: Vec<QueryResult>,
 }
 
+#[derive(Clone)]
 pub struct DBConfig {
     pub host: String,
     pub user: String,
     pub password: String,
     pub db_name: String,
     pub port: String,
+    pub pool_min_size: u32,
+    pub pool_max_size: u32,
+    pub pool_idle_timeout: u64,
+    pub pool_checkout_timeout: u64,
+    pub ssl_mode: DBSslMode,
+    pub ssl_root_cert: Option<PathBuf>,
+    /// Streaming replicas of this database as "host" or "host:port"
+    pub replicas: Vec<String>,
+}
+
+impl fmt::Debug for DBConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DBConfig")
+            .field("host", &self.host)
+            .field("user", &self.user)
+            .field("password", &"<redacted>")
+            .field("db_name", &self.db_name)
+            .field("port", &self.port)
+            .field("ssl_mode", &self.ssl_mode)
+            .field("replicas", &self.replicas)
+            .finish()
+    }
 }
 
 impl DBConfig {
-    pub fn new(env_path: &String) -> Self {
-        dotenv::from_path(env_path).ok();
-        let host = dotenv::var("DATABASE_HOST").unwrap();
-        let user = dotenv::var("DATABASE_USERNAME").unwrap();
-        let password = dotenv::var("DATABASE_PASSWORD").unwrap();
-        let db_name = dotenv::var("DATABASE_NAME").unwrap();
-        let port = dotenv::var("DATABASE_PORT_NUM").unwrap();
-        DBConfig {
-            host,
-            user,
-            password,
-            db_name,
-            port,
+    pub fn url(&self) -> String {
+        format!(
+            "host={} user={} password={} dbname={} port={}",
+            self.host, self.user, self.password, self.db_name, self.port
+        )
+    }
+
+    pub fn pg_config(&self) -> Result<tokio_postgres::Config, String> {
+        let mut config = self
+            .url()
+            .parse::<tokio_postgres::Config>()
+            .map_err(|e| format!("invalid database config: {}", e))?;
+        config.ssl_mode(match self.ssl_mode {
+            DBSslMode::Disable => SslMode::Disable,
+            DBSslMode::Require | DBSslMode::VerifyFull => SslMode::Require,
+        });
+        Ok(config)
+    }
+
+    /*
+     * replica(addr)
+     *      Settings for one of the replicas, which share everything but the
+     *      host and port with the primary.
+     */
+    pub fn replica(&self, addr: &str) -> Result<DBConfig, String> {
+        let (host, port) = match addr.rsplit_once(':') {
+            Some((host, port)) => (host, port),
+            None => (addr, self.port.as_str()),
+        };
+        if host.is_empty() || port.parse::<u16>().is_err() {
+            return Err(format!("invalid replica address {:?}", addr));
         }
+        let mut config = self.clone();
+        config.host = host.to_string();
+        config.port = port.to_string();
+        config.replicas = Vec::new();
+        Ok(config)
+    }
+
+    pub fn tls_connector(&self) -> Result<MakeTlsConnector, String> {
+        db_connector(self.ssl_mode, self.ssl_root_cert.as_deref())
     }
 }
 
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
 pub struct TaoServer {
-    pub db_config: DBConfig,
-    pub tao_crypto: TaoCrypto,
+    pub store: Box<dyn GraphStore>,
+    pub namespaces: HashMap<String, Namespace>,
+    pub policy: Policy,
+    pub audit: AuditLog,
+    pub cache: GraphCache,
+    pub feed: Feed,
+    pub tier: Tier,
+    pub metrics: Metrics,
+    /// The server's keys, shared by namespaces without keys of their own
+    pub tao_crypto: Arc<TaoCrypto>,
     pub encrypted: bool,
+    pub read_your_writes: Duration,
+    pub timeouts: Timeouts,
+    pub limits: Limits,
+    pub rate_limiter: RateLimiter,
+    pub import_batch_size: usize,
+    pub shutdown: Shutdown,
 }
 
 impl TaoServer {
-    pub fn new(env_path: String, cache_size: usize, encrypted: bool) -> Self {
-        let db_config = DBConfig::new(&env_path);
-        let tao_crypto = TaoCrypto::new(&env_path, cache_size);
-        TaoServer {
-            db_config,
-            tao_crypto,
-            encrypted,
-        }
-    }
-
-    async fn db_connect(&self) -> Option<Client> {
-        let db_url = format!(
-            "host={} user={} password={} dbname={} port={}",
-            self.db_config.host,
-            self.db_config.user,
-            self.db_config.password,
-            self.db_config.db_name,
-            self.db_config.port
-        );
-        let (client, conn) = connect(db_url.as_str(), NoTls).await.unwrap();
-        tokio::spawn(async move {
-            if let Err(e) = conn.await {
-                println!("connection error: {}", e);
+    pub fn new(config: ServerConfig) -> Result<Self, String> {
+        let store: Box<dyn GraphStore> = match config.storage {
+            StorageBackend::Postgres => {
+                Box::new(PostgresStore::new(config.shards.clone())?)
             }
-        });
-        return Some(client);
-    }
-
-    async fn db_execute(&self, query: Query) -> Option<Vec<DBRow>> {
-        let res = match query.op {
-            TaoOp::AssocAdd => self.assoc_add(query).await,
-            TaoOp::AssocGet => self.assoc_get(query).await,
-            TaoOp::AssocRangeGet => self.assoc_range_get(query).await,
-            TaoOp::AssocCount => self.assoc_count(query).await,
-            TaoOp::AssocRange => self.assoc_range(query).await,
-            TaoOp::ObjAdd => self.obj_add(query).await,
-            TaoOp::ObjGet => self.obj_get(query).await,
-            _ => panic!("todo!"),
+            StorageBackend::Sqlite => Box::new(SqliteStore::open(
+                &config.sqlite_file,
+                &config.namespaces,
+            )?),
         };
-
-        return res;
+        TaoServer::with_store(config, store)
     }
 
-    pub async fn pipeline(&mut self, query_input: String) -> HttpResponse {
-        println!("Received Query: {:#?}", query_input);
-        let parsed_queries = parser::parse(query_input.as_str());
-        let tao_queries = match self.encrypted {
-            true => parsed_queries
-                .iter()
-                .map(|q| self.tao_crypto.encrypt_query(q.clone()))
-                .collect::<Vec<Query>>(),
-            false => parsed_queries,
+    /*
+     * with_store(config, store)
+     *      A server on any GraphStore, the shards in `config` are ignored.
+     */
+    pub fn with_store(
+        config: ServerConfig,
+        store: Box<dyn GraphStore>,
+    ) -> Result<Self, String> {
+        let key_files = (&config.ope_key_file, &config.aes_keyset_file);
+        let (keys, key_ids) = match key_files {
+            (Some(ope_key_file), Some(aes_keyset_file)) => {
+                let keys = CryptKeys::load(ope_key_file, aes_keyset_file)?;
+                let ids = keys.ids();
+                (keys, Some(ids))
+            }
+            _ => (CryptKeys::generate(), None),
         };
-        let response = join_all(
-            tao_queries
-                .iter()
-                .map(|q| async { self.db_execute(q.clone()).await.unwrap() }),
-        )
-        .await;
+        let tao_crypto = Arc::new(TaoCrypto::new(
+            keys,
+            config.aes_cache_size,
+            config.ope_cache_size,
+        ));
+        let mut namespaces = HashMap::new();
+        for ns_config in config.namespaces {
+            let namespace = Namespace::new(
+                ns_config,
+                &tao_crypto,
+                key_ids.as_ref(),
+                config.aes_cache_size,
+                config.ope_cache_size,
+            )?;
+            namespaces.insert(namespace.config.name.clone(), namespace);
+        }
+        let policy = match &config.policy_file {
+            Some(path) => Policy::load(path)?,
+            None => Policy::allow_all(),
+        };
+        let audit = match &config.audit_log_file {
+            Some(path) => AuditLog::open(path)?,
+            None => AuditLog::disabled(),
+        };
+        Ok(TaoServer {
+            store,
+            namespaces,
+            policy,
+            audit,
+            cache: GraphCache::new(
+                config.graph_cache_mb * 1024 * 1024,
+                config.graph_cache_max_assoc_list,
+            ),
+            feed: Feed::new(config.feed_buffer),
+            tier: Tier::new(
+                config.tier_role,
+                config.tier_leader_url,
+                config.tier_follower_urls,
+                config.tier_token,
+            ),
+            metrics: Metrics::new(),
+            tao_crypto,
+            encrypted: config.encrypted,
+            read_your_writes: Duration::from_secs(
+                config.read_your_writes_secs,
+            ),
+            timeouts: Timeouts::from_millis(
+                config.request_timeout_ms,
+                config.query_timeout_ms,
+            ),
+            limits: config.limits,
+            rate_limiter: RateLimiter::new(
+                config.rate_limit_qps,
+                config.rate_limit_burst,
+            ),
+            import_batch_size: config.import_batch_size,
+            shutdown: Shutdown::new(),
+        })
+    }
 
-        let results = match self.encrypted {
-            true => response
-                .into_iter()
-                .map(|rows| {
-                    rows
-                    .into_iter()
-                    .map(|row| self.tao_crypto.decrypt_result(row))
-                    .collect::<Vec<DBRow>>()
-                })
-                .collect::<Vec<Vec<DBRow>>>(),
-            false => response,
+    /*
+     * db_execute(ns, query, route, deadline)
+     *      Followers hand writes to the leader and drop what they cached for
+     *      them. The leader tells its followers after every write, and
+     *      publishes it to the change feed.
+     */
+    async fn db_execute(
+        &self,
+        ns: &NamespaceConfig,
+        query: Query,
+        route: Route,
+        deadline: Instant,
+    ) -> Result<Vec<DBRow>, TaoError> {
+        let invalidation = match Invalidation::of_write(&ns.name, &query) {
+            Some(invalidation) => invalidation,
+            None => {
+                return self.cached_execute(ns, query, route, deadline).await
+            }
         };
 
-        return HttpResponse::Ok().json(&QueryResponse { response: results });
+        match self.tier.role {
+            TierRole::Follower => {
+                let forwarded = self.tier.forward_write(&ns.name, query);
+                let res = deadline::within(deadline, forwarded).await;
+                self.cache.invalidate(&invalidation);
+                res
+            }
+            TierRole::Leader => {
+                let write = query.clone();
+                let res =
+                    self.cached_execute(ns, query, route, deadline).await;
+                self.tier.broadcast(&invalidation).await;
+                let event = res.as_ref().ok().and_then(|rows| {
+                    FeedEvent::of_write(&ns.name, &write, rows)
+                });
+                if let Some(event) = event {
+                    self.feed.publish(event);
+                }
+                res
+            }
+        }
     }
 
-    async fn assoc_add(&self, query: Query) -> Option<Vec<DBRow>> {
-        let client = self.db_connect().await.unwrap();
+    /*
+     * cached_execute(ns, query, route, deadline)
+     *      Reads go through the graph cache, writes update or invalidate it.
+     *      The query is already encrypted, so only ciphertext is cached.
+     *      Misses on keys written within the read-your-writes window are
+     *      filled from the primary, a replica may not have the write yet.
+     */
+    async fn cached_execute(
+        &self,
+        ns: &NamespaceConfig,
+        query: Query,
+        route: Route,
+        deadline: Instant,
+    ) -> Result<Vec<DBRow>, TaoError> {
+        if !self.cache.is_enabled() {
+            return self.db_dispatch(ns, query, route, deadline).await;
+        }
+
+        let window = self.read_your_writes;
+        match &query.args {
+            TaoArgs::ObjGetArgs { id } => {
+                if let Some(rows) = self.cache.get_obj(&ns.name, id) {
+                    return Ok(rows.to_vec());
+                }
+                let route =
+                    match self.cache.obj_written_within(&ns.name, id, window) {
+                        true => Route::Primary,
+                        false => route,
+                    };
+                let id = id.clone();
+                let epoch = self.cache.obj_epoch(&ns.name, &id);
+                let rows = self.db_dispatch(ns, query, route, deadline).await?;
+                self.cache.fill_obj(&ns.name, &id, rows.clone(), epoch);
+                Ok(rows)
+            }
+            TaoArgs::ObjAddArgs { id, otype, data } => {
+                let row = DBRow::ObjRow {
+                    id: id.clone(),
+                    otype: otype.clone(),
+                    data: data.clone(),
+                };
+                let id = id.clone();
+                let res = self.db_dispatch(ns, query, route, deadline).await;
+                match res {
+                    Ok(_) => self.cache.put_obj(&ns.name, &id, row),
+                    Err(_) => self.cache.invalidate_obj(&ns.name, &id),
+                }
+                res
+            }
+            TaoArgs::ObjUpdateArgs { id, .. }
+            | TaoArgs::ObjDeleteArgs { id } => {
+                let id = id.clone();
+                let res = self.db_dispatch(ns, query, route, deadline).await;
+                self.cache.invalidate_obj(&ns.name, &id);
+                res
+            }
+            TaoArgs::AssocAddArgs { id1, atype, .. }
+            | TaoArgs::AssocDeleteArgs { id1, atype, .. } => {
+                let (id1, atype) = (id1.clone(), atype.clone());
+                let res = self.db_dispatch(ns, query, route, deadline).await;
+                self.cache.invalidate_assoc_list(&ns.name, &id1, &atype);
+                res
+            }
+            TaoArgs::AssocGetArgs { id, atype, .. }
+            | TaoArgs::AssocRangeGetArgs { id, atype, .. }
+            | TaoArgs::AssocCountArgs { id, atype }
+            | TaoArgs::AssocRangeArgs { id, atype, .. } => {
+                let list = match self.cache.get_assoc_list(&ns.name, id, atype)
+                {
+                    Some(list) => list,
+                    None => {
+                        let written = self.cache.assoc_list_written_within(
+                            &ns.name, id, atype, window,
+                        );
+                        let route = match written {
+                            true => Route::Primary,
+                            false => route,
+                        };
+                        let epoch =
+                            self.cache.assoc_list_epoch(&ns.name, id, atype);
+                        let list = self
+                            .assoc_list(ns, id, atype, route, deadline)
+                            .await?;
+                        if list.len() > self.cache.max_assoc_list() {
+                            return self
+                                .db_dispatch(ns, query, route, deadline)
+                                .await;
+                        }
+                        self.cache.fill_assoc_list(
+                            &ns.name,
+                            id,
+                            atype,
+                            list.clone(),
+                            epoch,
+                        );
+                        Arc::new(list)
+                    }
+                };
+                match read_assoc_list(&list, &query.args) {
+                    Some(rows) => Ok(rows),
+                    None => self.db_dispatch(ns, query, route, deadline).await,
+                }
+            }
+        }
+    }
 
-        let sql_query = "INSERT INTO assoc_test(id1, atype, id2, t, data) \
-                         VALUES ($1, $2, $3, $4, $5)";
+    /*
+     * db_dispatch(ns, query, route, deadline)
+     *      Runs a query on the store in a "db" span, until its deadline.
+     */
+    async fn db_dispatch(
+        &self,
+        ns: &NamespaceConfig,
+        query: Query,
+        route: Route,
+        deadline: Instant,
+    ) -> Result<Vec<DBRow>, TaoError> {
+        let span = db_span(&ns.name, &query.op, route);
+        let op = query.op.clone();
+        let started = Instant::now();
+        let call = self.store_dispatch(ns, query, route);
+        let res = deadline::within(deadline, call)
+            .instrument(span.clone())
+            .await;
+        self.metrics.observe_db(&op, started.elapsed());
+        record_rows(&span, &res);
+        res
+    }
 
-        let (id1, ty, id2, time, data) = match query.args {
+    /*
+     * store_dispatch(ns, query, route)
+     *      Runs a query on the store, writes ignore the route. Updates and
+     *      deletes answer with the row they changed, if there was one.
+     */
+    async fn store_dispatch(
+        &self,
+        ns: &NamespaceConfig,
+        query: Query,
+        route: Route,
+    ) -> Result<Vec<DBRow>, TaoError> {
+        let store = &self.store;
+        match query.args {
             TaoArgs::AssocAddArgs {
                 id1,
                 atype,
                 id2,
                 time,
                 data,
-            } => (id1, atype, id2, time, data),
-            _ => panic!("Incorrect args to assoc add"),
-        };
-
-        let resp = &client
-            .query(
-                sql_query,
-                &[&id1, &ty.as_str(), &id2, &time, &data.as_str()],
-            )
-            .await
-            .unwrap();
-
-        let res = deserialize_rows(&query.op, resp);
-        return Some(res);
-    }
-
-    async fn assoc_get(&self, query: Query) -> Option<Vec<DBRow>> {
-        let client = self.db_connect().await.unwrap();
-
-        let (id, ty, idset) = match query.args {
-            TaoArgs::AssocGetArgs { id, atype, idset } => (id, atype, idset),
-            _ => panic!("Incorrect args to assoc get"),
-        };
-
-        let in_set = format_in_clause(&idset, 2);
-        let sql_query = format!(
-            "SELECT * \
-             FROM assoc_test \
-             WHERE id1 = $1 \
-             AND atype = $2 \
-             AND id2 in {in_set}"
-        );
-
-        let idset: Vec<_> =
-            idset.iter().map(|x| x as &(dyn ToSql + Sync)).collect();
-
-        let mut params =
-            vec![&id as &(dyn ToSql + Sync), &ty as &(dyn ToSql + Sync)];
-        params.extend(idset);
-        let resp = &client.query(&sql_query, &params).await.unwrap();
-
-        let res = deserialize_rows(&query.op, resp);
-        return Some(res);
-    }
-
-    async fn assoc_range_get(&self, query: Query) -> Option<Vec<DBRow>> {
-        let client = self.db_connect().await.unwrap();
-
-        let (id, ty, idset, tstart, tend) = match query.args {
+            } => store
+                .assoc_add(ns, &id1, &atype, &id2, time, &data)
+                .await
+                .map(|_| Vec::new()),
+            TaoArgs::AssocGetArgs { id, atype, idset } => {
+                store.assoc_get(ns, &id, &atype, &idset, None, route).await
+            }
             TaoArgs::AssocRangeGetArgs {
                 id,
                 atype,
                 idset,
                 tstart,
                 tend,
-            } => (id, atype, idset, tstart, tend),
-            _ => panic!("Incorrect args to assoc get"),
-        };
-
-        let in_set = format_in_clause(&idset, 4);
-        let sql_query = format!(
-            "SELECT * \
-             FROM assoc_test \
-             WHERE id1 = $1 \
-             AND atype = $2 \
-             AND t >= $3 \
-             AND t <= $4 \
-             AND id2 in {in_set}"
-        );
-
-        let idset: Vec<_> =
-            idset.iter().map(|x| x as &(dyn ToSql + Sync)).collect();
-
-        let mut params = vec![
-            &id as &(dyn ToSql + Sync),
-            &ty as &(dyn ToSql + Sync),
-            &tstart as &(dyn ToSql + Sync),
-            &tend as &(dyn ToSql + Sync),
-        ];
-        params.extend(idset);
-
-        let resp = &client.query(&sql_query, &params).await.unwrap();
-
-        let res = deserialize_rows(&query.op, resp);
-        return Some(res);
-    }
-
-    async fn assoc_count(&self, query: Query) -> Option<Vec<DBRow>> {
-        let client = self.db_connect().await.unwrap();
-        let sql_query = "SELECT COUNT(*) \
-                     FROM assoc_test \
-                     WHERE id1 = $1 \
-                       AND atype = $2";
-
-        let (id, atype) = match query.args {
-            TaoArgs::AssocCountArgs { id, atype } => (id, atype),
-            _ => panic!("Incorrect args to obj get"),
-        };
-        // here !
-        let resp = &client
-            .query(sql_query, &[&id.as_str(), &atype.as_str()])
-            .await
-            .unwrap();
-
-        let res = deserialize_rows(&query.op, resp);
-        return Some(res);
-    }
-    
-    async fn assoc_range(&self, query: Query) -> Option<Vec<DBRow>> {
-        let client = self.db_connect().await.unwrap();
-
-        let sql_query = "SELECT * \
-                     FROM assoc_test \
-                     WHERE id1 = $1 \
-                       AND atype = $2 \
-                       AND t >= $3 \
-                       AND t <= $4 \
-                     ORDER BY t DESC \
-                     LIMIT $5";
-
-        let (id, atype, tstart, tend, lim) = match query.args {
+            } => {
+                let range = Some((tstart, tend));
+                store.assoc_get(ns, &id, &atype, &idset, range, route).await
+            }
+            TaoArgs::AssocCountArgs { id, atype } => store
+                .assoc_count(ns, &id, &atype, route)
+                .await
+                .map(|count| vec![DBRow::Count(count)]),
             TaoArgs::AssocRangeArgs {
                 id,
                 atype,
                 tstart,
                 tend,
                 lim,
-            } => (id, atype, tstart, tend, lim),
-            _ => panic!("Incorrect args to obj get"),
+            } => {
+                store
+                    .assoc_range(ns, &id, &atype, tstart, tend, lim, route)
+                    .await
+            }
+            TaoArgs::ObjAddArgs { id, otype, data } => store
+                .obj_add(ns, &id, &otype, &data)
+                .await
+                .map(|_| Vec::new()),
+            TaoArgs::ObjGetArgs { id } => store
+                .obj_get(ns, &id, route)
+                .await
+                .map(|row| row.into_iter().collect()),
+            TaoArgs::AssocDeleteArgs { id1, atype, id2 } => store
+                .assoc_delete(ns, &id1, &atype, &id2)
+                .await
+                .map(|row| row.into_iter().collect()),
+            TaoArgs::ObjUpdateArgs { id, otype, data } => {
+                let updated = store.obj_update(ns, &id, &otype, &data).await?;
+                let row = DBRow::ObjRow { id, otype, data };
+                Ok(updated.then_some(row).into_iter().collect())
+            }
+            TaoArgs::ObjDeleteArgs { id } => store
+                .obj_delete(ns, &id)
+                .await
+                .map(|row| row.into_iter().collect()),
+        }
+    }
+
+    /*
+     * assoc_list(ns, id1, atype, route, deadline)
+     *      Loads the association list the cache keeps for (id1, atype),
+     *      stopping one past the longest list the cache will hold.
+     */
+    async fn assoc_list(
+        &self,
+        ns: &NamespaceConfig,
+        id1: &str,
+        atype: &str,
+        route: Route,
+        deadline: Instant,
+    ) -> Result<Vec<DBRow>, TaoError> {
+        let limit = self.cache.max_assoc_list() as i64 + 1;
+        let span = db_span(&ns.name, &TaoOp::AssocRange, route);
+        let started = Instant::now();
+        let call = self.store.assoc_range(
+            ns,
+            id1,
+            atype,
+            i64::MIN,
+            i64::MAX,
+            limit,
+            route,
+        );
+        let res = deadline::within(deadline, call)
+            .instrument(span.clone())
+            .await;
+        self.metrics.observe_db(&TaoOp::AssocRange, started.elapsed());
+        record_rows(&span, &res);
+        res
+    }
+
+    /*
+     * resolve_namespace(header, prefix)
+     *      The X-Tao-Namespace header and a `USE name;` prefix may both be
+     *      given, but must then agree.
+     */
+    pub fn resolve_namespace(
+        &self,
+        header: Option<&str>,
+        prefix: Option<&str>,
+    ) -> Result<&Namespace, TaoError> {
+        let name = match (header, prefix) {
+            (Some(h), Some(p)) if h != p => {
+                return Err(TaoError::Validation(format!(
+                    "{} {} conflicts with USE {}",
+                    NAMESPACE_HEADER, h, p
+                )))
+            }
+            (Some(name), _) | (None, Some(name)) => name,
+            (None, None) => DEFAULT_NAMESPACE,
         };
+        self.namespaces.get(name).ok_or_else(|| {
+            TaoError::Validation(format!("unknown namespace {}", name))
+        })
+    }
 
-        let resp = &client
-            .query(sql_query, &[&id.as_str(), &atype.as_str(), &tstart, &tend, &lim])
-            .await
-            .unwrap();
+    /*
+     * read_route(session)
+     *      Clients that wrote within the read-your-writes window read from
+     *      the primaries, everyone else from the replicas.
+     */
+    pub fn read_route(&self, session: Option<u64>) -> Route {
+        let written = match session {
+            Some(written) => written,
+            None => return Route::Replica,
+        };
+        match unix_millis().saturating_sub(written)
+            < self.read_your_writes.as_millis() as u64
+        {
+            true => Route::Primary,
+            false => Route::Replica,
+        }
+    }
 
-        let res = deserialize_rows(&query.op, resp);
-        return Some(res);
+    /*
+     * admit(principal, queries, args)
+     *      Turns away a batch of `queries` over the limits or the caller's
+     *      rate, see limits.rs. `args` are those of the queries that parsed.
+     */
+    pub fn admit(
+        &self,
+        principal: &Principal,
+        queries: usize,
+        args: &[&TaoArgs],
+    ) -> Result<(), Rejected> {
+        let admitted = self
+            .limits
+            .check_batch(queries)
+            .and_then(|_| {
+                args.iter().try_for_each(|a| self.limits.check_query(a))
+            })
+            .and_then(|_| self.rate_limiter.take(&principal.name, queries));
+        if let Err(rejected) = &admitted {
+            warn!(
+                principal = %principal.name,
+                reason = rejected.kind(),
+                "request rejected"
+            );
+        }
+        admitted
     }
 
-    async fn obj_get(&self, query: Query) -> Option<Vec<DBRow>> {
-        let client = self.db_connect().await.unwrap();
+    /*
+     * pipeline(principal, query_input, namespace, session, timeouts)
+     *      Runs a batch of queries side by side, each until its own deadline
+     *      or that of the batch.
+     */
+    pub async fn pipeline(
+        &self,
+        principal: &Principal,
+        query_input: String,
+        namespace: Option<String>,
+        session: Option<u64>,
+        timeouts: Timeouts,
+    ) -> HttpResponse {
+        let _running = self.shutdown.enter();
+        let deadlines = timeouts.start();
+        let (use_namespace, query_input) =
+            parser::split_namespace(query_input.as_str());
+        let namespace =
+            self.resolve_namespace(namespace.as_deref(), use_namespace);
+        let parsed_queries = info_span!("parse")
+            .in_scope(|| parser::parse_batch(query_input));
+        info!(
+            queries = parsed_queries.len(),
+            principal = %principal.name,
+            namespace = namespace.as_ref().map_or("", |ns| &ns.config.name),
+            "received queries"
+        );
+        let args = parsed_queries
+            .iter()
+            .flatten()
+            .map(|q| &q.args)
+            .collect::<Vec<&TaoArgs>>();
+        if let Err(rejected) =
+            self.admit(principal, parsed_queries.len(), &args)
+        {
+            return rejected.response();
+        }
+        let route = self.read_route(session);
+        let results = join_all(parsed_queries.into_iter().map(|q| {
+            let span = match &q {
+                Ok(q) => info_span!("query", op = ?q.op, error = Empty),
+                Err(_) => info_span!("query", error = Empty),
+            };
+            let query = async {
+                let ns = namespace.as_ref().map_err(|e| e.clone())?;
+                let q = q?;
+                let write = q.is_write();
+                let deadline = deadlines.query();
+                let rows =
+                    self.run_query(principal, ns, q, route, deadline).await?;
+                Ok((write, rows))
+            };
+            async move {
+                let res: Result<_, TaoError> =
+                    query.instrument(span.clone()).await;
+                if let Err(e) = &res {
+                    span.record("error", e.kind());
+                    warn!(parent: &span, error = e.kind(), "query failed");
+                }
+                res
+            }
+        }))
+        .await;
 
-        let sql_query = "SELECT * \
-                         FROM obj_test \
-                         WHERE id = $1";
+        let wrote = results.iter().any(|r| matches!(r, Ok((true, _))));
+        let results = results
+            .into_iter()
+            .map(|r| r.map(|(_, rows)| rows))
+            .collect::<Vec<QueryResult>>();
+        let mut resp = HttpResponse::Ok();
+        if wrote {
+            resp.insert_header((SESSION_HEADER, unix_millis().to_string()));
+        }
+        resp.json(&QueryResponse { response: results })
+    }
 
-        let id = match query.args {
-            TaoArgs::ObjGetArgs { id } => id,
-            _ => panic!("Incorrect args to obj get"),
+    /*
+     * run_query(principal, ns, query, route, deadline)
+     *      Runs one parsed query for a caller and returns the rows it may
+     *      see, as POST /query and the gRPC API do for each of theirs.
+     */
+    pub async fn run_query(
+        &self,
+        principal: &Principal,
+        ns: &Namespace,
+        query: Query,
+        route: Route,
+        deadline: Instant,
+    ) -> QueryResult {
+        let op = query.op.clone();
+        let started = Instant::now();
+        let executed = self.execute(principal, ns, query, route, deadline);
+        let res = match executed.await {
+            Ok(rows) => self.policy.check_rows(principal, &rows).map(|_| rows),
+            Err(e) => Err(e),
         };
+        self.metrics.observe_query(&op, &res, started.elapsed());
+        res
+    }
 
-        let resp = &client.query(sql_query, &[&id.as_str()]).await.unwrap();
-
-        let res = deserialize_rows(&query.op, resp);
-        
-        return Some(res);
+    /*
+     * render_metrics()
+     *      The Prometheus metrics, with the crypto caches of the server's
+     *      keys labelled "server" and those of namespaces with their own
+     *      keys by namespace.
+     */
+    pub fn render_metrics(&self) -> String {
+        let mut crypto = Vec::new();
+        if self.encrypted {
+            crypto.push(("server".to_string(), self.tao_crypto.stats()));
+            let mut own_keys = self
+                .namespaces
+                .values()
+                .filter(|ns| !Arc::ptr_eq(&ns.tao_crypto, &self.tao_crypto))
+                .map(|ns| (ns.config.name.clone(), ns.tao_crypto.stats()))
+                .collect::<Vec<_>>();
+            own_keys.sort_by(|a, b| a.0.cmp(&b.0));
+            crypto.extend(own_keys);
+        }
+        self.metrics.render(&Scrape {
+            pools: &self.store.pools(),
+            graph_cache: &self.cache.stats(),
+            crypto: &crypto,
+        })
     }
 
-    async fn obj_add(&self, query: Query) -> Option<Vec<DBRow>> {
-        let client = self.db_connect().await.unwrap();
+    /*
+     * feed_event(principal, ns, mode, event)
+     *      The data line of a change feed event, None when the subscriber
+     *      may not see it.
+     */
+    fn feed_event(
+        &self,
+        principal: &Principal,
+        ns: &Namespace,
+        mode: FeedMode,
+        event: &FeedEvent,
+    ) -> Option<String> {
+        let row = match (self.encrypted, mode) {
+            (true, FeedMode::Plaintext) => {
+                ns.tao_crypto.decrypt_result(event.row.clone()).ok()?
+            }
+            _ => event.row.clone(),
+        };
+        if mode == FeedMode::Plaintext {
+            self.policy
+                .check_rows(principal, std::slice::from_ref(&row))
+                .ok()?;
+        }
+        serde_json::to_string(&EventData {
+            op: &event.op,
+            row: &row,
+        })
+        .ok()
+    }
 
-        let sql_query = "INSERT INTO obj_test(id, otype, data) \
-                         VALUES ($1, $2, $3)";
+    /*
+     * feed_topics(principal, ns, params)
+     *      Subscriptions are policy checked like the reads they stand for,
+     *      and encrypted to match the stored ids.
+     */
+    fn feed_topics(
+        &self,
+        principal: &Principal,
+        ns: &Namespace,
+        params: &FeedParams,
+    ) -> Result<HashSet<Topic>, TaoError> {
+        let queries = feed::subscriptions(
+            params.assocs.as_deref(),
+            params.objs.as_deref(),
+        )?;
+        let mut topics = HashSet::new();
+        for query in queries {
+            self.policy.check(principal, &query)?;
+            let query = match self.encrypted {
+                true => ns.tao_crypto.encrypt_query(query)?,
+                false => query,
+            };
+            topics.insert(Topic::of(&query.args));
+        }
+        Ok(topics)
+    }
 
-        let (id, ty, data) = match query.args {
-            TaoArgs::ObjAddArgs { id, otype, data } => (id, otype, data),
-            _ => panic!("Incorrect args to obj add"),
+    /*
+     * execute(principal, ns, query, route, deadline)
+     *      Writes are audited once their outcome is known, denied ones
+     *      included. The policy runs on the plaintext query, the audit log
+     *      gets the encrypted one. A denied write is answered as denied,
+     *      also when it would not have encrypted. A write that cannot be
+     *      recorded fails, and once the audit log failed no write runs.
+     */
+    async fn execute(
+        &self,
+        principal: &Principal,
+        ns: &Namespace,
+        query: Query,
+        route: Route,
+        deadline: Instant,
+    ) -> QueryResult {
+        let allowed = self.policy.check(principal, &query);
+        let audited = query.is_write() && self.audit.is_enabled();
+        let op = query.op.clone();
+        let tao_query = match self.encrypted {
+            true => info_span!("encrypt")
+                .in_scope(|| ns.tao_crypto.encrypt_query(query)),
+            false => Ok(query),
         };
+        let (stored, res, ran) = match (allowed, tao_query) {
+            (Err(e), tao_query) => (tao_query.ok(), Err(e), false),
+            (Ok(()), Err(e)) => (None, Err(e), false),
+            (Ok(()), Ok(tao_query)) => {
+                if audited {
+                    self.audit.check().map_err(unaudited)?;
+                }
+                let stored = audited.then(|| tao_query.clone());
+                let res = self
+                    .db_execute(&ns.config, tao_query, route, deadline)
+                    .await;
+                (stored, res, true)
+            }
+        };
+        if audited {
+            let (name, ns_name) = (&principal.name, &ns.config.name);
+            let recorded = match &stored {
+                Some(query) => self.audit.record(name, ns_name, query, &res),
+                None => self.audit.record_op(name, ns_name, &op, &res),
+            };
+            if let Err(e) = recorded {
+                error!(error = %e, "cannot write to the audit log");
+                // Nothing was written unless the store ran the query
+                if ran {
+                    return Err(unaudited(e));
+                }
+            }
+        }
+        let rows = res?;
 
-        let resp = &client
-            .query(sql_query, &[&id.as_str(), &ty.as_str(), &data.as_str()])
-            .await
-            .unwrap();
-
-        let res = deserialize_rows(&query.op, resp);
-        return Some(res);
+        match self.encrypted {
+            true => info_span!("decrypt", rows = rows.len()).in_scope(|| {
+                rows.into_iter()
+                    .map(|row| ns.tao_crypto.decrypt_result(row))
+                    .collect::<QueryResult>()
+            }),
+            false => Ok(rows),
+        }
     }
 }
 
+/*
+ * db_span(namespace, op, route)
+ *      The span of one store call. It records how many rows came back, or
+ *      the kind of error, but never their contents.
+ */
+fn db_span(namespace: &str, op: &TaoOp, route: Route) -> Span {
+    info_span!(
+        "db",
+        op = ?op,
+        namespace,
+        route = ?route,
+        rows = Empty,
+        error = Empty
+    )
+}
+
+fn record_rows(span: &Span, res: &Result<Vec<DBRow>, TaoError>) {
+    match res {
+        Ok(rows) => span.record("rows", rows.len()),
+        Err(e) => span.record("error", e.kind()),
+    };
+}
+
 #[get("/")]
 async fn hello() -> impl Responder {
     HttpResponse::Ok().json("TAO Server")
 }
 
+/*
+ * ready_handler
+ *      503 once the server is draining or its audit log failed, so that it
+ *      gets no new traffic.
+ */
+#[get("/ready")]
+async fn ready_handler(tao: Data<TaoServer>) -> HttpResponse {
+    let mut readiness = tao.shutdown.readiness();
+    // Writes fail once the audit log does
+    readiness.ready &= tao.audit.check().is_ok();
+    match readiness.ready {
+        true => HttpResponse::Ok().json(&readiness),
+        false => HttpResponse::ServiceUnavailable().json(&readiness),
+    }
+}
+
+#[get("/pool")]
+async fn pool_handler(tao: Data<TaoServer>) -> HttpResponse {
+    HttpResponse::Ok().json(tao.store.pools())
+}
+
+#[get("/metrics")]
+async fn metrics_handler(tao: Data<TaoServer>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(metrics::CONTENT_TYPE)
+        .body(tao.render_metrics())
+}
+
+#[get("/cache")]
+async fn cache_handler(tao: Data<TaoServer>) -> HttpResponse {
+    HttpResponse::Ok().json(tao.cache.stats())
+}
+
+/*
+ * tier_write_handler
+ *      Followers forward their writes here, already encrypted.
+ */
+#[post("/tier/write")]
+async fn tier_write_handler(
+    tao: Data<TaoServer>,
+    req: HttpRequest,
+    write: Json<WriteRequest>,
+) -> HttpResponse {
+    if !tao.tier.authorized(&req) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    if tao.tier.role != TierRole::Leader {
+        return HttpResponse::Conflict().json("not the leader");
+    }
+    let WriteRequest { namespace, query } = write.into_inner();
+    if Invalidation::of_write(&namespace, &query).is_none() {
+        return HttpResponse::BadRequest().json("not a write");
+    }
+    let deadline = tao.timeouts.start().query();
+    let result: QueryResult = match tao.namespaces.get(&namespace) {
+        Some(ns) => {
+            tao.db_execute(&ns.config, query, Route::Primary, deadline)
+                .await
+        }
+        None => Err(TaoError::Validation(format!(
+            "unknown namespace {}",
+            namespace
+        ))),
+    };
+    HttpResponse::Ok().json(&result)
+}
+
+#[post("/tier/invalidate")]
+async fn tier_invalidate_handler(
+    tao: Data<TaoServer>,
+    req: HttpRequest,
+    invalidation: Json<Invalidation>,
+) -> HttpResponse {
+    if !tao.tier.authorized(&req) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    tao.cache.invalidate(&invalidation);
+    HttpResponse::NoContent().finish()
+}
+
+/*
+ * feed_handler
+ *      Server-sent events for the subscriptions in the query string, see
+ *      feed.rs. Only the leader publishes events, so followers answer 409.
+ */
+#[get("/feed")]
+async fn feed_handler(
+    principal: Principal,
+    tao: Data<TaoServer>,
+    req: HttpRequest,
+    params: web::Query<FeedParams>,
+) -> HttpResponse {
+    if tao.tier.role != TierRole::Leader {
+        return HttpResponse::Conflict().json("not the leader");
+    }
+    let params = params.into_inner();
+    let header = req
+        .headers()
+        .get(NAMESPACE_HEADER)
+        .and_then(|v| v.to_str().ok());
+    let subscription = tao
+        .resolve_namespace(header, params.namespace.as_deref())
+        .and_then(|ns| {
+            let topics = tao.feed_topics(&principal, ns, &params)?;
+            Ok((ns.config.name.clone(), topics))
+        });
+    let (namespace, topics) = match subscription {
+        Ok(subscription) => subscription,
+        Err(e @ TaoError::Forbidden(_)) => {
+            return HttpResponse::Forbidden().json(e.to_string())
+        }
+        Err(e) => return HttpResponse::BadRequest().json(e.to_string()),
+    };
+
+    let receiver = tao.feed.subscribe();
+    let server = tao.clone();
+    let name = namespace.clone();
+    let stream = event_stream(receiver, topics, namespace, move |event| {
+        let ns = server.namespaces.get(&name)?;
+        server.feed_event(&principal, ns, params.mode, event)
+    })
+    // Subscribers reconnect to another server rather than hold up shutdown
+    .take_until(tao.shutdown.draining());
+    HttpResponse::Ok()
+        .insert_header((CONTENT_TYPE, "text/event-stream"))
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .streaming(stream)
+}
+
+/*
+ * import_handler
+ *      Bulk import of the records in the body, see import.rs. The report
+ *      comes with 500 when the import stopped early. Bodies over
+ *      MAX_IMPORT_BYTES are refused if their length says so, and stopped
+ *      once they are over it otherwise.
+ */
+#[post("/import")]
+async fn import_handler(
+    principal: Principal,
+    tao: Data<TaoServer>,
+    req: HttpRequest,
+    params: web::Query<ImportParams>,
+    body: web::Payload,
+) -> HttpResponse {
+    if tao.tier.role != TierRole::Leader {
+        return HttpResponse::Conflict().json("not the leader");
+    }
+    let header = req
+        .headers()
+        .get(NAMESPACE_HEADER)
+        .and_then(|v| v.to_str().ok());
+    let ns = match tao.resolve_namespace(header, params.namespace.as_deref()) {
+        Ok(ns) => ns,
+        Err(e) => return HttpResponse::BadRequest().json(e.to_string()),
+    };
+    let length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    let admitted = length
+        .map_or(Ok(()), |length| tao.limits.check_import(length))
+        .and_then(|_| tao.admit(&principal, 1, &[]));
+    if let Err(rejected) = admitted {
+        return rejected.response();
+    }
+    let _running = tao.shutdown.enter();
+    let options = ImportOptions {
+        format: params.format,
+        skip: params.skip,
+        batch_size: tao.import_batch_size,
+    };
+    let body = import::capped(body, tao.limits.max_import_bytes);
+    let lines = import::lines(body, tao.limits.max_body_bytes);
+    let report =
+        import::import(&tao, Some(&principal), ns, options, lines, |_, _| {
+            Ok(())
+        })
+        .await;
+    match report.error {
+        Some(_) => HttpResponse::InternalServerError().json(&report),
+        None => HttpResponse::Ok().json(&report),
+    }
+}
+
 #[post("/query")]
 pub async fn query_handler(
-    tao: Data<Mutex<TaoServer>>,
+    principal: Principal,
+    tao: Data<TaoServer>,
+    req: HttpRequest,
     query: Json<QueryRequest>,
 ) -> HttpResponse {
-    let res = tao.lock().unwrap().pipeline(query.into_inner().query).await;
-    return res;
+    let namespace = req
+        .headers()
+        .get(NAMESPACE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string());
+    let session = req
+        .headers()
+        .get(SESSION_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    let timeout = |name: &str| {
+        req.headers()
+            .get(name)
+            .map(|v| parse_millis(name, v.to_str().unwrap_or("")))
+            .transpose()
+    };
+    let asked = (timeout(TIMEOUT_HEADER), timeout(QUERY_TIMEOUT_HEADER));
+    let timeouts = match asked {
+        (Ok(request), Ok(query)) => tao.timeouts.limit(request, query),
+        (Err(e), _) | (_, Err(e)) => {
+            return HttpResponse::BadRequest().json(e.to_string())
+        }
+    };
+    let query = query.into_inner().query;
+    tao.pipeline(&principal, query, namespace, session, timeouts)
+        .await
 }
 
 pub fn config(cfg: &mut ServiceConfig) {
-    cfg.service(scope("").service(hello).service(query_handler));
+    cfg.service(
+        scope("")
+            .service(hello)
+            .service(ready_handler)
+            .service(pool_handler)
+            .service(cache_handler)
+            .service(metrics_handler)
+            .service(tier_write_handler)
+            .service(tier_invalidate_handler)
+            .service(feed_handler)
+            .service(import_handler)
+            .service(query_handler),
+    );
 }
 
 /*
@@ -354,17 +1136,307 @@ pub fn config(cfg: &mut ServiceConfig) {
 #[cfg(test)]
 mod tests {
 
-    use crate::ope::ope::ope::Range;
-    use crate::ope::ope::ope::OPE;
-    use crate::query::{
-        parser,
-        query::{format_in_clause, Query, TaoArgs, TaoOp},
-        results::{deserialize_rows, DBRow},
-    };
+    use crate::query::{parser, query::TaoArgs, results::DBRow};
+    use actix_web::body::to_bytes;
+
+    use std::sync::{Arc, Mutex};
+
+    use tracing_subscriber::fmt::format::FmtSpan;
+
+    use super::{QueryResponse, QueryResult, TaoServer};
+    use crate::query::error::TaoError;
+    use crate::service::audit::{self, AuditLog};
+    use crate::service::auth::Principal;
+    use crate::service::config::{ServerConfig, StorageBackend};
+    use crate::service::namespace::DEFAULT_NAMESPACE;
+    use crate::service::policy::Policy;
+    use crate::service::shard::Route;
+    use crate::service::store::MemoryStore;
+    use crate::service::testing::{self, TempDir};
 
     #[test]
     fn test_assoc_get() {
         let query_input = "ASSOC RANGE 55 AUTHORED 0 100 10;".to_string();
         let tao_queries = parser::parse(query_input.as_str());
+        assert!(matches!(
+            &tao_queries[..],
+            [q] if matches!(&q.args, TaoArgs::AssocRangeArgs {
+                id, tstart: 0, tend: 100, lim: 10, ..
+            } if id == "55")
+        ));
+    }
+
+    #[test]
+    fn test_server_is_shareable() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<super::TaoServer>();
+        assert_send_sync::<crate::query::crypto::TaoCrypto>();
+    }
+
+    /*
+     * Pipeline tests
+     *      Whole queries through parsing, encryption, the graph cache and
+     *      decryption, on the in-memory store.
+     */
+    fn config(encrypted: bool, cache_mb: u64, dir: &TempDir) -> ServerConfig {
+        let keys = encrypted.then_some("pipeline");
+        let mut config = testing::config(keys, dir);
+        config.graph_cache_mb = cache_mb;
+        config
+    }
+
+    fn server(encrypted: bool, cache_mb: u64) -> TaoServer {
+        let dir = TempDir::new("keys");
+        let config = config(encrypted, cache_mb, &dir);
+        TaoServer::with_store(config, Box::new(MemoryStore::new())).unwrap()
+    }
+
+    async fn run(tao: &TaoServer, query: &str) -> Vec<QueryResult> {
+        let principal = Principal {
+            name: "test".to_string(),
+        };
+        let resp = tao
+            .pipeline(&principal, query.to_string(), None, None, tao.timeouts)
+            .await;
+        let body = to_bytes(resp.into_body()).await.unwrap();
+        serde_json::from_slice::<QueryResponse>(&body)
+            .unwrap()
+            .response
+    }
+
+    async fn status(tao: &TaoServer, query: &str) -> u16 {
+        let principal = Principal {
+            name: "test".to_string(),
+        };
+        let resp = tao
+            .pipeline(&principal, query.to_string(), None, None, tao.timeouts)
+            .await;
+        resp.status().as_u16()
+    }
+
+    fn ids(result: &QueryResult) -> Vec<String> {
+        result
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|row| match row {
+                DBRow::AssocRow { id2, .. } => id2.clone(),
+                DBRow::ObjRow { data, .. } => data.clone(),
+                other => format!("{:?}", other),
+            })
+            .collect()
+    }
+
+    async fn check_pipeline(tao: &TaoServer) {
+        let writes = run(
+            tao,
+            r#"OBJ ADD 1 USER "alice"; ASSOC ADD 1 LIKES 10 100 "a";
+               ASSOC ADD 1 LIKES 20 200 "b"; ASSOC ADD 1 LIKES 30 300 "c";"#,
+        )
+        .await;
+        assert!(writes.iter().all(|r| r.is_ok()), "{:?}", writes);
+        assert!(run(tao, r#"OBJ ADD 1 USER "bob";"#).await[0].is_err());
+
+        // Twice, so that the second round is served by the cache if any
+        for _ in 0..2 {
+            let reads = run(
+                tao,
+                "OBJ GET 1; OBJ GET 2; ASSOC RANGE 1 LIKES 50 1000 2; \
+                 ASSOC COUNT 1 LIKES; ASSOC GET 1 LIKES [20, 40]; \
+                 ASSOC RGET 1 LIKES [10, 30] 150 400;",
+            )
+            .await;
+            assert_eq!(ids(&reads[0]), ["alice"]);
+            assert!(ids(&reads[1]).is_empty());
+            assert_eq!(ids(&reads[2]), ["30", "20"]);
+            assert_eq!(ids(&reads[3]), ["Count(3)"]);
+            assert_eq!(ids(&reads[4]), ["20"]);
+            assert_eq!(ids(&reads[5]), ["30"]);
+        }
+
+        run(tao, r#"ASSOC ADD 1 LIKES 40 400 "d";"#).await;
+        let reads = run(tao, "ASSOC RANGE 1 LIKES 50 1000 10;").await;
+        assert_eq!(ids(&reads[0]), ["40", "30", "20", "10"]);
+
+        // Bounds past the encryptable times match as they do in plaintext
+        run(
+            tao,
+            r#"ASSOC ADD 1 FRIEND 50 1 "e"; ASSOC ADD 1 FRIEND 60 65534 "f";"#,
+        )
+        .await;
+        let reads = run(
+            tao,
+            "ASSOC RANGE 1 FRIEND 70000 80000 10; ASSOC RANGE 1 FRIEND 0 0 10; \
+             ASSOC RANGE 1 FRIEND 0 70000 10; ASSOC RANGE 1 FRIEND 9 3 10;",
+        )
+        .await;
+        assert!(ids(&reads[0]).is_empty());
+        assert!(ids(&reads[1]).is_empty());
+        assert_eq!(ids(&reads[2]), ["60", "50"]);
+        assert!(ids(&reads[3]).is_empty());
+
+        run(tao, r#"OBJ ADD 3 USER "carol"; OBJ GET 3;"#).await;
+        let writes = run(
+            tao,
+            r#"OBJ UPDATE 3 USER "dave"; OBJ UPDATE 9 USER "nobody";
+               ASSOC DELETE 1 LIKES 20; ASSOC DELETE 1 LIKES 99;"#,
+        )
+        .await;
+        let changed = writes.iter().map(ids).collect::<Vec<_>>();
+        assert_eq!(changed, [vec!["dave"], vec![], vec!["20"], vec![]]);
+        let reads =
+            run(tao, "OBJ GET 3; ASSOC RANGE 1 LIKES 50 1000 10;").await;
+        assert_eq!(ids(&reads[0]), ["dave"]);
+        assert_eq!(ids(&reads[1]), ["40", "30", "10"]);
+        let deleted = run(tao, "OBJ DELETE 3; OBJ GET 3;").await;
+        assert_eq!(ids(&deleted[0]), ["dave"]);
+        assert!(ids(&deleted[1]).is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_plaintext_pipeline() {
+        let tao = server(false, 0);
+        check_pipeline(&tao).await;
+        let ns = &tao.namespaces[DEFAULT_NAMESPACE].config;
+        let obj = tao.store.obj_get(ns, "1", Route::Primary).await.unwrap();
+        assert!(
+            matches!(obj, Some(DBRow::ObjRow { data, .. }) if data == "alice")
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_encrypted_pipeline() {
+        let tao = server(true, 1);
+        check_pipeline(&tao).await;
+        assert!(tao.cache.stats().obj_hits > 0);
+
+        let ns = &tao.namespaces[DEFAULT_NAMESPACE].config;
+        let stored = tao.store.obj_get(ns, "1", Route::Primary).await;
+        assert!(stored.unwrap().is_none(), "ids must be stored encrypted");
+    }
+
+    #[actix_web::test]
+    async fn test_sqlite_pipeline() {
+        let dir = TempDir::new("sqlite-pipeline");
+        let mut config = config(true, 0, &dir);
+        config.storage = StorageBackend::Sqlite;
+        config.sqlite_file = dir.join("tao.db");
+        check_pipeline(&TaoServer::new(config).unwrap()).await;
+    }
+
+    #[actix_web::test]
+    async fn test_admission() {
+        let dir = TempDir::new("admission");
+        let mut config = config(false, 0, &dir);
+        config.limits.max_batch_queries = 2;
+        config.limits.max_id_set = 2;
+        config.limits.max_range_limit = 10;
+        config.rate_limit_qps = 1;
+        config.rate_limit_burst = 3;
+        let store = Box::new(MemoryStore::new());
+        let tao = TaoServer::with_store(config, store).unwrap();
+
+        // Turned away before taking any tokens
+        assert_eq!(status(&tao, "OBJ GET 1; OBJ GET 2; OBJ GET 3;").await, 413);
+        assert_eq!(status(&tao, "ASSOC GET 1 LIKES [1, 2, 3];").await, 413);
+        assert_eq!(status(&tao, "ASSOC RANGE 1 LIKES 0 10 11;").await, 413);
+
+        assert_eq!(status(&tao, "OBJ GET 1; OBJ GET 2;").await, 200);
+        assert_eq!(status(&tao, "OBJ GET 1; OBJ GET 2;").await, 429);
+        assert_eq!(status(&tao, "OBJ GET 1;").await, 200);
+    }
+
+    #[actix_web::test]
+    async fn test_denied_writes_are_audited() {
+        let dir = TempDir::new("denied-audit");
+        let (path, policy) = (dir.join("audit.log"), dir.join("policy.json"));
+        let rules = r#"{ "test": { "ops": ["OBJ GET", "ASSOC ADD"],
+                                   "types": ["LIKES"] } }"#;
+        std::fs::write(&policy, rules).unwrap();
+        let mut tao = server(true, 0);
+        tao.policy = Policy::load(&policy).unwrap();
+        tao.audit = AuditLog::open(&path).unwrap();
+
+        // The times are outside the OPE range, the denials still win
+        let results = run(
+            &tao,
+            r#"ASSOC ADD 1 LIKES 2 70000 "x";
+               ASSOC ADD 1 FRIEND 2 99999999 "x"; OBJ ADD 1 USER "a";"#,
+        )
+        .await;
+        assert!(matches!(results[0], Err(TaoError::Validation(_))));
+        assert!(matches!(results[1], Err(TaoError::Forbidden(_))));
+        assert!(matches!(results[2], Err(TaoError::Forbidden(_))));
+        assert_eq!(audit::verify(&path).unwrap().0, 3);
+        let log = std::fs::read_to_string(&path).unwrap();
+        let records = log
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect::<Vec<serde_json::Value>>();
+        assert!(records[0]["args"].is_null());
+        assert!(records[1]["args"].is_null());
+        assert!(!records[2]["args"].is_null());
+        let errors = records
+            .iter()
+            .map(|r| r["error"].as_str().unwrap())
+            .collect::<Vec<&str>>();
+        assert_eq!(errors, ["Validation", "Forbidden", "Forbidden"]);
+        for plaintext in ["70000", "99999999", "FRIEND", "USER"] {
+            let found = records.iter().any(|r| {
+                r["args"].to_string().contains(plaintext)
+                    || r["error"].to_string().contains(plaintext)
+            });
+            assert!(!found, "{} in the audit log", plaintext);
+        }
+    }
+
+    #[derive(Clone)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_logs_hide_plaintext() {
+        let captured = Captured(Arc::new(Mutex::new(Vec::new())));
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .with_span_events(FmtSpan::CLOSE)
+            .with_ansi(false)
+            // Timestamps may hold any digits, the ids looked for included
+            .without_time()
+            .with_writer(move || writer.clone())
+            .finish();
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let tao = server(true, 0);
+        let results = run(
+            &tao,
+            r#"OBJ ADD 7071 USER "hunter2"; ASSOC ADD 7071 LIKES 8082 90 "x";
+               OBJ GET 7071; ASSOC GET 7071 LIKES [8082]; OBJ GET "#,
+        )
+        .await;
+        assert_eq!(ids(&results[2]), ["hunter2"]);
+        assert_eq!(ids(&results[3]), ["8082"]);
+
+        let logs = captured.0.lock().unwrap().clone();
+        let logs = String::from_utf8(logs).unwrap();
+        for span in ["parse", "query", "encrypt", "db", "decrypt"] {
+            let entered = [format!("{}:", span), format!("{}{{", span)];
+            assert!(entered.iter().any(|e| logs.contains(e)), "{}", logs);
+        }
+        assert!(logs.contains("error=\"Parse\""), "{}", logs);
+        for plaintext in ["hunter2", "7071", "8082"] {
+            assert!(!logs.contains(plaintext), "{} in {}", plaintext, logs);
+        }
     }
 }
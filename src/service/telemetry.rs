@@ -0,0 +1,341 @@
+/*
+ * File: telemetry.rs
+ *      Logging and tracing. Log lines are text or JSON on stdout, filtered
+ *      by LOG_LEVEL. Spans can also be exported in OpenTelemetry format,
+ *      over OTLP/gRPC to a collector, as OTLP JSON lines to a file, or both.
+ *
+ *      Each HTTP request and gRPC call gets a "request" span with a request
+ *      id, taken from the X-Request-Id header when the client sends one and
+ *      returned in the response either way. A W3C traceparent header makes
+ *      the span part of the caller's trace. Below it are the spans of the
+ *      pipeline: parse, query, encrypt, db and decrypt.
+ *
+ *      Spans and events carry operations, counts, namespaces and principals
+ *      but never ids, types or data, which are plaintext before encryption
+ *      and after decryption.
+ */
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    middleware::Next,
+};
+use futures::future::BoxFuture;
+use opentelemetry::{global, trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+use opentelemetry_proto::transform::common::tonic::ResourceAttributesWithSchema;
+use opentelemetry_proto::transform::trace::tonic::group_spans_by_resource_and_scope;
+use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::runtime::TokioCurrentThread;
+use opentelemetry_sdk::trace::TracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing::{info, info_span, Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{fmt, EnvFilter};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+use crate::service::config::{LogFormat, ServerConfig};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const TRACEPARENT_HEADER: &str = "traceparent";
+const TRACESTATE_HEADER: &str = "tracestate";
+const MAX_REQUEST_ID_LEN: usize = 128;
+const SERVICE_NAME: &str = "tao-server";
+
+/*
+ * Telemetry
+ *      Keeps the span exporters running, shutdown() flushes them.
+ */
+pub struct Telemetry {
+    provider: Option<TracerProvider>,
+}
+
+impl Telemetry {
+    /*
+     * shutdown()
+     *      Exports the spans still queued. The exporters may need the
+     *      runtime this is called from, so it must not block it.
+     */
+    pub async fn shutdown(self) {
+        let provider = match self.provider {
+            Some(provider) => provider,
+            None => return,
+        };
+        let flushed = tokio::task::spawn_blocking(move || provider.shutdown());
+        if let Ok(Err(e)) = flushed.await {
+            eprintln!("tao-server: exporting spans: {}", e);
+        }
+    }
+}
+
+/*
+ * init(config)
+ *      Installs the global subscriber, once per process.
+ */
+pub fn init(config: &ServerConfig) -> Result<Telemetry, String> {
+    let filter = EnvFilter::try_new(&config.log_level)
+        .map_err(|e| format!("LOG_LEVEL: {}", e))?;
+
+    let mut exporting = false;
+    let mut builder = TracerProvider::builder().with_resource(Resource::new([
+        KeyValue::new("service.name", SERVICE_NAME),
+    ]));
+    if let Some(endpoint) = &config.otlp_endpoint {
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build()
+            .map_err(|e| format!("OTEL_EXPORTER_OTLP_ENDPOINT: {}", e))?;
+        builder = builder.with_batch_exporter(exporter, TokioCurrentThread);
+        exporting = true;
+    }
+    if let Some(path) = &config.trace_file {
+        let exporter = FileExporter::create(path)?;
+        builder = builder.with_batch_exporter(exporter, TokioCurrentThread);
+        exporting = true;
+    }
+    let provider = match exporting {
+        true => Some(builder.build()),
+        false => None,
+    };
+    let spans = provider.as_ref().map(|provider| {
+        tracing_opentelemetry::layer()
+            .with_tracer(provider.tracer(SERVICE_NAME))
+    });
+    let (text, json) = match config.log_format {
+        LogFormat::Text => (Some(fmt::layer()), None),
+        LogFormat::Json => (None, Some(fmt::layer().json())),
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(text)
+        .with(json)
+        .with(spans)
+        .try_init()
+        .map_err(|e| format!("cannot set up logging: {}", e))?;
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    Ok(Telemetry { provider })
+}
+
+/*
+ * request_id(incoming)
+ *      The client's request id if it sent a usable one, a fresh one
+ *      otherwise.
+ */
+pub fn request_id(incoming: Option<&str>) -> String {
+    match incoming {
+        Some(id)
+            if !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LEN
+                && id.bytes().all(|b| b.is_ascii_graphic()) =>
+        {
+            id.to_string()
+        }
+        _ => uuid::Uuid::new_v4().simple().to_string(),
+    }
+}
+
+/*
+ * request_span(request_id, method, path, traceparent, tracestate)
+ *      The root span of a request, or a child of the caller's span when it
+ *      sent a W3C trace context.
+ */
+pub fn request_span(
+    request_id: &str,
+    method: &str,
+    path: &str,
+    traceparent: Option<&str>,
+    tracestate: Option<&str>,
+) -> Span {
+    let span = info_span!("request", request_id, method, path);
+    if let Some(traceparent) = traceparent {
+        let mut carrier = HashMap::new();
+        carrier.insert(TRACEPARENT_HEADER.to_string(), traceparent.to_string());
+        if let Some(tracestate) = tracestate {
+            carrier
+                .insert(TRACESTATE_HEADER.to_string(), tracestate.to_string());
+        }
+        let parent =
+            global::get_text_map_propagator(|prop| prop.extract(&carrier));
+        span.set_parent(parent);
+    }
+    span
+}
+
+/*
+ * grpc_request_span(request)
+ *      request_span() of a gRPC call, from its HTTP/2 request.
+ */
+pub fn grpc_request_span<B>(
+    request: &tonic::codegen::http::Request<B>,
+) -> Span {
+    let header =
+        |name: &str| request.headers().get(name).and_then(|v| v.to_str().ok());
+    request_span(
+        &request_id(header(REQUEST_ID_HEADER)),
+        request.method().as_str(),
+        request.uri().path(),
+        header(TRACEPARENT_HEADER),
+        header(TRACESTATE_HEADER),
+    )
+}
+
+/*
+ * http_request_span(req, next)
+ *      Middleware running each HTTP request in its request span, and
+ *      answering with its request id.
+ */
+pub async fn http_request_span(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let header =
+        |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok());
+    let id = request_id(header(REQUEST_ID_HEADER));
+    let span = request_span(
+        &id,
+        req.method().as_str(),
+        req.path(),
+        header(TRACEPARENT_HEADER),
+        header(TRACESTATE_HEADER),
+    );
+    let mut res = next.call(req).instrument(span.clone()).await?;
+    info!(parent: &span, status = res.status().as_u16(), "request finished");
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        res.headers_mut()
+            .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+    Ok(res)
+}
+
+/*
+ * FileExporter
+ *      Appends each batch of spans to a file as one line of OTLP JSON, the
+ *      format of the collector's file exporter.
+ */
+#[derive(Debug)]
+struct FileExporter {
+    file: File,
+    resource: ResourceAttributesWithSchema,
+}
+
+impl FileExporter {
+    fn create(path: &Path) -> Result<Self, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("TRACE_FILE {}: {}", path.display(), e))?;
+        Ok(FileExporter {
+            file,
+            resource: ResourceAttributesWithSchema::default(),
+        })
+    }
+
+    fn write(&mut self, batch: Vec<SpanData>) -> Result<(), String> {
+        let request = ExportTraceServiceRequest {
+            resource_spans: group_spans_by_resource_and_scope(
+                batch,
+                &self.resource,
+            ),
+        };
+        let line =
+            serde_json::to_string(&request).map_err(|e| e.to_string())?;
+        writeln!(self.file, "{}", line).map_err(|e| e.to_string())
+    }
+}
+
+impl SpanExporter for FileExporter {
+    fn export(
+        &mut self,
+        batch: Vec<SpanData>,
+    ) -> BoxFuture<'static, ExportResult> {
+        let res = self.write(batch).map_err(Into::into);
+        Box::pin(std::future::ready(res))
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.resource = resource.into();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use opentelemetry::{global, trace::TracerProvider as _};
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use opentelemetry_sdk::trace::TracerProvider;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::{request_id, request_span, FileExporter};
+    use crate::service::testing::TempDir;
+
+    #[test]
+    fn test_request_id() {
+        assert_eq!(request_id(Some("req-42")), "req-42");
+        for bad in [None, Some(""), Some("two words"), Some(&"x".repeat(200))] {
+            let id = request_id(bad);
+            assert_eq!(id.len(), 32);
+            assert_ne!(Some(id.as_str()), bad);
+        }
+        assert_ne!(request_id(None), request_id(None));
+    }
+
+    #[test]
+    fn test_file_exporter() {
+        let dir = TempDir::new("trace");
+        let path = dir.join("trace.jsonl");
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(FileExporter::create(&path).unwrap())
+            .build();
+        let subscriber = tracing_subscriber::registry().with(
+            tracing_opentelemetry::layer().with_tracer(provider.tracer("test")),
+        );
+        tracing::subscriber::with_default(subscriber, || {
+            let traceparent =
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+            let _request = request_span(
+                "req-7",
+                "POST",
+                "/query",
+                Some(traceparent),
+                None,
+            )
+            .entered();
+            let _parse = tracing::info_span!("parse", queries = 2).entered();
+        });
+        provider.shutdown().unwrap();
+
+        let lines = fs::read_to_string(&path).unwrap();
+        let spans = lines
+            .lines()
+            .flat_map(|line| {
+                let export: serde_json::Value =
+                    serde_json::from_str(line).unwrap();
+                export["resourceSpans"][0]["scopeSpans"][0]["spans"]
+                    .as_array()
+                    .unwrap()
+                    .clone()
+            })
+            .collect::<Vec<serde_json::Value>>();
+        assert_eq!(spans.len(), 2);
+        for span in &spans {
+            assert_eq!(span["traceId"], "0af7651916cd43dd8448eb211c80319c");
+        }
+        let request = spans.iter().find(|s| s["name"] == "request").unwrap();
+        assert_eq!(request["parentSpanId"], "b7ad6b7169203331");
+        assert!(request["attributes"].to_string().contains("req-7"));
+        let parse = spans.iter().find(|s| s["name"] == "parse").unwrap();
+        assert_eq!(parse["parentSpanId"], request["spanId"]);
+    }
+}
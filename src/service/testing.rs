@@ -0,0 +1,113 @@
+/*
+ * File: testing.rs
+ *      Fixtures shared by the service tests: temporary directories, server
+ *      configs and servers on the in-memory store, with generated key files
+ *      when encrypted.
+ */
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use clap::Parser;
+
+use crate::query::crypto::CryptKeys;
+use crate::service::config::{ServerArgs, ServerConfig};
+use crate::service::store::MemoryStore;
+use crate::service::tao::TaoServer;
+
+/// The contents of an OPE key file and an AES keyset file
+type KeyFiles = (Vec<u8>, Vec<u8>);
+
+/// The key files generated per name
+static KEYS: Mutex<BTreeMap<String, KeyFiles>> = Mutex::new(BTreeMap::new());
+
+/*
+ * TempDir
+ *      A directory of a test's own under the system's temp dir, named
+ *      after the test and unique per process and call. It is removed with
+ *      everything in it when dropped, so tests running at the same time
+ *      never share files and leave none behind.
+ */
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "tao-{}-{}-{}",
+            name,
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, file: &str) -> PathBuf {
+        self.0.join(file)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/*
+ * key_files(keys, dir)
+ *      Writes the OPE key and AES keyset named `keys` into `dir`, generated
+ *      on first use. Servers given the same name share their keys.
+ */
+pub fn key_files(keys: &str, dir: &TempDir) -> (PathBuf, PathBuf) {
+    let ope = dir.join(&format!("{}-ope.key", keys));
+    let aes = dir.join(&format!("{}-aes.json", keys));
+    let mut generated = KEYS.lock().unwrap_or_else(|e| e.into_inner());
+    let (ope_key, aes_keyset) =
+        generated.entry(keys.to_string()).or_insert_with(|| {
+            let fresh = TempDir::new("keygen");
+            let (ope, aes) = (fresh.join("ope.key"), fresh.join("aes.json"));
+            CryptKeys::generate().write(&ope, &aes).unwrap();
+            (fs::read(&ope).unwrap(), fs::read(&aes).unwrap())
+        });
+    fs::write(&ope, ope_key).unwrap();
+    fs::write(&aes, aes_keyset).unwrap();
+    (ope, aes)
+}
+
+/*
+ * config(keys, dir)
+ *      A config for the in-memory store, encrypted with the keys named
+ *      `keys` if given, whose files are written into `dir`. The files are
+ *      read when the server is built, so `dir` only needs to last till then.
+ */
+pub fn config(keys: Option<&str>, dir: &TempDir) -> ServerConfig {
+    let mut args = ServerArgs::parse_from([
+        "tao-server",
+        "--encryption=off",
+        "--db-host=localhost",
+        "--db-user=tao",
+        "--db-name=tao",
+    ]);
+    args.db_password = Some("unused".to_string());
+    let mut config = ServerConfig::from_args(args).unwrap();
+    if let Some(keys) = keys {
+        let (ope, aes) = key_files(keys, dir);
+        config.encrypted = true;
+        config.ope_key_file = Some(ope);
+        config.aes_keyset_file = Some(aes);
+    }
+    config
+}
+
+pub fn server(keys: Option<&str>) -> TaoServer {
+    let dir = TempDir::new("keys");
+    let config = config(keys, &dir);
+    TaoServer::with_store(config, Box::new(MemoryStore::new())).unwrap()
+}
@@ -0,0 +1,198 @@
+/*
+ * File: tier.rs
+ *      Leader and follower tiers. Followers answer reads from their own
+ *      cache and forward writes to the leader. The leader applies writes to
+ *      Postgres and tells every follower which cache entries went stale.
+ *
+ *      Writes travel already encrypted and invalidations name ciphertext
+ *      keys, so no plaintext crosses between tiers. Tiers must therefore
+ *      share their namespaces and keys. Both tier endpoints require the
+ *      shared TIER_TOKEN.
+ */
+use std::time::Duration;
+
+use actix_web::{http::header::AUTHORIZATION, HttpRequest};
+use awc::Client;
+use clap::ValueEnum;
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::query::error::TaoError;
+use crate::query::query::Query;
+use crate::service::cache::Invalidation;
+use crate::service::tao::QueryResult;
+
+const TIER_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TierRole {
+    /// Applies writes, a standalone server is a leader without followers
+    Leader,
+    /// Serves reads and forwards writes to the leader
+    Follower,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WriteRequest {
+    pub namespace: String,
+    pub query: Query,
+}
+
+pub struct Tier {
+    pub role: TierRole,
+    leader_url: Option<String>,
+    follower_urls: Vec<String>,
+    token: Option<String>,
+}
+
+impl Tier {
+    pub fn new(
+        role: TierRole,
+        leader_url: Option<String>,
+        follower_urls: Vec<String>,
+        token: Option<String>,
+    ) -> Self {
+        let trim = |url: String| url.trim_end_matches('/').to_string();
+        Tier {
+            role,
+            leader_url: leader_url.map(trim),
+            follower_urls: follower_urls.into_iter().map(trim).collect(),
+            token,
+        }
+    }
+
+    /*
+     * client()
+     *      awc clients are not Send, so one is made per call instead of
+     *      being kept in TaoServer.
+     */
+    fn client(&self) -> Client {
+        let builder = Client::builder().timeout(TIER_TIMEOUT);
+        match &self.token {
+            Some(token) => builder.bearer_auth(token).finish(),
+            None => builder.finish(),
+        }
+    }
+
+    pub fn authorized(&self, req: &HttpRequest) -> bool {
+        let expected = match &self.token {
+            Some(token) => format!("Bearer {}", token),
+            None => return false,
+        };
+        match req.headers().get(AUTHORIZATION) {
+            Some(value) => {
+                let value = value.as_bytes();
+                value.len() == expected.len()
+                    && openssl::memcmp::eq(value, expected.as_bytes())
+            }
+            None => false,
+        }
+    }
+
+    /*
+     * forward_write(namespace, query)
+     *      Runs a write on the leader, on behalf of a follower.
+     */
+    pub async fn forward_write(
+        &self,
+        namespace: &str,
+        query: Query,
+    ) -> QueryResult {
+        let leader_url = match &self.leader_url {
+            Some(url) => url,
+            None => {
+                return Err(TaoError::Database(
+                    "no leader configured".to_string(),
+                ))
+            }
+        };
+        let url = format!("{}/tier/write", leader_url);
+        let request = WriteRequest {
+            namespace: namespace.to_string(),
+            query,
+        };
+        let mut resp =
+            self.client().post(&url).send_json(&request).await.map_err(
+                |e| TaoError::Database(format!("cannot reach leader: {}", e)),
+            )?;
+        if !resp.status().is_success() {
+            return Err(TaoError::Database(format!(
+                "leader answered {}",
+                resp.status()
+            )));
+        }
+        resp.json::<QueryResult>().await.map_err(|e| {
+            TaoError::Database(format!("invalid answer from leader: {}", e))
+        })?
+    }
+
+    /*
+     * broadcast(invalidation)
+     *      Waits for every follower, so that once a write returns no
+     *      follower serves what it replaced. Unreachable followers are
+     *      logged and skipped.
+     */
+    pub async fn broadcast(&self, invalidation: &Invalidation) {
+        if self.follower_urls.is_empty() {
+            return;
+        }
+        let client = self.client();
+        let sends = self.follower_urls.iter().map(|follower| {
+            let url = format!("{}/tier/invalidate", follower);
+            let client = &client;
+            async move {
+                match client.post(&url).send_json(invalidation).await {
+                    Ok(resp) if resp.status().is_success() => (),
+                    Ok(resp) => warn!(
+                        follower = %follower,
+                        status = resp.status().as_u16(),
+                        "invalidation failed"
+                    ),
+                    Err(e) => warn!(
+                        follower = %follower,
+                        error = %e,
+                        "invalidation failed"
+                    ),
+                }
+            }
+        });
+        join_all(sends).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Tier, TierRole};
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_authorized() {
+        let tier = Tier::new(
+            TierRole::Leader,
+            None,
+            vec!["http://localhost:8081/".to_string()],
+            Some("s3cret".to_string()),
+        );
+        assert_eq!(tier.follower_urls, vec!["http://localhost:8081"]);
+
+        let req = TestRequest::default()
+            .insert_header(("Authorization", "Bearer s3cret"))
+            .to_http_request();
+        assert!(tier.authorized(&req));
+        let req = TestRequest::default()
+            .insert_header(("Authorization", "Bearer guess"))
+            .to_http_request();
+        assert!(!tier.authorized(&req));
+        assert!(!tier.authorized(&TestRequest::default().to_http_request()));
+    }
+
+    #[test]
+    fn test_no_token_refuses_everything() {
+        let tier = Tier::new(TierRole::Follower, None, vec![], None);
+        let req = TestRequest::default()
+            .insert_header(("Authorization", "Bearer "))
+            .to_http_request();
+        assert!(!tier.authorized(&req));
+    }
+}
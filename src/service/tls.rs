@@ -0,0 +1,113 @@
+/*
+ * File: tls.rs
+ *      TLS setup for the HTTP listener, the HTTP clients and the Postgres
+ *      connection, all backed by openssl
+ */
+use std::path::Path;
+
+use clap::ValueEnum;
+use openssl::ssl::{
+    SslAcceptor, SslAcceptorBuilder, SslConnector, SslConnectorBuilder,
+    SslFiletype, SslMethod, SslVerifyMode,
+};
+use openssl::x509::{store::X509StoreBuilder, X509};
+use postgres_openssl::MakeTlsConnector;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DBSslMode {
+    /// Plain TCP
+    Disable,
+    /// Encrypt, but accept any server certificate
+    Require,
+    /// Encrypt and verify the certificate chain and host name
+    VerifyFull,
+}
+
+fn tls_error(path: &Path, e: impl std::fmt::Display) -> String {
+    format!("{}: {}", path.display(), e)
+}
+
+/*
+ * server_acceptor(cert_file, key_file, client_ca_file)
+ *      With a client CA, clients are asked for a certificate issued by it.
+ *      Presenting one stays optional, callers may use a token instead.
+ */
+pub fn server_acceptor(
+    cert_file: &Path,
+    key_file: &Path,
+    client_ca_file: Option<&Path>,
+) -> Result<SslAcceptorBuilder, String> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())
+        .map_err(|e| e.to_string())?;
+    builder
+        .set_certificate_chain_file(cert_file)
+        .map_err(|e| tls_error(cert_file, e))?;
+    builder
+        .set_private_key_file(key_file, SslFiletype::PEM)
+        .map_err(|e| tls_error(key_file, e))?;
+    builder.check_private_key().map_err(|e| {
+        format!(
+            "{} does not match {}: {}",
+            key_file.display(),
+            cert_file.display(),
+            e
+        )
+    })?;
+    if let Some(ca_file) = client_ca_file {
+        builder
+            .set_ca_file(ca_file)
+            .map_err(|e| tls_error(ca_file, e))?;
+        builder.set_verify(SslVerifyMode::PEER);
+    }
+    Ok(builder)
+}
+
+/*
+ * pinned_connector(ca_file)
+ *      With a CA file only certificates issued by that CA are trusted, the
+ *      system roots are ignored. Without one the system roots are used.
+ */
+pub fn pinned_connector(
+    ca_file: Option<&Path>,
+) -> Result<SslConnectorBuilder, String> {
+    let mut builder =
+        SslConnector::builder(SslMethod::tls()).map_err(|e| e.to_string())?;
+    if let Some(ca_file) = ca_file {
+        let pem = std::fs::read(ca_file).map_err(|e| tls_error(ca_file, e))?;
+        let certs =
+            X509::stack_from_pem(&pem).map_err(|e| tls_error(ca_file, e))?;
+        if certs.is_empty() {
+            return Err(tls_error(ca_file, "no certificates found"));
+        }
+        let mut store = X509StoreBuilder::new().map_err(|e| e.to_string())?;
+        for cert in certs {
+            store.add_cert(cert).map_err(|e| tls_error(ca_file, e))?;
+        }
+        builder.set_cert_store(store.build());
+    }
+    Ok(builder)
+}
+
+/*
+ * db_connector(ssl_mode, root_cert)
+ *      Whether TLS is used at all is decided by the ssl mode set on the
+ *      connection config, see DBConfig::pg_config.
+ */
+pub fn db_connector(
+    ssl_mode: DBSslMode,
+    root_cert: Option<&Path>,
+) -> Result<MakeTlsConnector, String> {
+    if ssl_mode != DBSslMode::Require {
+        return Ok(MakeTlsConnector::new(pinned_connector(root_cert)?.build()));
+    }
+
+    let mut builder =
+        SslConnector::builder(SslMethod::tls()).map_err(|e| e.to_string())?;
+    builder.set_verify(SslVerifyMode::NONE);
+    let mut connector = MakeTlsConnector::new(builder.build());
+    connector.set_callback(|config, _| {
+        config.set_verify_hostname(false);
+        Ok(())
+    });
+    Ok(connector)
+}